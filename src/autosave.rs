@@ -0,0 +1,65 @@
+use std::time::Instant;
+
+use crate::{commands, AppMode, AppState, Message};
+
+// When the current sheet is written without :w, from the autosave option. It
+// takes a comma separated list of
+//
+//   interval     when a change is autosaveinterval seconds old
+//   modechange   when going back to Normal mode, e.g. after editing a cell
+//   focus        when the terminal loses focus, e.g. when switching windows
+//
+// Only sheets with a file which aren't read-only are written, the others
+// would need a name or a confirmation.
+pub const EVENTS: &[&str] = &["interval", "modechange", "focus"];
+
+#[derive(Default)]
+pub struct Autosave {
+    changed_since: Option<Instant>, // When changes were first seen after the last write
+}
+
+fn enabled(state: &AppState, event: &str) -> bool {
+    state.options.autosave.split(',').any(|e| e == event)
+}
+
+// Called every second by the main loop. Returns whether the file was
+// written, which changes the status line.
+pub fn tick(state: &mut AppState) -> bool {
+    if !state.is_modified() {
+        state.autosave.changed_since = None;
+        return false;
+    }
+    let since = *state.autosave.changed_since.get_or_insert_with(Instant::now);
+    if !enabled(state, "interval") || since.elapsed().as_secs() < state.options.autosaveinterval as u64 {
+        return false;
+    }
+    save(state)
+}
+
+// Called after every key, with the mode before it
+pub fn mode_changed(state: &mut AppState, before: AppMode) {
+    if before != state.mode && state.mode == AppMode::Normal && enabled(state, "modechange") {
+        save(state);
+    }
+}
+
+// Returns whether the file was written like tick
+pub fn focus_lost(state: &mut AppState) -> bool {
+    enabled(state, "focus") && save(state)
+}
+
+// Returns whether writing was tried
+fn save(state: &mut AppState) -> bool {
+    if !state.is_modified() || state.file.is_none() || state.options.readonly {
+        return false;
+    }
+    // A message which is shown stays, unless writing failed
+    let message = state.message.take();
+    let result = commands::execute(state, "write");
+    state.autosave.changed_since = None;
+    match result {
+        Ok(()) => state.message = message,
+        Err(e) => state.message = Some(Message::Error(format!("Autosave failed: {}", e))),
+    }
+    true
+}
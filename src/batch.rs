@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use crate::{commands, AppState, Message, Result, VispError};
+
+// Runs a file of commands without a terminal, e.g.
+//
+//   visp --batch clean.visp data.csv -o clean.csv
+//
+// with clean.visp containing
+//
+//   " Lines starting with " are comments
+//   %s/n.a//
+//   sort! C
+//   source helpers.rhai
+//
+// Each line is run like after :, the first error stops the batch. Messages
+// go to stderr and text shown in the pager, e.g. from :messages, to stdout.
+// The config file and init.rhai are read before, so aliases, options and
+// script functions are there. Start with --noconfig for a batch which does
+// the same everywhere.
+pub fn run(state: &mut AppState, script: &Path, input: Option<&str>, output: Option<&str>) -> Result<()> {
+    let text = std::fs::read_to_string(script)?;
+    if let Some(input) = input {
+        commands::execute(state, &format!("edit {}", input))?;
+        report(state);
+    }
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('"') {
+            continue;
+        }
+        tracing::debug!(command = line, "batch");
+        commands::execute(state, line)
+            .map_err(|e| VispError::Command(format!("{}:{}: {}", script.display(), number + 1, e)))?;
+        report(state);
+        if state.quit {
+            return Ok(());
+        }
+    }
+    if let Some(output) = output {
+        commands::execute(state, &format!("write {}", output))?;
+        report(state);
+    }
+    Ok(())
+}
+
+// What would be shown on screen after a command
+fn report(state: &mut AppState) {
+    match state.message.take() {
+        Some(Message::Info(text)) => {
+            tracing::info!("{}", text);
+            eprintln!("{}", text);
+        }
+        Some(Message::Error(text)) => {
+            tracing::warn!("{}", text);
+            eprintln!("{}", text);
+        }
+        None => {}
+    }
+    if let Some(pager) = state.pager.take() {
+        for line in pager.lines {
+            println!("{}", line);
+        }
+    }
+    state.picker = None;
+}
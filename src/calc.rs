@@ -0,0 +1,83 @@
+use crate::{format, formula, AppState, Message, VispError};
+use crate::edit::LineBuffer;
+
+// Expressions kept in the pane and for Up and Down
+const HISTORY_SIZE: usize = 100;
+
+// The pane opened with :calc. What is typed is a formula without the =,
+// evaluated on the current sheet without taking up a cell, e.g.
+// AVG(C2:C500) - C1. The entries stay while visp runs.
+#[derive(Default)]
+pub struct Calc {
+    pub open: bool,
+    pub line: LineBuffer,
+    pub entries: Vec<(String, String)>, // Expression and result, oldest first
+    browsing: Option<(usize, String)>, // Entry shown and the text typed before
+}
+
+impl Calc {
+    // Up goes to older expressions, Down back to newer ones and at last to
+    // the text which was typed
+    pub fn browse(&mut self, older: bool) {
+        let (index, typed) = match self.browsing.take() {
+            Some((index, typed)) => (Some(index), typed),
+            None => (None, self.line.text.clone()),
+        };
+        let index = match (index, older) {
+            (None, true) => self.entries.len().checked_sub(1),
+            (None, false) => return,
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (Some(index), false) => Some(index + 1).filter(|&i| i < self.entries.len()),
+        };
+        match index {
+            Some(index) => {
+                self.line = LineBuffer::new(self.entries[index].0.clone());
+                self.browsing = Some((index, typed));
+            }
+            None => self.line = LineBuffer::new(typed),
+        }
+    }
+}
+
+// The result of an expression as shown in the pane, errors like in cells
+pub fn evaluate(state: &AppState, expression: &str) -> String {
+    let source = expression.trim().strip_prefix('=').unwrap_or(expression.trim());
+    match formula::evaluate_source(&state.table_content, source) {
+        Ok(value) => format::format_number(value),
+        Err(VispError::Formula(text)) => text,
+        Err(e) => e.to_string(),
+    }
+}
+
+// Enter in the pane
+pub fn submit(state: &mut AppState) {
+    let expression = std::mem::take(&mut state.calc.line).text;
+    state.calc.browsing = None;
+    if expression.trim().is_empty() {
+        return;
+    }
+    formula::refresh_all(&mut state.table_content);
+    let result = evaluate(state, &expression);
+    add(state, expression, result);
+}
+
+// :calc with an expression shows the result without opening the pane
+pub fn run(state: &mut AppState, expression: &str) {
+    let result = evaluate(state, expression);
+    state.message = Some(Message::Info(format!("{} = {}", expression.trim(), result)));
+    add(state, expression.trim().to_string(), result);
+}
+
+fn add(state: &mut AppState, expression: String, result: String) {
+    let entries = &mut state.calc.entries;
+    entries.push((expression, result));
+    if entries.len() > HISTORY_SIZE {
+        entries.remove(0);
+    }
+}
+
+pub fn close(state: &mut AppState) {
+    state.calc.open = false;
+    state.calc.line = LineBuffer::default();
+    state.calc.browsing = None;
+}
@@ -0,0 +1,117 @@
+use crate::AppMode;
+use crate::edit::LineBuffer;
+
+// Number of commands or searches kept for Up and Down
+const HISTORY_SIZE: usize = 100;
+
+// What the line is typed for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Prompt {
+    Command,
+    Search { forward: bool, mode: AppMode }, // The mode to go back to afterwards
+}
+
+impl Prompt {
+    pub fn symbol(&self) -> char {
+        match self {
+            Self::Command => ':',
+            Self::Search { forward: true, .. } => '/',
+            Self::Search { forward: false, .. } => '?',
+        }
+    }
+}
+
+// The line after : or / with the commands and searches entered before
+pub struct CommandLine {
+    pub line: LineBuffer,
+    pub prompt: Prompt,
+    pub history: Vec<String>, // Oldest first, without duplicates
+    pub search_history: Vec<String>, // Like history, for / and ?
+    browsing: Option<(usize, String)>, // History index shown and the text typed before
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self {
+            line: LineBuffer::default(),
+            prompt: Prompt::Command,
+            history: Vec::new(),
+            search_history: Vec::new(),
+            browsing: None,
+        }
+    }
+}
+
+impl CommandLine {
+    pub fn start(&mut self, text: &str) {
+        self.line = LineBuffer::new(text.to_string());
+        self.prompt = Prompt::Command;
+        self.browsing = None;
+    }
+
+    pub fn start_search(&mut self, forward: bool, mode: AppMode) {
+        self.line = LineBuffer::default();
+        self.prompt = Prompt::Search { forward, mode };
+        self.browsing = None;
+    }
+
+    fn history(&mut self) -> &mut Vec<String> {
+        match self.prompt {
+            Prompt::Command => &mut self.history,
+            Prompt::Search { .. } => &mut self.search_history,
+        }
+    }
+
+    // Takes the line to run it and remembers it
+    pub fn submit(&mut self) -> String {
+        let command = std::mem::take(&mut self.line).text;
+        self.browsing = None;
+        if !command.trim().is_empty() {
+            let history = self.history();
+            history.retain(|c| *c != command);
+            history.push(command.clone());
+            if history.len() > HISTORY_SIZE {
+                history.remove(0);
+            }
+        }
+        command
+    }
+
+    // Like in vim only commands starting with the typed text are shown
+    pub fn older(&mut self) {
+        let (index, prefix) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => (self.history().len(), self.line.text.clone()),
+        };
+        let history = self.history();
+        let index = match history[..index].iter().rposition(|c| c.starts_with(&prefix)) {
+            Some(older) => {
+                self.line = LineBuffer::new(history[older].clone());
+                older
+            }
+            None => index,
+        };
+        self.browsing = Some((index, prefix));
+    }
+
+    // Past the newest command the typed text comes back
+    pub fn newer(&mut self) {
+        let (index, prefix) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => return,
+        };
+        let newer = self.history().iter().enumerate().skip(index + 1).find(|(_, c)| c.starts_with(&prefix));
+        match newer {
+            Some((newer, command)) => {
+                self.line = LineBuffer::new(command.clone());
+                self.browsing = Some((newer, prefix));
+            }
+            None => self.line = LineBuffer::new(prefix),
+        }
+    }
+
+    // Typing makes the line the new starting point for Up
+    pub fn stop_browsing(&mut self) {
+        self.browsing = None;
+    }
+}
@@ -0,0 +1,1814 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::{AppState, AppMode, Message, Pager, Result, VispError};
+use crate::picker::{Picker, PickerKind};
+use crate::grid::{cell_name, col_label_to_nr, parse_cell_name, Axis, CellStyle, Selection, TableCell, TableContent};
+use crate::theme::{Theme, COLOR_SCHEMES};
+use crate::serve::Server;
+use crate::{calc, csv, diff, edit, fetch, fill, format, formula, goalseek, input, lock, merge, oldfiles, print, register, script, search, share, sheet, sort, structure, swap, task, undo, window, workbook};
+use crate::format::{CellFormat, Locale};
+use crate::undo::{Change, FormatChange, StyleChange};
+use crate::keymap::{self, Keymap, PRESETS};
+use crate::register::PasteSpecial;
+use crate::task::Progress;
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub short: &'static str, // Shortest abbreviation, like :se for :set in vim
+    pub args: &'static str, // Usage of the arguments, empty if there are none
+    pub description: &'static str,
+    pub run: fn(&mut AppState, &Args) -> Result<()>,
+}
+
+// What a command is called with
+pub struct Args<'a> {
+    pub range: Selection, // Given before the name, otherwise the selection
+    pub bang: bool, // The name ended with !, e.g. :q!
+    pub text: &'a str, // Everything after the name
+}
+
+// All commands, in the order of the command palette. New commands only need
+// an entry here.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo { name: "quit", short: "q", args: "", description: "Quit VISP, quit! throws away unsaved changes", run: quit },
+    CommandInfo { name: "edit", short: "e", args: "[file]", description: "Open a CSV, XLSX or ODS file, edit! throws away unsaved changes", run: edit },
+    CommandInfo { name: "write", short: "w", args: "[file]", description: "Save the table as CSV, or all sheets as XLSX or ODS, write! asks before replacing a read-only file and writes a file open in another visp", run: |state, args| {
+        if ask_readonly(state, args, "write")? {
+            return Ok(());
+        }
+        write(state, args.text, args.bang)
+    } },
+    CommandInfo { name: "recover", short: "rec", args: "", description: "Bring back the unsaved changes from the swap file left by a crash", run: |state, _| swap::recover(state) },
+    CommandInfo { name: "wq", short: "wq", args: "[file]", description: "Save the table and quit", run: write_quit },
+    CommandInfo { name: "undo", short: "u", args: "", description: "Undo the last change", run: |state, _| {
+        undo::undo(state, 1);
+        Ok(())
+    } },
+    CommandInfo { name: "redo", short: "red", args: "", description: "Redo the last undone change", run: |state, _| {
+        undo::redo(state, 1);
+        Ok(())
+    } },
+    CommandInfo { name: "undolist", short: "undol", args: "", description: "List the changes which can be undone and redone", run: |state, _| {
+        state.pager = Some(Pager { title: "Undo history".to_string(), lines: state.undo.list() });
+        Ok(())
+    } },
+    CommandInfo { name: "registers", short: "reg", args: "", description: "Show what the registers for y, d and p hold", run: |state, _| {
+        state.pager = Some(Pager { title: "Registers".to_string(), lines: state.registers.lines() });
+        Ok(())
+    } },
+    CommandInfo { name: "pastespecial", short: "pastes", args: "[all|formulas|values|formats] [transpose] [\"x]", description: "Put a yanked block with its formats, as values, only its formats or transposed, pastespecial! before the cursor", run: paste_special },
+    CommandInfo { name: "sheet", short: "sh", args: "[new [name]|rename name|delete|name|number]", description: "Switch to another sheet, add one, or rename or delete this one", run: sheet },
+    CommandInfo { name: "badd", short: "bad", args: "file", description: "Open a CSV file in a new sheet named after it, next to the others", run: |state, args| add_file(state, Path::new(args.text)) },
+    CommandInfo { name: "ls", short: "ls", args: "", description: "List the sheets with their files, + marks unsaved changes", run: |state, _| {
+        state.pager = Some(Pager { title: "Sheets".to_string(), lines: sheet::list(state) });
+        Ok(())
+    } },
+    CommandInfo { name: "bnext", short: "bn", args: "[count]", description: "Go to the next sheet, like gt", run: |state, args| {
+        let index = (state.sheet + parse_count(args.text)? as usize) % state.sheets.len();
+        sheet::show(state, index);
+        Ok(())
+    } },
+    CommandInfo { name: "bprevious", short: "bp", args: "[count]", description: "Go to the previous sheet, like gT", run: |state, args| {
+        sheet::next(state, false, Some(parse_count(args.text)? as u32));
+        Ok(())
+    } },
+    CommandInfo { name: "goto", short: "go", args: "cell|row", description: "Move the cursor to a cell like B12 or to a row", run: goto },
+    CommandInfo { name: "intro", short: "intro", args: "", description: "Show the start screen with the most important keys", run: |state, _| {
+        state.pager = Some(intro(state));
+        Ok(())
+    } },
+    CommandInfo { name: "oldfiles", short: "ol", args: "", description: "Pick one of the recently opened files to open it", run: |state, _| {
+        let files: Vec<PathBuf> = state.oldfiles.clone().unwrap_or_default();
+        if files.is_empty() {
+            return Err(VispError::Command("No recent files".to_string()));
+        }
+        let items = files.iter().map(|file| file.display().to_string()).collect();
+        state.picker = Some(Picker::new("Recent files", items, PickerKind::File(files)));
+        Ok(())
+    } },
+    CommandInfo { name: "fetch", short: "fet", args: "[command]", description: "Fill the sheet with the CSV a shell command prints, :set refresh=60s runs it again", run: |state, args| fetch::fetch(state, args.text) },
+    CommandInfo { name: "entry", short: "ent", args: "", description: "Type into the cells of the range one after another, Enter goes down and Tab right", run: |state, args| edit::start_entry(state, args.range) },
+    CommandInfo { name: "explore", short: "ex", args: "[directory]", description: "Pick a CSV file in a directory, new files show up while it is open", run: explore },
+    CommandInfo { name: "set", short: "se", args: "option[=value]", description: "Change or show an option", run: set },
+    CommandInfo { name: "colorscheme", short: "colo", args: "[name]", description: "Change the colors or show the current scheme", run: colorscheme },
+    CommandInfo { name: "profile", short: "prof", args: "start|stop|report", description: "Measure how long drawing, input and commands take", run: profile },
+    CommandInfo { name: "memory", short: "memory", args: "", description: "Show roughly how much memory cells, caches and history use", run: memory },
+    CommandInfo { name: "print", short: "print", args: "file", description: "Write the table as a paginated plain text report", run: print },
+    CommandInfo { name: "serve", short: "serve", args: "[address:]port", description: "Show the table as a web page which reloads itself", run: serve },
+    CommandInfo { name: "host", short: "host", args: "[address:]port", description: "Share the current sheet, another visp can :join it and edit it with you", run: |state, args| {
+        if args.text.is_empty() {
+            return Err(VispError::Command("Usage: host [address:]port".to_string()));
+        }
+        // Like :serve only reachable from this machine unless an address is given
+        let address = if args.text.contains(':') { args.text.to_string() } else { format!("127.0.0.1:{}", args.text) };
+        share::host(state, &address)
+    } },
+    CommandInfo { name: "join", short: "join", args: "[address:]port", description: "Edit the sheet shared with :host in a new sheet", run: |state, args| {
+        if args.text.is_empty() {
+            return Err(VispError::Command("Usage: join [address:]port".to_string()));
+        }
+        let address = if args.text.contains(':') { args.text.to_string() } else { format!("127.0.0.1:{}", args.text) };
+        share::join(state, &address)
+    } },
+    CommandInfo { name: "leave", short: "leave", args: "", description: "Stop sharing the sheet of :host or :join", run: |state, _| share::leave(state) },
+    CommandInfo { name: "source", short: "so", args: "file", description: "Run a Rhai script which adds formula functions and commands", run: source },
+    CommandInfo { name: "keymap", short: "keymap", args: "[preset]", description: "Switch to other key bindings, e.g. for colemak, or show the current ones", run: keymap },
+    CommandInfo { name: "messages", short: "mes", args: "", description: "Show the message log", run: |state, _| {
+        state.pager = Some(Pager { title: "Messages".to_string(), lines: state.log.lines() });
+        Ok(())
+    } },
+    CommandInfo { name: "split", short: "sp", args: "", description: "Split the window, both show the sheet with their own cursor", run: |state, _| {
+        window::split(state, false);
+        Ok(())
+    } },
+    CommandInfo { name: "vsplit", short: "vs", args: "", description: "Split the window side by side", run: |state, _| {
+        window::split(state, true);
+        Ok(())
+    } },
+    CommandInfo { name: "close", short: "clo", args: "", description: "Close the current window", run: |state, _| window::close(state) },
+    CommandInfo { name: "only", short: "on", args: "", description: "Close all windows but the current one", run: |state, _| {
+        window::only(state);
+        Ok(())
+    } },
+    CommandInfo { name: "selections", short: "sel", args: "", description: "Pick one of the recent visual selections", run: selections },
+    CommandInfo { name: "precedents", short: "prec", args: "", description: "Go to the cells the formula under the cursor reads, or pick one of them", run: |state, _| trace(state, false) },
+    CommandInfo { name: "dependents", short: "dep", args: "", description: "Go to the formulas which read the cell under the cursor, or pick one of them", run: |state, _| trace(state, true) },
+    CommandInfo { name: "note", short: "note", args: "[text]", description: "Attach a note to the cell under the cursor, or show it", run: note },
+    CommandInfo { name: "delnote", short: "delnote", args: "", description: "Remove the note from the cell under the cursor", run: |state, _| {
+        let cursor = state.table_content.selection.cursor();
+        set_note(state, cursor, None)
+    } },
+    CommandInfo { name: "overview", short: "overview", args: "", description: "Toggle the compact overview with one character per cell", run: |state, _| {
+        state.viewport.set_compact(!state.viewport.compact);
+        Ok(())
+    } },
+    CommandInfo { name: "fit", short: "fit", args: "", description: "Fit the width of the columns in the range to the visible cells", run: |state, args| fit(state, args.range) },
+    CommandInfo { name: "diffget", short: "diffg", args: "ours|theirs|base", description: "Resolve the conflicts of visp --merge in the range with one side", run: |state, args| merge::diffget(state, args.range, args.text) },
+    CommandInfo { name: "diffoff", short: "diffo", args: "", description: "Stop showing the old file of visp --diff", run: |state, _| diff::stop(state) },
+    CommandInfo { name: "snapshot", short: "snapshot", args: "take|restore|delete name", description: "Save the table under a name to go back to it later", run: |state, args| snapshot(state, args.text) },
+    CommandInfo { name: "snapshots", short: "snapshots", args: "", description: "List the saved snapshots", run: |state, _| {
+        state.pager = Some(Pager {
+            title: "Snapshots".to_string(),
+            lines: state.snapshots.iter().map(|(name, _)| name.clone()).collect(),
+        });
+        Ok(())
+    } },
+    CommandInfo { name: "changes", short: "changes", args: "[snapshot]", description: "List the cells changed since trackchanges was set or since a snapshot", run: changes },
+    CommandInfo { name: "editlog", short: "editlog", args: "[write file]", description: "Show every change of a cell, or save them as CSV", run: editlog },
+    CommandInfo { name: "protect", short: "protect", args: "", description: "Refuse edits to the range until :set noprotect", run: |state, args| {
+        state.table_content.protected.push(args.range);
+        state.message = Some(Message::Info(format!("{} protected", args.range.name())));
+        Ok(())
+    } },
+    CommandInfo { name: "unprotect", short: "unprotect", args: "", description: "Allow edits to the range again", run: unprotect },
+    CommandInfo { name: "hide", short: "hide", args: "", description: "Hide the columns of the range, :w still writes them", run: |state, args| {
+        // A range over all columns would hide everything
+        let right = args.range.right().min(state.table_content.used_cols() - 1);
+        let mut hidden = state.table_content.hidden_cols.clone();
+        hidden.extend(args.range.col..=right);
+        set_hidden_cols(state, hidden)
+    } },
+    CommandInfo { name: "unhide", short: "unhide", args: "[all]", description: "Show hidden columns in the range again, or all of them", run: unhide },
+    CommandInfo { name: "sort", short: "sor", args: "[column]", description: "Sort the rows of the range or the table by a column, sort! in descending order", run: sort },
+    CommandInfo { name: "fill", short: "fil", args: "[down|right]", description: "Continue the first cells of the range down or right, numbers, dates and month names as a series", run: fill },
+    CommandInfo { name: "goalseek", short: "goal", args: "cell=number by cell", description: "Change a number until a formula comes out as wanted, e.g. goalseek B10=1000 by B2", run: goalseek },
+    CommandInfo { name: "calc", short: "cal", args: "[expression]", description: "Work out a formula without putting it in a cell, without one open a pane for several", run: |state, args| {
+        if args.text.trim().is_empty() {
+            state.calc.open = true;
+        } else {
+            calc::run(state, args.text);
+        }
+        Ok(())
+    } },
+    CommandInfo { name: "filter", short: "filt", args: "[column op value]", description: "Hide rows where a column doesn't match, e.g. B > 10, filter! hides those which match, no argument shows all rows", run: filter },
+    CommandInfo { name: "movecol", short: "movecol", args: "+n|-n|column", description: "Move the column under the cursor, e.g. by +1 or to C", run: |state, args| move_col(state, args.text) },
+    CommandInfo { name: "insertrow", short: "insertrow", args: "[count]", description: "Insert empty rows below the range, or above it with !", run: |state, args| {
+        let at = if args.bang { args.range.row } else { args.range.bottom().saturating_add(1) };
+        structure::insert(state, Axis::Rows, at, parse_count(args.text)?)
+    } },
+    CommandInfo { name: "insertcol", short: "insertcol", args: "[count]", description: "Insert empty columns right of the range, or left of it with !", run: |state, args| {
+        let at = if args.bang { args.range.col } else { args.range.right().saturating_add(1) };
+        structure::insert(state, Axis::Cols, at, parse_count(args.text)?)
+    } },
+    CommandInfo { name: "delete", short: "d", args: "", description: "Delete the rows of the range like deleterow, e.g. :g/FAILED/d", run: |state, args| {
+        structure::delete(state, Axis::Rows, args.range.row, args.range.rows)
+    } },
+    CommandInfo { name: "normal", short: "norm", args: "keys", description: "Type keys in Normal mode on each row of the range, e.g. :g/x/normal a!<Esc>", run: |state, args| normal(state, args.range, args.text) },
+    CommandInfo { name: "deleterow", short: "deleterow", args: "", description: "Delete the rows of the range, the rows below move up", run: |state, args| {
+        structure::delete(state, Axis::Rows, args.range.row, args.range.rows)
+    } },
+    CommandInfo { name: "deletecol", short: "deletecol", args: "", description: "Delete the columns of the range, the columns to the right move left", run: |state, args| {
+        structure::delete(state, Axis::Cols, args.range.col, args.range.cols)
+    } },
+    CommandInfo { name: "colwidth", short: "colw", args: "[width]", description: "Set the width of the columns in the range, or show it", run: |state, args| size(state, args, Axis::Cols) },
+    CommandInfo { name: "rowheight", short: "rowh", args: "[height]", description: "Set the height of the rows in the range, or show it", run: |state, args| size(state, args, Axis::Rows) },
+    CommandInfo { name: "substitute", short: "s", args: "/pattern/replacement/[gi]", description: "Replace matches of a regular expression in the range, \\1 and & stand for what matched", run: |state, args| substitute(state, args.range, args.text) },
+    CommandInfo { name: "style", short: "style", args: "[no]bold|italic|underline fg=|bg=color|none", description: "Format the cells of the range", run: |state, args| style(state, args.range, args.text.split_whitespace()) },
+    CommandInfo { name: "fmt", short: "fmt", args: "[left|center|right] [spec]|none", description: "Set how the values in the range are shown, e.g. %,.2f or %d.%m.%Y, or show it", run: |state, args| format_cells(state, args.range, args.text) },
+    CommandInfo { name: "apply", short: "apply", args: "{+-*/}number", description: "Do arithmetic on every number in the range", run: |state, args| apply(state, args.range, &args.text.split_whitespace().collect::<String>()) },
+];
+
+// The command `name` abbreviates, e.g. "se" or "set" for set
+pub fn find(name: &str) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|c| name.starts_with(c.short) && c.name.starts_with(name))
+}
+
+// Runs a command and reports a failure in the message area
+pub fn dispatch(state: &mut AppState, command: &str) {
+    tracing::debug!(command, "executing command");
+    let start = Instant::now();
+    let result = execute(state, command);
+    state.profiler.record("command", start.elapsed());
+    if let Err(e) = result {
+        state.message = Some(Message::Error(e.to_string()));
+    }
+}
+
+// Runs a single command line, e.g. "q" or "'<,'>apply *2"
+pub fn execute(state: &mut AppState, command: &str) -> Result<()> {
+    let (range, command) = parse_range(state, command.trim_start())?;
+    let command = command.trim();
+
+    if let Some((invert, pattern, command)) = parse_global(command)? {
+        // Like in vim :g works on everything by default
+        let range = range.unwrap_or_else(|| used_range(state));
+        return global(state, range, invert, pattern, command);
+    }
+    // Without a range commands work on the current selection
+    let range = range.unwrap_or(state.table_content.selection);
+    run(state, range, command)
+}
+
+fn run(state: &mut AppState, range: Selection, command: &str) -> Result<()> {
+    // Commands may read any cell, e.g. :w or :sort
+    formula::refresh_all(&mut state.table_content);
+    // Like in vim :s/a/b/ needs no space after the name
+    for prefix in ["substitute", "s"] {
+        if let Some(rest) = command.strip_prefix(prefix).filter(|rest| rest.starts_with(|c: char| !c.is_alphanumeric() && !c.is_whitespace() && c != '!')) {
+            return substitute(state, range, rest);
+        }
+    }
+    let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+    if name.is_empty() {
+        return Ok(());
+    }
+    let (name, bang) = match name.strip_suffix('!') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let Some(command) = find(name) else {
+        if let Some(script) = state.script.clone().filter(|script| script.has_command(name)) {
+            return script::run_command(state, &script, name, range, rest.trim_start());
+        }
+        return match state.aliases.get(name).cloned() {
+            Some(alias) => run_alias(state, range, &alias, bang, rest),
+            None => Err(VispError::Command(format!("Not an editor command: {}", name))),
+        };
+    };
+    (command.run)(state, &Args { range, bang, text: rest.trim_start() })
+}
+
+fn quit(state: &mut AppState, args: &Args) -> Result<()> {
+    if state.is_modified() && !args.bang {
+        return Err(not_written());
+    }
+    other_sheets_written(state, args)?;
+    state.quit = true;
+    Ok(())
+}
+
+fn other_sheets_written(state: &AppState, args: &Args) -> Result<()> {
+    match sheet::unsaved(state) {
+        Some(name) if !args.bang => Err(VispError::Command(format!("No write since last change in sheet {} (add ! to override)", name))),
+        _ => Ok(()),
+    }
+}
+
+fn edit(state: &mut AppState, args: &Args) -> Result<()> {
+    if state.is_modified() && !args.bang {
+        return Err(not_written());
+    }
+    let path = match (args.text, &state.file) {
+        ("", Some(file)) => file.clone(),
+        ("", None) => return Err(VispError::Command("No file name".to_string())),
+        (path, _) => PathBuf::from(path),
+    };
+    // A workbook replaces all sheets
+    if workbook::is_workbook(&path) {
+        other_sheets_written(state, args)?;
+    }
+    open(state, &path)
+}
+
+fn write_quit(state: &mut AppState, args: &Args) -> Result<()> {
+    if ask_readonly(state, args, "wq")? {
+        return Ok(());
+    }
+    write(state, args.text, args.bang)?;
+    other_sheets_written(state, args)?;
+    state.quit = true;
+    Ok(())
+}
+
+// Like Paste Special of other spreadsheets. all puts the cells with their
+// formats, formulas the cells alone, values the cells with formulas as their
+// values and formats only the formats. Any of them can be transposed.
+fn paste_special(state: &mut AppState, args: &Args) -> Result<()> {
+    let mut contents = None;
+    let mut transpose = false;
+    let mut register = None;
+    for word in args.text.split_whitespace() {
+        match word {
+            "all" | "formulas" | "values" | "formats" if contents.is_some() => {
+                return Err(VispError::Command("Only one of all, formulas, values and formats can be given".to_string()));
+            }
+            "all" | "formulas" | "values" | "formats" => contents = Some(word),
+            "transpose" => transpose = true,
+            _ => match word.strip_prefix('"').and_then(|name| name.parse::<char>().ok()) {
+                Some(name) if register::is_register_name(name) => register = Some(name),
+                _ => return Err(VispError::Command(format!("Unknown argument: {}", word))),
+            },
+        }
+    }
+    let contents = contents.unwrap_or("all");
+    let special = PasteSpecial {
+        values: contents == "values",
+        cells: contents != "formats",
+        formats: matches!(contents, "all" | "formats"),
+        transpose,
+    };
+    register::put_special(state, args.range, args.bang, register, &special)
+}
+
+fn sheet(state: &mut AppState, args: &Args) -> Result<()> {
+    let (subcommand, name) = args.text.split_once(char::is_whitespace).unwrap_or((args.text, ""));
+    let name = name.trim();
+    match subcommand {
+        "" => {
+            let sheet = &state.sheets[state.sheet];
+            state.message = Some(Message::Info(format!("{} ({} of {})", sheet.name, state.sheet + 1, state.sheets.len())));
+            Ok(())
+        }
+        "new" | "rename" | "delete" | "delete!" if state.options.readonly => edit::check_writable(&state.options),
+        "new" => sheet::add(state, Some(name).filter(|name| !name.is_empty())),
+        "rename" if !name.is_empty() => sheet::rename(state, name),
+        "rename" => Err(VispError::Command("Usage: sheet rename name".to_string())),
+        // The ! can also go after the subcommand
+        "delete" | "delete!" => sheet::delete(state, args.bang || subcommand.ends_with('!')),
+        name => {
+            let index = sheet::find(state, name).ok_or_else(|| VispError::Command(format!("No sheet called {}", name)))?;
+            sheet::show(state, index);
+            Ok(())
+        }
+    }
+}
+
+// A cell name moves to that cell, a number to that row in the same column
+fn goto(state: &mut AppState, args: &Args) -> Result<()> {
+    let usage = || VispError::Command("Usage: goto cell|row".to_string());
+    let target = args.text;
+    let (row, col) = if let Ok(row) = target.parse::<u16>() {
+        (row.checked_sub(1).ok_or_else(usage)?, state.table_content.selection.cursor().1)
+    } else {
+        let split = target.find(|c: char| c.is_ascii_digit()).ok_or_else(usage)?;
+        let col = col_label_to_nr(&target[..split]).ok_or_else(usage)?;
+        let row = target[split..].parse::<u16>().ok().and_then(|r| r.checked_sub(1)).ok_or_else(usage)?;
+        (row, col)
+    };
+    state.table_content.selection.set_cursor(row, col);
+    Ok(())
+}
+
+// Runs what an alias stands for with the typed arguments appended. The bang
+// goes to the command the alias starts with.
+fn run_alias(state: &mut AppState, range: Selection, alias: &str, bang: bool, rest: &str) -> Result<()> {
+    let (name, arguments) = alias.split_once(char::is_whitespace).unwrap_or((alias, ""));
+    if state.aliases.contains_key(name.trim_end_matches('!')) {
+        return Err(VispError::Command(format!("Alias runs another alias: {}", alias)));
+    }
+    let command = format!("{}{} {} {}", name, if bang { "!" } else { "" }, arguments, rest);
+    run(state, range, command.trim())
+}
+
+fn set(state: &mut AppState, args: &Args) -> Result<()> {
+    let (colwidth, lazycalc) = (state.options.colwidth, state.options.lazycalc);
+    for argument in split_escaped(args.text) {
+        if let Some(text) = state.options.set(&argument)? {
+            state.message = Some(Message::Info(text));
+        }
+    }
+    if state.options.trackchanges != state.change_baseline.is_some() {
+        state.change_baseline = state.options.trackchanges.then(|| state.table_content.snapshot());
+    }
+    if state.options.colwidth != colwidth || state.options.lazycalc != lazycalc {
+        sheet::link(state);
+    }
+    state.undo.prune(&state.options);
+    Ok(())
+}
+
+fn colorscheme(state: &mut AppState, args: &Args) -> Result<()> {
+    if args.text.is_empty() {
+        state.message = Some(Message::Info(state.theme.name.to_string()));
+    } else {
+        state.theme = Theme::by_name(args.text, state.theme.support)
+            .ok_or_else(|| VispError::Command(format!("Cannot find color scheme '{}', try one of {}", args.text, COLOR_SCHEMES.join(", "))))?;
+    }
+    Ok(())
+}
+
+fn source(state: &mut AppState, args: &Args) -> Result<()> {
+    if args.text.is_empty() {
+        return Err(VispError::Command("Usage: source file".to_string()));
+    }
+    script::load(state, Path::new(args.text))?;
+    let (functions, commands) = state.script.as_ref().map_or((0, 0), |script| script.counts());
+    state.message = Some(Message::Info(format!("\"{}\" {} functions, {} commands", args.text, functions, commands)));
+    Ok(())
+}
+
+fn keymap(state: &mut AppState, args: &Args) -> Result<()> {
+    if args.text.is_empty() {
+        state.message = Some(Message::Info(state.keymap.name.to_string()));
+    } else {
+        state.keymap = Keymap::preset(args.text)
+            .ok_or_else(|| VispError::Command(format!("Unknown keymap '{}', try one of {}", args.text, PRESETS.join(", "))))?;
+    }
+    Ok(())
+}
+
+fn profile(state: &mut AppState, args: &Args) -> Result<()> {
+    match args.text {
+        "start" => state.profiler.start(),
+        "stop" => state.profiler.stop(),
+        "report" => {
+            state.pager = Some(Pager {
+                title: "Profile".to_string(),
+                lines: state.profiler.report(),
+            });
+        }
+        _ => return Err(VispError::Command("Usage: profile start|stop|report".to_string())),
+    }
+    Ok(())
+}
+
+fn memory(state: &mut AppState, _: &Args) -> Result<()> {
+    let sizes = [
+        ("Cells and notes", state.table_content.memory_size()),
+        ("Undo history", state.undo.memory_size()),
+        ("Other sheets", state.sheets.iter().map(|sheet| sheet.content.memory_size() + sheet.undo.memory_size()).sum()),
+        ("Format cache", state.viewport.cache.memory_size()),
+        ("Message log", state.log.memory_size()),
+        ("Selection history", state.selection_history.capacity() * std::mem::size_of::<(AppMode, Selection)>()),
+    ];
+    let total: usize = sizes.iter().map(|(_, size)| size).sum();
+    state.pager = Some(Pager {
+        title: "Memory".to_string(),
+        lines: sizes.iter().chain(&[("Total", total)])
+            .map(|(what, size)| format!("{:<20} {:>10}", what, format_bytes(*size)))
+            .collect(),
+    });
+    Ok(())
+}
+
+fn print(state: &mut AppState, args: &Args) -> Result<()> {
+    if args.text.is_empty() {
+        return Err(VispError::Command("Usage: print file".to_string()));
+    }
+    let pages = print::print(state, Path::new(args.text))?;
+    state.message = Some(Message::Info(format!("{} pages written to {}", pages, args.text)));
+    Ok(())
+}
+
+fn serve(state: &mut AppState, args: &Args) -> Result<()> {
+    if let Some(server) = &state.server {
+        return Err(VispError::Command(format!("Already serving at http://{}", server.address)));
+    }
+    if args.text.is_empty() {
+        return Err(VispError::Command("Usage: serve [address:]port".to_string()));
+    }
+    // Only reachable from this machine unless an address is given
+    let address = if args.text.contains(':') { args.text.to_string() } else { format!("127.0.0.1:{}", args.text) };
+    let mut server = Server::start(&address)?;
+    formula::refresh_all(&mut state.table_content);
+    server.update(&state.table_content);
+    state.message = Some(Message::Info(format!("Serving at http://{}", server.address)));
+    state.server = Some(server);
+    Ok(())
+}
+
+fn selections(state: &mut AppState, _: &Args) -> Result<()> {
+    let selections: Vec<_> = state.selection_history.iter().copied().collect();
+    if selections.is_empty() {
+        return Err(VispError::Command("No previous selections".to_string()));
+    }
+    let items = selections.iter().map(|(_, s)| s.name()).collect();
+    state.picker = Some(Picker::new("Selections", items, PickerKind::Selection(selections)));
+    Ok(())
+}
+
+fn unprotect(state: &mut AppState, args: &Args) -> Result<()> {
+    let range = args.range;
+    let overlaps = |p: &Selection| {
+        p.row <= range.bottom() && range.row <= p.bottom() && p.col <= range.right() && range.col <= p.right()
+    };
+    state.table_content.protected.retain(|p| !overlaps(p));
+    Ok(())
+}
+
+fn unhide(state: &mut AppState, args: &Args) -> Result<()> {
+    let mut hidden = state.table_content.hidden_cols.clone();
+    match args.text {
+        "all" => hidden.clear(),
+        "" => hidden.retain(|&col| !args.range.col_selected(col)),
+        _ => return Err(VispError::Command("Usage: unhide [all]".to_string())),
+    }
+    set_hidden_cols(state, hidden)
+}
+
+// For :hide and :unhide, as a change which can be undone. The hidden columns
+// are saved with the file, see format::Sidecar.
+fn set_hidden_cols(state: &mut AppState, hidden: BTreeSet<u16>) -> Result<()> {
+    let old = &state.table_content.hidden_cols;
+    if *old == hidden {
+        return Ok(());
+    }
+    let changed: Vec<u16> = old.symmetric_difference(&hidden).copied().collect();
+    changed.iter().try_for_each(|&col| edit::check_lines(state, Axis::Cols, col, col))?;
+    let old = std::mem::replace(&mut state.table_content.hidden_cols, hidden.clone());
+    state.table_content.changed();
+    state.undo.record(Change::HiddenCols { old, new: hidden }, &state.options);
+    Ok(())
+}
+
+fn sort(state: &mut AppState, args: &Args) -> Result<()> {
+    let col = match args.text {
+        "" => state.table_content.selection.cursor().1,
+        label => col_label_to_nr(&label.to_ascii_uppercase()).ok_or_else(|| VispError::Command(format!("Not a column: {}", label)))?,
+    };
+    let moved = sort::sort(state, args.range, col, args.bang)?;
+    state.message = Some(Message::Info(format!("{} rows moved", moved)));
+    Ok(())
+}
+
+fn fill(state: &mut AppState, args: &Args) -> Result<()> {
+    let right = match args.text {
+        "" => args.range.rows == 1 && args.range.cols > 1,
+        "down" => false,
+        "right" => true,
+        other => return Err(VispError::Command(format!("Fill goes down or right, not {}", other))),
+    };
+    let changed = fill::fill(state, args.range, right)?;
+    state.message = Some(Message::Info(format!("{} cells filled", changed)));
+    Ok(())
+}
+
+fn goalseek(state: &mut AppState, args: &Args) -> Result<()> {
+    let usage = || VispError::Command("Usage: goalseek cell=number by cell".to_string());
+    let (goal, input) = args.text.split_once(" by ").ok_or_else(usage)?;
+    let (target, number) = goal.split_once('=').ok_or_else(usage)?;
+    let cell = |name: &str| parse_cell_name(&name.trim().to_ascii_uppercase()).ok_or_else(|| VispError::Parse(format!("Not a cell: {}", name.trim())));
+    let (target, input) = (cell(target)?, cell(input)?);
+    let number: f64 = number.trim().parse().map_err(|_| VispError::Parse(format!("Not a number: {}", number.trim())))?;
+    let found = goalseek::goal_seek(state, target, number, input)?;
+    state.table_content.selection.set_cursor(input.0, input.1);
+    state.message = Some(Message::Info(format!("{}={}", cell_name(input.0, input.1), format::format_number(found))));
+    Ok(())
+}
+
+fn filter(state: &mut AppState, args: &Args) -> Result<()> {
+    if args.text.is_empty() {
+        state.table_content.hidden_rows.clear();
+        state.message = Some(Message::Info("All rows shown".to_string()));
+        return Ok(());
+    }
+    let hidden = sort::filter(state, args.range, args.text, args.bang)?;
+    state.message = Some(Message::Info(format!("{} rows hidden", hidden)));
+    Ok(())
+}
+
+fn editlog(state: &mut AppState, args: &Args) -> Result<()> {
+    match args.text.split_once(char::is_whitespace) {
+        Some(("write", path)) => {
+            state.edit_log.write_csv(Path::new(path.trim()))?;
+            state.message = Some(Message::Info(format!("{} edits written to {}", state.edit_log.entries.len(), path.trim())));
+        }
+        _ if args.text.is_empty() => {
+            state.pager = Some(Pager { title: "Edit log".to_string(), lines: state.edit_log.lines() });
+        }
+        _ => return Err(VispError::Command("Usage: editlog [write file]".to_string())),
+    }
+    Ok(())
+}
+
+fn changes(state: &mut AppState, args: &Args) -> Result<()> {
+    let name = args.text;
+    let baseline = if name.is_empty() {
+        state.change_baseline.as_ref()
+            .ok_or_else(|| VispError::Command("Changes are not tracked, use :set trackchanges or give a snapshot".to_string()))?
+    } else {
+        state.snapshots.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+            .ok_or_else(|| VispError::Command(format!("No snapshot named {}", name)))?
+    };
+    let lines = baseline.changed_cells(&state.table_content).into_iter()
+        .map(|(row, col)| {
+            format!("{:<8} {}", cell_name(row, col), state.table_content.display(row, col))
+        })
+        .collect();
+    state.pager = Some(Pager { title: "Changes".to_string(), lines });
+    Ok(())
+}
+
+fn note(state: &mut AppState, args: &Args) -> Result<()> {
+    let cursor = state.table_content.selection.cursor();
+    if args.text.is_empty() {
+        let note = state.table_content.notes.get(&cursor)
+            .ok_or_else(|| VispError::Command("No note on this cell".to_string()))?;
+        state.pager = Some(Pager {
+            title: format!("Note on {}", cell_name(cursor.0, cursor.1)),
+            lines: note.lines().map(str::to_string).collect(),
+        });
+    } else {
+        set_note(state, cursor, Some(args.text.to_string()))?;
+    }
+    Ok(())
+}
+
+// Replaces the note of a cell, None removes it
+fn set_note(state: &mut AppState, cell: (u16, u16), note: Option<String>) -> Result<()> {
+    edit::check_cells(state, Selection { row: cell.0, col: cell.1, ..Selection::default() })?;
+    let content = &mut state.table_content;
+    let old = match &note {
+        Some(note) => content.notes.insert(cell, note.clone()),
+        None => content.notes.remove(&cell),
+    };
+    if old == note {
+        return Ok(());
+    }
+    // Workbooks and the sidecar keep notes, so this is a change to write
+    content.changed();
+    state.undo.record(Change::Note { cell, old, new: note }, &state.options);
+    Ok(())
+}
+
+// :precedents and :dependents, for following formulas through a table. A
+// single cell or range is gone to right away, a range is selected. Of
+// several one is picked. Only cells on this sheet are found.
+pub fn trace(state: &mut AppState, dependents: bool) -> Result<()> {
+    let content = &state.table_content;
+    let cursor = content.selection.cursor();
+    let name = cell_name(cursor.0, cursor.1);
+    let (title, found) = if dependents {
+        let cells: Vec<(AppMode, Selection)> = content.dependencies.dependents_of(cursor).into_iter()
+            .map(|(row, col)| (AppMode::Normal, Selection { row, col, ..Selection::default() }))
+            .collect();
+        if cells.is_empty() {
+            return Err(VispError::Command(format!("No formula reads {}", name)));
+        }
+        (format!("Formulas reading {}", name), cells)
+    } else {
+        let areas = content.dependencies.precedents_of(cursor)
+            .ok_or_else(|| VispError::Command(format!("No formula in {}", name)))?;
+        if areas.is_empty() {
+            return Err(VispError::Command(format!("The formula in {} reads no cells on this sheet", name)));
+        }
+        let areas = areas.into_iter().map(|(top, left, bottom, right)| {
+            let mut selection = Selection::default();
+            selection.span((top, left), (bottom, right));
+            let mode = if selection.rows == 1 && selection.cols == 1 { AppMode::Normal } else { AppMode::Visual };
+            (mode, selection)
+        });
+        (format!("Read by {}", name), areas.collect())
+    };
+    if let [(mode, selection)] = found[..] {
+        state.remember_visual();
+        state.table_content.selection = selection;
+        state.mode = mode;
+        return Ok(());
+    }
+    let items = found.iter().map(|(_, selection)| {
+        let (row, col) = (selection.row, selection.col);
+        match content.get_cell(row, col) {
+            Some(cell) if dependents => format!("{}  {}", selection.name(), cell.source_string()),
+            _ if selection.rows == 1 && selection.cols == 1 => format!("{}  {}", selection.name(), content.display(row, col)),
+            _ => selection.name(),
+        }
+    }).collect();
+    state.picker = Some(Picker::new(&title, items, PickerKind::Selection(found)));
+    Ok(())
+}
+
+// Number of rows or columns to insert, 1 if none is given
+fn parse_count(text: &str) -> Result<u16> {
+    if text.is_empty() {
+        return Ok(1);
+    }
+    text.parse().ok().filter(|&n| n > 0).ok_or_else(|| VispError::Parse(format!("Not a number: {}", text)))
+}
+
+// Sets the width of columns or the height of rows in the range. Ranges over
+// all of them end at the last one in use.
+fn size(state: &mut AppState, args: &Args, axis: Axis) -> Result<()> {
+    let content = &state.table_content;
+    let (name, first, last) = match axis {
+        Axis::Cols => ("colwidth", args.range.col, args.range.right().min(content.used_cols() - 1)),
+        Axis::Rows => ("rowheight", args.range.row, args.range.bottom().min(content.used_rows() - 1)),
+    };
+    if args.text.is_empty() {
+        let size = match axis {
+            Axis::Cols => content.col_width(first),
+            Axis::Rows => content.row_height(first),
+        };
+        state.message = Some(Message::Info(format!("{}={}", name, size)));
+        return Ok(());
+    }
+    let size: u16 = args.text.parse().ok().filter(|&n| n > 0)
+        .ok_or_else(|| VispError::Parse(format!("Not a number: {}", args.text)))?;
+    structure::resize(state, axis, (first..=last.max(first)).map(|i| (i, Some(size))).collect())
+}
+
+// Like in vim 'readonly' keeps :w from replacing the file which was opened,
+// and with ! it asks first. Other files are written without asking. Returns
+// whether the command waits for the answer.
+fn ask_readonly(state: &mut AppState, args: &Args, command: &str) -> Result<bool> {
+    let path = match (args.text, &state.file) {
+        ("", Some(file)) => file.clone(),
+        (path, Some(file)) if Path::new(path) == file => file.clone(),
+        _ => return Ok(false),
+    };
+    if !state.options.readonly {
+        return Ok(false);
+    }
+    if !args.bang {
+        return Err(VispError::Command("'readonly' option is set (add ! to override)".to_string()));
+    }
+    state.confirm = Some(format!("{}! {}", command, args.text));
+    state.message = Some(Message::Info(format!("\"{}\" is read-only, write anyway? (y/n)", path.display())));
+    Ok(true)
+}
+
+// Runs what was asked about, e.g. by ask_readonly, after y was pressed
+pub fn run_confirmed(state: &mut AppState, command: &str) {
+    let readonly = std::mem::take(&mut state.options.readonly);
+    dispatch(state, command);
+    state.options.readonly = readonly;
+}
+
+fn not_written() -> VispError {
+    VispError::Command("No write since last change (add ! to override)".to_string())
+}
+
+// Files smaller than this are read right away, the status of a task would
+// only flash by
+const BACKGROUND_SIZE: u64 = 1 << 20;
+
+// What open reads, None for a file which doesn't exist yet
+enum FileData {
+    Csv(Option<(Vec<Vec<TableCell>>, &'static str)>, Result<Box<format::Sidecar>>),
+    Workbook(Option<Vec<workbook::Sheet>>),
+}
+
+fn read_file(path: &Path, delimiter: char, locale: Option<&Locale>, progress: &Progress) -> Result<FileData> {
+    if workbook::is_workbook(path) {
+        let sheets = if path.exists() { Some(workbook::read(path, progress)?) } else { None };
+        return Ok(FileData::Workbook(sheets));
+    }
+    let read = match csv::read(path, delimiter, locale, progress) {
+        Ok(read) => Some(read),
+        Err(VispError::Io(e)) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    Ok(FileData::Csv(read, format::read_sidecar(path).map(Box::new)))
+}
+
+// Replaces the table with a CSV file, or all sheets with a workbook. A file
+// which doesn't exist yet gives an empty table which :w creates, like in vim.
+// Big files are read in the background, see task.rs.
+fn open(state: &mut AppState, path: &Path) -> Result<()> {
+    let big = fs::metadata(path).is_ok_and(|metadata| metadata.len() > BACKGROUND_SIZE);
+    let title = format!("Reading \"{}\"", path.display());
+    let delimiter = state.options.delimiter();
+    let (file, path) = (path.to_path_buf(), path.to_path_buf());
+    let locale = state.options.locale();
+    let work = move |progress: &Progress| read_file(&file, delimiter, locale, progress);
+    let finish = move |state: &mut AppState, data| match data {
+        FileData::Csv(read, sidecar) => opened(state, &path, read, sidecar),
+        FileData::Workbook(sheets) => opened_workbook(state, &path, sheets),
+    };
+    if big {
+        task::run(state, title, work, finish)
+    } else {
+        finish(state, work(&Progress::default())?)
+    }
+}
+
+fn opened(state: &mut AppState, path: &Path, read: Option<(Vec<Vec<TableCell>>, &'static str)>, sidecar: Result<Box<format::Sidecar>>) -> Result<()> {
+    let new = read.is_none();
+    let cells = match read {
+        Some((cells, encoding)) => {
+            // Like in vim :w writes it back the way it was
+            state.options.fileencoding = encoding.to_string();
+            cells
+        }
+        None => Vec::new(),
+    };
+    let rows = cells.len();
+    // A broken sidecar only loses the formats, styles and widths, the table
+    // still opens
+    let (sidecar, sidecar_error) = match sidecar {
+        Ok(sidecar) => (*sidecar, None),
+        Err(e) => (format::Sidecar::default(), Some(e)),
+    };
+    state.table_content = TableContent::from_rows(cells);
+    sidecar.apply(&mut state.table_content);
+    state.viewport.row = 0;
+    state.viewport.col = 0;
+    window::clamp(state);
+    state.saved_revision = state.table_content.revision;
+    state.file = Some(path.to_path_buf());
+    state.undo.clear();
+    // The sheet is the file's now, :fetch won't replace it
+    state.sheets[state.sheet].source = None;
+    sheet::link(state);
+    // After link calculated the formulas
+    if state.options.trackchanges {
+        formula::refresh_all(&mut state.table_content);
+        state.change_baseline = Some(state.table_content.snapshot());
+    }
+    let status = match (new, state.options.fileencoding.as_str()) {
+        (true, _) => "[New]".to_string(),
+        (false, "utf-8") => format!("{} rows", rows),
+        (false, encoding) => format!("[{}] {} rows", encoding, rows),
+    };
+    state.message = Some(match sidecar_error {
+        Some(e) => Message::Error(format!("\"{}\" {}, \"{}\" not read: {}", path.display(), status, format::sidecar(path).display(), e)),
+        None => Message::Info(format!("\"{}\" {}", path.display(), status)),
+    });
+    oldfiles::remember(state, path);
+    // The swap file of a visp which is still running isn't from a crash
+    if !warn_locked(state, path) {
+        swap::check(state, path);
+    }
+    lock::update(state);
+    Ok(())
+}
+
+// Opens a CSV file in a new sheet after the current one, which is called
+// like the file. If it can't be read the sheet goes away again. A file which
+// is already open is shown instead.
+fn add_file(state: &mut AppState, path: &Path) -> Result<()> {
+    if path.as_os_str().is_empty() {
+        return Err(VispError::Command("Usage: badd file".to_string()));
+    }
+    // They come with sheets of their own
+    if workbook::is_workbook(path) {
+        return Err(VispError::Command("Workbooks replace all sheets, open them with :e".to_string()));
+    }
+    // Two sheets writing the same file would overwrite each other
+    if let Some(index) = sheet::with_file(state, path) {
+        sheet::show(state, index);
+        return Ok(());
+    }
+    let name = sheet::usable_name(state, &file_stem(path));
+    let before = state.sheet;
+    sheet::add(state, Some(&name))?;
+    if let Err(e) = task::blocking(state, |state| open(state, path)) {
+        sheet::delete(state, true)?;
+        sheet::show(state, before);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// visp a.csv b.csv opens each file in a sheet named after it and shows the
+// first one. A single file goes into Sheet1 like with :e.
+pub fn open_files(state: &mut AppState, files: &[String]) {
+    match files {
+        [] => {}
+        [directory] if Path::new(directory).is_dir() => dispatch(state, &format!("explore {}", directory)),
+        [file] => dispatch(state, &format!("edit {}", file)),
+        // The sheets are made as the files are read, one after another
+        [first, rest @ ..] => task::blocking(state, |state| open_into_sheets(state, first, rest)),
+    }
+}
+
+fn open_into_sheets(state: &mut AppState, first: &str, rest: &[String]) {
+    dispatch(state, &format!("edit {}", first));
+    if state.file.is_some() && !workbook::is_workbook(Path::new(first)) {
+        let name = sheet::usable_name(state, &file_stem(Path::new(first)));
+        dispatch(state, &format!("sheet rename {}", name));
+    }
+    let mut error = None;
+    for file in rest {
+        if let Err(e) = add_file(state, Path::new(file)) {
+            error = Some(e);
+        }
+    }
+    sheet::show(state, 0);
+    state.message = Some(match error {
+        Some(e) => Message::Error(e.to_string()),
+        None => Message::Info(format!("{} files opened, :ls lists them and :bn goes to the next", rest.len() + 1)),
+    });
+}
+
+// :explore, e.g. for the results of a batch job. Without a directory that of
+// the current file.
+fn explore(state: &mut AppState, args: &Args) -> Result<()> {
+    let directory = match (args.text, &state.file) {
+        ("", Some(file)) => file.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf(),
+        ("", None) => PathBuf::from("."),
+        (directory, _) => PathBuf::from(directory),
+    };
+    let files = directory_files(&directory)
+        .map_err(|e| VispError::Command(format!("\"{}\" not read: {}", directory.display(), e)))?;
+    let title = format!("Files in {}", directory.display());
+    state.picker = Some(Picker::new(&title, file_names(&files), PickerKind::Directory(directory, files)));
+    Ok(())
+}
+
+// CSV files and workbooks, the newest first
+fn directory_files(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        if (csv || workbook::is_workbook(&path)) && entry.file_type()?.is_file() {
+            let modified = entry.metadata()?.modified().ok();
+            files.push((modified, path));
+        }
+    }
+    files.sort_by(|(a, a_path), (b, b_path)| b.cmp(a).then(a_path.cmp(b_path)));
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+fn file_names(files: &[PathBuf]) -> Vec<String> {
+    files.iter().map(|file| file.file_name().unwrap_or_default().to_string_lossy().into_owned()).collect()
+}
+
+// Called every second by the main loop. Lists the files again while the
+// picker of :explore is open. Returns whether they changed.
+pub fn refresh_directory(state: &mut AppState) -> bool {
+    let Some(picker) = &mut state.picker else {
+        return false;
+    };
+    let PickerKind::Directory(directory, files) = &picker.kind else {
+        return false;
+    };
+    match directory_files(directory) {
+        Ok(found) if found != *files => {
+            let items = file_names(&found);
+            picker.kind = PickerKind::Directory(directory.clone(), found);
+            picker.set_items(items);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+// Replaces all sheets with those of an XLSX or ODS file
+fn opened_workbook(state: &mut AppState, path: &Path, sheets: Option<Vec<workbook::Sheet>>) -> Result<()> {
+    let new = sheets.is_none();
+    let sheets = sheets.unwrap_or_default().into_iter().map(workbook::Sheet::into_content).collect();
+    sheet::replace_all(state, sheets, path);
+    let status = if new { "[New]".to_string() } else { format!("{} sheets", state.sheets.len()) };
+    state.message = Some(Message::Info(format!("\"{}\" {}", path.display(), status)));
+    oldfiles::remember(state, path);
+    warn_locked(state, path);
+    lock::update(state);
+    Ok(())
+}
+
+// Shows that another visp has the file open, returns whether it does
+fn warn_locked(state: &mut AppState, path: &Path) -> bool {
+    let Some(pid) = lock::other_owner(path) else {
+        return false;
+    };
+    state.message = Some(Message::Error(format!(
+        "\"{}\" is also open in another visp (process {}), :w! would overwrite its changes",
+        path.display(),
+        pid
+    )));
+    true
+}
+
+// Writes to `path`, or the current file if it is empty. Like in vim, the
+// table only takes the name if it had none. A file which another visp has
+// open is only written with `force`.
+fn write(state: &mut AppState, path: &str, force: bool) -> Result<()> {
+    let path = match (path, &state.file) {
+        ("", Some(file)) => file.clone(),
+        ("", None) => return Err(VispError::Command("No file name".to_string())),
+        (path, _) => PathBuf::from(path),
+    };
+    if let Some(pid) = lock::other_owner(&path).filter(|_| !force) {
+        return Err(VispError::Command(format!("\"{}\" is open in another visp (process {}), add ! to write anyway", path.display(), pid)));
+    }
+    if workbook::is_workbook(&path) {
+        return write_workbook(state, path);
+    }
+    let rows = csv::write(&path, &state.table_content, &state.options)?;
+    format::write_sidecar(&path, &state.table_content)?;
+    // Hiding is only for viewing, the columns aren't lost by saving
+    let hidden = match state.table_content.hidden_cols.len() {
+        0 => String::new(),
+        1 => ", 1 hidden column included".to_string(),
+        count => format!(", {} hidden columns included", count),
+    };
+    if state.file.is_none() {
+        state.file = Some(path.clone());
+    }
+    if state.file.as_ref() == Some(&path) {
+        state.saved_revision = state.table_content.revision;
+    }
+    state.message = Some(Message::Info(format!("\"{}\" {} rows written{}", path.display(), rows, hidden)));
+    Ok(())
+}
+
+// Writes all sheets, which are saved if they belong to the workbook or had
+// no file yet
+fn write_workbook(state: &mut AppState, path: PathBuf) -> Result<()> {
+    // Formulas are written with their values, which have to be up to date
+    sheet::link(state);
+    let sheets: Vec<(&str, &TableContent)> = state.sheets.iter().enumerate()
+        .map(|(i, sheet)| (sheet.name.as_str(), if i == state.sheet { &state.table_content } else { &sheet.content }))
+        .collect();
+    let lost = workbook::write(&path, &sheets)?;
+    for (i, sheet) in state.sheets.iter_mut().enumerate() {
+        let (file, revision, saved) = if i == state.sheet {
+            (&mut state.file, state.table_content.revision, &mut state.saved_revision)
+        } else {
+            (&mut sheet.file, sheet.content.revision, &mut sheet.saved_revision)
+        };
+        if file.is_none() {
+            *file = Some(path.clone());
+        }
+        if file.as_ref() == Some(&path) {
+            *saved = revision;
+        }
+    }
+    state.message = Some(match lost {
+        0 => Message::Info(format!("\"{}\" {} sheets written", path.display(), state.sheets.len())),
+        lost => Message::Error(format!("\"{}\" {} sheets written, {} formats have no equivalent and were left out", path.display(), state.sheets.len(), lost)),
+    });
+    Ok(())
+}
+
+// Splits at whitespace, except where it is escaped with a backslash as in
+// :set statusline=%mode\ %cell
+fn split_escaped(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => part.extend(chars.next()),
+            c if c.is_whitespace() => {
+                if !part.is_empty() {
+                    parts.push(std::mem::take(&mut part));
+                }
+            }
+            c => part.push(c),
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+// e.g. 1.5 MiB
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Title of the start screen, which opens the recent files it lists when
+// their number is pressed
+pub const INTRO_TITLE: &str = "Welcome";
+pub const INTRO_FILES: usize = 9;
+
+// Shown on startup and with :intro
+fn intro(state: &AppState) -> Pager {
+    let mut lines = vec![
+        format!("VISP {} - VI-style SPreadsheet", env!("CARGO_PKG_VERSION")),
+        String::new(),
+        "  h j k l      move, with a count like 5j".to_string(),
+        "  gg G 0 $     go to the first or last row or column".to_string(),
+        "  w b          go to the next or previous block of data in the row".to_string(),
+        "  v V Ctrl-V   select cells, rows or columns".to_string(),
+        "  o O          insert rows, or jump to the other corner of a selection".to_string(),
+        "  ip           select the block of data around the cursor".to_string(),
+        "  gv           reselect the last selection".to_string(),
+        "  Ctrl-A       select everything".to_string(),
+        "  gt gT        go to the next or previous sheet".to_string(),
+        "  qa q @a      record keys into register a, then play them".to_string(),
+        "  Ctrl-P       search all commands".to_string(),
+        "  :            enter a command, :q to quit".to_string(),
+        String::new(),
+    ];
+    let files = oldfiles::existing(state, INTRO_FILES);
+    if !files.is_empty() {
+        lines.push("Recent files, press the number to open one or see :oldfiles".to_string());
+        for (i, file) in files.iter().enumerate() {
+            lines.push(format!("  {}  {}", i + 1, file.display()));
+        }
+        lines.push(String::new());
+    }
+    lines.push("Press any key to start with a blank sheet".to_string());
+    Pager {
+        title: INTRO_TITLE.to_string(),
+        lines,
+    }
+}
+
+// Splits off a leading range: '<,'> for the last visual selection or % for
+// everything in use
+fn parse_range<'a>(state: &AppState, command: &'a str) -> Result<(Option<Selection>, &'a str)> {
+    if let Some(rest) = command.strip_prefix("'<,'>") {
+        match state.last_visual {
+            Some((_, selection)) => Ok((Some(selection), rest)),
+            None => Err(VispError::Command("No visual selection".to_string())),
+        }
+    } else if let Some(rest) = command.strip_prefix('%') {
+        Ok((Some(used_range(state)), rest))
+    } else {
+        Ok((None, command))
+    }
+}
+
+// Everything in use, except the header block
+fn used_range(state: &AppState) -> Selection {
+    let bottom = state.table_content.used_rows() - 1;
+    let top = bottom.min(state.options.header_rows());
+    let mut all = Selection::default();
+    all.span((top, 0), (bottom, state.table_content.used_cols() - 1));
+    all
+}
+
+// Splits "g/pattern/command" into whether it is inverted (:g! or :v), the
+// pattern and the command. Any character which isn't a letter can be used
+// instead of the slash.
+fn parse_global(command: &str) -> Result<Option<(bool, &str, &str)>> {
+    let prefixes = [("global!", true), ("global", false), ("g!", true), ("g", false), ("vglobal", true), ("v", true)];
+    for (prefix, invert) in prefixes {
+        let rest = match command.strip_prefix(prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let delimiter = match rest.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '!' => c,
+            _ => continue,
+        };
+        let (pattern, command) = rest[delimiter.len_utf8()..].split_once(delimiter)
+            .ok_or_else(|| VispError::Command(format!("Usage: {}/pattern/command", prefix)))?;
+        return Ok(Some((invert, pattern, command)));
+    }
+    Ok(None)
+}
+
+// Runs the command on each row in the range which has a cell matching the
+// pattern like / does, or on each row which doesn't when inverted. It is
+// undone as one change and the message tells what it did to all rows.
+fn global(state: &mut AppState, range: Selection, invert: bool, pattern: &str, command: &str) -> Result<()> {
+    if parse_global(command.trim())?.is_some() {
+        return Err(VispError::Command("Cannot do :global recursively".to_string()));
+    }
+    let regex = search::compile(pattern)?;
+    let content = &state.table_content;
+    let found: BTreeSet<u16> = content.cells_in(range.bounds())
+        .filter(|&((row, col), _)| regex.is_match(&content.display(row, col)))
+        .map(|((row, _), _)| row)
+        .collect();
+    let bottom = range.bottom().min(content.used_rows() - 1);
+    let rows: Vec<u16> = (range.row..=bottom).filter(|row| found.contains(row) != invert).collect();
+    if rows.is_empty() {
+        return Err(VispError::Command(format!("Pattern not found: {}", pattern)));
+    }
+
+    // Bottom up, so rows which the command inserts or deletes don't move the
+    // rows still to come
+    state.undo.begin_group();
+    let result = rows.iter().rev().try_for_each(|&row| {
+        let mut row_range = Selection::default();
+        row_range.set_cursor(row, 0);
+        row_range.whole_rows();
+        run(state, row_range, command.trim())
+    });
+    let summary = state.undo.group_summary();
+    state.undo.end_group(&state.options);
+    result?;
+    state.message = Some(Message::Info(summary.unwrap_or_else(|| {
+        format!("{} matching row{}, nothing changed", rows.len(), if rows.len() == 1 { "" } else { "s" })
+    })));
+    Ok(())
+}
+
+// :normal keys, typed on each row of the range in the column of the cursor,
+// bottom up like :g
+fn normal(state: &mut AppState, range: Selection, text: &str) -> Result<()> {
+    let keys = keymap::parse_keys(text).ok_or_else(|| VispError::Command("Usage: normal keys".to_string()))?;
+    let col = if range.cols == u16::MAX { state.table_content.selection.cursor().1 } else { range.col };
+    let bottom = range.bottom().min(state.table_content.used_rows().saturating_sub(1)).max(range.row);
+    for row in (range.row..=bottom).rev() {
+        state.table_content.selection.set_cursor(row, col);
+        input::type_keys(state, &keys)?;
+    }
+    Ok(())
+}
+
+// Replaces the first match of a regular expression in each cell of the
+// range, or all of them with the g flag. The i flag ignores case. Cells are
+// changed as if their new text was typed, so formulas can be edited too.
+fn substitute(state: &mut AppState, range: Selection, args: &str) -> Result<()> {
+    let usage = || VispError::Command("Usage: s/pattern/replacement/[gi]".to_string());
+    let delimiter = args.chars().next().ok_or_else(usage)?;
+    let parts = split_unescaped(&args[delimiter.len_utf8()..], delimiter);
+    let (pattern, replacement, flags) = match parts.as_slice() {
+        [pattern, replacement] => (pattern, replacement, ""),
+        [pattern, replacement, flags] => (pattern, replacement, flags.trim()),
+        _ => return Err(usage()),
+    };
+    if let Some(flag) = flags.chars().find(|c| !matches!(c, 'g' | 'i')) {
+        return Err(VispError::Command(format!("Unknown flag: {}", flag)));
+    }
+    let all = flags.contains('g');
+    let regex = search::compile(&if flags.contains('i') { format!("(?i){}", pattern) } else { pattern.clone() })?;
+    let replacement = vim_replacement(replacement);
+
+    let mut substitutions = 0;
+    let cells: Vec<_> = state.table_content.cells_in(range.bounds())
+        .filter_map(|(position, cell)| {
+            let text = cell.source_string();
+            let matches = regex.find_iter(&text).count();
+            if matches == 0 {
+                return None;
+            }
+            substitutions += if all { matches } else { 1 };
+            let new = if all { regex.replace_all(&text, &replacement) } else { regex.replace(&text, &replacement) };
+            Some((position, edit::parse_cell(&new)))
+        })
+        .collect();
+    if cells.is_empty() {
+        return Err(VispError::Command(format!("Pattern not found: {}", pattern)));
+    }
+    let changed = edit::replace_cells(state, cells)?;
+    state.message = Some(Message::Info(format!("{} substitutions in {} cells", substitutions, changed)));
+    Ok(())
+}
+
+// Splits at the delimiter, a backslash before it keeps it in the text.
+// Other backslashes stay for the regular expression.
+fn split_unescaped(text: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&delimiter) {
+            parts.last_mut().unwrap().push(chars.next().unwrap());
+        } else if c == delimiter {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    parts
+}
+
+// The regex crate writes groups as $1, vim as \1 and the whole match as &
+fn vim_replacement(text: &str) -> String {
+    let mut replacement = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => replacement.push_str(&format!("${{{}}}", d)),
+                Some(c) => replacement.push(c),
+                None => replacement.push('\\'),
+            },
+            '&' => replacement.push_str("${0}"),
+            '$' => replacement.push_str("$$"),
+            c => replacement.push(c),
+        }
+    }
+    replacement
+}
+
+// Applies an operation like "*1.1" or "+5" to every number in the range.
+// Amounts of money stay in their currency.
+fn apply(state: &mut AppState, range: Selection, operation: &str) -> Result<()> {
+    let mut chars = operation.chars();
+    let operator = chars.next().ok_or_else(|| VispError::Command("Usage: apply {+-*/}number".to_string()))?;
+    let operand: f64 = chars.as_str().parse()
+        .map_err(|_| VispError::Parse(format!("Not a number: {}", chars.as_str())))?;
+    let operation: fn(f64, f64) -> f64 = match operator {
+        '+' => |a, b| a + b,
+        '-' => |a, b| a - b,
+        '*' => |a, b| a * b,
+        '/' if operand == 0.0 => return Err(VispError::Command("Division by zero".to_string())),
+        '/' => |a, b| a / b,
+        _ => return Err(VispError::Command(format!("Unknown operator: {}", operator))),
+    };
+
+    // Only numbers are changed, so protected text like a header row is fine
+    let cells = state.table_content.cells_in(range.bounds())
+        .filter_map(|(position, cell)| match cell {
+            TableCell::Currency(currency) => {
+                let cents = (operation(currency.amount(), operand) * 100.0).round() as i64;
+                Some((position, TableCell::Currency(format::Currency { cents, ..*currency })))
+            }
+            TableCell::Value(_) | TableCell::Float(_) => {
+                let result = operation(cell.number()?, operand);
+                result.is_finite().then(|| (position, TableCell::from_number(result)))
+            }
+            _ => None,
+        })
+        .collect();
+    let changed = edit::replace_cells(state, cells)?;
+    state.message = Some(Message::Info(format!("{} cells changed", changed)));
+    Ok(())
+}
+
+// Sets the widths of the columns in the range so the cells on screen fit.
+// Only the visible rows are measured, which keeps this fast on long tables.
+fn fit(state: &mut AppState, range: Selection) -> Result<()> {
+    let content = &state.table_content;
+    let rows = state.viewport.visible_rows(content);
+    let right = range.right().min(content.used_cols() - 1);
+    let widths = (range.col..=right).map(|col| {
+        let text_width = rows.clone()
+            .map(|row| content.display(row, col).chars().count())
+            .max()
+            .unwrap_or(0);
+        // One column of space to the next cell
+        (col, Some((text_width + 1).clamp(2, u16::MAX as usize) as u16))
+    }).collect();
+    structure::resize(state, Axis::Cols, widths)
+}
+
+// Changes the formatting of every cell in the range, e.g. with
+// "bold fg=red" or "none" to remove it
+fn style<'a>(state: &mut AppState, range: Selection, args: impl Iterator<Item = &'a str>) -> Result<()> {
+    let changes: Vec<&str> = args.collect();
+    if changes.is_empty() {
+        return Err(VispError::Command("Usage: style [no]bold|italic|underline fg=|bg=color|none".to_string()));
+    }
+    // Check everything before changing any cell
+    for change in &changes {
+        if !CellStyle::default().set(change) {
+            return Err(match change.split_once('=') {
+                Some(("fg" | "bg", color)) => VispError::Parse(format!("Unknown color: {}", color)),
+                _ => VispError::Command(format!("Unknown style: {}", change)),
+            });
+        }
+    }
+    let content = &state.table_content;
+    let Some(range) = used_part(content, range) else {
+        return Ok(());
+    };
+    edit::check_cells(state, range)?;
+
+    let mut styles = Vec::new();
+    for row in range.row..=range.bottom() {
+        for col in range.col..=range.right() {
+            let old = content.styles.get(&(row, col)).copied();
+            let mut cell_style = old.unwrap_or_default();
+            for change in &changes {
+                cell_style.set(change);
+            }
+            let new = Some(cell_style).filter(|&style| style != CellStyle::default());
+            if new != old {
+                styles.push(StyleChange { cell: (row, col), old, new });
+            }
+        }
+    }
+    if styles.is_empty() {
+        return Ok(());
+    }
+    let content = &mut state.table_content;
+    for change in &styles {
+        match change.new {
+            Some(style) => content.styles.insert(change.cell, style),
+            None => content.styles.remove(&change.cell),
+        };
+    }
+    // Workbooks and the sidecar keep styles, so the file has to be written again
+    content.changed();
+    state.undo.record(Change::Styles(styles), &state.options);
+    Ok(())
+}
+
+// Sets the format of every cell in the range, e.g. "right %,.2f", or
+// removes it with "none". Without arguments shows the cursor cell's format.
+fn format_cells(state: &mut AppState, range: Selection, args: &str) -> Result<()> {
+    let content = &mut state.table_content;
+    if args.trim().is_empty() {
+        let cursor = content.selection.cursor();
+        let text = content.formats.get(&cursor).map_or("none".to_string(), |f| f.describe());
+        state.message = Some(Message::Info(format!("fmt {}", text)));
+        return Ok(());
+    }
+    let format = if args.trim() == "none" { CellFormat::default() } else { CellFormat::parse(args)? };
+    let Some(range) = used_part(content, range) else {
+        return Ok(());
+    };
+    edit::check_cells(state, range)?;
+
+    // Only an alignment keeps the spec and the other way around
+    let content = &state.table_content;
+    let mut formats = Vec::new();
+    for row in range.row..=range.bottom() {
+        for col in range.col..=range.right() {
+            let old = content.formats.get(&(row, col)).cloned();
+            let mut cell_format = old.clone().unwrap_or_default();
+            if format == CellFormat::default() {
+                cell_format = format.clone();
+            } else {
+                cell_format.align = format.align.or(cell_format.align);
+                cell_format.spec = format.spec.clone().or(cell_format.spec);
+            }
+            let new = Some(cell_format).filter(|format| *format != CellFormat::default());
+            if new != old {
+                formats.push(FormatChange { cell: (row, col), old, new });
+            }
+        }
+    }
+    if formats.is_empty() {
+        return Ok(());
+    }
+    let content = &mut state.table_content;
+    for change in &formats {
+        match &change.new {
+            Some(format) => content.formats.insert(change.cell, format.clone()),
+            None => content.formats.remove(&change.cell),
+        };
+    }
+    // Cells are shown differently and the file has to be written again
+    content.changed_rows(formats.iter().map(|change| change.cell.0));
+    state.undo.record(Change::Formats(formats), &state.options);
+    Ok(())
+}
+
+// The part of the range with cells, for changes to how they look. None if
+// it is below or right of them.
+fn used_part(content: &TableContent, range: Selection) -> Option<Selection> {
+    let bottom = range.bottom().min(content.used_rows().checked_sub(1)?);
+    let right = range.right().min(content.used_cols().checked_sub(1)?);
+    Some(Selection { rows: bottom.checked_sub(range.row)? + 1, cols: right.checked_sub(range.col)? + 1, ..range })
+}
+
+// Moves the cursor's column by an offset like +2 or to a column given by its
+// label or number
+fn move_col(state: &mut AppState, target: &str) -> Result<()> {
+    let (row, col) = state.table_content.selection.cursor();
+    let usage = || VispError::Command("Usage: movecol +n|-n|column".to_string());
+    let to = if let Some(offset) = target.strip_prefix('+') {
+        col.checked_add(offset.parse().map_err(|_| usage())?)
+    } else if let Some(offset) = target.strip_prefix('-') {
+        col.checked_sub(offset.parse().map_err(|_| usage())?)
+    } else if let Ok(number) = target.parse::<u16>() {
+        number.checked_sub(1)
+    } else {
+        col_label_to_nr(target)
+    };
+    let to = to.ok_or_else(usage)?;
+    // Every column from one to the other moves
+    edit::check_lines(state, Axis::Cols, col.min(to), col.max(to))?;
+    let formulas = state.table_content.move_col(col, to, true);
+    state.table_content.selection.set_cursor(row, to);
+    state.undo.record(Change::MoveCol { from: col, to, formulas }, &state.options);
+    Ok(())
+}
+
+fn snapshot(state: &mut AppState, args: &str) -> Result<()> {
+    let (action, name) = args.split_once(char::is_whitespace)
+        .map(|(action, name)| (action, name.trim()))
+        .ok_or_else(|| VispError::Command("Usage: snapshot take|restore|delete name".to_string()))?;
+    let index = state.snapshots.iter().position(|(n, _)| n == name);
+    let not_found = || VispError::Command(format!("No snapshot named {}", name));
+    match action {
+        "take" => {
+            let snapshot = state.table_content.snapshot();
+            match index {
+                Some(i) => state.snapshots[i].1 = snapshot,
+                None => state.snapshots.push((name.to_string(), snapshot)),
+            }
+        }
+        "restore" => {
+            edit::check_writable(&state.options)?;
+            let (_, snapshot) = &state.snapshots[index.ok_or_else(not_found)?];
+            for (row, col) in snapshot.changed_cells(&state.table_content) {
+                let old = state.table_content.get_cell(row, col).cloned().unwrap_or(TableCell::Empty);
+                state.edit_log.record((row, col), &old, snapshot.get(row, col).unwrap_or(&TableCell::Empty));
+            }
+            // Undone as a whole, the widths, notes, styles and formats too
+            let old = Box::new(state.table_content.snapshot());
+            state.table_content.restore(snapshot);
+            let new = Box::new(state.table_content.snapshot());
+            state.undo.record(Change::Restore { old, new }, &state.options);
+        }
+        "delete" => {
+            state.snapshots.remove(index.ok_or_else(not_found)?);
+        }
+        _ => return Err(VispError::Command(format!("Unknown snapshot action: {}", action))),
+    }
+    Ok(())
+}
+
+pub fn open_palette(state: &mut AppState) {
+    let items = COMMANDS.iter()
+        .map(|c| format!("{:<12} {:<14} {}", c.name, c.args, c.description))
+        .collect();
+    state.picker = Some(Picker::new("Commands", items, PickerKind::Command));
+}
+
+// Runs a command chosen in the palette, commands with arguments are put on
+// the command line to complete them
+pub fn run_from_palette(state: &mut AppState, index: usize) {
+    let command = &COMMANDS[index];
+    if command.args.is_empty() {
+        dispatch(state, command.name);
+    } else {
+        state.remember_visual();
+        state.mode = AppMode::Command;
+        state.command_line.start(&format!("{} ", command.name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(lines: &[&str]) -> AppState {
+        let rows = lines.iter().map(|line| csv::parse_record(line, ',', None)).collect();
+        AppState::new(TableContent::from_rows(rows))
+    }
+
+    fn column(state: &AppState, col: u16) -> Vec<String> {
+        (0..state.table_content.used_rows()).map(|row| state.table_content.display(row, col)).collect()
+    }
+
+    fn info(state: &AppState) -> &str {
+        match &state.message {
+            Some(Message::Info(text)) => text,
+            _ => panic!("no info message"),
+        }
+    }
+
+    const LOG: &[&str] = &["build,ok", "test,FAILED", "lint,ok", "bench,FAILED"];
+
+    #[test]
+    fn global_delete() {
+        let mut state = state(LOG);
+        execute(&mut state, "g/FAILED/d").unwrap();
+        assert_eq!(column(&state, 0), ["build", "lint"]);
+        assert_eq!(info(&state), "2 rows deleted");
+        // Undone as one change
+        undo::undo(&mut state, 1);
+        assert_eq!(column(&state, 0), ["build", "test", "lint", "bench"]);
+    }
+
+    #[test]
+    fn global_pattern_is_a_regex() {
+        let mut state = state(LOG);
+        execute(&mut state, "g/^FAIL/deleterow").unwrap();
+        assert_eq!(column(&state, 0), ["build", "lint"]);
+        let mut state = self::state(LOG);
+        execute(&mut state, "v/^(ok|FAILED)$/d").unwrap_err();
+        execute(&mut state, "v/FAIL/s/$/!/").unwrap();
+        assert_eq!(column(&state, 1), ["ok!", "FAILED", "ok!", "FAILED"]);
+        assert_eq!(info(&state), "4 cells changed");
+        assert!(execute(&mut state, "g/(/d").is_err());
+    }
+
+    #[test]
+    fn global_normal() {
+        let mut state = state(LOG);
+        execute(&mut state, "g/ok/normal a?<Esc>").unwrap();
+        assert_eq!(column(&state, 0), ["build?", "test", "lint?", "bench"]);
+        assert_eq!(info(&state), "2 cells changed");
+        execute(&mut state, "g/FAILED/normal j").unwrap();
+        assert_eq!(info(&state), "2 matching rows, nothing changed");
+    }
+
+    #[test]
+    fn normal_on_a_range() {
+        let mut state = state(LOG);
+        state.table_content.selection.span((1, 1), (2, 1));
+        // Insert mode is left open
+        execute(&mut state, "normal a!").unwrap();
+        assert_eq!(column(&state, 1), ["ok", "FAILED!", "ok!", "FAILED"]);
+        assert_eq!(state.mode, AppMode::Normal);
+    }
+
+    const SCORES: &[&str] = &["name,score", "bob,7", "amy,", "cid,10", "dan,x", "eve,7"];
+
+    #[test]
+    fn sort_and_undo() {
+        let mut state = state(SCORES);
+        state.options.set("header").unwrap();
+        execute(&mut state, "sort B").unwrap();
+        // Numbers first, equal ones keep their order, empty cells last
+        assert_eq!(column(&state, 0), ["name", "bob", "eve", "cid", "dan", "amy"]);
+        assert_eq!(info(&state), "2 rows moved");
+        execute(&mut state, "sort! B").unwrap();
+        assert_eq!(column(&state, 0), ["name", "dan", "cid", "bob", "eve", "amy"]);
+        undo::undo(&mut state, 2);
+        assert_eq!(column(&state, 0), ["name", "bob", "amy", "cid", "dan", "eve"]);
+        assert!(execute(&mut state, "sort 1").is_err());
+    }
+
+    #[test]
+    fn sort_moves_formulas_and_notes() {
+        let mut state = state(&["3,=A1*2", "1,=A2*2", "2,=A3*2"]);
+        state.table_content.notes.insert((0, 0), "three".to_string());
+        execute(&mut state, "sort A").unwrap();
+        assert_eq!(column(&state, 1), ["2", "4", "6"]);
+        assert_eq!(state.table_content.notes.get(&(2, 0)).map(String::as_str), Some("three"));
+        undo::undo(&mut state, 1);
+        assert_eq!(column(&state, 1), ["6", "2", "4"]);
+        assert_eq!(state.table_content.notes.get(&(0, 0)).map(String::as_str), Some("three"));
+    }
+
+    #[test]
+    fn filter_rows() {
+        let mut state = state(SCORES);
+        state.options.set("header").unwrap();
+        execute(&mut state, "filter B >= 7").unwrap();
+        // Text and empty cells aren't compared with numbers
+        assert_eq!(info(&state), "2 rows hidden");
+        assert_eq!(state.table_content.hidden_rows.iter().copied().collect::<Vec<_>>(), [2, 4]);
+        execute(&mut state, "filter").unwrap();
+        assert!(state.table_content.hidden_rows.is_empty());
+        // Looked up in the search index
+        execute(&mut state, "filter! A ~ ^eve$").unwrap();
+        assert_eq!(state.table_content.hidden_rows.iter().copied().collect::<Vec<_>>(), [5]);
+        assert!(execute(&mut state, "filter B ~ (").is_err());
+    }
+
+    #[test]
+    fn movecol_keeps_formulas_reading_the_same_cells() {
+        let mut state = state(&["1,2,=A1*10+B1,=SUM(A1:B1)"]);
+        execute(&mut state, "movecol +1").unwrap();
+        assert_eq!(state.table_content.get_cell(0, 2).unwrap().source_string(), "=B1*10+A1");
+        formula::refresh_all(&mut state.table_content);
+        assert_eq!(state.table_content.display(0, 2), "12");
+        assert_eq!(state.table_content.display(0, 3), "3");
+        state.table_content.selection.set_cursor(0, 3);
+        execute(&mut state, "movecol A").unwrap();
+        assert_eq!(state.table_content.get_cell(0, 0).unwrap().source_string(), "=SUM(B1:C1)");
+        assert_eq!(state.table_content.get_cell(0, 3).unwrap().source_string(), "=C1*10+B1");
+        undo::undo(&mut state, 2);
+        assert_eq!(state.table_content.get_cell(0, 2).unwrap().source_string(), "=A1*10+B1");
+        assert_eq!(state.table_content.get_cell(0, 3).unwrap().source_string(), "=SUM(A1:B1)");
+    }
+
+    #[test]
+    fn protected_columns() {
+        let mut state = state(&["1,2,3,4"]);
+        state.table_content.protected.push(Selection { row: 0, col: 2, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        assert!(execute(&mut state, "movecol D").is_err());
+        assert!(execute(&mut state, "movecol B").is_ok());
+        state.table_content.selection.set_cursor(0, 2);
+        assert!(execute(&mut state, "hide").is_err());
+        assert!(state.table_content.hidden_cols.is_empty());
+    }
+
+    #[test]
+    fn hide_and_undo() {
+        let mut state = state(&["1,2,3,4"]);
+        let revision = state.table_content.revision;
+        state.table_content.selection.span((0, 1), (0, 2));
+        execute(&mut state, "hide").unwrap();
+        assert_eq!(state.table_content.hidden_cols, BTreeSet::from([1, 2]));
+        assert!(state.table_content.revision > revision);
+        execute(&mut state, "unhide all").unwrap();
+        assert!(state.table_content.hidden_cols.is_empty());
+        undo::undo(&mut state, 1);
+        assert_eq!(state.table_content.hidden_cols, BTreeSet::from([1, 2]));
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.hidden_cols.is_empty());
+    }
+
+    #[test]
+    fn sizes_and_undo() {
+        let mut state = state(&["1,2,3"]);
+        let revision = state.table_content.revision;
+        state.table_content.selection.span((0, 0), (0, 1));
+        execute(&mut state, "colwidth 20").unwrap();
+        assert_eq!((state.table_content.col_width(0), state.table_content.col_width(1)), (20, 20));
+        assert!(state.table_content.revision > revision);
+        structure::resize_cols(&mut state, 1, 2, 2).unwrap();
+        assert_eq!(state.table_content.col_width(1), 22);
+        undo::undo(&mut state, 1);
+        assert_eq!(state.table_content.col_width(1), 20);
+        assert!(!state.table_content.col_widths.contains_key(&2));
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.col_widths.is_empty());
+        state.table_content.protected.push(Selection { row: 0, col: 1, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        assert!(execute(&mut state, "colwidth 5").is_err());
+        assert!(execute(&mut state, "rowheight 2").is_err());
+        assert!(state.table_content.col_widths.is_empty());
+    }
+
+    #[test]
+    fn style_and_undo() {
+        let mut state = state(&["1,2", "3,4"]);
+        state.table_content.selection.span((0, 0), (1, 1));
+        execute(&mut state, "style bold fg=red").unwrap();
+        state.table_content.selection.set_cursor(0, 0);
+        execute(&mut state, "style nobold").unwrap();
+        let bold = |state: &AppState| (0..2).flat_map(|row| (0..2).map(move |col| (row, col)))
+            .filter(|position| state.table_content.styles.get(position).is_some_and(|style| style.bold))
+            .count();
+        assert_eq!(bold(&state), 3);
+        assert_eq!(state.table_content.styles[&(0, 0)].describe(), "fg=red");
+        undo::undo(&mut state, 1);
+        assert_eq!(bold(&state), 4);
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.styles.is_empty());
+        assert!(execute(&mut state, "style fg=mauve").is_err());
+    }
+
+    #[test]
+    fn style_protected() {
+        let mut state = state(&["1,2", "3,4"]);
+        state.table_content.protected.push(Selection { row: 1, col: 1, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        state.table_content.selection.span((0, 0), (1, 1));
+        assert!(execute(&mut state, "style bold").is_err());
+        assert!(state.table_content.styles.is_empty());
+        state.table_content.selection.set_cursor(0, 0);
+        assert!(execute(&mut state, "style bold").is_ok());
+    }
+
+    #[test]
+    fn notes_and_undo() {
+        let mut state = state(&["1,2"]);
+        execute(&mut state, "note first").unwrap();
+        execute(&mut state, "note second").unwrap();
+        execute(&mut state, "delnote").unwrap();
+        assert!(state.table_content.notes.is_empty());
+        undo::undo(&mut state, 1);
+        assert_eq!(state.table_content.notes[&(0, 0)], "second");
+        undo::undo(&mut state, 1);
+        assert_eq!(state.table_content.notes[&(0, 0)], "first");
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.notes.is_empty());
+
+        state.table_content.protected.push(Selection { row: 0, col: 1, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        state.table_content.selection.set_cursor(0, 1);
+        assert!(execute(&mut state, "note locked").is_err());
+        assert!(state.table_content.notes.is_empty());
+    }
+
+    #[test]
+    fn fmt_and_undo() {
+        let mut state = state(&["1.5,2", "3,4"]);
+        state.table_content.selection.span((0, 0), (1, 0));
+        execute(&mut state, "fmt right %.2f").unwrap();
+        execute(&mut state, "fmt center").unwrap();
+        assert_eq!(column(&state, 0), ["1.50", "3.00"]);
+        assert_eq!(state.table_content.formats[&(1, 0)].describe(), "center %.2f");
+        undo::undo(&mut state, 1);
+        assert_eq!(state.table_content.formats[&(1, 0)].describe(), "right %.2f");
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.formats.is_empty());
+
+        state.table_content.protected.push(Selection { row: 1, col: 0, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        assert!(execute(&mut state, "fmt %.1f").is_err());
+        assert!(state.table_content.formats.is_empty());
+    }
+
+    #[test]
+    fn fit_and_undo() {
+        let mut state = state(&["a,a long text"]);
+        state.table_content.selection.span((0, 0), (0, 1));
+        state.viewport.area = tui::layout::Rect::new(0, 0, 80, 24);
+        execute(&mut state, "fit").unwrap();
+        assert_eq!((state.table_content.col_width(0), state.table_content.col_width(1)), (2, 12));
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.col_widths.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+
+use crate::{commands, script, AppMode, AppState, Result, VispError};
+use crate::keymap::{parse_keys, Action};
+
+// Read at startup. For example:
+//
+//   keymap = "colemak"
+//   colorscheme = "mono"
+//
+//   [options]       # Like :set
+//   delimiter = ";"
+//   colwidth = 8
+//   number = false
+//
+//   [keys.normal]   # Also visual, visual_row and visual_column
+//   "<C-s>" = "undo"
+//   "q" = "none"    # Removes the binding
+//
+//   [aliases]
+//   W = "write"
+//   total = "apply +0"
+
+// VISP_CONFIG if set, otherwise $XDG_CONFIG_HOME/visp/config.toml with
+// ~/.config as the default
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("VISP_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let dir = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("visp").join("config.toml"))
+}
+
+// Applies the config file and runs init.rhai next to it, if they exist.
+// Everything before an error in the file stays applied.
+pub fn load(state: &mut AppState) -> Result<()> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+    if path.exists() {
+        let text = std::fs::read_to_string(&path)?;
+        let config: toml::Table = text.parse()
+            .map_err(|e: toml::de::Error| VispError::Parse(format!("{}: {}", path.display(), e.message())))?;
+        apply(state, &config).map_err(|e| VispError::Command(format!("{}: {}", path.display(), e)))?;
+        tracing::info!("Read config {}", path.display());
+    }
+    let script = path.with_file_name("init.rhai");
+    if script.exists() {
+        script::load(state, &script)?;
+    }
+    Ok(())
+}
+
+fn apply(state: &mut AppState, config: &toml::Table) -> Result<()> {
+    // The preset first, keys are changed on top of it
+    for name in ["keymap", "colorscheme"] {
+        if let Some(value) = config.get(name) {
+            commands::execute(state, &format!("{} {}", name, string(name, value)?))?;
+        }
+    }
+    for (name, value) in config {
+        match name.as_str() {
+            "keymap" | "colorscheme" => {}
+            "options" => set_options(state, table(name, value)?)?,
+            "keys" => bind_keys(state, table(name, value)?)?,
+            "aliases" => {
+                for (alias, command) in table(name, value)? {
+                    if commands::find(alias).is_some() {
+                        return Err(VispError::Command(format!("Alias hides a command: {}", alias)));
+                    }
+                    state.aliases.insert(alias.clone(), string(alias, command)?.to_string());
+                }
+            }
+            _ => return Err(VispError::Command(format!("Unknown setting: {}", name))),
+        }
+    }
+    crate::sheet::link(state);
+    Ok(())
+}
+
+fn set_options(state: &mut AppState, options: &toml::Table) -> Result<()> {
+    for (name, value) in options {
+        let argument = match value {
+            toml::Value::Boolean(true) => name.clone(),
+            toml::Value::Boolean(false) => format!("no{}", name),
+            toml::Value::Integer(n) => format!("{}={}", name, n),
+            toml::Value::String(s) => format!("{}={}", name, s),
+            _ => return Err(VispError::Command(format!("Option needs a boolean, number or string: {}", name))),
+        };
+        state.options.set(&argument)?;
+    }
+    Ok(())
+}
+
+fn bind_keys(state: &mut AppState, modes: &toml::Table) -> Result<()> {
+    for (mode, bindings) in modes {
+        let mode = match mode.as_str() {
+            "normal" => AppMode::Normal,
+            "visual" => AppMode::Visual,
+            "visual_row" => AppMode::VisualRow,
+            "visual_column" => AppMode::VisualColumn,
+            _ => return Err(VispError::Command(format!("Keys can't be bound in mode: {}", mode))),
+        };
+        for (keys, action) in table(mode.name(), bindings)? {
+            let sequence = parse_keys(keys).ok_or_else(|| VispError::Command("Empty key binding".to_string()))?;
+            match string(keys, action)? {
+                "none" => state.keymap.unbind(mode, &sequence),
+                name => {
+                    let action = Action::by_name(name).ok_or_else(|| VispError::Command(format!("Unknown action: {}", name)))?;
+                    state.keymap.bind(mode, &sequence, action);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn table<'a>(name: &str, value: &'a toml::Value) -> Result<&'a toml::Table> {
+    value.as_table().ok_or_else(|| VispError::Command(format!("Table required: {}", name)))
+}
+
+fn string<'a>(name: &str, value: &'a toml::Value) -> Result<&'a str> {
+    value.as_str().ok_or_else(|| VispError::Command(format!("String required: {}", name)))
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{encoding, format, Result};
+use crate::format::Locale;
+use crate::formula::Formula;
+use crate::grid::{TableCell, TableContent};
+use crate::options::Options;
+use crate::task::Progress;
+
+// Reads a file into rows of cells, see parse_field, and tells which encoding
+// it was in, see encoding::decode. At most u16::MAX rows are read, that is
+// all the table can hold.
+pub fn read(path: &Path, delimiter: char, locale: Option<&Locale>, progress: &Progress) -> Result<(Vec<Vec<TableCell>>, &'static str)> {
+    let (text, encoding) = encoding::decode(fs::read(path)?);
+    progress.check()?;
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut rows = parse(&text, delimiter, locale, threads, progress)?;
+    rows.truncate(u16::MAX as usize);
+    Ok((rows, encoding))
+}
+
+// Files smaller than this are read in one piece, starting threads takes longer
+const CHUNK_SIZE: usize = 1 << 20;
+
+// Chunks per thread, more of them show the progress in smaller steps
+const CHUNKS_PER_THREAD: usize = 8;
+
+// Rows of cells from the text of a file. Large files are cut into chunks at
+// line breaks between records, which are parsed by up to `threads` threads.
+// The rows are the same as with one thread. Fails if `progress` is cancelled.
+pub fn parse(text: &str, delimiter: char, locale: Option<&Locale>, threads: usize, progress: &Progress) -> Result<Vec<Vec<TableCell>>> {
+    let parse_chunk = |chunk: &str| -> Vec<Vec<TableCell>> {
+        records(chunk, delimiter).into_iter().map(|r| r.into_iter().map(|field| parse_field(field, locale)).collect()).collect()
+    };
+    let count = (text.len() / CHUNK_SIZE).min(threads.max(1) * CHUNKS_PER_THREAD);
+    if count <= 1 || delimiter == '"' {
+        return Ok(parse_chunk(text));
+    }
+    let threads = threads.clamp(1, count);
+    let cuts = record_starts(text.as_bytes(), count);
+    let chunks: Vec<&str> = cuts.windows(2).map(|w| &text[w[0]..w[1]]).collect();
+    progress.set(0, chunks.len() as u64);
+    // Each thread takes the next chunk nobody has taken yet
+    let next = AtomicUsize::new(0);
+    let mut parsed: Vec<(usize, Vec<Vec<TableCell>>)> = std::thread::scope(|scope| {
+        let take = || {
+            let mut parsed = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= chunks.len() || progress.cancelled() {
+                    return parsed;
+                }
+                parsed.push((i, parse_chunk(chunks[i])));
+                progress.advance();
+            }
+        };
+        let handles: Vec<_> = (0..threads).map(|_| scope.spawn(take)).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    progress.check()?;
+    parsed.sort_unstable_by_key(|(i, _)| *i);
+    Ok(parsed.into_iter().flat_map(|(_, rows)| rows).collect())
+}
+
+// Where to cut `text` into about `count` chunks, from 0 to its length. A
+// line break ends a record if an even number of quotes comes before it,
+// otherwise it is inside a quoted field. Quotes and line breaks are single
+// bytes in UTF-8, so the bytes can be searched.
+fn record_starts(text: &[u8], count: usize) -> Vec<usize> {
+    let size = text.len().div_ceil(count);
+    let quotes: Vec<usize> = std::thread::scope(|scope| {
+        let handles: Vec<_> = text.chunks(size).map(|part| scope.spawn(move || part.iter().filter(|&&b| b == b'"').count())).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    let mut cuts = vec![0];
+    let mut quotes_before = 0;
+    for (i, quotes_in) in quotes[..quotes.len() - 1].iter().enumerate() {
+        quotes_before += quotes_in;
+        // The first line break after the end of this part which ends a record
+        let mut quoted = quotes_before % 2 == 1;
+        let start = (i + 1) * size;
+        let cut = text[start..].iter().position(|&b| {
+            if b == b'"' {
+                quoted = !quoted;
+            }
+            b == b'\n' && !quoted
+        });
+        if let Some(cut) = cut.map(|cut| start + cut + 1).filter(|&cut| cut > *cuts.last().unwrap()) {
+            cuts.push(cut);
+        }
+    }
+    cuts.push(text.len());
+    cuts.dedup();
+    cuts
+}
+
+// Writes the table so that read gives the same cells again, with the
+// delimiter, quoting, line breaks and encoding from the options. With
+// csvquote=never that only holds if no field contains the delimiter or a line
+// break. Returns the number of rows written.
+pub fn write(path: &Path, content: &TableContent, options: &Options) -> Result<usize> {
+    let (text, rows) = to_text(content, options);
+    // Nothing is written if a character can't be encoded
+    let bytes = encoding::encode(&text, &options.fileencoding)?;
+    fs::write(path, bytes)?;
+    Ok(rows)
+}
+
+// The file write would write before it is encoded, and its number of rows
+pub fn to_text(content: &TableContent, options: &Options) -> (String, usize) {
+    let mut text = String::new();
+    let delimiter = options.delimiter();
+    let locale = options.locale();
+    let rows = if content.iter().next().is_none() { 0 } else { content.used_rows() as u32 };
+    for row in 0..rows {
+        // Fields up to the last cell of the row, empty rows stay empty lines
+        let mut fields = Vec::new();
+        for ((_, col), cell) in content.iter_from((row as u16, 0)).take_while(|&((r, _), _)| r as u32 == row) {
+            fields.resize(col as usize, String::new());
+            fields.push(match (locale, cell) {
+                (Some(locale), TableCell::Value(_) | TableCell::Float(_)) => locale.write(&field(cell)),
+                _ => field(cell),
+            });
+        }
+        let fields: Vec<String> = match options.csvquote.as_str() {
+            "always" => fields.iter().map(|f| format!("\"{}\"", f.replace('"', "\"\""))).collect(),
+            "never" => fields,
+            _ => fields.iter().map(|f| quote(f, delimiter)).collect(),
+        };
+        let _ = write!(text, "{}", fields.join(&delimiter.to_string()));
+        if row + 1 < rows || options.endofline {
+            text += options.line_break();
+        }
+    }
+    (text, rows as usize)
+}
+
+// A single line, e.g. from --stream
+pub fn parse_record(line: &str, delimiter: char, locale: Option<&Locale>) -> Vec<TableCell> {
+    let record = records(line.trim_end_matches('\r'), delimiter).into_iter().next().unwrap_or_default();
+    record.into_iter().map(|field| parse_field(field, locale)).collect()
+}
+
+// Values become numbers, dates etc. only if they are written the way we would
+// write them again, so "007" or "1.0" stay text. With a locale only numbers
+// in its form are numbers, their groups may come and go. Fields starting with
+// = are formulas, with '= they are text, see field.
+pub fn parse_field(field: String, locale: Option<&Locale>) -> TableCell {
+    let value = match locale {
+        Some(locale) => locale.read(&field)
+            .and_then(|number| format::parse_value(&number).filter(|cell| cell.format_string() == number))
+            .or_else(|| format::parse_value(&field).filter(|cell| !matches!(cell, TableCell::Value(_) | TableCell::Float(_)) && cell.format_string() == field)),
+        None => format::parse_value(&field).filter(|cell| cell.format_string() == field),
+    };
+    if field.is_empty() {
+        TableCell::Empty
+    } else if let Some(cell) = value {
+        cell
+    } else if let Some(source) = field.strip_prefix('=') {
+        TableCell::Formula(Box::new(Formula::new(source)))
+    } else if field.trim_start_matches('\'').starts_with('=') {
+        TableCell::String(field[1..].to_string())
+    } else {
+        TableCell::String(field)
+    }
+}
+
+// How a cell is written. Text starting with = gets a ' in front, like
+// spreadsheets do, so only formulas are read as formulas again and other
+// programs don't run it either. Text which already starts with ' and then =
+// gets another one.
+pub fn field(cell: &TableCell) -> String {
+    match cell {
+        TableCell::String(text) if text.trim_start_matches('\'').starts_with('=') => format!("'{}", text),
+        cell => cell.source_string(),
+    }
+}
+
+// Splits text into records of fields. Fields can be quoted with " to contain
+// the delimiter, line breaks or "" for a quote.
+pub fn records(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                record.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => record.push(String::new()),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => records.push(std::mem::replace(&mut record, vec![String::new()])),
+            c => record.last_mut().unwrap().push(c),
+        }
+    }
+    // No line break at the end of the last record
+    if record.len() > 1 || !record[0].is_empty() {
+        records.push(record);
+    }
+    records
+}
+
+// Quotes a field if it contains anything which would split it
+pub fn quote(text: &str, delimiter: char) -> String {
+    if text.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "name,count,price,date,note\n\
+                        apple,3,1.25,2024-01-31,\"red, green\"\n\
+                        pear,007,1.0,,\"say \"\"hi\"\"\"\n\
+                        \n\
+                        sum,=SUM(B2:B3),'=text,\"two\nlines\",true\n";
+
+    fn sources(rows: &[Vec<TableCell>]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(TableCell::source_string).collect()).collect()
+    }
+
+    #[test]
+    fn fields_keep_their_kind() {
+        let rows = parse(TEXT, ',', None, 1, &Progress::default()).unwrap();
+        assert_eq!(rows.len(), 5);
+        assert!(matches!(rows[1][1], TableCell::Value(3)));
+        assert!(matches!(rows[1][2], TableCell::Float(_)));
+        assert!(matches!(rows[1][3], TableCell::Date(_)));
+        assert!(matches!(&rows[1][4], TableCell::String(text) if text == "red, green"));
+        // Numbers written differently stay text
+        assert!(matches!(&rows[2][1], TableCell::String(text) if text == "007"));
+        assert!(matches!(&rows[2][2], TableCell::String(text) if text == "1.0"));
+        assert!(matches!(rows[2][3], TableCell::Empty));
+        assert!(matches!(&rows[2][4], TableCell::String(text) if text == "say \"hi\""));
+        assert!(rows[3].iter().all(|cell| *cell == TableCell::Empty));
+        assert!(matches!(&rows[4][1], TableCell::Formula(f) if f.source == "SUM(B2:B3)"));
+        assert!(matches!(&rows[4][2], TableCell::String(text) if text == "=text"));
+        assert!(matches!(&rows[4][3], TableCell::String(text) if text == "two\nlines"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let rows = parse(TEXT, ',', None, 1, &Progress::default()).unwrap();
+        let content = TableContent::from_rows(rows);
+        let (text, count) = to_text(&content, &Options::default());
+        assert_eq!(count, 5);
+        assert_eq!(text, TEXT);
+    }
+
+    #[test]
+    fn threads_give_the_same_rows() {
+        let text = TEXT.repeat(200);
+        let one = parse(&text, ',', None, 1, &Progress::default()).unwrap();
+        let four = parse(&text, ',', None, 4, &Progress::default()).unwrap();
+        assert_eq!(one.len(), 1000);
+        assert_eq!(sources(&one), sources(&four));
+    }
+
+    #[test]
+    fn options_of_written_files() {
+        let content = TableContent::from_rows(parse("a;b\n1;x y\n", ';', None, 1, &Progress::default()).unwrap());
+        let mut options = Options::default();
+        options.set("delimiter=;").unwrap();
+        options.set("fileformat=dos").unwrap();
+        options.set("noendofline").unwrap();
+        assert_eq!(to_text(&content, &options).0, "a;b\r\n1;x y");
+        options.set("csvquote=always").unwrap();
+        assert_eq!(to_text(&content, &options).0, "\"a\";\"b\"\r\n\"1\";\"x y\"");
+    }
+
+    #[test]
+    fn locale_round_trip() {
+        let locale = Locale::find("de");
+        let text = "Preis;Menge;Jahr\n1.234,5;3;2024\n0,25;1.000;x\n";
+        let rows = parse(text, ';', locale, 1, &Progress::default()).unwrap();
+        assert!(matches!(rows[1][0], TableCell::Float(f) if f == 1234.5));
+        assert!(matches!(rows[1][1], TableCell::Value(3)));
+        assert!(matches!(rows[2][0], TableCell::Float(f) if f == 0.25));
+        assert!(matches!(rows[2][1], TableCell::Value(1000)));
+        let mut options = Options::default();
+        options.set("delimiter=;").unwrap();
+        options.set("locale=de").unwrap();
+        // Whole numbers are written without groups
+        let (written, _) = to_text(&TableContent::from_rows(rows), &options);
+        assert_eq!(written, "Preis;Menge;Jahr\n1.234,5;3;2024\n0,25;1000;x\n");
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("visp-csv-test-{}.csv", std::process::id()));
+        let text = format!("{}Äpfel,ß\n", TEXT);
+        let content = TableContent::from_rows(parse(&text, ',', None, 1, &Progress::default()).unwrap());
+        let mut options = Options::default();
+        options.set("fileencoding=cp1252").unwrap();
+        write(&path, &content, &options).unwrap();
+        let (rows, encoding) = read(&path, ',', None, &Progress::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(encoding, "cp1252");
+        assert_eq!(sources(&rows), sources(&parse(&text, ',', None, 1, &Progress::default()).unwrap()));
+    }
+}
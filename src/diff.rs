@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use crate::{commands, csv, formula, AppState, Message, Result, VispError};
+use crate::grid::{TableCell, TableContent};
+use crate::render::Viewport;
+use crate::task::Progress;
+
+// visp --diff old.csv new.csv, e.g. as the difftool of git:
+//   [difftool "visp"]
+//       cmd = visp --diff "$LOCAL" "$REMOTE"
+// The new file is opened as usual and the old one is shown left of it,
+// scrolled along with the current window. Cells which differ are
+// highlighted on both sides, ]c and [c go to them and :diffoff ends it.
+pub struct Diff {
+    pub sheet: String, // Name of the sheet with the new file
+    pub old: TableContent,
+    pub viewport: Viewport, // Follows that of the current window
+}
+
+pub fn start(state: &mut AppState, old: &Path, new: &Path) -> Result<()> {
+    let (rows, _) = csv::read(old, state.options.delimiter(), state.options.locale(), &Progress::default())
+        .map_err(|e| VispError::Command(format!("\"{}\" not read: {}", old.display(), e)))?;
+    commands::dispatch(state, &format!("edit {}", new.display()));
+    if let Some(Message::Error(e)) = &state.message {
+        return Err(VispError::Command(e.clone()));
+    }
+    let mut old_content = TableContent::from_rows(rows);
+    old_content.default_col_width = Some(state.options.colwidth);
+    formula::refresh_all(&mut old_content);
+    let count = differences(&old_content, &state.table_content).len();
+    state.diff = Some(Diff { sheet: state.sheets[state.sheet].name.clone(), old: old_content, viewport: Viewport::default() });
+    state.message = Some(Message::Info(format!(
+        "\"{}\" left of \"{}\", {} cells differ, ]c goes to the next",
+        old.display(),
+        new.display(),
+        count
+    )));
+    if count > 0 {
+        next(state, true, None);
+    }
+    Ok(())
+}
+
+// The diff, if the current sheet is the new side of it
+pub fn current(state: &AppState) -> Option<&Diff> {
+    state.diff.as_ref().filter(|diff| state.sheets[state.sheet].name == diff.sheet)
+}
+
+// Cells which differ between both tables, row by row. Formulas differ by
+// their source, not their value.
+fn differences(old: &TableContent, new: &TableContent) -> Vec<(u16, u16)> {
+    let mut differences = Vec::new();
+    let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+    loop {
+        let (a, b) = (old.peek().map(|&(position, _)| position), new.peek().map(|&(position, _)| position));
+        match (a, b) {
+            (None, None) => break,
+            (Some(a), b) if b.is_none_or(|b| a < b) => {
+                old.next();
+                differences.push(a);
+            }
+            (a, Some(b)) if a.is_none_or(|a| b < a) => {
+                new.next();
+                differences.push(b);
+            }
+            _ => {
+                let ((position, before), (_, after)) = (old.next().unwrap(), new.next().unwrap());
+                if !before.same_source(after) {
+                    differences.push(position);
+                }
+            }
+        }
+    }
+    differences
+}
+
+// For drawing either side
+pub fn differs(a: &TableContent, b: &TableContent, row: u16, col: u16) -> bool {
+    let (a, b) = (a.get_cell(row, col).unwrap_or(&TableCell::Empty), b.get_cell(row, col).unwrap_or(&TableCell::Empty));
+    !a.same_source(b)
+}
+
+// ]c and [c, like in vim's diff mode
+pub fn next(state: &mut AppState, forward: bool, count: Option<u32>) {
+    let Some(diff) = current(state) else {
+        return;
+    };
+    let cursor = state.table_content.selection.cursor();
+    let differences = differences(&diff.old, &state.table_content);
+    let count = count.unwrap_or(1).max(1) as usize;
+    let found = if forward {
+        differences.iter().filter(|&&position| position > cursor).nth(count - 1)
+    } else {
+        differences.iter().rev().filter(|&&position| position < cursor).nth(count - 1)
+    };
+    match found.copied() {
+        Some((row, col)) => state.table_content.selection.set_cursor(row, col),
+        None => state.message = Some(Message::Error("No more differences".to_string())),
+    }
+}
+
+// :diffoff
+pub fn stop(state: &mut AppState) -> Result<()> {
+    state.diff.take().ok_or_else(|| VispError::Command("Not comparing, see visp --diff".to_string()))?;
+    Ok(())
+}
@@ -0,0 +1,397 @@
+use std::collections::HashSet;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::{format, formula, AppState, AppMode, Message, Result, VispError};
+use crate::formula::Formula;
+use crate::line_normal::LineNormal;
+use crate::grid::{self, cell_name, Axis, Selection, TableCell, TableContent};
+use crate::options::Options;
+use crate::undo::{CellChange, Change};
+
+// Text of the cell being edited in Insert mode
+pub struct EditBuffer {
+    pub cell: (u16, u16),
+    pub line: LineBuffer,
+    pub completion: Option<Completion>, // While Ctrl-N and Ctrl-P are pressed
+    pub point: Option<Point>, // After Ctrl-O until another key is typed
+    pub normal: LineNormal, // Active after Ctrl-F until i, a, c or the like
+}
+
+// The reference Ctrl-O put into a formula, changed by moving the cursor of
+// the table. It stays on the edited cell otherwise.
+pub struct Point {
+    start: usize, // In characters, like the cursor
+    len: usize,
+    range: bool, // After v the cursor extends the reference
+}
+
+// Values of the column offered by Ctrl-N and Ctrl-P, in the order Ctrl-N
+// goes through them
+pub struct Completion {
+    typed: String, // Before the cursor when completion started
+    rest: String, // After the cursor, kept behind every match
+    matches: Vec<String>,
+    index: usize, // Into matches, or matches.len() for what was typed
+}
+
+// A line of text being typed, in Insert mode or on the command line
+#[derive(Default)]
+pub struct LineBuffer {
+    pub text: String,
+    pub cursor: usize, // In characters, not bytes
+}
+
+impl LineBuffer {
+    // The cursor starts at the end
+    pub fn new(text: String) -> Self {
+        let cursor = text.chars().count();
+        Self { text, cursor }
+    }
+
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.text.char_indices().nth(cursor).map_or(self.text.len(), |(i, _)| i)
+    }
+
+    pub fn insert(&mut self, c: char) {
+        let index = self.byte_index(self.cursor);
+        self.text.insert(index, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.delete();
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            let index = self.byte_index(self.cursor);
+            self.text.remove(index);
+        }
+    }
+
+    // Deletes back to the start of the word before the cursor, like Ctrl-W in vim
+    pub fn delete_word(&mut self) {
+        let chars: Vec<char> = self.text.chars().collect();
+        let mut start = self.cursor;
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        let (from, to) = (self.byte_index(start), self.byte_index(self.cursor));
+        self.text.replace_range(from..to, "");
+        self.cursor = start;
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    // The part of the text shown in a cell of `width` characters, scrolled so
+    // that the cursor stays visible, and the cursor's offset in it
+    pub fn window(&self, width: u16) -> (String, u16) {
+        let start = self.cursor.saturating_sub(width.saturating_sub(1) as usize);
+        let text = self.text.chars().skip(start).take(width as usize).collect();
+        (text, (self.cursor - start) as u16)
+    }
+}
+
+// Numbers become values, text starting with = formulas, everything else text
+pub fn parse_cell(text: &str) -> TableCell {
+    if text.is_empty() {
+        TableCell::Empty
+    } else if let Some(source) = text.strip_prefix('=') {
+        TableCell::Formula(Box::new(Formula::new(source)))
+    } else if let Some(cell) = format::parse_value(text) {
+        cell
+    } else {
+        TableCell::String(text.to_string())
+    }
+}
+
+// Starts editing the cell under the cursor, empty if `clear` is set
+pub fn start_insert(state: &mut AppState, clear: bool) {
+    if let Err(e) = check_writable(&state.options) {
+        state.message = Some(Message::Error(e.to_string()));
+        return;
+    }
+    let cell = state.table_content.selection.cursor();
+    let text = match state.table_content.get_cell(cell.0, cell.1) {
+        Some(content) if !clear => content.source_string(),
+        _ => String::new(),
+    };
+    state.edit = Some(EditBuffer { cell, line: LineBuffer::new(text), completion: None, point: None, normal: LineNormal::default() });
+    state.mode = AppMode::Insert;
+}
+
+// Writes the edited text back into the table and leaves Insert mode
+pub fn commit(state: &mut AppState) {
+    state.mode = AppMode::Normal;
+    state.entry = None;
+    let edit = match state.edit.take() {
+        Some(edit) => edit,
+        None => return,
+    };
+    // The formula is stored anyway and shows an error, so the text isn't lost
+    if let Some(Err(e)) = edit.line.text.strip_prefix('=').map(formula::check) {
+        state.message = Some(Message::Error(e.to_string()));
+    }
+    if let Err(e) = replace_cells(state, vec![(edit.cell, parse_cell(&edit.line.text))]) {
+        state.message = Some(Message::Error(e.to_string()));
+    }
+}
+
+// :entry, for typing in data from paper. The cells of `range` are edited one
+// after another: Enter writes one and goes down, Tab goes right and Shift-Tab
+// left. At an edge of the range they wrap into the next column or row and
+// from its end back to the start. Esc writes the cell and stops. A single
+// cell stands for everything below and right of it.
+pub fn start_entry(state: &mut AppState, range: Selection) -> Result<()> {
+    check_writable(&state.options)?;
+    let (top, left, bottom, right) = range.bounds();
+    let range = if (top, left) == (bottom, right) {
+        Selection { rows: u16::MAX - top, cols: u16::MAX - left, ..range }
+    } else {
+        range
+    };
+    state.table_content.selection.set_cursor(top, left);
+    start_insert(state, false);
+    state.entry = Some(range);
+    Ok(())
+}
+
+// Enter, Tab and Shift-Tab while :entry runs, returns whether they were used
+pub fn entry_key(state: &mut AppState, key: KeyCode) -> bool {
+    let (Some(range), Some(edit)) = (state.entry, &state.edit) else {
+        return false;
+    };
+    let (top, left, bottom, right) = range.bounds();
+    let (row, col) = edit.cell;
+    let (row, col) = match key {
+        KeyCode::Enter if row < bottom => (row + 1, col),
+        KeyCode::Enter => (top, if col < right { col + 1 } else { left }),
+        KeyCode::Tab if col < right => (row, col + 1),
+        KeyCode::Tab => (if row < bottom { row + 1 } else { top }, left),
+        KeyCode::BackTab if col > left => (row, col - 1),
+        KeyCode::BackTab => (if row > top { row - 1 } else { bottom }, right),
+        _ => return false,
+    };
+    commit(state);
+    state.table_content.selection.set_cursor(row, col);
+    start_insert(state, false);
+    state.entry = Some(range);
+    true
+}
+
+// Ctrl-N and Ctrl-P, like keyword completion in vim. The text before the
+// cursor is completed with the other values in the column which start with
+// it, ignoring case. Ctrl-N offers the nearest below first and Ctrl-P the
+// nearest above, after the last match the typed text comes back.
+pub fn complete(state: &mut AppState, forward: bool) {
+    let Some(edit) = &mut state.edit else {
+        return;
+    };
+    if edit.completion.is_none() {
+        let typed: String = edit.line.text.chars().take(edit.line.cursor).collect();
+        let rest = edit.line.text.chars().skip(edit.line.cursor).collect();
+        let matches = column_values(&state.table_content, edit.cell, &typed);
+        if matches.is_empty() {
+            let column = grid::col_nr_to_label(edit.cell.1);
+            state.message = Some(Message::Error(format!("No value in column {} starts with \"{}\"", column, typed)));
+            return;
+        }
+        let index = matches.len();
+        edit.completion = Some(Completion { typed, rest, matches, index });
+    }
+    let Some(completion) = &mut edit.completion else {
+        return;
+    };
+    let stops = completion.matches.len() + 1;
+    let steps = if forward { 1 } else { stops - 1 };
+    completion.index = (completion.index + steps) % stops;
+    let (text, message) = match completion.matches.get(completion.index) {
+        Some(value) => (value, format!("Match {} of {}", completion.index + 1, completion.matches.len())),
+        None => (&completion.typed, "Back at the typed text".to_string()),
+    };
+    edit.line = LineBuffer::new(text.clone());
+    edit.line.text.push_str(&completion.rest);
+    state.message = Some(Message::Info(message));
+}
+
+// Distinct values of the column of `cell` starting with `typed`, from the
+// one below `cell` down, then from the top. Formulas aren't offered.
+fn column_values(content: &TableContent, cell: (u16, u16), typed: &str) -> Vec<String> {
+    let typed = typed.to_lowercase();
+    let below = content.iter_from(cell).filter(|&(position, _)| position != cell);
+    let mut seen = HashSet::new();
+    below.chain(content.iter_before(cell))
+        .filter(|&((_, col), value)| col == cell.1 && !matches!(value, TableCell::Formula(_)))
+        .map(|(_, value)| value.source_string())
+        .filter(|value| value.to_lowercase().starts_with(&typed) && value.to_lowercase() != typed)
+        .filter(|value| seen.insert(value.clone()))
+        .collect()
+}
+
+// Ctrl-O in a formula. Until another key is typed, h, j, k, l and the arrow
+// keys move the table's cursor and write the cell it is on at the text
+// cursor, v makes it a range from there and Esc keeps the reference.
+pub fn start_point(state: &mut AppState) {
+    let Some(edit) = &mut state.edit else {
+        return;
+    };
+    if !edit.line.text.starts_with('=') {
+        state.message = Some(Message::Error("Only formulas point at cells, start with =".to_string()));
+        return;
+    }
+    edit.completion = None;
+    edit.point = Some(Point { start: edit.line.cursor, len: 0, range: false });
+}
+
+// A key typed while pointing, returns whether it was used. Other keys end
+// pointing and are typed as usual.
+pub fn point_key(state: &mut AppState, key: KeyEvent) -> bool {
+    let content = &mut state.table_content;
+    let Some(edit) = &mut state.edit else {
+        return false;
+    };
+    let Some(point) = &mut edit.point else {
+        return false;
+    };
+    let (row, col) = content.selection.cursor();
+    // The arrow keys do the same as hjkl, Ctrl with a letter ends pointing
+    let code = match key.code {
+        KeyCode::Left => KeyCode::Char('h'),
+        KeyCode::Down => KeyCode::Char('j'),
+        KeyCode::Up => KeyCode::Char('k'),
+        KeyCode::Right => KeyCode::Char('l'),
+        KeyCode::Char(_) if key.modifiers.contains(KeyModifiers::CONTROL) => KeyCode::Null,
+        code => code,
+    };
+    let (row, col) = match code {
+        KeyCode::Char('h') => (row, content.step_cols(col, 1, false)),
+        KeyCode::Char('j') => (content.step_rows(row, 1, true), col),
+        KeyCode::Char('k') => (content.step_rows(row, 1, false), col),
+        KeyCode::Char('l') => (row, content.step_cols(col, 1, true)),
+        KeyCode::Char('v') => {
+            point.range = !point.range;
+            (row, col)
+        }
+        code => {
+            edit.point = None;
+            content.selection.set_cursor(edit.cell.0, edit.cell.1);
+            return code == KeyCode::Esc;
+        }
+    };
+    if point.range {
+        content.selection.extend_to_row(row);
+        content.selection.extend_to_col(col);
+    } else {
+        content.selection.set_cursor(row, col);
+    }
+    // Nothing is written before the first move, the edited cell can't be meant
+    if content.selection.cursor() == edit.cell && point.len == 0 {
+        return true;
+    }
+    let name = content.selection.name();
+    let line = &mut edit.line;
+    let (from, to) = (line.byte_index(point.start), line.byte_index(point.start + point.len));
+    line.text.replace_range(from..to, &name);
+    point.len = name.chars().count();
+    line.cursor = point.start + point.len;
+    true
+}
+
+// Every change to the table checks this first, see :set readonly
+pub fn check_writable(options: &Options) -> Result<()> {
+    if options.readonly {
+        return Err(VispError::Command("The table is read-only, see :set noreadonly".to_string()));
+    }
+    Ok(())
+}
+
+// Changes to whole columns or rows, like moving, hiding or resizing them,
+// are refused where a protected range is
+pub fn check_lines(state: &AppState, axis: Axis, first: u16, last: u16) -> Result<()> {
+    check_writable(&state.options)?;
+    if state.options.protect {
+        let crosses = |range: &&Selection| match axis {
+            Axis::Cols => range.col <= last && range.right() >= first,
+            Axis::Rows => range.row <= last && range.bottom() >= first,
+        };
+        if let Some(range) = state.table_content.protected.iter().find(crosses) {
+            return Err(VispError::Command(format!("{} is protected, see :set noprotect", range.name())));
+        }
+    }
+    Ok(())
+}
+
+// Changes to how cells look, like :style, are refused if any cell of the
+// range is protected
+pub fn check_cells(state: &AppState, range: Selection) -> Result<()> {
+    check_writable(&state.options)?;
+    if state.options.protect {
+        let overlaps = |protected: &&Selection| protected.row <= range.bottom() && protected.bottom() >= range.row
+            && protected.col <= range.right() && protected.right() >= range.col;
+        if let Some(protected) = state.table_content.protected.iter().find(overlaps) {
+            return Err(VispError::Command(format!("{} is protected, see :set noprotect", protected.name())));
+        }
+    }
+    Ok(())
+}
+
+// Replaces cells as one step which can be undone and returns how many
+// changed. Nothing is changed if one of them is protected.
+pub fn replace_cells(state: &mut AppState, cells: Vec<((u16, u16), TableCell)>) -> Result<usize> {
+    check_writable(&state.options)?;
+    let content = &state.table_content;
+    if state.options.protect {
+        if let Some(((row, col), _)) = cells.iter().find(|((row, col), _)| content.is_protected(*row, *col)) {
+            return Err(VispError::Command(format!("{} is protected, see :set noprotect", cell_name(*row, *col))));
+        }
+    }
+
+    let mut replaced = Vec::new();
+    let mut changes = Vec::new();
+    for (cell, new) in cells {
+        let old = content.get_cell(cell.0, cell.1).cloned().unwrap_or(TableCell::Empty);
+        if old != new {
+            state.edit_log.record(cell, &old, &new);
+            replaced.push((cell, new.clone()));
+            changes.push(CellChange { cell, old, new });
+        }
+    }
+    let changed = changes.len();
+    if changed > 0 {
+        state.table_content.set_cells(replaced);
+        state.undo.record(Change::Cells(changes), &state.options);
+    }
+    Ok(changed)
+}
+
+pub fn cancel(state: &mut AppState) {
+    state.edit = None;
+    state.entry = None;
+    state.mode = AppMode::Normal;
+}
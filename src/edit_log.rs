@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{csv, Result};
+use crate::grid::{cell_name, TableCell};
+
+// Every change of a cell value, oldest first, see :editlog
+#[derive(Default)]
+pub struct EditLog {
+    pub entries: Vec<Edit>,
+}
+
+pub struct Edit {
+    pub time: SystemTime,
+    pub user: String,
+    pub cell: (u16, u16),
+    pub old: String,
+    pub new: String,
+}
+
+impl EditLog {
+    pub fn record(&mut self, cell: (u16, u16), old: &TableCell, new: &TableCell) {
+        self.record_by(&std::env::var("USER").unwrap_or_default(), cell, old, new);
+    }
+
+    // A change made by someone else, see share.rs
+    pub fn record_by(&mut self, user: &str, cell: (u16, u16), old: &TableCell, new: &TableCell) {
+        self.entries.push(Edit {
+            time: SystemTime::now(),
+            user: user.to_string(),
+            cell,
+            old: old.source_string(),
+            new: new.source_string(),
+        });
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.entries.iter()
+            .map(|e| format!("{}  {:<10} {:<8} {} -> {}", timestamp(e.time), e.user, cell_name(e.cell.0, e.cell.1), e.old, e.new))
+            .collect()
+    }
+
+    pub fn write_csv(&self, path: &Path) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "time,user,cell,old,new")?;
+        for e in &self.entries {
+            let fields = [timestamp(e.time), e.user.clone(), cell_name(e.cell.0, e.cell.1), e.old.clone(), e.new.clone()];
+            let fields: Vec<String> = fields.iter().map(|f| csv::quote(f, ',')).collect();
+            writeln!(file, "{}", fields.join(","))?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// ISO 8601 in UTC, e.g. 2023-04-01T12:30:00Z
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
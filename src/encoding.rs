@@ -0,0 +1,66 @@
+use crate::{Result, VispError};
+
+// Values of the fileencoding option
+pub const ENCODINGS: &[&str] = &["utf-8", "utf-8-bom", "latin1", "cp1252", "utf-16le", "utf-16be"];
+
+// What Windows-1252 has at 0x80 to 0x9F, where Latin-1 has control
+// characters. The five bytes it leaves out stay those control characters.
+const CP1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+// The text of a file and its encoding. A byte order mark tells UTF-8 and
+// UTF-16 apart, otherwise text which isn't valid UTF-8 is taken to be
+// Windows-1252, which most legacy exports are and which reads any Latin-1
+// text except for control characters.
+pub fn decode(bytes: Vec<u8>) -> (String, &'static str) {
+    if let Some(rest) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        return (String::from_utf8_lossy(rest).into_owned(), "utf-8-bom");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xff\xfe") {
+        return (utf16(rest, u16::from_le_bytes), "utf-16le");
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xfe\xff") {
+        return (utf16(rest, u16::from_be_bytes), "utf-16be");
+    }
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, "utf-8"),
+        Err(e) => (e.as_bytes().iter().map(|&b| cp1252_char(b)).collect(), "cp1252"),
+    }
+}
+
+fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+fn cp1252_char(b: u8) -> char {
+    match b {
+        0x80..=0x9f => CP1252[(b - 0x80) as usize],
+        b => b as char,
+    }
+}
+
+// The bytes of `text` in one of ENCODINGS. Fails on the first character the
+// encoding doesn't have, rather than writing something else.
+pub fn encode(text: &str, encoding: &str) -> Result<Vec<u8>> {
+    let single_byte = |to_byte: fn(char) -> Option<u8>| {
+        text.chars()
+            .map(|c| to_byte(c).ok_or_else(|| VispError::Command(format!("{} can't be written in {}, see :set fileencoding", c, encoding))))
+            .collect::<Result<Vec<u8>>>()
+    };
+    match encoding {
+        "utf-8-bom" => Ok([b"\xef\xbb\xbf".as_slice(), text.as_bytes()].concat()),
+        "latin1" => single_byte(|c| u8::try_from(c).ok()),
+        "cp1252" => single_byte(|c| match CP1252.iter().position(|&other| other == c) {
+            Some(i) => Some(0x80 + i as u8),
+            None => u8::try_from(c).ok().filter(|b| !(0x80..=0x9f).contains(b)),
+        }),
+        "utf-16le" => Ok([0xfeffu16].into_iter().chain(text.encode_utf16()).flat_map(u16::to_le_bytes).collect()),
+        "utf-16be" => Ok([0xfeffu16].into_iter().chain(text.encode_utf16()).flat_map(u16::to_be_bytes).collect()),
+        _ => Ok(text.as_bytes().to_vec()),
+    }
+}
@@ -0,0 +1,18 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum VispError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Formula error: {0}")]
+    Formula(String),
+    #[error("{0}")]
+    Command(String),
+    #[error("Script error: {0}")]
+    Script(String),
+}
+
+pub type Result<T> = std::result::Result<T, VispError>;
@@ -0,0 +1,146 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{csv, edit, encoding, sheet, task, AppState, Message, Result, VispError};
+use crate::format::Locale;
+use crate::grid::TableCell;
+use crate::io::AppEvent;
+use crate::task::Progress;
+
+// :fetch fills the current sheet with the CSV a shell command prints, e.g.
+// curl -s for a URL or sqlite3 -csv for a query. The sheet keeps the command,
+// :fetch alone runs it again and with :set refresh=60s it runs in the
+// background every minute. The new data replaces the old in place. Cells
+// outside of it, like a column of formulas added right of the data, stay.
+pub struct Source {
+    pub command: String,
+    size: (u16, u16), // Rows and columns of the data put in last
+    last: Instant, // When the data was put in
+    running: bool, // In the background
+}
+
+// :fetch [command]
+pub fn fetch(state: &mut AppState, command: &str) -> Result<()> {
+    let command = match (command.trim(), &state.sheets[state.sheet].source) {
+        ("", Some(source)) => source.command.clone(),
+        ("", None) => return Err(VispError::Command("Usage: fetch command, e.g. fetch curl -s https://example.com/data.csv".to_string())),
+        (command, _) => command.to_string(),
+    };
+    edit::check_writable(&state.options)?;
+    let name = state.sheets[state.sheet].name.clone();
+    let (delimiter, locale) = (state.options.delimiter(), state.options.locale());
+    let title = format!("Running \"{}\"", command);
+    let work = {
+        let command = command.clone();
+        move |progress: &Progress| run(&command, delimiter, locale, progress)
+    };
+    task::run(state, title, work, move |state, rows| {
+        let (count, changed) = (rows.len(), put(state, &name, &command, rows)?);
+        state.message = Some(Message::Info(format!("{} rows from \"{}\", {} cells changed", count, command, changed)));
+        Ok(())
+    })
+}
+
+// Runs `command` with sh and reads what it prints as CSV
+fn run(command: &str, delimiter: char, locale: Option<&Locale>, progress: &Progress) -> Result<Vec<Vec<TableCell>>> {
+    let output = Command::new("sh").arg("-c").arg(command).output()?;
+    progress.check()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().find(|line| !line.trim().is_empty()).map_or_else(|| output.status.to_string(), str::to_string);
+        return Err(VispError::Command(format!("\"{}\" failed: {}", command, reason)));
+    }
+    let (text, _) = encoding::decode(output.stdout);
+    let mut rows = csv::parse(&text, delimiter, locale, 1, progress)?;
+    rows.truncate(u16::MAX as usize);
+    Ok(rows)
+}
+
+// Replaces the data of the sheet called `name` with `rows` and returns how
+// many cells changed. A sheet without other changes still counts as written.
+fn put(state: &mut AppState, name: &str, command: &str, rows: Vec<Vec<TableCell>>) -> Result<usize> {
+    let index = sheet::find(state, name).ok_or_else(|| VispError::Command(format!("No sheet called {}", name)))?;
+    let size = (rows.len() as u16, rows.iter().map(Vec::len).max().unwrap_or(0).min(u16::MAX as usize) as u16);
+    let old = state.sheets[index].source.as_ref().map_or((0, 0), |source| source.size);
+    let (content, saved) = if index == state.sheet {
+        (&mut state.table_content, &mut state.saved_revision)
+    } else {
+        let sheet = &mut state.sheets[index];
+        (&mut sheet.content, &mut sheet.saved_revision)
+    };
+    let mut cells = Vec::new();
+    for row in 0..old.0.max(size.0) {
+        for col in 0..old.1.max(size.1) {
+            let cell = rows.get(row as usize).and_then(|cells| cells.get(col as usize)).cloned().unwrap_or(TableCell::Empty);
+            if !content.get_cell(row, col).unwrap_or(&TableCell::Empty).same_source(&cell) {
+                cells.push(((row, col), cell));
+            }
+        }
+    }
+    let changed = cells.len();
+    let written = content.revision == *saved;
+    if changed > 0 {
+        content.set_cells(cells);
+    }
+    if written {
+        *saved = content.revision;
+    }
+    state.sheets[index].source = Some(Source { command: command.to_string(), size, last: Instant::now(), running: false });
+    Ok(changed)
+}
+
+// The value of the refresh option: a number of seconds, or of minutes or
+// hours with m or h after it. Empty or 0 for never.
+pub fn interval(value: &str) -> Result<Option<Duration>> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 3600),
+        _ => (value, 1),
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let number: u64 = number.parse().map_err(|_| VispError::Parse(format!("refresh takes seconds like 60s, 5m or 1h: {}", value)))?;
+    Ok((number > 0).then(|| Duration::from_secs(number * unit)))
+}
+
+// Called every second by the main loop. Runs the commands of sheets whose
+// data is older than the refresh option on threads of their own.
+pub fn tick(state: &mut AppState) {
+    let Ok(Some(refresh)) = interval(&state.options.refresh) else {
+        return;
+    };
+    let Some(sender) = &state.tasks.sender else {
+        return;
+    };
+    let (delimiter, locale) = (state.options.delimiter(), state.options.locale());
+    for sheet in &mut state.sheets {
+        let Some(source) = sheet.source.as_mut().filter(|source| !source.running && source.last.elapsed() >= refresh) else {
+            continue;
+        };
+        source.running = true;
+        let (name, command, sender) = (sheet.name.clone(), source.command.clone(), sender.clone());
+        thread::spawn(move || {
+            let rows = run(&command, delimiter, locale, &Progress::default());
+            let _ = sender.send(AppEvent::Fetched(name, command, rows));
+        });
+    }
+}
+
+// Called by the main loop with what a command started by tick printed. It
+// is dropped if the sheet got another command meanwhile.
+pub fn fetched(state: &mut AppState, name: &str, command: &str, rows: Result<Vec<Vec<TableCell>>>) {
+    let Some(index) = sheet::find(state, name) else {
+        return;
+    };
+    let Some(source) = state.sheets[index].source.as_mut().filter(|source| source.command == command) else {
+        return;
+    };
+    source.running = false;
+    source.last = Instant::now();
+    if let Err(e) = rows.and_then(|rows| put(state, name, command, rows)) {
+        state.message = Some(Message::Error(format!("Refresh of {} failed: {}", name, e)));
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::{Datelike, NaiveDate};
+
+use crate::{edit, AppState, AppMode, Message, Result};
+use crate::formula::{offset_references, Formula};
+use crate::grid::{Selection, TableCell};
+
+const MONTHS: &[&str] = &["January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December"];
+const WEEKDAYS: &[&str] = &["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+// Fills a range from the cells at its start, down each column or with
+// `right` along each row. The non-empty cells a line starts with are the
+// seed, or only the first cell if the line is full. A seed of numbers or
+// dates with even steps, or of month or weekday names, is continued. Other
+// seeds repeat, with the references of formulas moving along. Returns how
+// many cells changed.
+pub fn fill(state: &mut AppState, range: Selection, right: bool) -> Result<usize> {
+    let content = &state.table_content;
+    let (top, left) = (range.row, range.col);
+    // Whole rows and columns end at the last cell in use
+    let bottom = if range.rows == u16::MAX { range.bottom().min(content.used_rows() - 1).max(top) } else { range.bottom() };
+    let last_col = if range.cols == u16::MAX { range.right().min(content.used_cols() - 1).max(left) } else { range.right() };
+    let lines: Vec<Vec<(u16, u16)>> = if right {
+        (top..=bottom).map(|row| (left..=last_col).map(|col| (row, col)).collect()).collect()
+    } else {
+        (left..=last_col).map(|col| (top..=bottom).map(|row| (row, col)).collect()).collect()
+    };
+
+    let mut cells = Vec::new();
+    for line in lines {
+        let cell = |(row, col): (u16, u16)| content.get_cell(row, col).cloned().unwrap_or(TableCell::Empty);
+        let mut seeds: Vec<TableCell> = line.iter().map(|&position| cell(position)).take_while(|c| *c != TableCell::Empty).collect();
+        if seeds.is_empty() || seeds.len() == line.len() {
+            seeds = vec![cell(line[0])];
+        }
+        for (i, &position) in line.iter().enumerate().skip(seeds.len()) {
+            let filled = continue_series(&seeds, i + 1 - seeds.len()).unwrap_or_else(|| {
+                let distance = (i - i % seeds.len()) as i32;
+                let (rows, cols) = if right { (0, distance) } else { (distance, 0) };
+                repeat(&seeds[i % seeds.len()], rows, cols)
+            });
+            cells.push((position, filled));
+        }
+    }
+    edit::replace_cells(state, cells)
+}
+
+// fill on the selection, leaving the visual mode like y
+pub fn fill_selection(state: &mut AppState) {
+    let selection = state.table_content.selection;
+    // A single row is filled to the right
+    let right = selection.rows == 1 && selection.cols > 1;
+    match fill(state, selection, right) {
+        Ok(changed) => state.message = Some(Message::Info(format!("{} cells filled", changed))),
+        Err(e) => state.message = Some(Message::Error(e.to_string())),
+    }
+    state.remember_visual();
+    state.mode = AppMode::Normal;
+    state.table_content.selection.set_cursor(selection.row, selection.col);
+}
+
+fn repeat(cell: &TableCell, rows: i32, cols: i32) -> TableCell {
+    match cell {
+        TableCell::Formula(f) => TableCell::Formula(Box::new(Formula::new(&offset_references(&f.source, rows, cols)))),
+        cell => cell.clone(),
+    }
+}
+
+// The `n`th cell after `seeds` if they are a series. A single number is
+// copied, a single date or name counts up by one.
+fn continue_series(seeds: &[TableCell], n: usize) -> Option<TableCell> {
+    let n = n as f64;
+    if let Some(values) = seeds.iter().map(plain_number).collect::<Option<Vec<f64>>>() {
+        let value = values.last()? + step(&values)? * n;
+        return value.is_finite().then(|| TableCell::from_number(value));
+    }
+    if let Some(days) = seeds.iter().map(day_number).collect::<Option<Vec<f64>>>() {
+        let day = days.last()? + step(&days).unwrap_or(1.0) * n;
+        return NaiveDate::from_num_days_from_ce_opt(i32::try_from(day as i64).ok()?).map(TableCell::Date);
+    }
+    [MONTHS, WEEKDAYS].into_iter().find_map(|names| next_name(seeds, names, n as usize))
+}
+
+// Formulas aren't numbers here, they are repeated instead
+fn plain_number(cell: &TableCell) -> Option<f64> {
+    match cell {
+        TableCell::Value(v) => Some(*v as f64),
+        TableCell::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn day_number(cell: &TableCell) -> Option<f64> {
+    match cell {
+        TableCell::Date(date) => Some(date.num_days_from_ce() as f64),
+        _ => None,
+    }
+}
+
+// The difference between neighbouring values if it is always the same
+fn step(values: &[f64]) -> Option<f64> {
+    let first = values.get(1)? - values[0];
+    values.windows(2).all(|w| ((w[1] - w[0]) - first).abs() < 1e-9).then_some(first)
+}
+
+// Names like Jan or MONDAY continue in the spelling of the last seed
+fn next_name(seeds: &[TableCell], names: &[&str], n: usize) -> Option<TableCell> {
+    let indices = seeds.iter().map(|cell| match cell {
+        TableCell::String(text) => name_index(text, names),
+        _ => None,
+    }).collect::<Option<Vec<usize>>>()?;
+    let len = names.len();
+    let step = match indices.as_slice() {
+        [_] => 1,
+        [first, second, ..] => (second + len - first) % len,
+        [] => return None,
+    };
+    if indices.windows(2).any(|w| (w[1] + len - w[0]) % len != step) {
+        return None;
+    }
+    let TableCell::String(last) = seeds.last()? else {
+        return None;
+    };
+    let name = names[(indices.last()? + step * n) % len];
+    let name = if last.chars().count() == 3 { &name[..3] } else { name };
+    let text = if last.chars().all(|c| c.is_uppercase()) {
+        name.to_uppercase()
+    } else if last.chars().all(|c| c.is_lowercase()) {
+        name.to_lowercase()
+    } else {
+        name.to_string()
+    };
+    Some(TableCell::String(text))
+}
+
+// Full names and their first three letters, in any case
+fn name_index(text: &str, names: &[&str]) -> Option<usize> {
+    names.iter().position(|name| text.eq_ignore_ascii_case(name) || (text.len() == 3 && text.eq_ignore_ascii_case(&name[..3])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::parse_field;
+    use crate::grid::TableContent;
+
+    // Fills column A down to `rows` rows from `seeds` and returns it
+    fn filled(seeds: &[&str], rows: u16) -> Vec<String> {
+        let cells = seeds.iter().map(|&seed| vec![parse_field(seed.to_string(), None)]).collect();
+        let mut state = AppState::new(TableContent::from_rows(cells));
+        let range = Selection { rows, ..Selection::default() };
+        fill(&mut state, range, false).unwrap();
+        (0..rows).map(|row| state.table_content.get_cell(row, 0).map_or_else(String::new, TableCell::source_string)).collect()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(filled(&["1", "2"], 5), ["1", "2", "3", "4", "5"]);
+        assert_eq!(filled(&["10", "7"], 4), ["10", "7", "4", "1"]);
+        assert_eq!(filled(&["0.5", "0.75"], 4), ["0.5", "0.75", "1", "1.25"]);
+        // A single number is copied
+        assert_eq!(filled(&["3"], 3), ["3", "3", "3"]);
+    }
+
+    #[test]
+    fn uneven_steps_repeat() {
+        assert_eq!(filled(&["1", "2", "4"], 6), ["1", "2", "4", "1", "2", "4"]);
+        assert_eq!(filled(&["a", "b"], 5), ["a", "b", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(filled(&["2024-01-30"], 3), ["2024-01-30", "2024-01-31", "2024-02-01"]);
+        assert_eq!(filled(&["2024-01-01", "2024-01-08"], 3), ["2024-01-01", "2024-01-08", "2024-01-15"]);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(filled(&["November"], 4), ["November", "December", "January", "February"]);
+        assert_eq!(filled(&["jan", "mar"], 4), ["jan", "mar", "may", "jul"]);
+        assert_eq!(filled(&["SAT"], 3), ["SAT", "SUN", "MON"]);
+        assert_eq!(filled(&["Monday", "Wednesday", "Tuesday"], 5), ["Monday", "Wednesday", "Tuesday", "Monday", "Wednesday"]);
+    }
+
+    #[test]
+    fn formulas_move_along() {
+        assert_eq!(filled(&["=B1*2"], 3), ["=B1*2", "=B2*2", "=B3*2"]);
+        assert_eq!(filled(&["=B1", "=C1"], 4), ["=B1", "=C1", "=B3", "=C3"]);
+    }
+
+    #[test]
+    fn right() {
+        let cells = vec![vec![TableCell::String("Mon".to_string()), TableCell::Empty, TableCell::Empty]];
+        let mut state = AppState::new(TableContent::from_rows(cells));
+        let range = Selection { cols: 3, ..Selection::default() };
+        assert_eq!(fill(&mut state, range, true).unwrap(), 2);
+        assert_eq!(state.table_content.display(0, 2), "Wed");
+    }
+}
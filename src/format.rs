@@ -0,0 +1,497 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use crate::{csv, Result, VispError};
+use crate::grid::{cell_name, col_label_to_nr, col_nr_to_label, parse_cell_name, CellStyle, TableCell, TableContent};
+
+// How a cell's value is shown, see :fmt
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct CellFormat {
+    pub spec: Option<String>, // Like printf for numbers, e.g. %,.2f, like strftime for dates
+    pub align: Option<Align>, // Left if not given
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(Self::Left),
+            "center" => Some(Self::Center),
+            "right" => Some(Self::Right),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Left => "left",
+            Self::Center => "center",
+            Self::Right => "right",
+        }
+    }
+
+    // Where text of `len` characters starts in `width`
+    pub fn offset(&self, len: usize, width: u16) -> u16 {
+        let space = (width as usize).saturating_sub(len) as u16;
+        match self {
+            Self::Left => 0,
+            Self::Center => space / 2,
+            Self::Right => space,
+        }
+    }
+}
+
+impl CellFormat {
+    // The arguments of :fmt, e.g. "right %,.2f". A spec only has to make sense
+    // for some kind of value, as the cell decides how it is read.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut format = Self::default();
+        let mut rest = text.trim();
+        let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if let Some(align) = Align::parse(word) {
+            format.align = Some(align);
+            rest = after.trim_start();
+        }
+        if !rest.is_empty() {
+            if NumberSpec::parse(rest).is_none() && !is_date_spec(rest) {
+                return Err(VispError::Parse(format!("Invalid format: {}", rest)));
+            }
+            format.spec = Some(rest.to_string());
+        }
+        Ok(format)
+    }
+
+    // Like the arguments of :fmt
+    pub fn describe(&self) -> String {
+        let parts: Vec<&str> = self.align.map(|a| a.name()).into_iter().chain(self.spec.as_deref()).collect();
+        parts.join(" ")
+    }
+}
+
+// The spec for numbers: text around %[,][.N]f or %[,]d, e.g. "%.2f",
+// "$%,.2f" or "%d km". Like in printf, f without a precision has 6 decimals.
+pub struct NumberSpec<'a> {
+    pub prefix: &'a str,
+    pub thousands: bool,
+    pub decimals: usize,
+    pub suffix: &'a str,
+}
+
+impl<'a> NumberSpec<'a> {
+    pub fn parse(spec: &'a str) -> Option<Self> {
+        let (prefix, rest) = spec.split_once('%')?;
+        let (thousands, rest) = match rest.strip_prefix(',') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (decimals, rest) = match rest.strip_prefix('.') {
+            Some(rest) => {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (Some(rest[..digits].parse().ok()?), &rest[digits..])
+            }
+            None => (None, rest),
+        };
+        let decimals = match (rest.chars().next()?, decimals) {
+            ('f', decimals) => decimals.unwrap_or(6),
+            ('d', None) => 0,
+            _ => return None,
+        };
+        let suffix = &rest[1..];
+        if suffix.contains('%') || decimals > 20 {
+            return None;
+        }
+        Some(Self { prefix, thousands, decimals, suffix })
+    }
+
+    fn format(&self, value: f64) -> String {
+        let digits = format!("{:.*}", self.decimals, value.abs());
+        let digits = if self.thousands { group_thousands(&digits) } else { digits };
+        // No minus for numbers which round to 0
+        let sign = if value < 0.0 && digits.contains(|c: char| ('1'..='9').contains(&c)) { "-" } else { "" };
+        format!("{}{}{}{}", sign, self.prefix, digits, self.suffix)
+    }
+}
+
+// A strftime spec chrono understands
+pub fn is_date_spec(spec: &str) -> bool {
+    spec.contains('%') && !StrftimeItems::new(spec).any(|item| matches!(item, Item::Error))
+}
+
+// Puts commas between groups of three digits before the decimal point
+fn group_thousands(digits: &str) -> String {
+    let (integer, fraction) = match digits.find('.') {
+        Some(point) => digits.split_at(point),
+        None => (digits, ""),
+    };
+    let mut grouped = String::new();
+    for (i, c) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped + fraction
+}
+
+// Numbers as short as they can be written without losing anything. An
+// empty sum is -0.0, which is shown as 0.
+pub fn format_number(value: f64) -> String {
+    format!("{}", if value == 0.0 { 0.0 } else { value })
+}
+
+// Shows a number with `spec` if it is one for numbers
+pub fn format_with(value: f64, spec: &str) -> Option<String> {
+    NumberSpec::parse(spec).map(|spec| spec.format(value))
+}
+
+// Dates, times and both, shown with a strftime `spec` or the same way they
+// are typed
+pub fn format_date(date: &NaiveDate, spec: Option<&str>) -> String {
+    let mut text = String::new();
+    match spec {
+        Some(spec) if is_date_spec(spec) && write!(text, "{}", date.format(spec)).is_ok() => text,
+        _ => date.format(DATE).to_string(),
+    }
+}
+
+pub fn format_time(time: &NaiveTime, spec: Option<&str>) -> String {
+    let mut text = String::new();
+    match spec {
+        Some(spec) if is_date_spec(spec) && write!(text, "{}", time.format(spec)).is_ok() => text,
+        _ => time.format(time_spec(time)).to_string(),
+    }
+}
+
+pub fn format_date_time(date_time: &NaiveDateTime, spec: Option<&str>) -> String {
+    let mut text = String::new();
+    match spec {
+        Some(spec) if is_date_spec(spec) && write!(text, "{}", date_time.format(spec)).is_ok() => text,
+        _ => format!("{} {}", date_time.format(DATE), date_time.format(time_spec(&date_time.time()))),
+    }
+}
+
+const DATE: &str = "%Y-%m-%d";
+
+// Seconds are left out if there are none
+fn time_spec(time: &NaiveTime) -> &'static str {
+    if chrono::Timelike::second(time) == 0 { "%H:%M" } else { "%H:%M:%S" }
+}
+
+// Symbols an amount of money can start or end with
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥'];
+
+// An amount of money like $1,234.50 or 12.50 €, in cents so that adding
+// them up is exact
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Currency {
+    pub cents: i64,
+    pub symbol: char,
+    pub after: bool, // The symbol comes after the amount
+}
+
+impl Currency {
+    pub fn amount(&self) -> f64 {
+        self.cents as f64 / 100.0
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (symbol, after, amount) = if let Some(symbol) = text.chars().next().filter(|c| CURRENCY_SYMBOLS.contains(c)) {
+            (symbol, false, &text[symbol.len_utf8()..])
+        } else {
+            let symbol = text.chars().last().filter(|c| CURRENCY_SYMBOLS.contains(c))?;
+            (symbol, true, text[..text.len() - symbol.len_utf8()].trim_end())
+        };
+        let (integer, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+        let integer: String = integer.chars().filter(|&c| c != ',').collect();
+        if integer.is_empty() || fraction.len() > 2 || !integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let fraction = format!("{:0<2}", fraction);
+        let cents = integer.parse::<i64>().ok()?.checked_mul(100)?.checked_add(fraction.parse::<i64>().ok()?)?;
+        Some(Self { cents: if negative { -cents } else { cents }, symbol, after })
+    }
+
+    pub fn format(&self, spec: Option<&str>) -> String {
+        if let Some(text) = spec.and_then(|spec| format_with(self.amount(), spec)) {
+            return text;
+        }
+        let amount = group_thousands(&format!("{}.{:02}", self.cents.unsigned_abs() / 100, self.cents.unsigned_abs() % 100));
+        let sign = if self.cents < 0 { "-" } else { "" };
+        if self.after {
+            format!("{}{} {}", sign, amount, self.symbol)
+        } else {
+            format!("{}{}{}", sign, self.symbol, amount)
+        }
+    }
+}
+
+// How numbers are written in CSV files from other programs, see the locale
+// option. Inside visp they are written as always, as formulas need the point.
+pub struct Locale {
+    pub name: &'static str,
+    pub decimal: char,
+    pub group: char, // Between groups of three digits
+}
+
+pub const LOCALES: &[Locale] = &[
+    Locale { name: "en", decimal: '.', group: ',' }, // 1,234.56
+    Locale { name: "de", decimal: ',', group: '.' }, // 1.234,56
+    Locale { name: "fr", decimal: ',', group: ' ' }, // 1 234,56
+    Locale { name: "ch", decimal: '.', group: '\'' }, // 1'234.56
+];
+
+impl Locale {
+    pub fn find(name: &str) -> Option<&'static Locale> {
+        LOCALES.iter().find(|locale| locale.name == name)
+    }
+
+    // A number in this locale as visp writes it, e.g. 1.234,56 as 1234.56 for
+    // de. Groups are optional but must have three digits.
+    pub fn read(&self, text: &str) -> Option<String> {
+        let (sign, text) = match text.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", text),
+        };
+        let (integer, fraction) = match text.split_once(self.decimal) {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (text, None),
+        };
+        let digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        let groups: Vec<&str> = integer.split(self.group).collect();
+        let grouped = groups.len() == 1 || (groups[0].len() <= 3 && groups[1..].iter().all(|group| group.len() == 3));
+        if !grouped || !groups.iter().all(|group| digits(group)) || !fraction.is_none_or(digits) {
+            return None;
+        }
+        let fraction = fraction.map(|fraction| format!(".{}", fraction)).unwrap_or_default();
+        Some(format!("{}{}{}", sign, groups.concat(), fraction))
+    }
+
+    // The other way around. Only numbers with decimals get groups, those
+    // without are often years, ids or postal codes.
+    pub fn write(&self, number: &str) -> String {
+        let Some((integer, fraction)) = number.split_once('.') else {
+            return number.to_string();
+        };
+        let (sign, integer) = match integer.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", integer),
+        };
+        let integer = group_thousands(integer).replace(',', &self.group.to_string());
+        format!("{}{}{}{}", sign, integer, self.decimal, fraction)
+    }
+}
+
+// Typed text which is a value other than text, None for text. Numbers
+// without decimals become Value if they fit, others Float.
+pub fn parse_value(text: &str) -> Option<TableCell> {
+    let text = text.trim();
+    if let Ok(value) = text.parse::<i32>() {
+        return Some(TableCell::Value(value));
+    }
+    // Rust also reads "inf" and "NaN", which are no use in a table
+    if let Some(value) = text.parse::<f64>().ok().filter(|v| v.is_finite() && text.contains(|c: char| c.is_ascii_digit())) {
+        return Some(TableCell::from_number(value));
+    }
+    if text.eq_ignore_ascii_case("true") || text.eq_ignore_ascii_case("false") {
+        return Some(TableCell::Bool(text.eq_ignore_ascii_case("true")));
+    }
+    if let Some(currency) = Currency::parse(text) {
+        return Some(TableCell::Currency(currency));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, DATE) {
+        return Some(TableCell::Date(date));
+    }
+    for spec in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(date_time) = NaiveDateTime::parse_from_str(text, spec) {
+            return Some(TableCell::DateTime(date_time));
+        }
+    }
+    for spec in ["%H:%M:%S", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(text, spec) {
+            return Some(TableCell::Time(time));
+        }
+    }
+    None
+}
+
+// What CSV can't hold, :w puts into a file next to it, e.g. table.csv.fmt
+pub fn sidecar(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".fmt");
+    PathBuf::from(name)
+}
+
+// The contents of a sidecar, one line each:
+//   B3,right,%,.2f    the format of a cell: alignment and spec, either can be empty
+//   width,C,14        the width of a column
+//   height,3,2        the height of a row
+//   hidden,C          a hidden column
+#[derive(Default)]
+pub struct Sidecar {
+    pub formats: HashMap<(u16, u16), CellFormat>,
+    pub styles: HashMap<(u16, u16), CellStyle>,
+    pub notes: HashMap<(u16, u16), String>,
+    pub col_widths: HashMap<u16, u16>,
+    pub row_heights: HashMap<u16, u16>,
+    pub hidden_cols: BTreeSet<u16>,
+}
+
+impl Sidecar {
+    // Puts what was read into a table read from the CSV file
+    pub fn apply(self, content: &mut TableContent) {
+        content.formats = self.formats;
+        content.styles = self.styles;
+        content.notes = self.notes;
+        content.col_widths = self.col_widths;
+        content.row_heights = self.row_heights;
+        content.hidden_cols = self.hidden_cols;
+    }
+}
+
+// Removes the sidecar if there is nothing to write
+pub fn write_sidecar(path: &Path, content: &TableContent) -> Result<()> {
+    let path = sidecar(path);
+    let mut text = String::new();
+    let mut formats: Vec<_> = content.formats.iter().collect();
+    formats.sort_by_key(|(&position, _)| position);
+    for (&(row, col), format) in formats {
+        let align = format.align.map_or("", |a| a.name());
+        let spec = csv::quote(format.spec.as_deref().unwrap_or_default(), ',');
+        writeln!(text, "{},{},{}", cell_name(row, col), align, spec).unwrap();
+    }
+    let mut styles: Vec<_> = content.styles.iter().collect();
+    styles.sort_by_key(|(&position, _)| position);
+    for (&(row, col), style) in styles {
+        writeln!(text, "style,{},{}", cell_name(row, col), style.describe()).unwrap();
+    }
+    let mut notes: Vec<_> = content.notes.iter().collect();
+    notes.sort_by_key(|(&position, _)| position);
+    for (&(row, col), note) in notes {
+        writeln!(text, "note,{},{}", cell_name(row, col), csv::quote(note, ',')).unwrap();
+    }
+    let mut widths: Vec<_> = content.col_widths.iter().collect();
+    widths.sort();
+    for (&col, width) in widths {
+        writeln!(text, "width,{},{}", col_nr_to_label(col), width).unwrap();
+    }
+    let mut heights: Vec<_> = content.row_heights.iter().collect();
+    heights.sort();
+    for (&row, height) in heights {
+        writeln!(text, "height,{},{}", row as u32 + 1, height).unwrap();
+    }
+    for &col in &content.hidden_cols {
+        writeln!(text, "hidden,{}", col_nr_to_label(col)).unwrap();
+    }
+    if text.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    fs::write(path, text)?;
+    Ok(())
+}
+
+// What write_sidecar wrote, nothing if there is no such file
+pub fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    let text = match fs::read_to_string(sidecar(path)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Sidecar::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut read = Sidecar::default();
+    for record in csv::records(&text, ',') {
+        let invalid = || VispError::Parse(format!("Invalid line: {}", record.join(",")));
+        let col = |label: &str| col_label_to_nr(label).ok_or_else(invalid);
+        match record.as_slice() {
+            [kind, label, width] if kind == "width" => {
+                read.col_widths.insert(col(label)?, width.parse().ok().filter(|&width| width > 0).ok_or_else(invalid)?);
+            }
+            [kind, number, height] if kind == "height" => {
+                let row = number.parse::<u32>().ok().and_then(|row| u16::try_from(row.checked_sub(1)?).ok()).ok_or_else(invalid)?;
+                read.row_heights.insert(row, height.parse().ok().filter(|&height| height > 0).ok_or_else(invalid)?);
+            }
+            [kind, cell, style] if kind == "style" => {
+                let position = parse_cell_name(cell).ok_or_else(invalid)?;
+                read.styles.insert(position, CellStyle::parse(style).ok_or_else(invalid)?);
+            }
+            [kind, cell, note] if kind == "note" => {
+                read.notes.insert(parse_cell_name(cell).ok_or_else(invalid)?, note.clone());
+            }
+            [kind, label] if kind == "hidden" => {
+                read.hidden_cols.insert(col(label)?);
+            }
+            [cell, align, spec] => {
+                let position = parse_cell_name(cell).ok_or_else(invalid)?;
+                let align = if align.is_empty() { None } else { Some(Align::parse(align).ok_or_else(invalid)?) };
+                let spec = Some(spec.clone()).filter(|spec| !spec.is_empty());
+                read.formats.insert(position, CellFormat { spec, align });
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::CellColor;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("visp-sidecar-test-{}-{}.csv", std::process::id(), name))
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let path = path("round-trip");
+        let mut content = TableContent::default();
+        content.formats.insert((2, 1), CellFormat { spec: Some("%,.2f".to_string()), align: Some(Align::Right) });
+        content.formats.insert((0, 0), CellFormat { spec: None, align: Some(Align::Center) });
+        content.styles.insert((1, 0), CellStyle { bold: true, fg: Some(CellColor::Red), bg: Some(CellColor::Rgb(255, 128, 0)), ..CellStyle::default() });
+        content.notes.insert((0, 1), "Checked, \"twice\"\nby hand".to_string());
+        content.col_widths.insert(2, 14);
+        content.row_heights.insert(0, 3);
+        content.hidden_cols.insert(27);
+        write_sidecar(&path, &content).unwrap();
+        let text = fs::read_to_string(sidecar(&path)).unwrap();
+        let mut read = TableContent::default();
+        read_sidecar(&path).unwrap().apply(&mut read);
+        // Nothing left to keep removes the file
+        write_sidecar(&path, &TableContent::default()).unwrap();
+        assert!(!sidecar(&path).exists());
+        assert_eq!(text, "A1,center,\nB3,right,\"%,.2f\"\nstyle,A2,bold fg=red bg=#ff8000\nnote,B1,\"Checked, \"\"twice\"\"\nby hand\"\nwidth,C,14\nheight,1,3\nhidden,AB\n");
+        assert_eq!(read.formats, content.formats);
+        assert_eq!(read.styles, content.styles);
+        assert_eq!(read.notes, content.notes);
+        assert_eq!(read.col_widths, content.col_widths);
+        assert_eq!(read.row_heights, content.row_heights);
+        assert_eq!(read.hidden_cols, content.hidden_cols);
+    }
+
+    #[test]
+    fn broken_sidecar() {
+        let path = path("broken");
+        fs::write(sidecar(&path), "width,C,0\n").unwrap();
+        let read = read_sidecar(&path);
+        fs::remove_file(sidecar(&path)).unwrap();
+        assert!(read.is_err());
+        assert!(read_sidecar(&path).unwrap().formats.is_empty());
+    }
+}
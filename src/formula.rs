@@ -0,0 +1,958 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::rc::Rc;
+use std::str::Chars;
+
+use thiserror::Error;
+
+use crate::{Result, VispError};
+use crate::grid::{cell_name, col_label_to_nr, parse_cell_name, Axis, Shift, TableCell, TableContent};
+use crate::script::Script;
+
+// A cell starting with =, e.g. =SUM(A1:A5)*2. The value is cached and
+// recalculated when the cells it reads change.
+#[derive(Clone, PartialEq)]
+pub struct Formula {
+    pub source: String, // Without the =
+    expr: Option<Expr>, // None if the source doesn't parse
+    pub value: std::result::Result<f64, FormulaError>,
+}
+
+impl Formula {
+    pub fn new(source: &str) -> Self {
+        let expr = parse(source).ok();
+        let value = if expr.is_some() { Ok(0.0) } else { Err(FormulaError::Syntax) };
+        Self { source: source.to_string(), expr, value }
+    }
+
+    // Areas the formula reads on its own sheet, which is called `sheet`
+    fn areas(&self, sheet: &str) -> Vec<Area> {
+        let mut areas = Vec::new();
+        if let Some(expr) = &self.expr {
+            expr.collect_areas(sheet, &mut areas);
+        }
+        areas
+    }
+}
+
+// Shown in place of the value, like in other spreadsheets
+#[derive(Error, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FormulaError {
+    #[error("#SYNTAX!")]
+    Syntax,
+    #[error("#VALUE!")] // Text or a date where a number is needed
+    Value,
+    #[error("#DIV/0!")]
+    DivisionByZero,
+    #[error("#NUM!")] // Result too large to be a number
+    Overflow,
+    #[error("#CYCLE!")]
+    Cycle,
+    #[error("#REF!")] // The cell was deleted or the sheet doesn't exist
+    Reference,
+    #[error("#NAME?")] // No function of that name, built in or from the script
+    Name,
+}
+
+// Numbers of the non-empty cells of a sheet, text is Err(Value)
+pub type Values = BTreeMap<(u16, u16), std::result::Result<f64, FormulaError>>;
+
+// Top, left, bottom and right of the cells a reference points to
+pub type Bounds = (u16, u16, u16, u16);
+
+// What formulas know about the other sheets of the workbook, for references
+// like Sheet2!A1, and the functions of the script. Filled in by sheet::link.
+#[derive(Default)]
+pub struct Linked {
+    pub name: String, // Of the sheet itself, references to it read its own cells
+    pub sheets: HashMap<String, Rc<Values>>, // The other sheets by lower case name
+    pub script: Option<Rc<Script>>,
+}
+
+// The values of `content` for the other sheets
+pub fn values(content: &TableContent) -> Values {
+    content.iter().filter_map(|(position, cell)| Some((position, value(cell)?))).collect()
+}
+
+// Rectangle of cells, corners included
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct Area {
+    top: u16,
+    left: u16,
+    bottom: u16,
+    right: u16,
+}
+
+impl Area {
+    fn contains(&self, (row, col): (u16, u16)) -> bool {
+        (self.top..=self.bottom).contains(&row) && (self.left..=self.right).contains(&col)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Function {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Custom(String), // Upper case name of a function registered by the script
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Expr {
+    Number(f64),
+    Cell(u16, u16),
+    Range(Area), // Only valid as a function argument
+    Sheet(String, Box<Expr>), // A Cell or Range on another sheet, e.g. Sheet2!A1
+    Deleted, // #REF!, a cell which was deleted
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    // Other sheets are left out, they can't change while this one is shown
+    fn collect_areas(&self, sheet: &str, areas: &mut Vec<Area>) {
+        match self {
+            Self::Number(_) | Self::Deleted => {}
+            Self::Cell(row, col) => areas.push(Area { top: *row, left: *col, bottom: *row, right: *col }),
+            Self::Range(area) => areas.push(*area),
+            Self::Sheet(name, expr) if name.eq_ignore_ascii_case(sheet) => expr.collect_areas(sheet, areas),
+            Self::Sheet(..) => {}
+            Self::Negate(expr) => expr.collect_areas(sheet, areas),
+            Self::Binary(_, a, b) => {
+                a.collect_areas(sheet, areas);
+                b.collect_areas(sheet, areas);
+            }
+            Self::Call(_, args) => args.iter().for_each(|arg| arg.collect_areas(sheet, areas)),
+        }
+    }
+
+    // `content` is the formula's own table, `cells` where references are read
+    fn evaluate(&self, content: &TableContent, cells: Cells) -> std::result::Result<f64, FormulaError> {
+        let result = match self {
+            Self::Number(n) => *n,
+            Self::Cell(row, col) => cells.number(*row, *col)?.unwrap_or(0.0),
+            Self::Range(_) => return Err(FormulaError::Value),
+            Self::Sheet(name, expr) => return expr.evaluate(content, Cells::sheet(content, name)?),
+            Self::Deleted => return Err(FormulaError::Reference),
+            Self::Negate(expr) => -expr.evaluate(content, cells)?,
+            Self::Binary(op, a, b) => {
+                let (a, b) = (a.evaluate(content, cells)?, b.evaluate(content, cells)?);
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    _ if b == 0.0 => return Err(FormulaError::DivisionByZero),
+                    _ => a / b,
+                }
+            }
+            Self::Call(function, args) => {
+                let mut numbers = Vec::new();
+                for arg in args {
+                    match arg.range(content, cells)? {
+                        Some((cells, area)) => cells.range_numbers(area, &mut numbers)?,
+                        None => numbers.push(arg.evaluate(content, cells)?),
+                    }
+                }
+                match function {
+                    Function::Sum => numbers.iter().sum(),
+                    Function::Avg if numbers.is_empty() => return Err(FormulaError::DivisionByZero),
+                    Function::Avg => numbers.iter().sum::<f64>() / numbers.len() as f64,
+                    Function::Min => numbers.iter().copied().reduce(f64::min).unwrap_or(0.0),
+                    Function::Max => numbers.iter().copied().reduce(f64::max).unwrap_or(0.0),
+                    Function::Count => numbers.len() as f64,
+                    Function::Custom(name) => match &content.linked.script {
+                        Some(script) => script.call_function(name, numbers)?,
+                        None => return Err(FormulaError::Name),
+                    },
+                }
+            }
+        };
+        if result.is_finite() { Ok(result) } else { Err(FormulaError::Overflow) }
+    }
+
+    // A range argument like A1:B5 or Sheet2!A1:B5, with where to read it
+    fn range<'a>(&'a self, content: &'a TableContent, cells: Cells<'a>) -> std::result::Result<Option<(Cells<'a>, &'a Area)>, FormulaError> {
+        match self {
+            Self::Range(area) => Ok(Some((cells, area))),
+            Self::Sheet(name, expr) => expr.range(content, Cells::sheet(content, name)?),
+            _ => Ok(None),
+        }
+    }
+}
+
+// Where references are read from: the formula's own table, or the values of
+// another sheet
+#[derive(Clone, Copy)]
+enum Cells<'a> {
+    Table(&'a TableContent),
+    Linked(&'a Values),
+}
+
+impl<'a> Cells<'a> {
+    // The sheet called `name`, seen from `content`
+    fn sheet(content: &'a TableContent, name: &str) -> std::result::Result<Self, FormulaError> {
+        if name.eq_ignore_ascii_case(&content.linked.name) {
+            return Ok(Self::Table(content));
+        }
+        let values = content.linked.sheets.get(&name.to_ascii_lowercase()).ok_or(FormulaError::Reference)?;
+        Ok(Self::Linked(values))
+    }
+
+    // The number in a cell, None if it is empty
+    fn number(self, row: u16, col: u16) -> std::result::Result<Option<f64>, FormulaError> {
+        match self {
+            Self::Table(content) => content.get_cell(row, col).and_then(value).transpose(),
+            Self::Linked(values) => values.get(&(row, col)).copied().transpose(),
+        }
+    }
+
+    // Functions skip text in ranges, like a heading above a column of numbers
+    fn range_numbers(self, area: &Area, numbers: &mut Vec<f64>) -> std::result::Result<(), FormulaError> {
+        let mut add = |value| match value {
+            Ok(value) => {
+                numbers.push(value);
+                Ok(())
+            }
+            Err(FormulaError::Value) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match self {
+            Self::Table(content) => {
+                for (_, cell) in content.cells_in((area.top, area.left, area.bottom, area.right)) {
+                    value(cell).map_or(Ok(()), &mut add)?;
+                }
+            }
+            Self::Linked(values) => {
+                for row in area.top..=area.bottom {
+                    for (_, value) in values.range((row, area.left)..=(row, area.right)) {
+                        add(*value)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The number in a cell, None if it is empty. TRUE counts as 1.
+fn value(cell: &TableCell) -> Option<std::result::Result<f64, FormulaError>> {
+    match cell {
+        TableCell::Empty => None,
+        TableCell::String(_) | TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Time(_) => Some(Err(FormulaError::Value)),
+        TableCell::Bool(b) => Some(Ok(if *b { 1.0 } else { 0.0 })),
+        TableCell::Formula(formula) => Some(formula.value),
+        cell => cell.number().map(Ok),
+    }
+}
+
+// Source without the leading =. Grammar, lowest precedence first:
+//   expr    = term (('+' | '-') term)*
+//   term    = unary (('*' | '/') unary)*
+//   unary   = '-' unary | primary
+//   primary = number | [sheet '!'] cell [':' cell] | name '(' [expr (',' expr)*] ')' | '(' expr ')' | '#REF!'
+fn parse(source: &str) -> Result<Expr> {
+    let mut parser = Parser { chars: source.chars().peekable() };
+    let expr = parser.expr()?;
+    parser.skip_spaces();
+    match parser.chars.next() {
+        None => Ok(expr),
+        Some(c) => Err(VispError::Formula(format!("Unexpected '{}'", c))),
+    }
+}
+
+// The value of a formula's source on `content` without putting it into a
+// cell, for :calc
+pub fn evaluate_source(content: &TableContent, source: &str) -> Result<f64> {
+    let expr = parse(source)?;
+    expr.evaluate(content, Cells::Table(content)).map_err(|e| VispError::Formula(e.to_string()))
+}
+
+// Checks a formula before it is put into a cell, for an error message
+pub fn check(source: &str) -> Result<()> {
+    parse(source).map(|_| ())
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    // Skips spaces and takes `c` if it is next
+    fn accept(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.chars.next_if(|&c| f(c)) {
+            s.push(c);
+        }
+        s
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.accept('+') { '+' } else if self.accept('-') { '-' } else { return Ok(expr) };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.accept('*') { '*' } else if self.accept('/') { '/' } else { return Ok(expr) };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.accept('-') {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if self.accept('(') {
+            let expr = self.expr()?;
+            return if self.accept(')') { Ok(expr) } else { Err(VispError::Formula("Missing ')'".to_string())) };
+        }
+        self.skip_spaces();
+        match self.chars.peek() {
+            Some(c) if c.is_ascii_digit() || *c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number.parse().map(Expr::Number).map_err(|_| VispError::Formula(format!("Not a number: {}", number)))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let name = self.take_while(is_name_char);
+                if self.chars.next_if_eq(&'!').is_some() {
+                    let cell = self.take_while(is_name_char);
+                    return Ok(Expr::Sheet(name, Box::new(self.reference(&cell)?)));
+                }
+                if self.accept('(') {
+                    return self.call(&name);
+                }
+                self.reference(&name)
+            }
+            Some('#') => {
+                let name = self.take_while(|c| c == '#' || c == '!' || c.is_ascii_alphabetic());
+                if name == DELETED {
+                    Ok(Expr::Deleted)
+                } else {
+                    Err(VispError::Formula(format!("Unexpected '{}'", name)))
+                }
+            }
+            Some(c) => Err(VispError::Formula(format!("Unexpected '{}'", c))),
+            None => Err(VispError::Formula("Unexpected end".to_string())),
+        }
+    }
+
+    // A cell or a range starting with the cell `name`, which is already read
+    fn reference(&mut self, name: &str) -> Result<Expr> {
+        let start = cell(name)?;
+        if !self.accept(':') {
+            return Ok(Expr::Cell(start.0, start.1));
+        }
+        self.skip_spaces();
+        let end = cell(&self.take_while(is_name_char))?;
+        Ok(Expr::Range(Area {
+            top: start.0.min(end.0),
+            left: start.1.min(end.1),
+            bottom: start.0.max(end.0),
+            right: start.1.max(end.1),
+        }))
+    }
+
+    // Arguments of a function, the opening parenthesis is already read
+    // Unknown names are left to the script, like other spreadsheets they
+    // show #NAME? if there is no such function
+    fn call(&mut self, name: &str) -> Result<Expr> {
+        let function = builtin(name).unwrap_or_else(|| Function::Custom(name.to_ascii_uppercase()));
+        let mut args = Vec::new();
+        if !self.accept(')') {
+            loop {
+                args.push(self.expr()?);
+                if self.accept(')') {
+                    break;
+                }
+                if !self.accept(',') {
+                    return Err(VispError::Formula("Expected ',' or ')'".to_string()));
+                }
+            }
+        }
+        Ok(Expr::Call(function, args))
+    }
+}
+
+fn builtin(name: &str) -> Option<Function> {
+    match name.to_ascii_uppercase().as_str() {
+        "SUM" => Some(Function::Sum),
+        "AVG" | "AVERAGE" => Some(Function::Avg),
+        "MIN" => Some(Function::Min),
+        "MAX" => Some(Function::Max),
+        "COUNT" => Some(Function::Count),
+        _ => None,
+    }
+}
+
+// Whether formulas already know a function, so a script can't replace it
+pub fn is_builtin(name: &str) -> bool {
+    builtin(name).is_some()
+}
+
+// A cell name like B12
+fn cell(name: &str) -> Result<(u16, u16)> {
+    parse_cell_name(name).ok_or_else(|| VispError::Formula(format!("Not a cell: {}", name)))
+}
+
+// Sheet names, cell names and function names are made of these
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Written in place of references to deleted cells
+const DELETED: &str = "#REF!";
+
+// Rewrites the cell references in a formula's source after rows or columns
+// were inserted or deleted
+pub fn shift_references(source: &str, shift: Shift) -> String {
+    rewrite_references(source, |area, range| {
+        let (top, left, bottom, right) = area;
+        if !range {
+            return shift.cell((top, left)).map(|(row, col)| (row, col, row, col));
+        }
+        match shift.axis {
+            Axis::Rows => shift.span(top, bottom).map(|(top, bottom)| (top, left, bottom, right)),
+            Axis::Cols => shift.span(left, right).map(|(left, right)| (top, left, bottom, right)),
+        }
+    })
+}
+
+// Rewrites the references in a formula's source after column `from` moved
+// to `to`, see TableContent::move_col. References follow the cells, ranges
+// too if their cells are still side by side. Otherwise a range changes as if
+// `from` was deleted and a column inserted at `to`.
+pub fn move_col_references(source: &str, from: u16, to: u16) -> String {
+    let delete = Shift { axis: Axis::Cols, at: from, count: 1, insert: false };
+    let insert = Shift { axis: Axis::Cols, at: to, count: 1, insert: true };
+    let col = |col: u16| if col == from { Some(to) } else { insert.index(delete.index(col)?) };
+    rewrite_references(source, |(top, left, bottom, right), _| {
+        let moved = (left..=right).map(col).collect::<Option<Vec<u16>>>()?;
+        let (first, last) = (*moved.iter().min()?, *moved.iter().max()?);
+        if last - first == right - left {
+            return Some((top, first, bottom, last));
+        }
+        let (left, right) = delete.span(left, right)?;
+        let (left, right) = insert.span(left, right)?;
+        Some((top, left, bottom, right))
+    })
+}
+
+// Moves every reference in a formula's source by the same distance, for a
+// formula copied that far. References moved off the table become #REF!.
+pub fn offset_references(source: &str, rows: i32, cols: i32) -> String {
+    rewrite_references(source, |(top, left, bottom, right), _| {
+        let row = |row: u16| u16::try_from(row as i32 + rows).ok();
+        let col = |col: u16| u16::try_from(col as i32 + cols).ok();
+        Some((row(top)?, col(left)?, row(bottom)?, col(right)?))
+    })
+}
+
+// Replaces each reference with what `replace` makes of its area, given as
+// top, left, bottom, right and whether it was written as a range. None
+// stands for a deleted reference. The rest of the text stays as it was typed.
+fn rewrite_references(source: &str, replace: impl Fn((u16, u16, u16, u16), bool) -> Option<(u16, u16, u16, u16)>) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut shifted = String::with_capacity(source.len());
+    let mut i = 0;
+    while i < chars.len() {
+        // References to other sheets don't move
+        if let Some(end) = other_sheet_at(&chars, i) {
+            shifted.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        let (area, range, end) = match area_at(&chars, i) {
+            Some(found) => found,
+            None => {
+                shifted.push(chars[i]);
+                i += 1;
+                continue;
+            }
+        };
+
+        let text: String = chars[i..end].iter().collect();
+        let replacement = match range {
+            false => replace(area, false).map(|(row, col, _, _)| ((row, col, row, col) == area, cell_name(row, col))),
+            true => replace(area, true).map(|(top, left, bottom, right)| {
+                ((top, left, bottom, right) == area, format!("{}:{}", cell_name(top, left), cell_name(bottom, right)))
+            }),
+        };
+        match replacement {
+            // Unchanged references keep their spelling, e.g. lower case
+            Some((true, _)) => shifted.push_str(&text),
+            Some((false, name)) => shifted.push_str(&name),
+            None => shifted.push_str(DELETED),
+        }
+        i = end;
+    }
+    shifted
+}
+
+// The references to this sheet in a formula's source, as the characters
+// they take up and the areas they point to, e.g. to show them while the
+// formula is typed
+pub fn references(source: &str) -> Vec<(Range<usize>, Bounds)> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut references = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(end) = other_sheet_at(&chars, i) {
+            i = end;
+        } else if let Some((area, _, end)) = area_at(&chars, i) {
+            references.push((i..end, area));
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    references
+}
+
+// A reference like B12 or A1:B5 starting at `i`, with its area as top,
+// left, bottom, right, whether it is a range and the index after it
+fn area_at(chars: &[char], i: usize) -> Option<(Bounds, bool, usize)> {
+    let (start, end) = reference_at(chars, i)?;
+    // A range if a colon and another cell follow
+    let mut j = end;
+    while chars.get(j).is_some_and(|c| c.is_whitespace()) {
+        j += 1;
+    }
+    if chars.get(j) == Some(&':') {
+        j += 1;
+        while chars.get(j).is_some_and(|c| c.is_whitespace()) {
+            j += 1;
+        }
+        if let Some((range_end, after)) = reference_at(chars, j) {
+            let area = (start.0.min(range_end.0), start.1.min(range_end.1), start.0.max(range_end.0), start.1.max(range_end.1));
+            return Some((area, true, after));
+        }
+    }
+    Some(((start.0, start.1, start.0, start.1), false, end))
+}
+
+// A cell name like B12 starting at `i`, with the index after it. Function
+// names, sheet names and the letters inside other words don't count.
+fn reference_at(chars: &[char], i: usize) -> Option<((u16, u16), usize)> {
+    if i > 0 && is_name_char(chars[i - 1]) {
+        return None;
+    }
+    let letters = chars[i..].iter().take_while(|c| c.is_ascii_alphabetic()).count();
+    let digits = chars[i + letters..].iter().take_while(|c| c.is_ascii_digit()).count();
+    let end = i + letters + digits;
+    if letters == 0 || digits == 0 || chars.get(end).is_some_and(|&c| is_name_char(c) || c == '(' || c == '!') {
+        return None;
+    }
+    let label: String = chars[i..i + letters].iter().collect();
+    let number: String = chars[i + letters..end].iter().collect();
+    let col = col_label_to_nr(&label)?;
+    let row = number.parse::<u16>().ok()?.checked_sub(1)?;
+    Some(((row, col), end))
+}
+
+// A reference like Sheet2!A1 or Sheet2!A1:B5 starting at `i`, the index
+// after it
+fn other_sheet_at(chars: &[char], i: usize) -> Option<usize> {
+    if i > 0 && is_name_char(chars[i - 1]) {
+        return None;
+    }
+    let word = |start: usize| start + chars[start..].iter().take_while(|&&c| is_name_char(c)).count();
+    let name_end = word(i);
+    if name_end == i || chars.get(name_end) != Some(&'!') {
+        return None;
+    }
+    let end = word(name_end + 1);
+    let mut j = end;
+    while chars.get(j).is_some_and(|c| c.is_whitespace()) {
+        j += 1;
+    }
+    if chars.get(j) != Some(&':') {
+        return Some(end);
+    }
+    j += 1;
+    while chars.get(j).is_some_and(|c| c.is_whitespace()) {
+        j += 1;
+    }
+    Some(word(j))
+}
+
+// Ranges and the formulas reading them
+type Readers = HashSet<(Area, (u16, u16))>;
+
+// Which cells each formula reads, to find the formulas affected by a change,
+// and the other way around. Ranges are indexed by column, so finding the
+// formulas which read a cell only looks at those reading its column.
+#[derive(Default)]
+pub struct Dependencies {
+    precedents: HashMap<(u16, u16), Vec<Area>>, // Keyed by formula cell
+    cells: HashMap<(u16, u16), HashSet<(u16, u16)>>, // Single cells to the formulas reading them
+    ranges: HashMap<u16, Readers>, // Column to the ranges over it and the formulas reading them
+}
+
+impl Dependencies {
+    // Formulas which read `cell` directly. A formula can come up twice.
+    fn dependents(&self, (row, col): (u16, u16)) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let cells = self.cells.get(&(row, col)).into_iter().flatten().copied();
+        let ranges = self.ranges.get(&col).into_iter().flatten()
+            .filter(move |(area, _)| area.contains((row, col)))
+            .map(|&(_, formula)| formula);
+        cells.chain(ranges)
+    }
+
+    // The areas the formula at `formula` reads on its own sheet as (top,
+    // left, bottom, right), None if there is no formula
+    pub fn precedents_of(&self, formula: (u16, u16)) -> Option<Vec<(u16, u16, u16, u16)>> {
+        let areas = self.precedents.get(&formula)?;
+        Some(areas.iter().map(|a| (a.top, a.left, a.bottom, a.right)).collect())
+    }
+
+    // The formulas which read `cell` directly, each once and in order
+    pub fn dependents_of(&self, cell: (u16, u16)) -> Vec<(u16, u16)> {
+        self.dependents(cell).collect::<BTreeSet<_>>().into_iter().collect()
+    }
+
+    pub fn has_formulas(&self) -> bool {
+        !self.precedents.is_empty()
+    }
+
+    // The cells of `cells` which `formula` reads
+    fn precedents_in(&self, formula: (u16, u16), cells: &BTreeSet<(u16, u16)>) -> Vec<(u16, u16)> {
+        self.precedents.get(&formula).into_iter().flatten()
+            .flat_map(|a| cells.range((a.top, a.left)..=(a.bottom, a.right)).filter(|&&cell| a.contains(cell)))
+            .copied()
+            .collect()
+    }
+
+    // Keeps the graph in sync with a cell which was replaced, on the sheet
+    // called `sheet`
+    pub fn update(&mut self, cell: (u16, u16), content: &TableCell, sheet: &str) {
+        for area in self.precedents.remove(&cell).unwrap_or_default() {
+            self.unlink(cell, area);
+        }
+        if let TableCell::Formula(formula) = content {
+            let areas = formula.areas(sheet);
+            for &area in &areas {
+                self.link(cell, area);
+            }
+            self.precedents.insert(cell, areas);
+        }
+    }
+
+    fn link(&mut self, formula: (u16, u16), area: Area) {
+        if area.top == area.bottom && area.left == area.right {
+            self.cells.entry((area.top, area.left)).or_default().insert(formula);
+            return;
+        }
+        for col in area.left..=area.right {
+            self.ranges.entry(col).or_default().insert((area, formula));
+        }
+    }
+
+    fn unlink(&mut self, formula: (u16, u16), area: Area) {
+        if area.top == area.bottom && area.left == area.right {
+            if let Some(formulas) = self.cells.get_mut(&(area.top, area.left)) {
+                formulas.remove(&formula);
+                if formulas.is_empty() {
+                    self.cells.remove(&(area.top, area.left));
+                }
+            }
+            return;
+        }
+        for col in area.left..=area.right {
+            if let Some(ranges) = self.ranges.get_mut(&col) {
+                ranges.remove(&(area, formula));
+                if ranges.is_empty() {
+                    self.ranges.remove(&col);
+                }
+            }
+        }
+    }
+}
+
+// Recalculates the formulas which depend on the changed cells, directly or
+// through other formulas. Changed cells which are formulas themselves are
+// calculated as well.
+pub fn recalculate(content: &mut TableContent, changed: &[(u16, u16)]) {
+    let dependencies = &content.dependencies;
+    let mut affected: BTreeSet<(u16, u16)> = changed.iter()
+        .copied()
+        .filter(|cell| dependencies.precedents.contains_key(cell))
+        .collect();
+    let mut queue = changed.to_vec();
+    while let Some(cell) = queue.pop() {
+        for dependent in dependencies.dependents(cell) {
+            if affected.insert(dependent) {
+                queue.push(dependent);
+            }
+        }
+    }
+    schedule(content, affected);
+}
+
+// Rebuilds the dependency graph and calculates every formula, after the
+// cells were replaced as a whole
+pub fn recalculate_all(content: &mut TableContent) {
+    mark_all(content);
+    if !content.lazy {
+        refresh_all(content);
+    }
+}
+
+// Rebuilds the dependency graph and leaves every formula to be calculated
+// later, by refresh_all or refresh
+pub fn mark_all(content: &mut TableContent) {
+    content.dependencies = dependencies(content);
+    content.stale = content.dependencies.precedents.keys().copied().collect();
+}
+
+// Calculates `cells` now, or with lazycalc only when they are needed
+fn schedule(content: &mut TableContent, cells: BTreeSet<(u16, u16)>) {
+    if content.lazy {
+        content.stale.extend(cells);
+    } else {
+        evaluate(content, &cells);
+    }
+}
+
+// Calculates the formulas left for later by lazycalc in `areas`, as (top,
+// left, bottom, right), and the ones they read, directly or through others.
+// Returns the cells which were calculated.
+pub fn refresh(content: &mut TableContent, areas: &[Bounds]) -> BTreeSet<(u16, u16)> {
+    let stale = &content.stale;
+    let mut queue: Vec<(u16, u16)> = areas.iter()
+        .flat_map(|&(top, left, bottom, right)| {
+            stale.range((top, left)..=(bottom, right)).filter(move |&&(_, col)| (left..=right).contains(&col))
+        })
+        .copied()
+        .collect();
+    let mut needed = BTreeSet::new();
+    while let Some(cell) = queue.pop() {
+        if needed.insert(cell) {
+            queue.extend(content.dependencies.precedents_in(cell, stale));
+        }
+    }
+    content.stale.retain(|cell| !needed.contains(cell));
+    evaluate(content, &needed);
+    needed
+}
+
+// Calculates all formulas left for later, before something reads the values
+// of the whole table, e.g. :w or a search
+pub fn refresh_all(content: &mut TableContent) {
+    if !content.stale.is_empty() {
+        let stale = std::mem::take(&mut content.stale);
+        evaluate(content, &stale);
+    }
+}
+
+// Rebuilds the dependency graph after rows or columns moved. Formulas which
+// moved along still read the same cells, so only those in `changed` and what
+// reads them are calculated.
+pub fn recalculate_moved(content: &mut TableContent, changed: &[(u16, u16)]) {
+    content.dependencies = dependencies(content);
+    recalculate(content, changed);
+}
+
+fn dependencies(content: &TableContent) -> Dependencies {
+    let mut dependencies = Dependencies::default();
+    for (cell, value) in content.iter() {
+        if let TableCell::Formula(_) = value {
+            dependencies.update(cell, value, &content.linked.name);
+        }
+    }
+    dependencies
+}
+
+// Calculates the formulas in `cells` so that each one comes after the
+// formulas in `cells` it reads. Formulas on a cycle get an error.
+fn evaluate(content: &mut TableContent, cells: &BTreeSet<(u16, u16)>) {
+    let mut done = HashSet::new();
+    let mut cyclic = HashSet::new();
+    for &start in cells {
+        // Depth first, iterative so long chains of formulas don't overflow
+        // the stack. Each entry holds the precedents still to be visited.
+        let mut stack = Vec::new();
+        let mut on_stack = HashSet::new();
+        let mut next = Some(start);
+        loop {
+            if let Some(cell) = next.take().filter(|cell| !done.contains(cell)) {
+                if on_stack.contains(&cell) {
+                    let position = stack.iter().position(|(c, _)| *c == cell).unwrap_or(0);
+                    cyclic.extend(stack[position..].iter().map(|(c, _)| *c));
+                } else {
+                    let precedents = content.dependencies.precedents_in(cell, cells);
+                    stack.push((cell, precedents));
+                    on_stack.insert(cell);
+                }
+            }
+            let (cell, precedents) = match stack.last_mut() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Some(precedent) = precedents.pop() {
+                next = Some(precedent);
+                continue;
+            }
+
+            let cell = *cell;
+            stack.pop();
+            on_stack.remove(&cell);
+            let value = if cyclic.contains(&cell) {
+                Err(FormulaError::Cycle)
+            } else if let Some(TableCell::Formula(formula)) = content.get_cell(cell.0, cell.1) {
+                formula.expr.as_ref().map_or(Err(FormulaError::Syntax), |expr| expr.evaluate(content, Cells::Table(content)))
+            } else {
+                continue;
+            };
+            if let Some(TableCell::Formula(formula)) = content.get_cell_mut(cell.0, cell.1) {
+                formula.value = value;
+            }
+            done.insert(cell);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rows: &[&[i32]]) -> TableContent {
+        TableContent::from_rows(rows.iter().map(|row| row.iter().map(|&v| TableCell::Value(v)).collect()).collect())
+    }
+
+    fn value(content: &TableContent, row: u16, col: u16) -> std::result::Result<f64, FormulaError> {
+        match content.get_cell(row, col) {
+            Some(TableCell::Formula(f)) => f.value,
+            _ => panic!("no formula in {}", cell_name(row, col)),
+        }
+    }
+
+    #[test]
+    fn precedence_and_parentheses() {
+        let content = TableContent::default();
+        assert_eq!(evaluate_source(&content, "1+2*3").unwrap(), 7.0);
+        assert_eq!(evaluate_source(&content, "(1+2)*3").unwrap(), 9.0);
+        assert_eq!(evaluate_source(&content, "10-4-3").unwrap(), 3.0);
+        assert_eq!(evaluate_source(&content, "-2*-3").unwrap(), 6.0);
+        assert_eq!(evaluate_source(&content, " 1.5 / 3 ").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(check("1+").is_err());
+        assert!(check("(1+2").is_err());
+        assert!(check("A1:").is_err());
+        assert!(check("SUM(A1,").is_err());
+        assert!(check("SUM(A1:B2, 3)").is_ok());
+        assert_eq!(Formula::new("1+*2").value, Err(FormulaError::Syntax));
+    }
+
+    #[test]
+    fn references_and_functions() {
+        let content = table(&[&[1, 2], &[3, 4], &[5, 6]]);
+        assert_eq!(evaluate_source(&content, "A1+B3").unwrap(), 7.0);
+        assert_eq!(evaluate_source(&content, "SUM(A1:B3)").unwrap(), 21.0);
+        assert_eq!(evaluate_source(&content, "sum(B3:A1)").unwrap(), 21.0);
+        assert_eq!(evaluate_source(&content, "AVG(A1:A3)").unwrap(), 3.0);
+        assert_eq!(evaluate_source(&content, "AVERAGE(B1:B3)").unwrap(), 4.0);
+        assert_eq!(evaluate_source(&content, "MIN(A1:B3)").unwrap(), 1.0);
+        assert_eq!(evaluate_source(&content, "MAX(A1:B3, 10)").unwrap(), 10.0);
+        assert_eq!(evaluate_source(&content, "COUNT(A1:C5)").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn errors_in_cells() {
+        let mut content = table(&[&[1, 0]]);
+        content.set_cells(vec![
+            ((1, 0), TableCell::Formula(Box::new(Formula::new("A1/B1")))),
+            ((1, 1), TableCell::Formula(Box::new(Formula::new("B2+1")))),
+            ((1, 2), TableCell::Formula(Box::new(Formula::new("A1+C1")))),
+            ((1, 3), TableCell::Formula(Box::new(Formula::new("NOSUCH(1)")))),
+            ((1, 4), TableCell::Formula(Box::new(Formula::new("#REF!+1")))),
+        ]);
+        content.set_cell(0, 2, TableCell::String("text".to_string()));
+        recalculate_all(&mut content);
+        refresh_all(&mut content);
+        assert_eq!(value(&content, 1, 0), Err(FormulaError::DivisionByZero));
+        assert_eq!(value(&content, 1, 1), Err(FormulaError::Cycle));
+        assert_eq!(value(&content, 1, 2), Err(FormulaError::Value));
+        assert_eq!(value(&content, 1, 3), Err(FormulaError::Name));
+        assert_eq!(value(&content, 1, 4), Err(FormulaError::Reference));
+    }
+
+    #[test]
+    fn formulas_follow_their_cells() {
+        let mut content = table(&[&[1], &[2]]);
+        content.set_cell(2, 0, TableCell::Formula(Box::new(Formula::new("SUM(A1:A2)"))));
+        content.set_cell(3, 0, TableCell::Formula(Box::new(Formula::new("A3*2"))));
+        recalculate_all(&mut content);
+        refresh_all(&mut content);
+        assert_eq!(value(&content, 3, 0), Ok(6.0));
+        content.set_cell(0, 0, TableCell::Value(10));
+        recalculate(&mut content, &[(0, 0)]);
+        refresh_all(&mut content);
+        assert_eq!(value(&content, 2, 0), Ok(12.0));
+        assert_eq!(value(&content, 3, 0), Ok(24.0));
+    }
+
+    #[test]
+    fn shifting_references() {
+        let rows = |at, count, insert| Shift { axis: Axis::Rows, at, count, insert };
+        let cols = |at, count, insert| Shift { axis: Axis::Cols, at, count, insert };
+        // Rows inserted before or deleted from row 2
+        assert_eq!(shift_references("A1+A2*b5", rows(1, 1, true)), "A1+A3*B6");
+        assert_eq!(shift_references("SUM(A1:A4)", rows(1, 2, true)), "SUM(A1:A6)");
+        assert_eq!(shift_references("SUM(A1:A4) + A5", rows(1, 2, false)), "SUM(A1:A2) + A3");
+        assert_eq!(shift_references("A2+1", rows(1, 1, false)), "#REF!+1");
+        assert_eq!(shift_references("SUM(A2:A3)", rows(1, 2, false)), "SUM(#REF!)");
+        assert_eq!(shift_references("B1+C1", cols(1, 1, true)), "C1+D1");
+        assert_eq!(shift_references("SUM(A1:C1)", cols(0, 1, false)), "SUM(A1:B1)");
+        // Unchanged references keep their spelling, other sheets don't move
+        assert_eq!(shift_references("a1 + Data!A5", rows(2, 1, true)), "a1 + Data!A5");
+    }
+
+    #[test]
+    fn moving_columns() {
+        // B moves to E, C to E shift left
+        assert_eq!(move_col_references("B1+C1+E1+F1", 1, 4), "E1+B1+D1+F1");
+        assert_eq!(move_col_references("SUM(B1:B3)", 1, 4), "SUM(E1:E3)");
+        // The moved column leaves a range and another one moves in
+        assert_eq!(move_col_references("SUM(A1:C1)", 1, 4), "SUM(A1:B1)");
+        assert_eq!(move_col_references("SUM(A1:C1)", 3, 1), "SUM(A1:D1)");
+        assert_eq!(move_col_references("SUM(A1:C1)", 0, 4), "SUM(A1:B1)");
+        assert_eq!(move_col_references("SUM(A1:F1)", 1, 4), "SUM(A1:F1)");
+        // Still side by side
+        assert_eq!(move_col_references("SUM(A1:B1)", 0, 1), "SUM(A1:B1)");
+        assert_eq!(move_col_references("SUM(B1:C1)", 3, 0), "SUM(C1:D1)");
+        assert_eq!(move_col_references("Other!B1", 1, 4), "Other!B1");
+    }
+
+    #[test]
+    fn offsetting_references() {
+        assert_eq!(offset_references("A1+SUM(B2:C3)", 2, 1), "B3+SUM(C4:D5)");
+        assert_eq!(offset_references("B2*2", -1, -1), "A1*2");
+        assert_eq!(offset_references("A1+B2", -1, 0), "#REF!+B1");
+        assert_eq!(offset_references("Other!A1", 1, 0), "Other!A1");
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{edit, format, formula, AppState, Result, VispError};
+use crate::grid::{cell_name, TableCell, TableContent};
+
+// Tries before giving up, the secant method needs few for smooth formulas
+const MAX_TRIES: usize = 100;
+
+// Changes the number in `input` until the formula in `target` comes out as
+// `goal`, starting from the number which is there. The tries are no changes
+// of their own, only the number found is, so it can be undone as one.
+pub fn goal_seek(state: &mut AppState, target: (u16, u16), goal: f64, input: (u16, u16)) -> Result<f64> {
+    edit::check_writable(&state.options)?;
+    let content = &mut state.table_content;
+    let (target_name, input_name) = (cell_name(target.0, target.1), cell_name(input.0, input.1));
+    if !matches!(content.get_cell(target.0, target.1), Some(TableCell::Formula(_))) {
+        return Err(VispError::Command(format!("No formula in {}", target_name)));
+    }
+    let original = content.get_cell(input.0, input.1).cloned().unwrap_or(TableCell::Empty);
+    let start = match original {
+        TableCell::Empty => 0.0,
+        TableCell::Value(v) => v as f64,
+        TableCell::Float(f) => f,
+        _ => return Err(VispError::Command(format!("{} must hold a number or be empty", input_name))),
+    };
+
+    let revision = content.revision;
+    // Close enough is relative, large goals can't be hit exactly
+    let tolerance = 1e-9 * goal.abs().max(1.0);
+    let found = secant(start, tolerance, |x| miss(content, target, goal, input, x)).map(|x| {
+        // 20 rather than 20.000000000000057 when both hit the goal
+        let mut hits = |x| miss(content, target, goal, input, x).is_some_and(|m| m.abs() <= tolerance);
+        (1..16).map(|digits| round(x, digits)).find(|&r| hits(r)).unwrap_or(x)
+    });
+    content.set_cell(input.0, input.1, original);
+    // The table is as before, so it isn't modified by the tries
+    content.revision = revision;
+
+    let x = found.ok_or_else(|| {
+        VispError::Command(format!("No number in {} found which makes {} {}", input_name, target_name, format::format_number(goal)))
+    })?;
+    edit::replace_cells(state, vec![(input, TableCell::from_number(x))])?;
+    Ok(x)
+}
+
+// How far the formula is off with `x` in the input cell, None on an error
+fn miss(content: &mut TableContent, target: (u16, u16), goal: f64, input: (u16, u16), x: f64) -> Option<f64> {
+    content.set_cell(input.0, input.1, TableCell::from_number(x));
+    formula::refresh(content, &[(target.0, target.1, target.0, target.1)]);
+    match content.get_cell(target.0, target.1) {
+        Some(TableCell::Formula(formula)) => formula.value.ok().map(|value| value - goal),
+        _ => None,
+    }
+}
+
+// `x` with at most `digits` significant digits
+fn round(x: f64, digits: i32) -> f64 {
+    if x == 0.0 {
+        return x;
+    }
+    let scale = 10f64.powi(digits - 1 - x.abs().log10().floor() as i32);
+    let rounded = (x * scale).round() / scale;
+    if rounded.is_finite() { rounded } else { x }
+}
+
+// Where `f` is within `tolerance` of 0, searched from `start` with the
+// secant method. Gives up when `f` fails, stays flat or the tries run out.
+fn secant(start: f64, tolerance: f64, mut f: impl FnMut(f64) -> Option<f64>) -> Option<f64> {
+    let close = |miss: f64| miss.abs() <= tolerance;
+    let (mut x0, mut x1) = (start, if start == 0.0 { 1.0 } else { start * 1.01 });
+    let mut f0 = f(x0)?;
+    if close(f0) {
+        return Some(x0);
+    }
+    for _ in 0..MAX_TRIES {
+        let f1 = f(x1)?;
+        if close(f1) {
+            return Some(x1);
+        }
+        if f1 == f0 {
+            return None;
+        }
+        let x2 = x1 - f1 * (x1 - x0) / (f1 - f0);
+        if !x2.is_finite() {
+            return None;
+        }
+        (x0, f0, x1) = (x1, f1, x2);
+    }
+    None
+}
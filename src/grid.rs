@@ -0,0 +1,1078 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use crate::format::{self, CellFormat, Currency};
+use crate::formula::{self, Dependencies, Formula, Linked};
+use crate::search;
+
+// Spreadsheet style name of a cell, e.g. B3
+pub fn cell_name(row: u16, col: u16) -> String {
+    format!("{}{}", col_nr_to_label(col), row as u32 + 1)
+}
+
+// Inverse of cell_name, e.g. "B3" -> (2, 1)
+pub fn parse_cell_name(name: &str) -> Option<(u16, u16)> {
+    let split = name.find(|c: char| c.is_ascii_digit())?;
+    let col = col_label_to_nr(&name[..split])?;
+    let row = name[split..].parse::<u16>().ok()?.checked_sub(1)?;
+    Some((row, col))
+}
+
+pub const DEFAULT_COL_WIDTH: u16 = 4;
+pub const DEFAULT_ROW_HEIGHT: u16 = 1;
+
+// Inverse of col_nr_to_label, e.g. "AB" -> 27
+pub fn col_label_to_nr(label: &str) -> Option<u16> {
+    if label.is_empty() {
+        return None;
+    }
+    let mut nr: u32 = 0;
+    for c in label.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        nr = nr * 26 + (c.to_ascii_uppercase() as u32 - 'A' as u32 + 1);
+        if nr > u16::MAX as u32 {
+            return None;
+        }
+    }
+    Some((nr - 1) as u16)
+}
+
+pub fn col_nr_to_label(col: u16) -> String {
+    if col < 26 {
+        char::from_u32('A' as u32 + col as u32).unwrap().to_string()
+    } else {
+        let front = col / 26;
+        col_nr_to_label(front - 1) + col_nr_to_label(col - (26 * front)).as_str()
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum TableCell {
+    Empty,
+    String(String),
+    Value(i32),
+    Float(f64),
+    Bool(bool),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    Time(NaiveTime),
+    Currency(Currency),
+    Formula(Box<Formula>),
+}
+
+impl TableCell {
+    // A Value if the number is whole and fits, otherwise a Float
+    pub fn from_number(number: f64) -> Self {
+        if number.fract() == 0.0 && number >= i32::MIN as f64 && number <= i32::MAX as f64 {
+            Self::Value(number as i32)
+        } else {
+            Self::Float(number)
+        }
+    }
+
+    pub fn format_string(&self) -> String {
+        self.display(None)
+    }
+
+    // The text shown for the cell with a format from :fmt
+    pub fn display(&self, format: Option<&CellFormat>) -> String {
+        let spec = format.and_then(|f| f.spec.as_deref());
+        let number = |value: f64| spec.and_then(|spec| format::format_with(value, spec));
+        match self {
+            Self::Empty => "".to_string(),
+            Self::String(s) => s.clone(),
+            Self::Value(v) => number(*v as f64).unwrap_or_else(|| format!("{}", v)),
+            Self::Float(v) => number(*v).unwrap_or_else(|| format::format_number(*v)),
+            Self::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+            Self::Date(date) => format::format_date(date, spec),
+            Self::DateTime(date_time) => format::format_date_time(date_time, spec),
+            Self::Time(time) => format::format_time(time, spec),
+            Self::Currency(currency) => currency.format(spec),
+            Self::Formula(f) => match f.value {
+                Ok(v) => number(v).unwrap_or_else(|| format::format_number(v)),
+                Err(e) => e.to_string(),
+            },
+        }
+    }
+
+    // What a formula evaluates to, other cells as they are. An error is
+    // kept as its text.
+    pub fn computed(&self) -> TableCell {
+        match self {
+            Self::Formula(f) => match f.value {
+                Ok(v) => Self::from_number(v),
+                Err(e) => Self::String(e.to_string()),
+            },
+            cell => cell.clone(),
+        }
+    }
+
+    // The text as it was typed, formulas with their =
+    pub fn source_string(&self) -> String {
+        match self {
+            Self::Formula(f) => format!("={}", f.source),
+            _ => self.format_string(),
+        }
+    }
+
+    // Whether both were typed the same, formulas by their source and not
+    // their value
+    pub fn same_source(&self, other: &TableCell) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other) && self.source_string() == other.source_string()
+    }
+
+    // Approximate number of bytes used outside of the cell itself
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Self::String(s) => s.capacity(),
+            Self::Formula(f) => std::mem::size_of::<Formula>() + f.source.capacity(),
+            _ => 0,
+        }
+    }
+
+    // Numbers, amounts of money and calculated formulas
+    pub fn number(&self) -> Option<f64> {
+        match self {
+            Self::Value(v) => Some(*v as f64),
+            Self::Float(v) => Some(*v),
+            Self::Currency(currency) => Some(currency.amount()),
+            Self::Formula(f) => f.value.ok(),
+            _ => None,
+        }
+    }
+}
+
+// Formatting of a single cell, see :style
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CellStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub fg: Option<CellColor>,
+    pub bg: Option<CellColor>,
+}
+
+impl CellStyle {
+    // Changes one attribute as written for :style, e.g. "nobold", "fg=red" or
+    // "none". False if there is no such attribute or color.
+    pub fn set(&mut self, change: &str) -> bool {
+        let color = |name: &str| if name == "none" { Some(None) } else { CellColor::parse(name).map(Some) };
+        match change.split_once('=') {
+            Some(("fg", name)) => match color(name) {
+                Some(color) => self.fg = color,
+                None => return false,
+            },
+            Some(("bg", name)) => match color(name) {
+                Some(color) => self.bg = color,
+                None => return false,
+            },
+            Some(_) => return false,
+            None if change == "none" => *self = Self::default(),
+            None => {
+                let (attribute, on) = match change.strip_prefix("no") {
+                    Some(attribute) => (attribute, false),
+                    None => (change, true),
+                };
+                match attribute {
+                    "bold" => self.bold = on,
+                    "italic" => self.italic = on,
+                    "underline" => self.underline = on,
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+
+    // The arguments of :style which give this style, e.g. "bold fg=red"
+    pub fn describe(&self) -> String {
+        let mut words: Vec<String> = [(self.bold, "bold"), (self.italic, "italic"), (self.underline, "underline")].iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| name.to_string())
+            .collect();
+        words.extend(self.fg.map(|color| format!("fg={}", color.name())));
+        words.extend(self.bg.map(|color| format!("bg={}", color.name())));
+        words.join(" ")
+    }
+
+    // What describe gives
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut style = Self::default();
+        text.split_whitespace().all(|change| style.set(change)).then_some(style)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CellColor {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Rgb(u8, u8, u8),
+}
+
+impl CellColor {
+    // A color name like "red" or "#ff8000"
+    pub fn parse(name: &str) -> Option<Self> {
+        if let Some(hex) = name.strip_prefix('#') {
+            let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+            return Some(Self::Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8));
+        }
+        Some(match name {
+            "black" => Self::Black,
+            "red" => Self::Red,
+            "green" => Self::Green,
+            "yellow" => Self::Yellow,
+            "blue" => Self::Blue,
+            "magenta" => Self::Magenta,
+            "cyan" => Self::Cyan,
+            "white" => Self::White,
+            _ => return None,
+        })
+    }
+
+    // A color read from a workbook, by its name if it has one
+    pub fn from_rgb(rgb: u32) -> Self {
+        const NAMED: [CellColor; 8] = [CellColor::Black, CellColor::Red, CellColor::Green, CellColor::Yellow, CellColor::Blue, CellColor::Magenta, CellColor::Cyan, CellColor::White];
+        NAMED.into_iter().find(|color| color.rgb() == rgb)
+            .unwrap_or(Self::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+    }
+
+    // What parse reads
+    pub fn name(&self) -> String {
+        match self {
+            Self::Black => "black".to_string(),
+            Self::Red => "red".to_string(),
+            Self::Green => "green".to_string(),
+            Self::Yellow => "yellow".to_string(),
+            Self::Blue => "blue".to_string(),
+            Self::Magenta => "magenta".to_string(),
+            Self::Cyan => "cyan".to_string(),
+            Self::White => "white".to_string(),
+            Self::Rgb(..) => format!("#{:06x}", self.rgb()),
+        }
+    }
+
+    // As 0xRRGGBB, for writing workbooks
+    pub fn rgb(&self) -> u32 {
+        match self {
+            Self::Black => 0x000000,
+            Self::Red => 0xff0000,
+            Self::Green => 0x008000,
+            Self::Yellow => 0xffff00,
+            Self::Blue => 0x0000ff,
+            Self::Magenta => 0xff00ff,
+            Self::Cyan => 0x00ffff,
+            Self::White => 0xffffff,
+            Self::Rgb(r, g, b) => (*r as u32) << 16 | (*g as u32) << 8 | *b as u32,
+        }
+    }
+}
+
+// Kind of data in a column, judged from its non-empty cells
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColumnType {
+    Number,
+    Text,
+    Mixed,
+}
+
+// Rectangle of selected cells. The cursor sits in one of its corners, the
+// opposite corner is the anchor which stays put while extending.
+#[derive(Clone, Copy)]
+pub struct Selection {
+    pub row: u16,
+    pub col: u16,
+    pub rows: u16,
+    pub cols: u16,
+    pub cursor_at_bottom: bool,
+    pub cursor_at_right: bool,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            row: 0,
+            col: 0,
+            rows: 1,
+            cols: 1,
+            cursor_at_bottom: false,
+            cursor_at_right: false,
+        }
+    }
+}
+
+impl Selection {
+    pub fn bottom(&self) -> u16 {
+        self.row.saturating_add(self.rows - 1)
+    }
+
+    pub fn right(&self) -> u16 {
+        self.col.saturating_add(self.cols - 1)
+    }
+
+    // (top, left, bottom, right)
+    pub fn bounds(&self) -> (u16, u16, u16, u16) {
+        (self.row, self.col, self.bottom(), self.right())
+    }
+
+    pub fn cursor(&self) -> (u16, u16) {
+        (
+            if self.cursor_at_bottom { self.bottom() } else { self.row },
+            if self.cursor_at_right { self.right() } else { self.col },
+        )
+    }
+
+    // Collapses the selection to the cell under the cursor
+    pub fn set_single(&mut self) {
+        let (row, col) = self.cursor();
+        self.set_cursor(row, col);
+    }
+
+    pub fn set_cursor(&mut self, row: u16, col: u16) {
+        *self = Self {
+            row,
+            col,
+            ..Self::default()
+        };
+    }
+
+    // Moves the cursor to another row, keeping the anchor
+    pub fn extend_to_row(&mut self, row: u16) {
+        let anchor = if self.cursor_at_bottom { self.row } else { self.bottom() };
+        self.row = anchor.min(row);
+        self.rows = anchor.max(row) - self.row + 1;
+        self.cursor_at_bottom = row > anchor;
+    }
+
+    // Moves the cursor to another column, keeping the anchor
+    pub fn extend_to_col(&mut self, col: u16) {
+        let anchor = if self.cursor_at_right { self.col } else { self.right() };
+        self.col = anchor.min(col);
+        self.cols = anchor.max(col) - self.col + 1;
+        self.cursor_at_right = col > anchor;
+    }
+
+    // Swaps cursor and anchor
+    pub fn swap_rows_corner(&mut self) {
+        self.cursor_at_bottom = !self.cursor_at_bottom;
+    }
+
+    pub fn swap_cols_corner(&mut self) {
+        self.cursor_at_right = !self.cursor_at_right;
+    }
+
+    // Extends the selection to the full width of the selected rows
+    pub fn whole_rows(&mut self) {
+        self.col = 0;
+        self.cols = u16::MAX;
+        self.cursor_at_right = false;
+    }
+
+    // Extends the selection to the full height of the selected columns
+    pub fn whole_cols(&mut self) {
+        self.row = 0;
+        self.rows = u16::MAX;
+        self.cursor_at_bottom = false;
+    }
+
+    // Selects the rectangle from the anchor `a` to the cursor `b`, both (row, col)
+    pub fn span(&mut self, a: (u16, u16), b: (u16, u16)) {
+        self.set_cursor(a.0, a.1);
+        self.extend_to_row(b.0);
+        self.extend_to_col(b.1);
+    }
+
+    pub fn same_cells(&self, other: &Selection) -> bool {
+        (self.row, self.col, self.rows, self.cols) == (other.row, other.col, other.rows, other.cols)
+    }
+
+    // Spreadsheet style range, e.g. A1:C4, 2:5 for whole rows or B:D for whole columns
+    pub fn name(&self) -> String {
+        if self.cols == u16::MAX {
+            format!("{}:{}", self.row as u32 + 1, self.bottom() as u32 + 1)
+        } else if self.rows == u16::MAX {
+            format!("{}:{}", col_nr_to_label(self.col), col_nr_to_label(self.right()))
+        } else if self.rows == 1 && self.cols == 1 {
+            cell_name(self.row, self.col)
+        } else {
+            format!("{}:{}", cell_name(self.row, self.col), cell_name(self.bottom(), self.right()))
+        }
+    }
+
+    pub fn row_selected(&self, row: u16) -> bool {
+        row >= self.row && row - self.row < self.rows
+    }
+
+    pub fn col_selected(&self, col: u16) -> bool {
+        col >= self.col && col - self.col < self.cols
+    }
+
+    pub fn selected(&self, row: u16, col: u16) -> bool {
+        self.row_selected(row) && self.col_selected(col)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    Rows,
+    Cols,
+}
+
+// Rows or columns which are inserted or deleted, the ones after them move
+// along. Cells moved beyond the last row or column are dropped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Shift {
+    pub axis: Axis,
+    pub at: u16,
+    pub count: u16,
+    pub insert: bool,
+}
+
+impl Shift {
+    // Undoes this shift, apart from what it removed
+    pub fn inverse(&self) -> Self {
+        Self { insert: !self.insert, ..*self }
+    }
+
+    // Where row or column `index` ends up, None if it is deleted
+    pub fn index(&self, index: u16) -> Option<u16> {
+        let (index, at, count) = (index as u32, self.at as u32, self.count as u32);
+        let moved = if index < at {
+            index
+        } else if self.insert {
+            index + count
+        } else if index < at + count {
+            return None;
+        } else {
+            index - count
+        };
+        u16::try_from(moved).ok()
+    }
+
+    pub fn cell(&self, (row, col): (u16, u16)) -> Option<(u16, u16)> {
+        match self.axis {
+            Axis::Rows => Some((self.index(row)?, col)),
+            Axis::Cols => Some((row, self.index(col)?)),
+        }
+    }
+
+    // Rows or columns from `start` to `end` afterwards. Ranges shrink when
+    // part of them is deleted and grow when rows are inserted inside them.
+    pub fn span(&self, start: u16, end: u16) -> Option<(u16, u16)> {
+        if self.insert {
+            return Some((self.index(start)?, self.index(end).unwrap_or(u16::MAX)));
+        }
+        let start = self.index(start).unwrap_or(self.at);
+        let end = match self.index(end) {
+            Some(end) => end,
+            None => self.at.checked_sub(1)?,
+        };
+        Some((start, end)).filter(|(start, end)| start <= end)
+    }
+
+    // Whole rows or columns across the shift keep their size
+    pub fn selection(&self, selection: &Selection) -> Option<Selection> {
+        let mut shifted = *selection;
+        match self.axis {
+            Axis::Rows if selection.rows != u16::MAX => {
+                let (top, bottom) = self.span(selection.row, selection.bottom())?;
+                shifted.row = top;
+                shifted.rows = (bottom - top).saturating_add(1);
+            }
+            Axis::Cols if selection.cols != u16::MAX => {
+                let (left, right) = self.span(selection.col, selection.right())?;
+                shifted.col = left;
+                shifted.cols = (right - left).saturating_add(1);
+            }
+            _ => {}
+        }
+        Some(shifted)
+    }
+}
+
+// What a shift deleted or rewrote, to bring it back on undo
+#[derive(Clone, Default)]
+pub struct Removed {
+    cells: Vec<((u16, u16), TableCell)>, // Where they were before, rewritten formulas included
+    sizes: Vec<(u16, u16)>, // Widths or heights of deleted columns or rows
+    notes: Vec<((u16, u16), String)>,
+    styles: Vec<((u16, u16), CellStyle)>,
+    formats: Vec<((u16, u16), CellFormat)>,
+    protected: Vec<Selection>,
+    hidden_cols: BTreeSet<u16>,
+    hidden_rows: BTreeSet<u16>,
+}
+
+impl Removed {
+    // Approximate number of bytes used
+    pub fn memory_size(&self) -> usize {
+        let cells: usize = self.cells.iter().map(|(_, cell)| std::mem::size_of::<((u16, u16), TableCell)>() + cell.heap_size()).sum();
+        let notes: usize = self.notes.iter().map(|(_, note)| std::mem::size_of::<((u16, u16), String)>() + note.capacity()).sum();
+        cells + notes
+            + self.sizes.capacity() * std::mem::size_of::<(u16, u16)>()
+            + self.styles.capacity() * std::mem::size_of::<((u16, u16), CellStyle)>()
+            + self.formats.capacity() * std::mem::size_of::<((u16, u16), CellFormat)>()
+            + self.protected.capacity() * std::mem::size_of::<Selection>()
+            + (self.hidden_cols.len() + self.hidden_rows.len()) * std::mem::size_of::<u16>()
+    }
+}
+
+// Copy of the contents of a table, see :snapshot
+pub struct Snapshot {
+    cells: BTreeMap<(u16, u16), TableCell>,
+    col_widths: HashMap<u16, u16>,
+    row_heights: HashMap<u16, u16>,
+    notes: HashMap<(u16, u16), String>,
+    styles: HashMap<(u16, u16), CellStyle>,
+    formats: HashMap<(u16, u16), CellFormat>,
+}
+
+impl Snapshot {
+    pub fn get(&self, row: u16, col: u16) -> Option<&TableCell> {
+        self.cells.get(&(row, col))
+    }
+
+    pub fn cell_changed(&self, content: &TableContent, row: u16, col: u16) -> bool {
+        let before = self.get(row, col).unwrap_or(&TableCell::Empty);
+        let after = content.get_cell(row, col).unwrap_or(&TableCell::Empty);
+        before != after
+    }
+
+    // Cells which differ in `content`, row by row
+    pub fn changed_cells(&self, content: &TableContent) -> Vec<(u16, u16)> {
+        let cells: BTreeSet<(u16, u16)> = self.cells.keys().chain(content.cells.keys()).copied().collect();
+        cells.into_iter().filter(|&(row, col)| self.cell_changed(content, row, col)).collect()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        let cells: usize = self.cells.values().map(|cell| std::mem::size_of::<((u16, u16), TableCell)>() + cell.heap_size()).sum();
+        let notes: usize = self.notes.values().map(|n| n.capacity() + std::mem::size_of::<((u16, u16), String)>()).sum();
+        cells + notes
+            + (self.col_widths.capacity() + self.row_heights.capacity()) * std::mem::size_of::<(u16, u16)>()
+            + self.styles.capacity() * std::mem::size_of::<((u16, u16), CellStyle)>()
+            + self.formats.capacity() * std::mem::size_of::<((u16, u16), CellFormat)>()
+    }
+}
+
+// Number of non-empty cells of each kind in a column
+#[derive(Clone, Copy, Default)]
+struct ColumnStats {
+    numbers: u32,
+    texts: u32,
+}
+
+#[derive(Default)]
+pub struct TableContent {
+    cells: BTreeMap<(u16, u16), TableCell>, // Never holds Empty, iterates row major
+    columns: BTreeMap<u16, ColumnStats>, // Only columns which contain cells
+    pub col_widths: HashMap<u16, u16>, // Columns without an entry have default_col_width
+    pub default_col_width: Option<u16>, // From the colwidth option, DEFAULT_COL_WIDTH if None
+    pub row_heights: HashMap<u16, u16>, // Rows without an entry have DEFAULT_ROW_HEIGHT
+    pub selection: Selection,
+    pub notes: HashMap<(u16, u16), String>, // Free text attached to cells
+    pub styles: HashMap<(u16, u16), CellStyle>, // Cells without an entry are unstyled
+    pub formats: HashMap<(u16, u16), CellFormat>, // Cells without an entry are shown as typed
+    pub revision: u64, // Increased on every change of cells, see changed()
+    pub protected: Vec<Selection>, // Ranges which must not be edited
+    pub hidden_cols: BTreeSet<u16>, // Drawn with a width of 0
+    pub hidden_rows: BTreeSet<u16>, // Left out by :filter, drawn with a height of 0
+    pub dependencies: Dependencies, // Cells read by the formulas
+    pub lazy: bool, // From the lazycalc option, formulas are calculated when shown
+    pub stale: BTreeSet<(u16, u16)>, // Formulas left for later by lazy, see formula::refresh
+    pub linked: Linked, // The other sheets, for formulas which read them
+    pub search_index: RefCell<search::Index>, // Brought up to date by searches
+}
+
+impl TableContent {
+    // A table holding `rows`, e.g. read from a file. Cells beyond the last
+    // row or column are dropped. The formulas are calculated by sheet::link,
+    // which gives them the other sheets.
+    pub fn from_rows(rows: Vec<Vec<TableCell>>) -> Self {
+        let mut content = Self::default();
+        for (row, cells) in rows.into_iter().enumerate().take(u16::MAX as usize + 1) {
+            for (col, cell) in cells.into_iter().enumerate().take(u16::MAX as usize + 1) {
+                content.store((row as u16, col as u16), cell);
+            }
+        }
+        formula::mark_all(&mut content);
+        content
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cells: self.cells.clone(),
+            col_widths: self.col_widths.clone(),
+            row_heights: self.row_heights.clone(),
+            notes: self.notes.clone(),
+            styles: self.styles.clone(),
+            formats: self.formats.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.cells = snapshot.cells.clone();
+        self.col_widths = snapshot.col_widths.clone();
+        self.row_heights = snapshot.row_heights.clone();
+        self.notes = snapshot.notes.clone();
+        self.styles = snapshot.styles.clone();
+        self.formats = snapshot.formats.clone();
+        self.count_columns();
+        formula::recalculate_all(self);
+        self.changed();
+    }
+
+    // Inserts or deletes rows or columns. Formulas keep reading the same
+    // cells, references to deleted ones become #REF!.
+    pub fn shift(&mut self, shift: Shift) -> Removed {
+        let (removed, rewritten) = self.move_cells(shift);
+        formula::recalculate_moved(self, &rewritten);
+        self.changed();
+        removed
+    }
+
+    // Inserts rows or columns and fills them with `cells`, for pasting large
+    // blocks. The formulas are recalculated once for both.
+    pub fn insert_filled(&mut self, shift: Shift, cells: Vec<((u16, u16), TableCell)>) -> Removed {
+        let (removed, mut changed) = self.move_cells(shift);
+        for (position, cell) in cells {
+            self.store(position, cell);
+            changed.push(position);
+        }
+        formula::recalculate_moved(self, &changed);
+        self.changed();
+        removed
+    }
+
+    // What shift does before recalculating. Also returns where the formulas
+    // whose references were rewritten went.
+    fn move_cells(&mut self, shift: Shift) -> (Removed, Vec<(u16, u16)>) {
+        let mut removed = Removed {
+            protected: self.protected.clone(),
+            hidden_cols: self.hidden_cols.clone(),
+            hidden_rows: self.hidden_rows.clone(),
+            ..Removed::default()
+        };
+        let mut cells = BTreeMap::new();
+        let mut rewritten = Vec::new();
+        for (position, cell) in std::mem::take(&mut self.cells) {
+            let to = match shift.cell(position) {
+                Some(to) => to,
+                None => {
+                    removed.cells.push((position, cell));
+                    continue;
+                }
+            };
+            let cell = match &cell {
+                TableCell::Formula(f) => {
+                    let source = formula::shift_references(&f.source, shift);
+                    if source == f.source {
+                        cell
+                    } else {
+                        removed.cells.push((position, cell.clone()));
+                        rewritten.push(to);
+                        TableCell::Formula(Box::new(Formula::new(&source)))
+                    }
+                }
+                _ => cell,
+            };
+            cells.insert(to, cell);
+        }
+        self.cells = cells;
+        self.stale = std::mem::take(&mut self.stale).into_iter().filter_map(|position| shift.cell(position)).collect();
+
+        let sizes = match shift.axis {
+            Axis::Rows => &mut self.row_heights,
+            Axis::Cols => &mut self.col_widths,
+        };
+        *sizes = sizes.drain().filter_map(|(index, size)| match shift.index(index) {
+            Some(to) => Some((to, size)),
+            None => {
+                removed.sizes.push((index, size));
+                None
+            }
+        }).collect();
+        self.notes = self.notes.drain().filter_map(|(position, note)| match shift.cell(position) {
+            Some(to) => Some((to, note)),
+            None => {
+                removed.notes.push((position, note));
+                None
+            }
+        }).collect();
+        self.styles = self.styles.drain().filter_map(|(position, style)| match shift.cell(position) {
+            Some(to) => Some((to, style)),
+            None => {
+                removed.styles.push((position, style));
+                None
+            }
+        }).collect();
+        self.formats = self.formats.drain().filter_map(|(position, format)| match shift.cell(position) {
+            Some(to) => Some((to, format)),
+            None => {
+                removed.formats.push((position, format));
+                None
+            }
+        }).collect();
+        self.protected = self.protected.iter().filter_map(|range| shift.selection(range)).collect();
+        match shift.axis {
+            Axis::Rows => self.hidden_rows = self.hidden_rows.iter().filter_map(|&row| shift.index(row)).collect(),
+            Axis::Cols => self.hidden_cols = self.hidden_cols.iter().filter_map(|&col| shift.index(col)).collect(),
+        }
+
+        self.count_columns();
+        (removed, rewritten)
+    }
+
+    // Reverts a shift, see shift
+    pub fn unshift(&mut self, shift: Shift, removed: &Removed) {
+        let (_, mut changed) = self.move_cells(shift.inverse());
+        for (position, cell) in &removed.cells {
+            self.store(*position, cell.clone());
+            changed.push(*position);
+        }
+        let sizes = match shift.axis {
+            Axis::Rows => &mut self.row_heights,
+            Axis::Cols => &mut self.col_widths,
+        };
+        sizes.extend(removed.sizes.iter().copied());
+        self.notes.extend(removed.notes.iter().cloned());
+        self.styles.extend(removed.styles.iter().copied());
+        self.formats.extend(removed.formats.iter().cloned());
+        self.protected = removed.protected.clone();
+        self.hidden_cols = removed.hidden_cols.clone();
+        self.hidden_rows = removed.hidden_rows.clone();
+        formula::recalculate_moved(self, &changed);
+        self.changed();
+    }
+
+    pub fn is_protected(&self, row: u16, col: u16) -> bool {
+        self.protected.iter().any(|range| range.selected(row, col))
+    }
+
+    // Moves column `from` to index `to`, the columns in between shift over.
+    // With `references` formulas are rewritten to read the same cells as
+    // before, see formula::move_col_references, and returned as they were.
+    // Undo moves back without and puts those in again.
+    pub fn move_col(&mut self, from: u16, to: u16, references: bool) -> Vec<((u16, u16), TableCell)> {
+        // Where a column ends up when `from` moves to `to`
+        let moved = |col: u16| {
+            if col == from {
+                to
+            } else if from < to && col > from && col <= to {
+                col - 1
+            } else if to < from && col >= to && col < from {
+                col + 1
+            } else {
+                col
+            }
+        };
+        let mut rewritten = Vec::new();
+        self.cells = std::mem::take(&mut self.cells).into_iter().map(|((row, col), cell)| {
+            let cell = match &cell {
+                TableCell::Formula(f) if references => {
+                    let source = formula::move_col_references(&f.source, from, to);
+                    if source == f.source {
+                        cell
+                    } else {
+                        rewritten.push(((row, col), cell.clone()));
+                        TableCell::Formula(Box::new(Formula::new(&source)))
+                    }
+                }
+                _ => cell,
+            };
+            ((row, moved(col)), cell)
+        }).collect();
+        self.col_widths = self.col_widths.drain().map(|(col, width)| (moved(col), width)).collect();
+        self.notes = self.notes.drain().map(|((row, col), note)| ((row, moved(col)), note)).collect();
+        self.styles = self.styles.drain().map(|((row, col), style)| ((row, moved(col)), style)).collect();
+        self.formats = self.formats.drain().map(|((row, col), format)| ((row, moved(col)), format)).collect();
+        self.hidden_cols = self.hidden_cols.iter().map(|&col| moved(col)).collect();
+        self.count_columns();
+        formula::recalculate_all(self);
+        self.changed();
+        rewritten
+    }
+
+    // Moves the notes, styles and formats of columns `left` to `right` from
+    // row to row, for :sort. The cells themselves are replaced as usual.
+    pub fn move_rows(&mut self, moves: &[(u16, u16)], left: u16, right: u16) {
+        fn take<T>(map: &mut HashMap<(u16, u16), T>, moves: &[(u16, u16)], left: u16, right: u16) {
+            let taken: Vec<((u16, u16), T)> = moves.iter()
+                .flat_map(|&(from, to)| (left..=right).map(move |col| ((from, col), (to, col))))
+                .filter_map(|(from, to)| Some((to, map.remove(&from)?)))
+                .collect();
+            map.extend(taken);
+        }
+        take(&mut self.notes, moves, left, right);
+        take(&mut self.styles, moves, left, right);
+        take(&mut self.formats, moves, left, right);
+    }
+
+    // Replaces a cell, setting it to Empty removes it. Formulas which read it
+    // are recalculated.
+    pub fn set_cell(&mut self, row: u16, col: u16, cell: TableCell) {
+        self.set_cells(vec![((row, col), cell)]);
+    }
+
+    // Like set_cell for many cells, formulas are only recalculated once
+    pub fn set_cells(&mut self, cells: Vec<((u16, u16), TableCell)>) {
+        let mut changed = Vec::with_capacity(cells.len());
+        for (position, cell) in cells {
+            self.dependencies.update(position, &cell, &self.linked.name);
+            self.store(position, cell);
+            changed.push(position);
+        }
+        formula::recalculate(self, &changed);
+        self.changed_rows(changed.into_iter().map(|(row, _)| row));
+    }
+
+    // Puts a cell into the map and keeps the column stats up to date, nothing else
+    fn store(&mut self, position: (u16, u16), cell: TableCell) {
+        let added = cell_type(&cell);
+        let removed = match cell {
+            TableCell::Empty => self.cells.remove(&position),
+            cell => self.cells.insert(position, cell),
+        };
+        let col = position.1;
+        if let Some(removed) = removed.as_ref().and_then(cell_type) {
+            let stats = self.columns.entry(col).or_default();
+            match removed {
+                ColumnType::Number => stats.numbers -= 1,
+                _ => stats.texts -= 1,
+            }
+        }
+        if let Some(added) = added {
+            let stats = self.columns.entry(col).or_default();
+            match added {
+                ColumnType::Number => stats.numbers += 1,
+                _ => stats.texts += 1,
+            }
+        }
+        if self.columns.get(&col).is_some_and(|s| s.numbers == 0 && s.texts == 0) {
+            self.columns.remove(&col);
+        }
+    }
+
+    // Rebuilds the column stats after the cells were replaced as a whole
+    fn count_columns(&mut self) {
+        self.columns.clear();
+        for (&(_, col), cell) in &self.cells {
+            let stats = self.columns.entry(col).or_default();
+            match cell_type(cell) {
+                Some(ColumnType::Number) => stats.numbers += 1,
+                _ => stats.texts += 1,
+            }
+        }
+    }
+
+    // Must be called after modifying cells so that caches are refreshed
+    pub fn changed(&mut self) {
+        self.revision += 1;
+    }
+
+    // Like changed when only the text of cells in `rows` changed, formula
+    // values aside. The search index then only looks at those rows again.
+    pub fn changed_rows(&mut self, rows: impl IntoIterator<Item = u16>) {
+        self.search_index.get_mut().touch(self.revision, rows);
+        self.changed();
+    }
+
+    // Widths of columns or heights of rows, None for the default, see
+    // structure::resize
+    pub fn set_sizes(&mut self, axis: Axis, sizes: impl Iterator<Item = (u16, Option<u16>)>) {
+        let map = match axis {
+            Axis::Cols => &mut self.col_widths,
+            Axis::Rows => &mut self.row_heights,
+        };
+        for (i, size) in sizes {
+            match size {
+                Some(size) => map.insert(i, size),
+                None => map.remove(&i),
+            };
+        }
+        self.changed();
+    }
+
+    pub fn col_width(&self, col: u16) -> u16 {
+        if self.hidden_cols.contains(&col) {
+            return 0;
+        }
+        self.col_widths.get(&col).copied().or(self.default_col_width).unwrap_or(DEFAULT_COL_WIDTH)
+    }
+
+    pub fn row_height(&self, row: u16) -> u16 {
+        if self.hidden_rows.contains(&row) {
+            return 0;
+        }
+        self.row_heights.get(&row).copied().unwrap_or(DEFAULT_ROW_HEIGHT)
+    }
+
+    // The row `steps` rows below or above `row`, not counting hidden ones.
+    // Stops at the last row there is in that direction.
+    pub fn step_rows(&self, row: u16, steps: u16, down: bool) -> u16 {
+        step(&self.hidden_rows, row, steps, down)
+    }
+
+    // Like step_rows, for h and l
+    pub fn step_cols(&self, col: u16, steps: u16, right: bool) -> u16 {
+        step(&self.hidden_cols, col, steps, right)
+    }
+
+    // `row` if it is shown, otherwise the next row shown below it, or above
+    // it at the end of the table
+    pub fn visible_row(&self, row: u16) -> u16 {
+        if !self.hidden_rows.contains(&row) {
+            return row;
+        }
+        shown_from(&self.hidden_rows, row, true).or_else(|| shown_from(&self.hidden_rows, row, false)).unwrap_or(row)
+    }
+
+    // None for empty cells
+    pub fn get_cell(&self, row: u16, col: u16) -> Option<&TableCell> {
+        self.cells.get(&(row, col))
+    }
+
+    pub fn get_cell_mut(&mut self, row: u16, col: u16) -> Option<&mut TableCell> {
+        self.cells.get_mut(&(row, col))
+    }
+
+    // The text shown for a cell, with its format applied
+    pub fn display(&self, row: u16, col: u16) -> String {
+        match self.get_cell(row, col) {
+            Some(cell) => cell.display(self.formats.get(&(row, col))),
+            None => String::new(),
+        }
+    }
+
+    pub fn is_empty(&self, row: u16, col: u16) -> bool {
+        self.get_cell(row, col).is_none()
+    }
+
+    // The non-empty cells, row by row
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = ((u16, u16), &TableCell)> {
+        self.cells.iter().map(|(&position, cell)| (position, cell))
+    }
+
+    // The non-empty cells from `start` on, or before it, row by row
+    pub fn iter_from(&self, start: (u16, u16)) -> impl DoubleEndedIterator<Item = ((u16, u16), &TableCell)> {
+        self.cells.range(start..).map(|(&position, cell)| (position, cell))
+    }
+
+    pub fn iter_before(&self, end: (u16, u16)) -> impl DoubleEndedIterator<Item = ((u16, u16), &TableCell)> {
+        self.cells.range(..end).map(|(&position, cell)| (position, cell))
+    }
+
+    // The non-empty cells in (top, left, bottom, right), row by row. Only
+    // rows which can contain cells are looked at.
+    pub fn cells_in(&self, (top, left, bottom, right): (u16, u16, u16, u16)) -> impl Iterator<Item = ((u16, u16), &TableCell)> {
+        (top..=bottom.min(self.used_rows() - 1)).flat_map(move |row| {
+            self.cells.range((row, left)..=(row, right)).map(|(&position, cell)| (position, cell))
+        })
+    }
+
+    // The block of non-empty cells around (row, col) as (top, left, bottom, right).
+    // Grows a rectangle until it is surrounded by empty cells, diagonals included.
+    pub fn data_region(&self, row: u16, col: u16) -> (u16, u16, u16, u16) {
+        let (mut top, mut left, mut bottom, mut right) = (row, col, row, col);
+        let any_data = |rows: (u16, u16), cols: (u16, u16)| {
+            (rows.0..=rows.1).any(|r| self.cells.range((r, cols.0)..=(r, cols.1)).next().is_some())
+        };
+
+        loop {
+            let outer_left = left.saturating_sub(1);
+            let outer_right = right.saturating_add(1);
+            if top > 0 && any_data((top - 1, top - 1), (outer_left, outer_right)) {
+                top -= 1;
+            } else if bottom < self.used_rows() && any_data((bottom + 1, bottom + 1), (outer_left, outer_right)) {
+                bottom += 1;
+            } else if left > 0 && any_data((top, bottom), (left - 1, left - 1)) {
+                left -= 1;
+            } else if right < self.used_cols() && any_data((top, bottom), (right + 1, right + 1)) {
+                right += 1;
+            } else {
+                break;
+            }
+        }
+        (top, left, bottom, right)
+    }
+
+    // None if the column is empty. Rows above `first_row` are not looked at.
+    pub fn column_type(&self, col: u16, first_row: u16) -> Option<ColumnType> {
+        let mut stats = self.columns.get(&col).copied().unwrap_or_default();
+        // Only the few header rows are looked at, not the whole column
+        for row in 0..first_row.min(self.used_rows()) {
+            match self.get_cell(row, col).and_then(cell_type) {
+                Some(ColumnType::Number) => stats.numbers -= 1,
+                Some(_) => stats.texts -= 1,
+                None => {}
+            }
+        }
+        match (stats.numbers > 0, stats.texts > 0) {
+            (true, true) => Some(ColumnType::Mixed),
+            (true, false) => Some(ColumnType::Number),
+            (false, true) => Some(ColumnType::Text),
+            (false, false) => None,
+        }
+    }
+
+    // Sum of the numbers in the selection
+    pub fn sum(&self, selection: &Selection) -> f64 {
+        self.cells_in(selection.bounds()).filter_map(|(_, cell)| cell.number()).sum()
+    }
+
+    // Approximate number of bytes used by cells and notes
+    pub fn memory_size(&self) -> usize {
+        let cells: usize = self.cells.values().map(|cell| std::mem::size_of::<((u16, u16), TableCell)>() + cell.heap_size()).sum();
+        let notes: usize = self.notes.values().map(|n| n.capacity() + std::mem::size_of::<((u16, u16), String)>()).sum();
+        cells + notes
+    }
+
+    // Number of rows/columns up to the last one which contains cells, at least 1
+    pub fn used_rows(&self) -> u16 {
+        self.cells.keys().next_back().map_or(1, |&(row, _)| row.saturating_add(1))
+    }
+
+    pub fn used_cols(&self) -> u16 {
+        self.columns.keys().next_back().map_or(1, |&col| col.saturating_add(1))
+    }
+}
+
+fn step(hidden: &BTreeSet<u16>, index: u16, steps: u16, forward: bool) -> u16 {
+    let mut index = index;
+    for _ in 0..steps {
+        match shown_from(hidden, index, forward) {
+            Some(next) => index = next,
+            None => break,
+        }
+    }
+    index
+}
+
+// The next row or column after `index` which isn't hidden
+fn shown_from(hidden: &BTreeSet<u16>, index: u16, forward: bool) -> Option<u16> {
+    let mut next = index;
+    loop {
+        next = if forward { next.checked_add(1)? } else { next.checked_sub(1)? };
+        if !hidden.contains(&next) {
+            return Some(next);
+        }
+    }
+}
+
+// How a cell counts for the type of its column, None for Empty
+fn cell_type(cell: &TableCell) -> Option<ColumnType> {
+    match cell {
+        TableCell::Value(_) | TableCell::Float(_) | TableCell::Currency(_) | TableCell::Formula(_) => Some(ColumnType::Number),
+        TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Time(_) => Some(ColumnType::Number),
+        TableCell::String(_) | TableCell::Bool(_) => Some(ColumnType::Text),
+        TableCell::Empty => None,
+    }
+}
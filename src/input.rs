@@ -0,0 +1,946 @@
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+use crate::{AppState, AppMode, Message, Result, VispError, autosave, calc, commands, diff, formula, edit, fill, line_normal, merge, oldfiles, operator, register, sheet, structure, task, undo, window};
+use crate::command_line::Prompt;
+use crate::edit::LineBuffer;
+use crate::grid::{Axis, TableContent};
+use crate::search::Search;
+use crate::picker::{Picker, PickerKind};
+use crate::keymap::{Action, Find, KeyPress, Lookup, MacroKey};
+use crate::render::GridPosition;
+
+// Rows (or columns with shift) per mouse wheel step
+const SCROLL_STEP: i32 = 3;
+
+// Returns false if the event can't have changed anything on screen
+pub fn handle_event(state: &mut AppState, event: Event) -> bool {
+    // Only Ctrl-C is taken while a task runs, see task.rs
+    if state.tasks.running.is_some() {
+        return match event {
+            Event::Key(key) if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) => {
+                task::cancel(state);
+                true
+            }
+            Event::Resize(_, _) => true,
+            _ => false,
+        };
+    }
+    match event {
+        Event::Key(key) => {
+            // The keys which start and stop a recording are not part of it,
+            // nor are the keys a macro plays
+            let recording = state.recording.is_some();
+            let mode = state.mode;
+            handle_key(state, key);
+            autosave::mode_changed(state, mode);
+            // Messages are cleared by the next key, :messages keeps them
+            match &state.message {
+                _ if key.kind == KeyEventKind::Release => {}
+                Some(Message::Info(text)) => tracing::info!("{}", text),
+                Some(Message::Error(text)) => tracing::warn!("{}", text),
+                None => {}
+            }
+            if let Some((_, keys)) = state.recording.as_mut().filter(|_| recording && key.kind != KeyEventKind::Release) {
+                keys.push(key.into());
+            }
+        }
+        Event::Mouse(MouseEvent { kind: MouseEventKind::Moved, .. }) => return false,
+        Event::Mouse(mouse) => handle_mouse(state, mouse),
+        Event::Resize(_, _) => {}
+        Event::FocusLost => return autosave::focus_lost(state),
+        _ => return false,
+    }
+    true
+}
+
+fn handle_mouse(state: &mut AppState, mouse: MouseEvent) {
+    // Clicking somewhere else finishes editing
+    if state.mode == AppMode::Insert && matches!(mouse.kind, MouseEventKind::Down(_)) {
+        edit::commit(state);
+    }
+    // Clicking or scrolling in another window makes it the current one
+    if matches!(mouse.kind, MouseEventKind::Down(_) | MouseEventKind::ScrollDown | MouseEventKind::ScrollUp) {
+        if let Some(index) = window::at(state, mouse.column, mouse.row) {
+            window::focus(state, index);
+        }
+    }
+    let position = state.viewport.position_at(&state.table_content, mouse.column, mouse.row);
+
+    match mouse.kind {
+        MouseEventKind::Down(MouseButton::Left) => {
+            state.drag = match position {
+                Some(GridPosition::Cell(row, col)) => Some(Drag::Cells((row, col))),
+                Some(GridPosition::ColumnHeader(col)) if is_right_border(state, col, mouse.column) => {
+                    match edit::check_lines(state, Axis::Cols, col, col) {
+                        Ok(()) => Some(Drag::Resize(col, state.table_content.col_widths.get(&col).copied())),
+                        Err(e) => {
+                            state.message = Some(Message::Error(e.to_string()));
+                            None
+                        }
+                    }
+                }
+                Some(GridPosition::RowHeader(_) | GridPosition::ColumnHeader(_)) => Some(Drag::Headers),
+                _ => None,
+            };
+            if let Some(position) = position {
+                click(state, position);
+            }
+        }
+        MouseEventKind::Drag(MouseButton::Left) => drag(state, position, mouse.column),
+        MouseEventKind::Up(MouseButton::Left) => {
+            drag(state, position, mouse.column);
+            if let Some(Drag::Resize(col, old)) = state.drag {
+                finish_resize(state, col, old);
+            }
+            state.drag = None;
+        }
+        MouseEventKind::ScrollDown | MouseEventKind::ScrollUp => {
+            let step = if mouse.kind == MouseEventKind::ScrollDown { SCROLL_STEP } else { -SCROLL_STEP };
+            if mouse.modifiers.contains(KeyModifiers::SHIFT) {
+                state.viewport.scroll_cols(step);
+            } else {
+                state.viewport.scroll_rows(step);
+            }
+        }
+        _ => {}
+    }
+}
+
+// What dragging with the left mouse button does, decided where it went down
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Drag {
+    Cells((u16, u16)), // Selects the rectangle from this cell
+    Headers, // Extends the rows or columns selected by clicking a header
+    Resize(u16, Option<u16>), // Moves the right border of the column, with its width before
+}
+
+// The last character of a column header is the handle for resizing it
+fn is_right_border(state: &AppState, col: u16, x: u16) -> bool {
+    let content = &state.table_content;
+    let width = state.viewport.col_width(content, col);
+    !state.viewport.compact && width > 0 && state.viewport.col_x(content, col).map(|left| left + width - 1) == Some(x)
+}
+
+fn drag(state: &mut AppState, position: Option<GridPosition>, x: u16) {
+    match (state.drag, position) {
+        (Some(Drag::Cells(start)), Some(GridPosition::Cell(row, col))) => {
+            if start != (row, col) {
+                state.mode = AppMode::Visual;
+            }
+            state.table_content.selection.span(start, (row, col));
+        }
+        // The visual mode of the header keeps the other axis whole
+        (Some(Drag::Headers), Some(GridPosition::Cell(row, col))) => move_to(state, (row, col)),
+        (Some(Drag::Headers), Some(GridPosition::RowHeader(row))) => move_to(state, (row, 0)),
+        (Some(Drag::Headers), Some(GridPosition::ColumnHeader(col))) => move_to(state, (0, col)),
+        // Works anywhere on the screen, the border follows the pointer
+        (Some(Drag::Resize(col, _)), _) => {
+            if let Some(left) = state.viewport.col_x(&state.table_content, col) {
+                let width = (x + 1).saturating_sub(left).max(1);
+                if state.table_content.col_width(col) != width {
+                    state.table_content.col_widths.insert(col, width);
+                    state.message = Some(Message::Info(format!("colwidth={}", width)));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// The widths shown while dragging become one change when the button is released
+fn finish_resize(state: &mut AppState, col: u16, old: Option<u16>) {
+    let width = state.table_content.col_widths.get(&col).copied();
+    match old {
+        Some(old) => state.table_content.col_widths.insert(col, old),
+        None => state.table_content.col_widths.remove(&col),
+    };
+    if let Err(e) = structure::resize(state, Axis::Cols, vec![(col, width)]) {
+        state.message = Some(Message::Error(e.to_string()));
+    }
+}
+
+fn click(state: &mut AppState, position: GridPosition) {
+    let selection = &mut state.table_content.selection;
+
+    match position {
+        GridPosition::Cell(row, col) => {
+            state.mode = AppMode::Normal;
+            selection.set_cursor(row, col);
+        }
+        GridPosition::RowHeader(row) => {
+            state.mode = AppMode::VisualRow;
+            selection.set_cursor(row, 0);
+            selection.whole_rows();
+        }
+        GridPosition::ColumnHeader(col) => {
+            state.mode = AppMode::VisualColumn;
+            selection.set_cursor(0, col);
+            selection.whole_cols();
+        }
+        GridPosition::Corner => select_all(state),
+    }
+}
+
+fn handle_key(state: &mut AppState, key: KeyEvent) {
+    if key.kind == KeyEventKind::Release {
+        return;
+    }
+    state.message = None;
+
+    // Any other key is a no
+    if let Some(command) = state.confirm.take() {
+        if key.code == KeyCode::Char('y') {
+            commands::run_confirmed(state, &command);
+        }
+        return;
+    }
+
+    if let Some(pager) = state.pager.take() {
+        // The start screen lists recent files by number
+        if let (commands::INTRO_TITLE, KeyCode::Char(c @ '1'..='9')) = (pager.title.as_str(), key.code) {
+            let files = oldfiles::existing(state, commands::INTRO_FILES);
+            if let Some(file) = files.get(c as usize - '1' as usize) {
+                commands::dispatch(state, &format!("edit {}", file.display()));
+            }
+        }
+        return;
+    }
+
+    if state.picker.is_some() {
+        handle_picker_key(state, key);
+        return;
+    }
+
+    if state.calc.open {
+        handle_calc_key(state, key);
+        return;
+    }
+
+    if state.mode == AppMode::Command {
+        handle_command_line_key(state, key);
+        return;
+    }
+
+    if state.mode == AppMode::Insert {
+        handle_insert_key(state, key);
+        return;
+    }
+
+    if let Some((find, count)) = state.pending_find.take() {
+        let pending = state.operator.take();
+        if let KeyCode::Char(c) = key.code {
+            state.last_find = Some((find, c));
+            find_cell(state, find, c, count);
+            if let Some(pending) = pending {
+                operator::after_find(state, pending, find.vertical);
+                state.register = None;
+            }
+        }
+        return;
+    }
+
+    if let Some((macro_key, count)) = state.pending_macro.take() {
+        if let KeyCode::Char(c) = key.code {
+            match macro_key {
+                MacroKey::Record => record_macro(state, c),
+                MacroKey::Play => play_macro(state, c, count),
+            }
+        }
+        return;
+    }
+
+    if state.pending_register {
+        state.pending_register = false;
+        match key.code {
+            KeyCode::Char(c) if register::is_register_name(c) => state.register = Some(c),
+            _ => state.message = Some(Message::Error("Invalid register name".to_string())),
+        }
+        return;
+    }
+
+    if let Some(digit) = count_digit(state, key) {
+        state.count = Some(state.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+        return;
+    }
+
+    if let (Some(pending), true, KeyCode::Char(c)) = (state.operator, state.pending_keys.is_empty(), key.code) {
+        if operator::axis_key(state, pending, c) {
+            return;
+        }
+    }
+
+    state.pending_keys.push(key.into());
+    match state.keymap.lookup(state.mode, &state.pending_keys) {
+        Lookup::Action(action) => {
+            state.pending_keys.clear();
+            let count = state.count.take();
+            match state.operator.take() {
+                Some(pending) => operator::motion(state, pending, action, count),
+                None => perform(state, action, count),
+            }
+            // A register only applies to the command right after it, or to
+            // the operator and its motion
+            if action != Action::SelectRegister && state.operator.is_none() {
+                state.register = None;
+            }
+        }
+        Lookup::Pending => {}
+        Lookup::Unbound => {
+            state.pending_keys.clear();
+            state.count = None;
+            state.operator = None;
+        }
+    }
+}
+
+// Digits before a command are its count. A leading 0 is not part of a count
+// so it stays available as a key binding.
+fn count_digit(state: &AppState, key: KeyEvent) -> Option<u32> {
+    if !state.pending_keys.is_empty() || !key.modifiers.is_empty() {
+        return None;
+    }
+    match key.code {
+        KeyCode::Char(c @ '1'..='9') => c.to_digit(10),
+        KeyCode::Char('0') if state.count.is_some() => Some(0),
+        _ => None,
+    }
+}
+
+fn handle_calc_key(state: &mut AppState, key: KeyEvent) {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Enter => calc::submit(state),
+        KeyCode::Esc => calc::close(state),
+        KeyCode::Char('c') if ctrl => calc::close(state),
+        KeyCode::Up => state.calc.browse(true),
+        KeyCode::Down => state.calc.browse(false),
+        _ => {
+            edit_line(&mut state.calc.line, key);
+        }
+    }
+}
+
+fn handle_picker_key(state: &mut AppState, key: KeyEvent) {
+    let picker = match &mut state.picker {
+        Some(picker) => picker,
+        None => return,
+    };
+
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Down => picker.next(),
+        KeyCode::Char('n' | 'j') if ctrl => picker.next(),
+        KeyCode::Up => picker.previous(),
+        KeyCode::Char('p' | 'k') if ctrl => picker.previous(),
+        KeyCode::Char(c) if !ctrl => picker.push_query(c),
+        KeyCode::Backspace => picker.pop_query(),
+        KeyCode::Enter => {
+            if let Some(picker) = state.picker.take() {
+                pick(state, picker);
+            }
+        }
+        KeyCode::Esc => state.picker = None,
+        _ => {}
+    }
+}
+
+fn pick(state: &mut AppState, picker: Picker) {
+    let index = match picker.selected() {
+        Some(index) => index,
+        None => return,
+    };
+
+    match picker.kind {
+        PickerKind::Selection(selections) => {
+            let (mode, selection) = selections[index];
+            state.remember_visual();
+            state.table_content.selection = selection;
+            state.mode = mode;
+        }
+        PickerKind::Command => commands::run_from_palette(state, index),
+        PickerKind::File(files) | PickerKind::Directory(_, files) => {
+            commands::dispatch(state, &format!("edit {}", files[index].display()))
+        }
+    }
+}
+
+fn handle_command_line_key(state: &mut AppState, key: KeyEvent) {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let command_line = &mut state.command_line;
+    match key.code {
+        KeyCode::Enter => {
+            let prompt = command_line.prompt;
+            let line = command_line.submit();
+            leave_command_line(state);
+            match prompt {
+                Prompt::Command => commands::dispatch(state, &line),
+                Prompt::Search { forward, .. } => search(state, &line, forward),
+            }
+        }
+        KeyCode::Esc => {
+            command_line.line.clear();
+            leave_command_line(state);
+        }
+        // Deleting past the ':' leaves the command line like in vim
+        KeyCode::Backspace if command_line.line.text.is_empty() => leave_command_line(state),
+        KeyCode::Up => command_line.older(),
+        KeyCode::Char('p') if ctrl => command_line.older(),
+        KeyCode::Down => command_line.newer(),
+        KeyCode::Char('n') if ctrl => command_line.newer(),
+        _ => {
+            if edit_line(&mut command_line.line, key) {
+                command_line.stop_browsing();
+            }
+        }
+    }
+}
+
+// Esc keeps the changes like in vim, Ctrl-C throws them away
+fn handle_insert_key(state: &mut AppState, key: KeyEvent) {
+    if edit::point_key(state, key) || line_normal::key(state, key) || edit::entry_key(state, key.code) {
+        return;
+    }
+    let edit = match &mut state.edit {
+        Some(edit) => edit,
+        None => return,
+    };
+    match key.code {
+        KeyCode::Enter | KeyCode::Esc => edit::commit(state),
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => edit::cancel(state),
+        KeyCode::Char(c @ ('n' | 'p')) if key.modifiers.contains(KeyModifiers::CONTROL) => edit::complete(state, c == 'n'),
+        KeyCode::Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => edit::start_point(state),
+        KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => line_normal::start(state),
+        _ => {
+            edit.completion = None;
+            edit_line(&mut edit.line, key);
+        }
+    }
+}
+
+// Keys which work the same wherever text is typed. Returns whether the key
+// was one of them.
+fn edit_line(line: &mut LineBuffer, key: KeyEvent) -> bool {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Char('w') if ctrl => line.delete_word(),
+        KeyCode::Char('u') if ctrl => line.clear(),
+        KeyCode::Char(c) if !ctrl => line.insert(c),
+        KeyCode::Backspace => line.backspace(),
+        KeyCode::Delete => line.delete(),
+        KeyCode::Left => line.left(),
+        KeyCode::Right => line.right(),
+        KeyCode::Home => line.home(),
+        KeyCode::End => line.end(),
+        _ => return false,
+    }
+    true
+}
+
+// A search goes back to the mode it was started in, so / can extend a
+// visual selection
+fn leave_command_line(state: &mut AppState) {
+    match state.command_line.prompt {
+        Prompt::Search { mode, .. } => state.mode = mode,
+        Prompt::Command => {
+            state.mode = AppMode::Normal;
+            state.table_content.selection.set_single();
+        }
+    }
+}
+
+// Searches for a pattern typed after / or ?, an empty one repeats the last
+// search in that direction
+fn search(state: &mut AppState, pattern: &str, forward: bool) {
+    if !pattern.is_empty() {
+        match Search::new(pattern, forward) {
+            Ok(search) => state.search = Some(search),
+            Err(e) => {
+                state.message = Some(Message::Error(e.to_string()));
+                return;
+            }
+        }
+    } else if let Some(search) = &mut state.search {
+        search.forward = forward;
+    }
+    search_next(state, true, None);
+}
+
+// `count` is the number typed before the key(s), if any
+pub fn perform(state: &mut AppState, action: Action, count: Option<u32>) {
+    let selection = &mut state.table_content.selection;
+    let steps = count.unwrap_or(1).min(u16::MAX as u32) as u16;
+
+    match (state.mode, action) {
+        // Rows hidden by :filter are skipped
+        (AppMode::Normal | AppMode::Visual | AppMode::VisualRow, Action::MoveDown | Action::MoveUp) => {
+            let (row, col) = selection.cursor();
+            let row = state.table_content.step_rows(row, steps, action == Action::MoveDown);
+            move_to(state, (row, col));
+        }
+        // Columns hidden by :hide as well
+        (AppMode::Normal | AppMode::Visual | AppMode::VisualColumn, Action::MoveRight | Action::MoveLeft) => {
+            let (row, col) = selection.cursor();
+            let col = state.table_content.step_cols(col, steps, action == Action::MoveRight);
+            move_to(state, (row, col));
+        }
+        (AppMode::VisualRow, Action::MoveRight | Action::MoveLeft) => {}
+        (AppMode::VisualColumn, Action::MoveDown | Action::MoveUp) => {}
+
+        (_, Action::GotoRow { last }) => {
+            let (_, col) = selection.cursor();
+            let row = match count {
+                Some(n) => (n.max(1) - 1).min(u16::MAX as u32) as u16,
+                None if last => state.table_content.used_rows() - 1,
+                None => 0,
+            };
+            move_to(state, (row, col));
+        }
+        (_, Action::GotoCol { last }) => {
+            let (row, _) = selection.cursor();
+            let content = &state.table_content;
+            // An empty row ends where the table does
+            let col = if last {
+                content.cells_in((row, 0, row, u16::MAX)).last().map_or(content.used_cols() - 1, |((_, col), _)| col)
+            } else {
+                0
+            };
+            move_to(state, (row, col));
+        }
+        (_, Action::NextBlock { forward, vertical }) => {
+            let (row, col) = selection.cursor();
+            if let Some(to) = next_block(&state.table_content, forward, vertical, count) {
+                move_to(state, if vertical { (to, col) } else { (row, to) });
+            }
+        }
+
+        // o goes to the diagonally opposite corner, O to the other corner in
+        // the same row. Only one of them exists for whole rows/columns.
+        (AppMode::Visual, Action::SwapCorner) => {
+            selection.swap_rows_corner();
+            selection.swap_cols_corner();
+        }
+        (AppMode::Visual, Action::SwapCornerHorizontal) => selection.swap_cols_corner(),
+        (AppMode::VisualRow, Action::SwapCorner | Action::SwapCornerHorizontal) => selection.swap_rows_corner(),
+        (AppMode::VisualColumn, Action::SwapCorner | Action::SwapCornerHorizontal) => selection.swap_cols_corner(),
+        (AppMode::Normal, Action::SwapCorner | Action::SwapCornerHorizontal) => {}
+
+        (_, Action::SelectAll) => select_all(state),
+        (_, Action::SelectDataRegion) => {
+            let (row, col) = selection.cursor();
+            let (top, left, bottom, right) = state.table_content.data_region(row, col);
+            state.table_content.selection.span((top, left), (bottom, right));
+            state.mode = AppMode::Visual;
+        }
+
+        (AppMode::Command | AppMode::Insert, _) => {}
+
+        (_, Action::ScrollHalfPage { down }) => {
+            // Like vim's scroll option a count changes the distance
+            let half_page = (state.viewport.visible_rows(&state.table_content).len() / 2).max(1) as u32;
+            let rows = count.unwrap_or(half_page).min(u16::MAX as u32) as i32;
+            scroll(state, if down { rows } else { -rows }, true);
+        }
+        (_, Action::ScrollLine { down }) => {
+            let rows = steps as i32;
+            scroll(state, if down { rows } else { -rows }, false);
+        }
+        (_, Action::SelectRegister) => state.pending_register = true,
+        (_, Action::Yank) => register::yank(state, false),
+        (_, Action::YankValues) => register::yank(state, true),
+        (_, Action::Delete) => register::delete(state),
+        (_, Action::Put { before }) => register::put(state, before),
+        (_, Action::Fill) => fill::fill_selection(state),
+        (_, Action::InsertRows { below }) => {
+            let (row, _) = selection.cursor();
+            if let Err(e) = structure::insert(state, Axis::Rows, if below { row.saturating_add(1) } else { row }, steps) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        }
+        (_, Action::InsertCols { right }) => {
+            let (_, col) = selection.cursor();
+            if let Err(e) = structure::insert(state, Axis::Cols, if right { col.saturating_add(1) } else { col }, steps) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        }
+        (_, Action::DeleteRows) => {
+            let (row, _) = selection.cursor();
+            register::delete_lines(state, Axis::Rows, row, steps);
+        }
+        (_, Action::DeleteCols) => {
+            let (_, col) = selection.cursor();
+            register::delete_lines(state, Axis::Cols, col, steps);
+        }
+        (_, Action::ResizeCol { grow }) => {
+            let (left, right) = (selection.col, selection.right());
+            let right = right.min(state.table_content.used_cols() - 1).max(left);
+            let delta = if grow { steps as i32 } else { -(steps as i32) };
+            if let Err(e) = structure::resize_cols(state, left, right, delta) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        }
+        (_, Action::Trace { dependents }) => {
+            if let Err(e) = commands::trace(state, dependents) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        }
+        (_, Action::NextChange { forward }) => {
+            if diff::current(state).is_some() {
+                diff::next(state, forward, count)
+            } else {
+                merge::next(state, forward, count)
+            }
+        }
+        (_, Action::NextSheet { forward }) => sheet::next(state, forward, count),
+        (_, Action::SplitWindow { vertical }) => window::split(state, vertical),
+        (_, Action::FocusWindow { forward, vertical }) => window::focus_towards(state, forward, vertical),
+        (_, Action::NextWindow) => window::next(state, count),
+        (_, Action::CloseWindow) => {
+            if let Err(e) = window::close(state) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        }
+        (_, Action::OnlyWindow) => window::only(state),
+        (_, Action::Undo) => undo::undo(state, count.unwrap_or(1)),
+        (_, Action::Redo) => undo::redo(state, count.unwrap_or(1)),
+        (_, Action::EnterInsert) => edit::start_insert(state, false),
+        (_, Action::ChangeCell) => edit::start_insert(state, true),
+        (AppMode::Normal, Action::Operator(operator)) => operator::start(state, operator, count),
+        (_, Action::Operator(_)) => {}
+
+        (_, Action::Find(find)) => state.pending_find = Some((find, count)),
+        (_, Action::RepeatFind) => {
+            if let Some((find, c)) = state.last_find {
+                find_cell(state, find, c, count);
+            }
+        }
+        (_, Action::SearchCell { forward }) => {
+            let (row, col) = selection.cursor();
+            formula::refresh_all(&mut state.table_content);
+            let pattern = state.table_content.display(row, col);
+            if pattern.is_empty() {
+                state.message = Some(Message::Error("No value under cursor".to_string()));
+                return;
+            }
+            match Search::literal(&pattern, forward, state.options.wholecell) {
+                Ok(search) => state.search = Some(search),
+                Err(e) => {
+                    state.message = Some(Message::Error(e.to_string()));
+                    return;
+                }
+            }
+            search_next(state, true, count);
+        }
+        (_, Action::SearchNext) => search_next(state, true, count),
+        (_, Action::SearchPrevious) => search_next(state, false, count),
+        (_, Action::RepeatFindReverse) => {
+            if let Some((find, c)) = state.last_find {
+                find_cell(state, Find { forward: !find.forward, ..find }, c, count);
+            }
+        }
+
+        (_, Action::EnterVisual) => enter_visual(state, AppMode::Visual),
+        (_, Action::EnterVisualRow) => enter_visual(state, AppMode::VisualRow),
+        (_, Action::EnterVisualColumn) => enter_visual(state, AppMode::VisualColumn),
+        (_, Action::ExitVisual) => exit_visual(state),
+        (_, Action::RestoreVisual) => {
+            if let Some((mode, last)) = state.last_visual {
+                state.remember_visual();
+                state.table_content.selection = last;
+                state.mode = mode;
+            }
+        }
+        (_, Action::CommandPalette) => commands::open_palette(state),
+        (_, Action::StartSearch { forward }) => {
+            state.command_line.start_search(forward, state.mode);
+            state.mode = AppMode::Command;
+        }
+        (_, Action::EnterCommandLine) => {
+            if state.mode.is_visual() {
+                state.remember_visual();
+                state.command_line.start("'<,'>");
+            } else {
+                state.command_line.start("");
+            }
+            state.mode = AppMode::Command;
+        }
+        (_, Action::RecordMacro) => match state.recording.take() {
+            Some((register, keys)) => state.registers.set_macro(register, keys),
+            None => state.pending_macro = Some((MacroKey::Record, None)),
+        },
+        (_, Action::PlayMacro) => state.pending_macro = Some((MacroKey::Play, count)),
+    }
+}
+
+fn record_macro(state: &mut AppState, register: char) {
+    if register.is_ascii_lowercase() {
+        state.recording = Some((register, Vec::new()));
+    } else {
+        state.message = Some(Message::Error("Invalid register name".to_string()));
+    }
+}
+
+// Feeds the keys of a macro through handle_key as if they were typed, `count`
+// times. Stops at the first error like vim, e.g. when a search fails.
+fn play_macro(state: &mut AppState, register: char, count: Option<u32>) {
+    let register = match register {
+        '@' => match state.registers.last_macro {
+            Some(register) => register,
+            None => {
+                state.message = Some(Message::Error("No previously used macro".to_string()));
+                return;
+            }
+        },
+        register => register,
+    };
+    let keys: Vec<KeyPress> = match state.registers.get_macro(register) {
+        Some(keys) => keys.clone(),
+        None => {
+            state.message = Some(Message::Error(format!("Register @{} is empty", register)));
+            return;
+        }
+    };
+    // A macro which plays itself would never end
+    if state.playing.contains(&register) {
+        state.message = Some(Message::Error(format!("Macro @{} plays itself", register)));
+        return;
+    }
+    state.registers.last_macro = Some(register);
+    state.playing.push(register);
+    // The keys after a command like :e work on what it read
+    task::blocking(state, |state| {
+        'repeat: for _ in 0..count.unwrap_or(1) {
+            for key in &keys {
+                handle_key(state, KeyEvent::new(key.code, key.modifiers));
+                if state.quit || matches!(state.message, Some(Message::Error(_))) {
+                    break 'repeat;
+                }
+            }
+        }
+    });
+    state.playing.pop();
+}
+
+// The keys of :normal, typed like those of a macro. A mode they leave open,
+// like Insert after A, is ended as with Esc.
+pub fn type_keys(state: &mut AppState, keys: &[KeyPress]) -> Result<()> {
+    task::blocking(state, |state| {
+        for key in keys {
+            handle_key(state, KeyEvent::new(key.code, key.modifiers));
+            if let Some(Message::Error(e)) = &state.message {
+                return Err(VispError::Command(e.clone()));
+            }
+            if state.quit {
+                return Ok(());
+            }
+        }
+        if state.mode != AppMode::Normal {
+            handle_key(state, KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE));
+        }
+        Ok(())
+    })
+}
+
+// Goes to the next match of the last search, `same_direction` is false for N
+fn search_next(state: &mut AppState, same_direction: bool, count: Option<u32>) {
+    formula::refresh_all(&mut state.table_content);
+    let search = match &state.search {
+        Some(search) => search,
+        None => {
+            state.message = Some(Message::Error("No previous search".to_string()));
+            return;
+        }
+    };
+    let forward = search.forward == same_direction;
+    let mut position = state.table_content.selection.cursor();
+    for _ in 0..count.unwrap_or(1).max(1) {
+        match search.next(&state.table_content, position, forward) {
+            Some(next) => position = next,
+            None => {
+                state.message = Some(Message::Error(format!("Pattern not found: {}", search.pattern)));
+                return;
+            }
+        }
+    }
+
+    move_to(state, position);
+}
+
+// Moves the cursor, or extends the selection as far as the visual mode allows
+fn move_to(state: &mut AppState, (row, col): (u16, u16)) {
+    let row = state.table_content.visible_row(row);
+    let selection = &mut state.table_content.selection;
+    if state.mode.is_visual() {
+        if state.mode != AppMode::VisualColumn {
+            selection.extend_to_row(row);
+        }
+        if state.mode != AppMode::VisualRow {
+            selection.extend_to_col(col);
+        }
+    } else {
+        selection.set_cursor(row, col);
+    }
+}
+
+// The first cell of the count-th block of non-empty cells after or before the
+// cursor in its row, or its column if `vertical`. Stops at the last block
+// there is, like w and b do at the end of the text.
+fn next_block(content: &TableContent, forward: bool, vertical: bool, count: Option<u32>) -> Option<u16> {
+    let (row, col) = content.selection.cursor();
+    let (position, cells): (u16, Vec<u16>) = if vertical {
+        (row, content.cells_in((0, col, u16::MAX, col)).map(|((row, _), _)| row).collect())
+    } else {
+        (col, content.cells_in((row, 0, row, u16::MAX)).map(|((_, col), _)| col).collect())
+    };
+    let starts = cells.iter().enumerate()
+        .filter(|&(i, &cell)| i == 0 || cells[i - 1] + 1 != cell)
+        .map(|(_, &cell)| cell);
+    let count = count.unwrap_or(1).max(1) as usize;
+    if forward {
+        starts.filter(|&start| start > position).take(count).last()
+    } else {
+        let before: Vec<u16> = starts.filter(|&start| start < position).collect();
+        before.get(before.len().saturating_sub(count)).copied()
+    }
+}
+
+// Moves the cursor to the count-th cell in its row or column whose text
+// starts with `c`. Hidden rows and columns are skipped.
+fn find_cell(state: &mut AppState, find: Find, c: char, count: Option<u32>) {
+    formula::refresh_all(&mut state.table_content);
+    let content = &state.table_content;
+    let (row, col) = content.selection.cursor();
+    let (position, end) = if find.vertical { (row, content.used_rows()) } else { (col, content.used_cols()) };
+    let shown = |i: u16| if find.vertical { content.row_height(i) > 0 } else { content.col_width(i) > 0 };
+    let candidates: Vec<u16> = if find.forward {
+        (position.saturating_add(1)..end).filter(|&i| shown(i)).collect()
+    } else {
+        (0..position).rev().filter(|&i| shown(i)).collect()
+    };
+
+    let starts_with = |i: u16| {
+        let text = if find.vertical { content.display(i, col) } else { content.display(row, i) };
+        text.starts_with(c)
+    };
+    let nth = count.unwrap_or(1).max(1) as usize - 1;
+    let found = match candidates.iter().enumerate().filter(|&(_, &i)| starts_with(i)).nth(nth) {
+        Some((index, _)) => index,
+        None => return,
+    };
+    // Till stops at the shown cell before the match
+    let target = match (find.till, found) {
+        (false, _) => candidates[found],
+        (true, 0) => position,
+        (true, _) => candidates[found - 1],
+    };
+
+    let selection = &mut state.table_content.selection;
+    match (state.mode, find.vertical) {
+        (AppMode::Normal, false) => selection.col = target,
+        (AppMode::Normal, true) => selection.row = target,
+        (AppMode::Visual | AppMode::VisualColumn, false) => selection.extend_to_col(target),
+        (AppMode::Visual | AppMode::VisualRow, true) => selection.extend_to_row(target),
+        _ => {}
+    }
+}
+
+// Scrolls the table by `rows` and moves the cursor by the same amount if
+// `move_cursor` is set, otherwise only as far as needed to keep it on screen
+fn scroll(state: &mut AppState, rows: i32, move_cursor: bool) {
+    let content = &mut state.table_content;
+    state.viewport.scroll_rows(rows);
+    let cursor = content.selection.cursor().0;
+    let visible = state.viewport.visible_rows(content);
+    let target = if move_cursor {
+        (cursor as i32 + rows).clamp(0, u16::MAX as i32) as u16
+    } else if cursor < state.viewport.frozen_rows {
+        cursor
+    } else {
+        cursor.clamp(visible.start, visible.end.saturating_sub(1).max(visible.start))
+    };
+
+    let selection = &mut content.selection;
+    match state.mode {
+        AppMode::Normal => selection.row = target,
+        AppMode::Visual | AppMode::VisualRow => selection.extend_to_row(target),
+        _ => {}
+    }
+}
+
+// Switches between the visual modes, pressing the key of the current mode
+// again leaves visual mode like in vim
+fn enter_visual(state: &mut AppState, mode: AppMode) {
+    if state.mode == mode {
+        exit_visual(state);
+        return;
+    }
+
+    let selection = &mut state.table_content.selection;
+
+    match state.mode {
+        AppMode::VisualRow => selection.cols = 1,
+        AppMode::VisualColumn => selection.rows = 1,
+        _ => {}
+    }
+    match mode {
+        AppMode::VisualRow => selection.whole_rows(),
+        AppMode::VisualColumn => selection.whole_cols(),
+        _ => {}
+    }
+    state.mode = mode;
+}
+
+fn exit_visual(state: &mut AppState) {
+    state.remember_visual();
+    state.mode = AppMode::Normal;
+    state.table_content.selection.set_single();
+}
+
+// Selects everything which is in use
+fn select_all(state: &mut AppState) {
+    let bottom = state.table_content.used_rows() - 1;
+    let right = state.table_content.used_cols() - 1;
+    state.table_content.selection.span((0, 0), (bottom, right));
+    state.mode = AppMode::Visual;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv;
+    use crate::grid::Selection;
+
+    fn mouse(kind: MouseEventKind, column: u16) -> MouseEvent {
+        MouseEvent { kind, column, row: 0, modifiers: KeyModifiers::NONE }
+    }
+
+    // Drags the right border of column B by `delta` characters
+    fn drag_border(state: &mut AppState, delta: u16) {
+        let content = &state.table_content;
+        let border = state.viewport.col_x(content, 1).unwrap() + content.col_width(1) - 1;
+        handle_mouse(state, mouse(MouseEventKind::Down(MouseButton::Left), border));
+        handle_mouse(state, mouse(MouseEventKind::Drag(MouseButton::Left), border + 1));
+        handle_mouse(state, mouse(MouseEventKind::Up(MouseButton::Left), border + delta));
+    }
+
+    #[test]
+    fn drag_resize_is_one_change() {
+        let mut state = AppState::new(TableContent::from_rows(vec![csv::parse_record("a,b", ',', None)]));
+        state.viewport.area = tui::layout::Rect::new(0, 0, 80, 24);
+        let width = state.table_content.col_width(1);
+        drag_border(&mut state, 4);
+        assert_eq!(state.table_content.col_width(1), width + 4);
+        undo::undo(&mut state, 1);
+        assert!(state.table_content.col_widths.is_empty());
+    }
+
+    #[test]
+    fn drag_resize_protected() {
+        let mut state = AppState::new(TableContent::from_rows(vec![csv::parse_record("a,b", ',', None)]));
+        state.viewport.area = tui::layout::Rect::new(0, 0, 80, 24);
+        state.table_content.protected.push(Selection { row: 0, col: 1, ..Selection::default() });
+        state.options.set("protect").unwrap();
+        drag_border(&mut state, 4);
+        assert!(state.table_content.col_widths.is_empty());
+        assert!(matches!(state.message, Some(Message::Error(_))));
+    }
+}
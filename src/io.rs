@@ -0,0 +1,212 @@
+use std::{io, panic, thread, ops::{Deref, DerefMut}, time::{Duration, Instant}};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use tui::{
+    backend::CrosstermBackend,
+    layout::Rect,
+    Terminal
+};
+use crossterm::{
+    cursor::Show,
+    event::{DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture, Event},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+use crate::{AppState, Result, autosave, commands, fetch, formula, input, lock, render, share, stream, swap, task};
+use crate::grid::TableCell;
+
+pub type VispTerminal = Terminal<CrosstermBackend<io::Stdout>>;
+
+// Owns the terminal and puts it back into its normal state when dropped,
+// no matter if we leave through an error or a panic
+pub struct TerminalGuard {
+    terminal: VispTerminal,
+}
+
+impl Deref for TerminalGuard {
+    type Target = VispTerminal;
+
+    fn deref(&self) -> &VispTerminal {
+        &self.terminal
+    }
+}
+
+impl DerefMut for TerminalGuard {
+    fn deref_mut(&mut self) -> &mut VispTerminal {
+        &mut self.terminal
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = reset_terminal();
+    }
+}
+
+pub fn setup_terminal() -> Result<TerminalGuard> {
+    install_panic_hook();
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    // Focus changes are for autosave=focus
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
+    let backend = CrosstermBackend::new(stdout);
+    Ok(TerminalGuard { terminal: Terminal::new(backend)? })
+}
+
+fn reset_terminal() -> Result<()> {
+    disable_raw_mode()?;
+    execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableFocusChange,
+        Show
+    )?;
+    Ok(())
+}
+
+// The default hook prints the panic message, which is unreadable (and leaves
+// the shell unusable) while we are still in raw mode on the alternate screen
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = reset_terminal();
+        default_hook(info);
+    }));
+}
+
+// Everything the main loop waits for
+pub enum AppEvent {
+    Input(Event),
+    InputError(io::Error),
+    StreamLine(String), // A row read from stdin with --stream
+    StreamClosed,
+    Tick, // Every second, for work which is done in the background
+    TaskProgress, // Redraws the status of a task, see task.rs
+    TaskDone(u64, task::Finish),
+    Share(share::ShareEvent), // From the other visp editing a sheet with us
+    Fetched(String, String, Result<Vec<Vec<TableCell>>>), // Sheet, command and what it printed, see fetch.rs
+}
+
+// Terminal input is read on its own thread so the main loop can block on a
+// single channel which other event sources can feed as well
+pub fn spawn_input_reader(sender: Sender<AppEvent>) {
+    thread::spawn(move || loop {
+        let event = match crossterm::event::read() {
+            Ok(event) => AppEvent::Input(event),
+            Err(e) => AppEvent::InputError(e),
+        };
+        if sender.send(event).is_err() {
+            break;
+        }
+    });
+}
+
+// Sends a Tick every second until the main loop is gone
+fn spawn_ticker(sender: Sender<AppEvent>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if sender.send(AppEvent::Tick).is_err() {
+            break;
+        }
+    });
+}
+
+// Shortest time between two draws, input in between is handled without drawing
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
+// With `stream_stdin` rows read from stdin are appended to the table. The
+// sender is also in state.tasks from before, so the files given to visp are
+// read in the background as well.
+pub fn run(terminal: &mut VispTerminal, state: &mut AppState, sender: Sender<AppEvent>, receiver: Receiver<AppEvent>, stream_stdin: bool) -> Result<()> {
+    if stream_stdin {
+        stream::spawn_stdin_reader(sender.clone());
+    }
+    spawn_ticker(sender.clone());
+    spawn_input_reader(sender);
+
+    let mut redraw = true;
+    let mut last_draw = Instant::now();
+    while !state.quit {
+        if let Some(server) = &mut state.server {
+            formula::refresh_all(&mut state.table_content);
+            server.update(&state.table_content);
+        }
+        share::update(state);
+        if redraw {
+            let start = Instant::now();
+            terminal.draw(|f| render::ui(f, state))?;
+            last_draw = Instant::now();
+            state.profiler.record("draw", last_draw - start);
+        }
+
+        let event = match receiver.recv() {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+        redraw = handle_app_event(terminal, state, event)?;
+
+        // Handle the input which arrives until the next frame is due, e.g.
+        // from a held key, before drawing again
+        let next_frame = last_draw + FRAME_TIME;
+        while !state.quit {
+            let timeout = next_frame.saturating_duration_since(Instant::now());
+            let event = match receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            redraw |= handle_app_event(terminal, state, event)?;
+        }
+    }
+    swap::remove_all(state);
+    lock::release_all(state);
+    Ok(())
+}
+
+// Returns whether the screen needs to be drawn again
+fn handle_app_event(terminal: &mut VispTerminal, state: &mut AppState, event: AppEvent) -> Result<bool> {
+    match event {
+        AppEvent::Input(event) => {
+            if let Event::Resize(width, height) = event {
+                // Drop whatever is left in the buffers from the old size,
+                // the next draw lays out the whole ui again
+                terminal.resize(Rect::new(0, 0, width, height))?;
+            }
+            let start = Instant::now();
+            let changed = input::handle_event(state, event);
+            state.profiler.record("input", start.elapsed());
+            Ok(changed)
+        }
+        AppEvent::InputError(e) => Err(e.into()),
+        AppEvent::StreamLine(line) => {
+            stream::append_line(state, &line);
+            Ok(true)
+        }
+        AppEvent::StreamClosed => {
+            tracing::info!("end of input stream");
+            Ok(false)
+        }
+        AppEvent::Tick => {
+            // Files which were written need no swap file
+            let saved = autosave::tick(state);
+            lock::update(state);
+            let listed = commands::refresh_directory(state);
+            fetch::tick(state);
+            Ok(swap::update(state) | saved | listed)
+        }
+        AppEvent::TaskProgress => Ok(state.tasks.running.is_some()),
+        AppEvent::TaskDone(id, finish) => {
+            task::done(state, id, finish);
+            Ok(true)
+        }
+        AppEvent::Share(event) => {
+            share::handle(state, event);
+            Ok(true)
+        }
+        AppEvent::Fetched(sheet, command, rows) => {
+            fetch::fetched(state, &sheet, &command, rows);
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::AppMode;
+
+// Everything a key can be bound to
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    GotoRow { last: bool }, // gg and G, the row given as count otherwise
+    GotoCol { last: bool }, // 0 and $, the last column with data in the row
+    NextBlock { forward: bool, vertical: bool }, // Start of the next block of non-empty cells
+    EnterVisual,
+    EnterVisualRow,
+    EnterVisualColumn,
+    ExitVisual,
+    RestoreVisual,
+    SwapCorner,
+    SwapCornerHorizontal,
+    SelectDataRegion,
+    SelectAll,
+    Find(Find),
+    RepeatFind,
+    RepeatFindReverse,
+    SearchCell { forward: bool },
+    StartSearch { forward: bool }, // Type a pattern after / or ?
+    SearchNext,
+    SearchPrevious,
+    ScrollHalfPage { down: bool }, // Cursor moves along
+    ScrollLine { down: bool }, // Cursor stays unless it would leave the screen
+    SelectRegister,
+    Yank,
+    YankValues, // Formulas as what they evaluate to
+    Delete,
+    Put { before: bool },
+    Fill, // Down, or right in a single row
+    Undo,
+    Redo,
+    EnterInsert,
+    ChangeCell, // Insert mode with the cell emptied
+    Operator(Operator), // Works on the cells the motion typed next goes over
+    InsertRows { below: bool },
+    InsertCols { right: bool },
+    DeleteRows,
+    DeleteCols,
+    ResizeCol { grow: bool }, // Every selected column
+    Trace { dependents: bool }, // Like :precedents and :dependents
+    NextChange { forward: bool }, // Conflict of visp --merge or difference of visp --diff
+    NextSheet { forward: bool },
+    SplitWindow { vertical: bool },
+    FocusWindow { forward: bool, vertical: bool }, // The nearest window that way
+    NextWindow, // Or the window given as count
+    CloseWindow,
+    OnlyWindow, // Closes the other windows
+    RecordMacro, // Starts recording into a register typed next, or stops
+    PlayMacro, // Plays the register typed next
+    EnterCommandLine,
+    CommandPalette,
+}
+
+// Jump to the next cell starting with a character typed after the key, like
+// vim's f, F, t and T. Vertical ones search the column instead of the row.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Find {
+    pub forward: bool,
+    pub till: bool, // Stop one cell before the match
+    pub vertical: bool,
+}
+
+// d, y and c in Normal mode, see operator.rs
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Change,
+}
+
+// Waits for a register name after q or @, like Find waits for a character
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MacroKey {
+    Record,
+    Play,
+}
+
+// A single key with its modifiers
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct KeyPress {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyPress {
+    // Shift is already part of the character for printable keys ('J' vs 'j'),
+    // so it is ignored there. Terminals are not consistent about reporting it.
+    pub fn new(code: KeyCode, mut modifiers: KeyModifiers) -> Self {
+        if let KeyCode::Char(_) = code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        Self { code, modifiers }
+    }
+}
+
+impl From<KeyCode> for KeyPress {
+    fn from(code: KeyCode) -> Self {
+        Self::new(code, KeyModifiers::NONE)
+    }
+}
+
+impl From<KeyEvent> for KeyPress {
+    fn from(key: KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+}
+
+pub enum Lookup {
+    Action(Action),
+    Pending, // The keys are the start of a longer binding
+    Unbound,
+}
+
+pub struct Keymap {
+    pub name: &'static str, // Preset the bindings started from
+    bindings: HashMap<(AppMode, Vec<KeyPress>), Action>,
+}
+
+// Names for :keymap
+pub const PRESETS: &[&str] = &["qwerty", "colemak", "wasd"];
+
+impl Keymap {
+    pub fn empty() -> Self {
+        Self {
+            name: "empty",
+            bindings: HashMap::new(),
+        }
+    }
+
+    // One of PRESETS. The default bindings are made for hjkl on qwerty, colemak
+    // moves them to hnei and wasd to the left hand.
+    pub fn preset(name: &str) -> Option<Self> {
+        let mut keymap = Self::default();
+        match name {
+            "qwerty" => {}
+            "colemak" => {
+                keymap.name = "colemak";
+                // The keys on the home row take the place of jkl, and the
+                // keys they replace move to where the old ones were
+                keymap.swap_keys('j', 'n');
+                keymap.swap_keys('J', 'N');
+                keymap.swap_keys('k', 'e');
+                keymap.swap_keys('K', 'E');
+                keymap.swap_keys('l', 'i');
+                keymap.swap_keys('L', 'I');
+            }
+            "wasd" => {
+                keymap.name = "wasd";
+                // Like with colemak the keys trade places, so nothing is
+                // lost: w a s d move and k h j l take over what they did.
+                // Insert is on h, change cell on j, the delete operator on l,
+                // the w motion is on k and Ctrl-W w and gd follow to Ctrl-W k
+                // and gl.
+                keymap.swap_keys('w', 'k');
+                keymap.swap_keys('W', 'K');
+                keymap.swap_keys('a', 'h');
+                keymap.swap_keys('A', 'H');
+                keymap.swap_keys('s', 'j');
+                keymap.swap_keys('S', 'J');
+                keymap.swap_keys('d', 'l');
+                keymap.swap_keys('D', 'L');
+            }
+            _ => return None,
+        }
+        Some(keymap)
+    }
+
+    // Exchanges two characters in all bindings. Keys with Ctrl or Alt stay
+    // where they are, so Ctrl-W is still Ctrl-W after w and k trade places.
+    fn swap_keys(&mut self, a: char, b: char) {
+        let swap = |key: KeyPress| match key.code {
+            _ if !key.modifiers.is_empty() => key,
+            KeyCode::Char(c) if c == a => KeyPress { code: KeyCode::Char(b), ..key },
+            KeyCode::Char(c) if c == b => KeyPress { code: KeyCode::Char(a), ..key },
+            _ => key,
+        };
+        self.bindings = self.bindings.drain()
+            .map(|((mode, keys), action)| ((mode, keys.into_iter().map(swap).collect()), action))
+            .collect();
+    }
+
+    pub fn bind(&mut self, mode: AppMode, keys: &[KeyPress], action: Action) {
+        self.bindings.insert((mode, keys.to_vec()), action);
+    }
+
+    pub fn unbind(&mut self, mode: AppMode, keys: &[KeyPress]) {
+        self.bindings.remove(&(mode, keys.to_vec()));
+    }
+
+    pub fn lookup(&self, mode: AppMode, keys: &[KeyPress]) -> Lookup {
+        if let Some(action) = self.bindings.get(&(mode, keys.to_vec())) {
+            return Lookup::Action(*action);
+        }
+        let is_prefix = self.bindings.keys().any(|(m, sequence)| {
+            *m == mode && sequence.len() > keys.len() && sequence.starts_with(keys)
+        });
+        if is_prefix {
+            Lookup::Pending
+        } else {
+            Lookup::Unbound
+        }
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        use Action::*;
+
+        let mut keymap = Self::empty();
+        keymap.name = "qwerty";
+        for mode in [AppMode::Normal, AppMode::Visual, AppMode::VisualRow, AppMode::VisualColumn] {
+            let mut bind = |code: KeyCode, action| keymap.bind(mode, &[code.into()], action);
+            bind(KeyCode::Char('j'), MoveDown);
+            bind(KeyCode::Char('k'), MoveUp);
+            bind(KeyCode::Char('l'), MoveRight);
+            bind(KeyCode::Char('h'), MoveLeft);
+            bind(KeyCode::Down, MoveDown);
+            bind(KeyCode::Up, MoveUp);
+            bind(KeyCode::Right, MoveRight);
+            bind(KeyCode::Left, MoveLeft);
+            bind(KeyCode::Char('v'), EnterVisual);
+            bind(KeyCode::Char('V'), EnterVisualRow);
+            bind(KeyCode::Esc, ExitVisual);
+            bind(KeyCode::Char(':'), EnterCommandLine);
+            let ctrl = |c| KeyPress::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+            keymap.bind(mode, &[ctrl('v')], EnterVisualColumn);
+            keymap.bind(mode, &[ctrl('a')], SelectAll);
+            keymap.bind(mode, &[ctrl('p')], CommandPalette);
+            keymap.bind(mode, &[ctrl('d')], ScrollHalfPage { down: true });
+            keymap.bind(mode, &[ctrl('u')], ScrollHalfPage { down: false });
+            keymap.bind(mode, &[ctrl('e')], ScrollLine { down: true });
+            keymap.bind(mode, &[ctrl('y')], ScrollLine { down: false });
+            // Like vim's windows
+            let mut window = |c, action| keymap.bind(mode, &[ctrl('w'), KeyCode::Char(c).into()], action);
+            window('s', SplitWindow { vertical: false });
+            window('v', SplitWindow { vertical: true });
+            window('h', FocusWindow { forward: false, vertical: false });
+            window('j', FocusWindow { forward: true, vertical: true });
+            window('k', FocusWindow { forward: false, vertical: true });
+            window('l', FocusWindow { forward: true, vertical: false });
+            window('w', NextWindow);
+            window('c', CloseWindow);
+            window('q', CloseWindow);
+            window('o', OnlyWindow);
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('v').into()], RestoreVisual);
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('g').into()], GotoRow { last: false });
+            keymap.bind(mode, &[KeyCode::Char('G').into()], GotoRow { last: true });
+            keymap.bind(mode, &[KeyCode::Char('0').into()], GotoCol { last: false });
+            keymap.bind(mode, &[KeyCode::Char('$').into()], GotoCol { last: true });
+            // Like words in vim, gw and gb go down and up the column
+            keymap.bind(mode, &[KeyCode::Char('w').into()], NextBlock { forward: true, vertical: false });
+            keymap.bind(mode, &[KeyCode::Char('b').into()], NextBlock { forward: false, vertical: false });
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('w').into()], NextBlock { forward: true, vertical: true });
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('b').into()], NextBlock { forward: false, vertical: true });
+
+            let find = |forward, till, vertical| Find(self::Find { forward, till, vertical });
+            keymap.bind(mode, &[KeyCode::Char('f').into()], find(true, false, false));
+            keymap.bind(mode, &[KeyCode::Char('F').into()], find(false, false, false));
+            keymap.bind(mode, &[KeyCode::Char('t').into()], find(true, true, false));
+            keymap.bind(mode, &[KeyCode::Char('T').into()], find(false, true, false));
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('f').into()], find(true, false, true));
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('F').into()], find(false, false, true));
+            keymap.bind(mode, &[KeyCode::Char(';').into()], RepeatFind);
+            keymap.bind(mode, &[KeyCode::Char(',').into()], RepeatFindReverse);
+            keymap.bind(mode, &[KeyCode::Char('*').into()], SearchCell { forward: true });
+            keymap.bind(mode, &[KeyCode::Char('#').into()], SearchCell { forward: false });
+            keymap.bind(mode, &[KeyCode::Char('/').into()], StartSearch { forward: true });
+            keymap.bind(mode, &[KeyCode::Char('?').into()], StartSearch { forward: false });
+            keymap.bind(mode, &[KeyCode::Char('n').into()], SearchNext);
+            keymap.bind(mode, &[KeyCode::Char('N').into()], SearchPrevious);
+            keymap.bind(mode, &[KeyCode::Char('"').into()], SelectRegister);
+            keymap.bind(mode, &[KeyCode::Char('p').into()], Put { before: false });
+            keymap.bind(mode, &[KeyCode::Char('P').into()], Put { before: true });
+            keymap.bind(mode, &[KeyCode::Char('>').into()], ResizeCol { grow: true });
+            keymap.bind(mode, &[KeyCode::Char('<').into()], ResizeCol { grow: false });
+        }
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('x').into()], Delete);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('u').into()], Undo);
+        keymap.bind(AppMode::Normal, &[KeyPress::new(KeyCode::Char('r'), KeyModifiers::CONTROL)], Redo);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('i').into()], EnterInsert);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('a').into()], EnterInsert);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('s').into()], ChangeCell);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('d').into()], Operator(self::Operator::Delete));
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('y').into()], Operator(self::Operator::Yank));
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('c').into()], Operator(self::Operator::Change));
+        // Like gf, the g variants work on the other axis
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('o').into()], InsertRows { below: true });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('O').into()], InsertRows { below: false });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('o').into()], InsertCols { right: true });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('O').into()], InsertCols { right: false });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('t').into()], NextSheet { forward: true });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('T').into()], NextSheet { forward: false });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('<').into()], Trace { dependents: false });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('g').into(), KeyCode::Char('>').into()], Trace { dependents: true });
+        // Like vim's diff mode
+        keymap.bind(AppMode::Normal, &[KeyCode::Char(']').into(), KeyCode::Char('c').into()], NextChange { forward: true });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('[').into(), KeyCode::Char('c').into()], NextChange { forward: false });
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('q').into()], RecordMacro);
+        keymap.bind(AppMode::Normal, &[KeyCode::Char('@').into()], PlayMacro);
+        for mode in [AppMode::Visual, AppMode::VisualRow, AppMode::VisualColumn] {
+            keymap.bind(mode, &[KeyCode::Char('o').into()], SwapCorner);
+            keymap.bind(mode, &[KeyCode::Char('O').into()], SwapCornerHorizontal);
+            keymap.bind(mode, &[KeyCode::Char('y').into()], Yank);
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('y').into()], YankValues);
+            keymap.bind(mode, &[KeyCode::Char('d').into()], Delete);
+            keymap.bind(mode, &[KeyCode::Char('x').into()], Delete);
+            keymap.bind(mode, &[KeyCode::Char('g').into(), KeyCode::Char('d').into()], Fill);
+            // Like vim's inner paragraph, so vip selects the table under the cursor
+            keymap.bind(mode, &[KeyCode::Char('i').into(), KeyCode::Char('p').into()], SelectDataRegion);
+        }
+        keymap
+    }
+}
+
+// Names of the actions in the config file
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("move_up", Action::MoveUp),
+    ("move_down", Action::MoveDown),
+    ("move_left", Action::MoveLeft),
+    ("move_right", Action::MoveRight),
+    ("goto_first_row", Action::GotoRow { last: false }),
+    ("goto_last_row", Action::GotoRow { last: true }),
+    ("goto_first_col", Action::GotoCol { last: false }),
+    ("goto_last_col", Action::GotoCol { last: true }),
+    ("block_right", Action::NextBlock { forward: true, vertical: false }),
+    ("block_left", Action::NextBlock { forward: false, vertical: false }),
+    ("block_down", Action::NextBlock { forward: true, vertical: true }),
+    ("block_up", Action::NextBlock { forward: false, vertical: true }),
+    ("enter_visual", Action::EnterVisual),
+    ("enter_visual_row", Action::EnterVisualRow),
+    ("enter_visual_column", Action::EnterVisualColumn),
+    ("exit_visual", Action::ExitVisual),
+    ("restore_visual", Action::RestoreVisual),
+    ("swap_corner", Action::SwapCorner),
+    ("swap_corner_horizontal", Action::SwapCornerHorizontal),
+    ("select_data_region", Action::SelectDataRegion),
+    ("select_all", Action::SelectAll),
+    ("find_right", Action::Find(Find { forward: true, till: false, vertical: false })),
+    ("find_left", Action::Find(Find { forward: false, till: false, vertical: false })),
+    ("till_right", Action::Find(Find { forward: true, till: true, vertical: false })),
+    ("till_left", Action::Find(Find { forward: false, till: true, vertical: false })),
+    ("find_down", Action::Find(Find { forward: true, till: false, vertical: true })),
+    ("find_up", Action::Find(Find { forward: false, till: false, vertical: true })),
+    ("till_down", Action::Find(Find { forward: true, till: true, vertical: true })),
+    ("till_up", Action::Find(Find { forward: false, till: true, vertical: true })),
+    ("repeat_find", Action::RepeatFind),
+    ("repeat_find_reverse", Action::RepeatFindReverse),
+    ("search_cell_forward", Action::SearchCell { forward: true }),
+    ("search_cell_backward", Action::SearchCell { forward: false }),
+    ("search_forward", Action::StartSearch { forward: true }),
+    ("search_backward", Action::StartSearch { forward: false }),
+    ("search_next", Action::SearchNext),
+    ("search_previous", Action::SearchPrevious),
+    ("scroll_half_page_down", Action::ScrollHalfPage { down: true }),
+    ("scroll_half_page_up", Action::ScrollHalfPage { down: false }),
+    ("scroll_line_down", Action::ScrollLine { down: true }),
+    ("scroll_line_up", Action::ScrollLine { down: false }),
+    ("select_register", Action::SelectRegister),
+    ("yank", Action::Yank),
+    ("yank_values", Action::YankValues),
+    ("delete", Action::Delete),
+    ("put_after", Action::Put { before: false }),
+    ("put_before", Action::Put { before: true }),
+    ("fill", Action::Fill),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+    ("enter_insert", Action::EnterInsert),
+    ("change_cell", Action::ChangeCell),
+    ("delete_operator", Action::Operator(Operator::Delete)),
+    ("yank_operator", Action::Operator(Operator::Yank)),
+    ("change_operator", Action::Operator(Operator::Change)),
+    ("insert_rows_below", Action::InsertRows { below: true }),
+    ("insert_rows_above", Action::InsertRows { below: false }),
+    ("insert_cols_right", Action::InsertCols { right: true }),
+    ("insert_cols_left", Action::InsertCols { right: false }),
+    ("delete_rows", Action::DeleteRows),
+    ("delete_cols", Action::DeleteCols),
+    ("grow_col", Action::ResizeCol { grow: true }),
+    ("shrink_col", Action::ResizeCol { grow: false }),
+    ("precedents", Action::Trace { dependents: false }),
+    ("dependents", Action::Trace { dependents: true }),
+    ("next_change", Action::NextChange { forward: true }),
+    ("previous_change", Action::NextChange { forward: false }),
+    ("next_sheet", Action::NextSheet { forward: true }),
+    ("previous_sheet", Action::NextSheet { forward: false }),
+    ("split_window", Action::SplitWindow { vertical: false }),
+    ("vsplit_window", Action::SplitWindow { vertical: true }),
+    ("window_left", Action::FocusWindow { forward: false, vertical: false }),
+    ("window_down", Action::FocusWindow { forward: true, vertical: true }),
+    ("window_up", Action::FocusWindow { forward: false, vertical: true }),
+    ("window_right", Action::FocusWindow { forward: true, vertical: false }),
+    ("next_window", Action::NextWindow),
+    ("close_window", Action::CloseWindow),
+    ("only_window", Action::OnlyWindow),
+    ("record_macro", Action::RecordMacro),
+    ("play_macro", Action::PlayMacro),
+    ("enter_command_line", Action::EnterCommandLine),
+    ("command_palette", Action::CommandPalette),
+];
+
+impl Action {
+    pub fn by_name(name: &str) -> Option<Self> {
+        ACTION_NAMES.iter().find(|(n, _)| *n == name).map(|(_, action)| *action)
+    }
+}
+
+// Names of the special keys between < and >, the first one is used for output
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("CR", KeyCode::Enter),
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Tab", KeyCode::Tab),
+    ("BS", KeyCode::Backspace),
+    ("Space", KeyCode::Char(' ')),
+    ("lt", KeyCode::Char('<')),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Del", KeyCode::Delete),
+];
+
+// Keys written the way vim does, e.g. gg, <C-v> or <Esc>. A < which doesn't
+// start a key name stands for itself.
+pub fn parse_keys(text: &str) -> Option<Vec<KeyPress>> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let special = rest.strip_prefix('<')
+            .and_then(|r| r.split_once('>'))
+            .and_then(|(name, after)| parse_key_name(name).map(|key| (key, after)));
+        match special {
+            Some((key, after)) => {
+                keys.push(key);
+                rest = after;
+            }
+            None => {
+                keys.push(KeyCode::Char(c).into());
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    (!keys.is_empty()).then_some(keys)
+}
+
+fn parse_key_name(name: &str) -> Option<KeyPress> {
+    if let Some(key) = name.strip_prefix("C-").or_else(|| name.strip_prefix("c-")) {
+        let mut chars = key.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(KeyPress::new(KeyCode::Char(c.to_ascii_lowercase()), KeyModifiers::CONTROL)),
+            _ => None,
+        };
+    }
+    KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, code)| (*code).into())
+}
+
+// The other way round, e.g. for :registers
+pub fn key_names(keys: &[KeyPress]) -> String {
+    keys.iter().map(|key| match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => format!("<C-{}>", c),
+        code => match KEY_NAMES.iter().find(|(_, c)| *c == code) {
+            Some((name, _)) => format!("<{}>", name),
+            None => match code {
+                KeyCode::Char(c) => c.to_string(),
+                _ => "<?>".to_string(),
+            },
+        },
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(keymap: &Keymap, keys: &str) -> Option<Action> {
+        match keymap.lookup(AppMode::Normal, &parse_keys(keys).unwrap()) {
+            Lookup::Action(action) => Some(action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn wasd() {
+        let keymap = Keymap::preset("wasd").unwrap();
+        assert_eq!(action(&keymap, "w"), Some(Action::MoveUp));
+        assert_eq!(action(&keymap, "a"), Some(Action::MoveLeft));
+        assert_eq!(action(&keymap, "s"), Some(Action::MoveDown));
+        assert_eq!(action(&keymap, "d"), Some(Action::MoveRight));
+        assert_eq!(action(&keymap, "k"), Some(Action::NextBlock { forward: true, vertical: false }));
+        assert_eq!(action(&keymap, "h"), Some(Action::EnterInsert));
+        // Keys with Ctrl keep their place, only the key after Ctrl-W moves
+        assert_eq!(action(&keymap, "<C-a>"), Some(Action::SelectAll));
+        assert_eq!(action(&keymap, "<C-d>"), Some(Action::ScrollHalfPage { down: true }));
+        assert_eq!(action(&keymap, "<C-w>k"), Some(Action::NextWindow));
+        assert_eq!(action(&keymap, "<C-w>w"), Some(Action::FocusWindow { forward: false, vertical: true }));
+        assert_eq!(action(&keymap, "<C-k>"), None);
+    }
+
+    #[test]
+    fn colemak() {
+        let keymap = Keymap::preset("colemak").unwrap();
+        assert_eq!(action(&keymap, "n"), Some(Action::MoveDown));
+        assert_eq!(action(&keymap, "e"), Some(Action::MoveUp));
+        assert_eq!(action(&keymap, "i"), Some(Action::MoveRight));
+        assert_eq!(action(&keymap, "J"), Some(Action::SearchPrevious));
+        assert_eq!(action(&keymap, "<C-e>"), Some(Action::ScrollLine { down: true }));
+        assert_eq!(action(&keymap, "<C-w>n"), Some(Action::FocusWindow { forward: true, vertical: true }));
+    }
+
+    #[test]
+    fn no_binding_is_lost() {
+        let qwerty = Keymap::default();
+        for name in PRESETS {
+            let keymap = Keymap::preset(name).unwrap();
+            let mut actions: Vec<_> = keymap.bindings.iter().map(|((mode, _), action)| format!("{:?} {:?}", mode, action)).collect();
+            let mut expected: Vec<_> = qwerty.bindings.iter().map(|((mode, _), action)| format!("{:?} {:?}", mode, action)).collect();
+            actions.sort();
+            expected.sort();
+            assert_eq!(actions, expected, "{}", name);
+        }
+    }
+}
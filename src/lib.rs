@@ -0,0 +1,266 @@
+// VISP: VI-style SPreadsheet
+
+pub mod autosave;
+pub mod batch;
+pub mod calc;
+pub mod grid;
+pub mod input;
+pub mod render;
+pub mod commands;
+pub mod config;
+pub mod diff;
+pub mod command_line;
+pub mod csv;
+pub mod io;
+pub mod error;
+pub mod fetch;
+pub mod format;
+pub mod formula;
+pub mod edit;
+pub mod edit_log;
+pub mod encoding;
+pub mod fill;
+pub mod goalseek;
+pub mod keymap;
+pub mod line_normal;
+pub mod lock;
+pub mod merge;
+pub mod logging;
+pub mod oldfiles;
+pub mod operator;
+pub mod options;
+pub mod picker;
+pub mod print;
+pub mod profiler;
+pub mod register;
+pub mod script;
+pub mod search;
+pub mod serve;
+pub mod share;
+pub mod sheet;
+pub mod sort;
+pub mod stream;
+pub mod structure;
+pub mod swap;
+pub mod task;
+pub mod theme;
+pub mod undo;
+pub mod window;
+pub mod workbook;
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use grid::{TableContent, Selection, Snapshot};
+use render::Viewport;
+use keymap::{Find, Keymap, KeyPress, MacroKey};
+use lock::Locks;
+use merge::Merge;
+use diff::Diff;
+use logging::MessageLog;
+use edit_log::EditLog;
+use edit::EditBuffer;
+use input::Drag;
+use autosave::Autosave;
+use calc::Calc;
+use command_line::CommandLine;
+use picker::Picker;
+use profiler::Profiler;
+use register::Registers;
+use script::Script;
+use search::Search;
+use serve::Server;
+use share::Share;
+use sheet::Sheet;
+use swap::SwapFiles;
+use task::Tasks;
+use options::Options;
+use theme::Theme;
+use undo::UndoHistory;
+use window::{Pane, Window};
+
+pub use error::{VispError, Result};
+
+pub struct AppState {
+    pub table_content: TableContent,
+    pub sheets: Vec<Sheet>, // In tab order, the current one is in the fields of AppState
+    pub sheet: usize, // Index of the current sheet
+    pub windows: Vec<Window>, // The current one is in viewport and the selection of the table
+    pub window: usize, // Index of the current window
+    pub layout: Pane,
+    pub viewport: Viewport,
+    pub mode: AppMode,
+    pub options: Options,
+    pub theme: Theme,
+    pub keymap: Keymap,
+    pub aliases: HashMap<String, String>, // Command names from the config file and what they run
+    pub script: Option<Rc<Script>>, // From init.rhai or :source
+    pub pending_keys: Vec<KeyPress>,
+    pub count: Option<u32>, // Count prefix typed so far, e.g. the 5 in 5j
+    pub pending_find: Option<(Find, Option<u32>)>, // f was typed, waiting for the character
+    pub last_find: Option<(Find, char)>, // For ; and ,
+    pub operator: Option<operator::Pending>, // d, y or c was typed, waiting for the motion
+    pub registers: Registers,
+    pub register: Option<char>, // Chosen with " for the next yank, delete or put
+    pub pending_register: bool, // " was typed, waiting for the register name
+    pub pending_macro: Option<(MacroKey, Option<u32>)>, // q or @ was typed, waiting for the register name
+    pub recording: Option<(char, Vec<KeyPress>)>, // Started with q, the keys typed since
+    pub playing: Vec<char>, // Registers of the macros being played, innermost last
+    pub search: Option<Search>,
+    pub last_visual: Option<(AppMode, Selection)>, // For gv
+    pub selection_history: VecDeque<(AppMode, Selection)>, // Newest first
+    pub quit: bool,
+    pub drag: Option<Drag>, // Set while the left mouse button is down
+    pub message: Option<Message>,
+    pub confirm: Option<String>, // Command waiting for y, see :set readonly and swap::check
+    pub command_line: CommandLine,
+    pub edit: Option<EditBuffer>, // The cell being edited in Insert mode
+    pub entry: Option<Selection>, // Range of :entry while it runs
+    pub pager: Option<Pager>,
+    pub picker: Option<Picker>,
+    pub calc: Calc, // Shown while calc.open is set
+    pub log: MessageLog,
+    pub profiler: Profiler,
+    pub server: Option<Server>, // Started with :serve
+    pub share: Option<Share>, // Started with :host or :join
+    pub merge: Option<Merge>, // From visp --merge
+    pub diff: Option<Diff>, // From visp --diff
+    pub snapshots: Vec<(String, Snapshot)>, // Oldest first
+    pub change_baseline: Option<Snapshot>, // Taken when trackchanges is set
+    pub edit_log: EditLog,
+    pub undo: UndoHistory,
+    pub file: Option<PathBuf>, // Opened with :e or written with :w
+    pub saved_revision: u64, // TableContent::revision when the file was opened or written
+    pub swap: SwapFiles,
+    pub oldfiles: Option<Vec<PathBuf>>, // Newest first, None while they aren't kept, e.g. in batch mode
+    pub autosave: Autosave,
+    pub locks: Locks,
+    pub tasks: Tasks,
+}
+
+impl AppState {
+    pub fn new(table_content: TableContent) -> Self {
+        let mut state = Self {
+            table_content,
+            sheets: vec![Sheet::new("Sheet1")],
+            sheet: 0,
+            windows: vec![Window::default()],
+            window: 0,
+            layout: Pane::default(),
+            viewport: Viewport::default(),
+            mode: AppMode::Normal,
+            options: Options::default(),
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            aliases: HashMap::new(),
+            script: None,
+            pending_keys: Vec::new(),
+            count: None,
+            pending_find: None,
+            last_find: None,
+            operator: None,
+            registers: Registers::default(),
+            register: None,
+            pending_register: false,
+            pending_macro: None,
+            recording: None,
+            playing: Vec::new(),
+            search: None,
+            last_visual: None,
+            selection_history: VecDeque::new(),
+            quit: false,
+            drag: None,
+            message: None,
+            confirm: None,
+            command_line: CommandLine::default(),
+            edit: None,
+            entry: None,
+            pager: None,
+            picker: None,
+            calc: Calc::default(),
+            log: MessageLog::default(),
+            profiler: Profiler::default(),
+            server: None,
+            share: None,
+            merge: None,
+            diff: None,
+            snapshots: Vec::new(),
+            change_baseline: None,
+            edit_log: EditLog::default(),
+            undo: UndoHistory::default(),
+            file: None,
+            saved_revision: 0,
+            swap: SwapFiles::default(),
+            oldfiles: None,
+            autosave: Autosave::default(),
+            locks: Locks::default(),
+            tasks: Tasks::default(),
+        };
+        sheet::link(&mut state);
+        state
+    }
+}
+
+// Number of visual selections kept for :selections
+const SELECTION_HISTORY_SIZE: usize = 20;
+
+impl AppState {
+    // Whether there are changes which aren't written to the file
+    pub fn is_modified(&self) -> bool {
+        self.table_content.revision != self.saved_revision
+    }
+
+    // Called when leaving a visual mode, so the selection can be brought back
+    pub fn remember_visual(&mut self) {
+        if !self.mode.is_visual() {
+            return;
+        }
+        let entry = (self.mode, self.table_content.selection);
+        self.last_visual = Some(entry);
+
+        let history = &mut self.selection_history;
+        history.retain(|(_, s)| !s.same_cells(&entry.1));
+        history.push_front(entry);
+        history.truncate(SELECTION_HISTORY_SIZE);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AppMode {
+    Normal,
+    Visual, // Rectangle
+    VisualRow,
+    VisualColumn,
+    Command,
+    Insert,
+}
+
+impl AppMode {
+    pub fn is_visual(&self) -> bool {
+        matches!(self, Self::Visual | Self::VisualRow | Self::VisualColumn)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Normal => "NORMAL",
+            Self::Visual => "VISUAL",
+            Self::VisualRow => "VISUAL ROW",
+            Self::VisualColumn => "VISUAL COLUMN",
+            Self::Command => "COMMAND",
+            Self::Insert => "INSERT",
+        }
+    }
+}
+
+// Shown in the message area below the table until the next key press
+pub enum Message {
+    Info(String),
+    Error(String),
+}
+
+// Read-only text shown on top of the table until a key is pressed
+pub struct Pager {
+    pub title: String,
+    pub lines: Vec<String>,
+}
@@ -0,0 +1,338 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::AppState;
+use crate::edit::{EditBuffer, LineBuffer};
+
+// Ctrl-F in Insert mode, like the command-line window of vim, makes keys work
+// on the edited text as in Normal mode until i, a, c or the like go back to
+// typing. Enter and Esc still write the cell and Ctrl-C throws it away. d, c
+// and y take a motion (h l w b e W B E 0 ^ $) or a text object (iw aw iW aW
+// i" a" i' a' i( a( ib ab and the same for [ and {), e.g. ci( replaces the
+// arguments of a function. The text goes into the register like a cell.
+#[derive(Default)]
+pub struct LineNormal {
+    pub active: bool, // Between Ctrl-F and going back to typing
+    keys: String, // Count, operator and motion typed so far, like d2 or ci
+    undo: Option<(String, usize)>, // Text and cursor before the last change, also while typing
+}
+
+// What follows the count and operator
+enum Target {
+    Motion(char),
+    Object { around: bool, kind: char },
+    Line, // dd, cc and yy
+}
+
+enum Parsed {
+    Pending,
+    Done { count: usize, operator: Option<char>, target: Target },
+}
+
+pub fn start(state: &mut AppState) {
+    let Some(edit) = &mut state.edit else {
+        return;
+    };
+    edit.completion = None;
+    edit.normal.active = true;
+    // Like leaving Insert mode in vim the cursor goes onto the character left of it
+    edit.line.cursor = clamp(edit.line.cursor.saturating_sub(1), len(&edit.line));
+}
+
+// A key typed in the Normal mode of the editor, returns whether it was used.
+// Enter, Esc and Ctrl-C are left to Insert mode, other keys which mean
+// nothing here are dropped.
+pub fn key(state: &mut AppState, key: KeyEvent) -> bool {
+    let Some(normal) = state.edit.as_mut().map(|edit| &mut edit.normal).filter(|normal| normal.active) else {
+        return false;
+    };
+    let c = match key.code {
+        KeyCode::Enter | KeyCode::Esc => return false,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+        KeyCode::Left | KeyCode::Backspace => 'h',
+        KeyCode::Right => 'l',
+        KeyCode::Home => '0',
+        KeyCode::End => '$',
+        KeyCode::Delete => 'x',
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => c,
+        _ => return true,
+    };
+    normal.keys.push(c);
+    let Parsed::Done { count, operator, target } = parse(&normal.keys) else {
+        return true;
+    };
+    normal.keys.clear();
+    // Like an operator in the table it takes the register typed before it
+    let register = state.register.take();
+    let edit = state.edit.as_mut().unwrap();
+    let chars: Vec<char> = edit.line.text.chars().collect();
+    let cursor = edit.line.cursor;
+    let (operator, range) = match (operator, target) {
+        (Some(operator), Target::Line) => (operator, Some((0, chars.len()))),
+        (Some(operator), Target::Object { around, kind }) => (operator, object(&chars, cursor, around, kind, count)),
+        // Like in vim cw changes to the end of the word
+        (Some('c'), Target::Motion(m @ ('w' | 'W'))) if chars.get(cursor).is_some_and(|c| !c.is_whitespace()) => {
+            ('c', motion_range(&chars, cursor, if m == 'w' { 'e' } else { 'E' }, count))
+        }
+        (Some(operator), Target::Motion(m)) => (operator, motion_range(&chars, cursor, m, count)),
+        (None, Target::Motion(m)) => {
+            if let Some((target, _)) = motion(&chars, cursor, m, count) {
+                edit.line.cursor = clamp(target, chars.len());
+                return true;
+            }
+            // The rest are commands of their own, some short for an operator
+            match m {
+                'x' => ('d', Some((cursor, (cursor + count).min(chars.len())))),
+                'X' => ('d', Some((cursor.saturating_sub(count), cursor))),
+                's' => ('c', Some((cursor, (cursor + count).min(chars.len())))),
+                'D' => ('d', Some((cursor, chars.len()))),
+                'C' => ('c', Some((cursor, chars.len()))),
+                'i' => return insert(edit, cursor),
+                'a' => return insert(edit, cursor + 1),
+                'I' => return insert(edit, 0),
+                'A' => return insert(edit, chars.len()),
+                'p' | 'P' => {
+                    let text = state.registers.text(register).unwrap_or_default().repeat(count);
+                    if !text.is_empty() {
+                        let at = if m == 'p' && !chars.is_empty() { cursor + 1 } else { cursor };
+                        edit.normal.undo = Some((edit.line.text.clone(), cursor));
+                        edit.line.text.insert_str(byte_index(&edit.line.text, at), &text);
+                        edit.line.cursor = at + text.chars().count() - 1;
+                    }
+                    return true;
+                }
+                'u' => {
+                    let normal = &mut edit.normal;
+                    if let Some((text, cursor)) = normal.undo.take() {
+                        normal.undo = Some((std::mem::replace(&mut edit.line.text, text), edit.line.cursor));
+                        edit.line.cursor = clamp(cursor, len(&edit.line));
+                    }
+                    return true;
+                }
+                _ => return true,
+            }
+        }
+        (None, _) => return true,
+    };
+    let Some((start, end)) = range.filter(|(start, end)| start < end || operator == 'c') else {
+        return true;
+    };
+    let line = &mut edit.line;
+    let (from, to) = (byte_index(&line.text, start), byte_index(&line.text, end));
+    state.registers.set_text(register, line.text[from..to].to_string());
+    if operator != 'y' {
+        edit.normal.undo = Some((line.text.clone(), line.cursor));
+        line.text.replace_range(from..to, "");
+    }
+    line.cursor = clamp(start, len(line));
+    if operator == 'c' {
+        return insert(edit, start);
+    }
+    true
+}
+
+// Back to typing with the cursor before character `at`
+fn insert(edit: &mut EditBuffer, at: usize) -> bool {
+    edit.normal.active = false;
+    edit.line.cursor = at.min(len(&edit.line));
+    true
+}
+
+// [count] [operator [count]] then a motion, a text object or the operator
+// again. The counts multiply like in vim.
+fn parse(keys: &str) -> Parsed {
+    let mut chars = keys.chars().peekable();
+    let count = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut count: Option<usize> = None;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)).filter(|&d| d > 0 || count.is_some()) {
+            count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+            chars.next();
+        }
+        count.unwrap_or(1)
+    };
+    let first = count(&mut chars);
+    let Some(c) = chars.next() else {
+        return Parsed::Pending;
+    };
+    if !matches!(c, 'd' | 'c' | 'y') {
+        return Parsed::Done { count: first, operator: None, target: Target::Motion(c) };
+    }
+    let second = count(&mut chars);
+    let count = first.saturating_mul(second);
+    let target = match (chars.next(), chars.next()) {
+        (None, _) => return Parsed::Pending,
+        (Some(m), _) if m == c => Target::Line,
+        (Some(m @ ('i' | 'a')), Some(kind)) => Target::Object { around: m == 'a', kind },
+        (Some('i' | 'a'), None) => return Parsed::Pending,
+        (Some(m), _) => Target::Motion(m),
+    };
+    Parsed::Done { count, operator: Some(c), target }
+}
+
+fn len(line: &LineBuffer) -> usize {
+    line.text.chars().count()
+}
+
+fn byte_index(text: &str, cursor: usize) -> usize {
+    text.char_indices().nth(cursor).map_or(text.len(), |(i, _)| i)
+}
+
+// In Normal mode the cursor is on a character, not after the last one
+fn clamp(cursor: usize, len: usize) -> usize {
+    cursor.min(len.saturating_sub(1))
+}
+
+// Letters, digits and _ make words, other characters which aren't blank make
+// words of their own. With `big` all of them do, like W in vim.
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+// Where a motion goes and whether an operator includes that character
+fn motion(chars: &[char], cursor: usize, m: char, count: usize) -> Option<(usize, bool)> {
+    let big = m.is_ascii_uppercase();
+    let repeat = |step: &dyn Fn(usize) -> usize| (0..count).fold(cursor, |i, _| step(i));
+    let len = chars.len();
+    Some(match m {
+        'h' => (cursor.saturating_sub(count), false),
+        'l' | ' ' => ((cursor + count).min(len), false),
+        '0' => (0, false),
+        '^' => (chars.iter().position(|c| !c.is_whitespace()).unwrap_or(len), false),
+        '$' => (len, false),
+        'w' | 'W' => (repeat(&|i| word_start(chars, i, big)), false),
+        'b' | 'B' => (repeat(&|i| previous_word_start(chars, i, big)), false),
+        'e' | 'E' => (repeat(&|i| word_end(chars, i, big)), true),
+        _ => return None,
+    })
+}
+
+fn motion_range(chars: &[char], cursor: usize, m: char, count: usize) -> Option<(usize, usize)> {
+    let (target, inclusive) = motion(chars, cursor, m, count)?;
+    Some(if target < cursor { (target, cursor) } else { (cursor, (target + inclusive as usize).min(chars.len())) })
+}
+
+fn word_start(chars: &[char], mut i: usize, big: bool) -> usize {
+    if let Some(&c) = chars.get(i) {
+        let start = class(c, big);
+        while i < chars.len() && start != 0 && class(chars[i], big) == start {
+            i += 1;
+        }
+    }
+    while i < chars.len() && chars[i].is_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+fn previous_word_start(chars: &[char], mut i: usize, big: bool) -> usize {
+    while i > 0 && chars[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    if i > 0 {
+        let start = class(chars[i - 1], big);
+        while i > 0 && class(chars[i - 1], big) == start {
+            i -= 1;
+        }
+    }
+    i
+}
+
+fn word_end(chars: &[char], mut i: usize, big: bool) -> usize {
+    i += 1;
+    while i < chars.len() && chars[i].is_whitespace() {
+        i += 1;
+    }
+    if i >= chars.len() {
+        return chars.len().saturating_sub(1);
+    }
+    let end = class(chars[i], big);
+    while i + 1 < chars.len() && class(chars[i + 1], big) == end {
+        i += 1;
+    }
+    i
+}
+
+// Characters of a text object around the cursor
+fn object(chars: &[char], cursor: usize, around: bool, kind: char, count: usize) -> Option<(usize, usize)> {
+    match kind {
+        'w' | 'W' => word_object(chars, cursor, around, kind == 'W'),
+        '"' | '\'' | '`' => quote_object(chars, cursor, around, kind),
+        '(' | ')' | 'b' => bracket_object(chars, cursor, around, ('(', ')'), count),
+        '[' | ']' => bracket_object(chars, cursor, around, ('[', ']'), count),
+        '{' | '}' | 'B' => bracket_object(chars, cursor, around, ('{', '}'), count),
+        _ => None,
+    }
+}
+
+// The word or blanks under the cursor. With `around` the blanks after it
+// come along, or those before it at the end of the text.
+fn word_object(chars: &[char], cursor: usize, around: bool, big: bool) -> Option<(usize, usize)> {
+    let under = class(*chars.get(cursor)?, big);
+    let same = |i: usize| class(chars[i], big) == under;
+    let (mut start, mut end) = (cursor, cursor + 1);
+    while start > 0 && same(start - 1) {
+        start -= 1;
+    }
+    while end < chars.len() && same(end) {
+        end += 1;
+    }
+    if around && under != 0 {
+        let blanks = chars[end..].iter().take_while(|c| c.is_whitespace()).count();
+        if blanks > 0 {
+            end += blanks;
+        } else {
+            start -= chars[..start].iter().rev().take_while(|c| c.is_whitespace()).count();
+        }
+    }
+    Some((start, end))
+}
+
+// Quotes pair up from the start of the text. The pair around the cursor, or
+// the next one after it like in vim.
+fn quote_object(chars: &[char], cursor: usize, around: bool, quote: char) -> Option<(usize, usize)> {
+    let quotes: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == quote).collect();
+    let (open, close) = quotes.chunks_exact(2).map(|pair| (pair[0], pair[1])).find(|&(_, close)| close >= cursor)?;
+    Some(if around { (open, close + 1) } else { (open + 1, close) })
+}
+
+// The `count`th pair of brackets around the cursor, counted outwards
+fn bracket_object(chars: &[char], cursor: usize, around: bool, (open, close): (char, char), count: usize) -> Option<(usize, usize)> {
+    let mut start = cursor.min(chars.len().checked_sub(1)?);
+    // On a closing bracket that pair is meant
+    if chars[start] == close {
+        start = matching_open(chars, start, (open, close))?;
+    } else if chars[start] != open {
+        start = enclosing_open(chars, start, (open, close))?;
+    }
+    for _ in 1..count {
+        start = enclosing_open(chars, start, (open, close))?;
+    }
+    let mut depth = 0;
+    let end = (start..chars.len()).find(|&i| {
+        depth += (chars[i] == open) as i32 - (chars[i] == close) as i32;
+        depth == 0
+    })?;
+    Some(if around { (start, end + 1) } else { (start + 1, end) })
+}
+
+// The opening bracket left of `i` which isn't closed before it
+fn enclosing_open(chars: &[char], i: usize, (open, close): (char, char)) -> Option<usize> {
+    let mut depth = 0;
+    (0..i).rev().find(|&j| {
+        depth += (chars[j] == close) as i32 - (chars[j] == open) as i32;
+        depth < 0
+    })
+}
+
+fn matching_open(chars: &[char], i: usize, (open, close): (char, char)) -> Option<usize> {
+    let mut depth = 0;
+    (0..=i).rev().find(|&j| {
+        depth += (chars[j] == close) as i32 - (chars[j] == open) as i32;
+        depth == 0
+    })
+}
@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::AppState;
+
+// Lock files, .name.csv.lock next to the files open in the sheets, so that a
+// second visp can warn before its saves and those of the first overwrite
+// each other. They hold the process id and are taken over when that process
+// is gone.
+#[derive(Default)]
+pub struct Locks {
+    held: HashSet<PathBuf>, // Lock files written by us
+}
+
+fn path(file: &Path) -> PathBuf {
+    let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    file.with_file_name(format!(".{}.lock", name))
+}
+
+// The process id of another visp which has `file` open
+pub fn other_owner(file: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path(file)).ok()?.trim().parse().ok()?;
+    (pid != std::process::id() && alive(pid)).then_some(pid)
+}
+
+// Without /proc, e.g. on macOS, a lock is taken to be in use
+fn alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.exists() || proc.join(pid.to_string()).exists()
+}
+
+// Locks the files which are open in the sheets and gives up the others.
+// Called after a file is opened and every second by the main loop.
+pub fn update(state: &mut AppState) {
+    let files: Vec<&PathBuf> = (0..state.sheets.len())
+        .filter_map(|i| if i == state.sheet { state.file.as_ref() } else { state.sheets[i].file.as_ref() })
+        .collect();
+    let wanted: HashSet<PathBuf> = files.iter().map(|file| path(file)).collect();
+    let held = &mut state.locks.held;
+    held.retain(|lock| {
+        let keep = wanted.contains(lock);
+        if !keep {
+            let _ = fs::remove_file(lock);
+        }
+        keep
+    });
+    for file in files {
+        let lock = path(file);
+        // A new file gets one when it is written
+        if held.contains(&lock) || other_owner(file).is_some() || !file.exists() {
+            continue;
+        }
+        match fs::write(&lock, format!("{}\n", std::process::id())) {
+            Ok(()) => {
+                held.insert(lock);
+            }
+            Err(e) => tracing::debug!("{} not written: {}", lock.display(), e),
+        }
+    }
+}
+
+// When visp quits
+pub fn release_all(state: &mut AppState) {
+    for lock in state.locks.held.drain() {
+        let _ = fs::remove_file(lock);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fmt, fs::File, path::Path, sync::{Arc, Mutex}};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use tracing::{Event, Level, Subscriber, field::{Field, Visit}};
+use tracing_subscriber::{layer::{Context, Layer, SubscriberExt}, util::SubscriberInitExt, filter::LevelFilter};
+
+use crate::Result;
+
+// Number of lines kept for :messages
+const LOG_CAPACITY: usize = 500;
+
+// Most recent log lines, shared between the tracing layer and the app
+#[derive(Clone, Default)]
+pub struct MessageLog {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl MessageLog {
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == LOG_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    // Approximate number of bytes used
+    pub fn memory_size(&self) -> usize {
+        self.lines.lock().unwrap().iter().map(|l| l.capacity() + std::mem::size_of::<String>()).sum()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+struct MessageVisitor {
+    line: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.line, " {:?}", value);
+        } else {
+            let _ = write!(self.line, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+struct MessageLogLayer {
+    log: MessageLog,
+}
+
+impl<S: Subscriber> Layer<S> for MessageLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor {
+            line: format!("{:5} {}:", event.metadata().level(), event.metadata().target()),
+        };
+        event.record(&mut visitor);
+        self.log.push(visitor.line);
+    }
+}
+
+// Routes all tracing events into `log` and, if given, appends them to a file
+pub fn init(log: &MessageLog, file: Option<&Path>) -> Result<()> {
+    let file_layer = match file {
+        Some(path) => {
+            let file = File::options().create(true).append(true).open(path)?;
+            Some(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(file)))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(MessageLogLayer { log: log.clone() }.with_filter(LevelFilter::from_level(Level::INFO)))
+        .with(file_layer.with_filter(LevelFilter::from_level(Level::DEBUG)))
+        .init();
+    Ok(())
+}
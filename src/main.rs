@@ -1,293 +1,103 @@
 // VISP: VI-style SPreadsheet
 
-use std::{io, time::Duration};
-use tui::{
-    backend::Backend,
-    backend::CrosstermBackend,
-    widgets::{Widget, Paragraph},
-    layout::{Layout, Constraint, Direction, Rect},
-    buffer::{Buffer},
-    style::{Style, Modifier, Color},
-    Frame,
-    Terminal
-};
-use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
-
-fn col_nr_to_label(col: u16) -> String {
-    if col < 26 {
-        char::from_u32('A' as u32 + col as u32).unwrap().to_string()
-    } else {
-        let front = col / 26;
-        col_nr_to_label(front - 1) + &col_nr_to_label(col - (26 * front))
-    }
-}
-
-fn add_clamp(val: &mut u16) {
-    if *val < u16::MAX {
-        *val += 1;
-    }
-}
-
-fn sub_clamp(val: &mut u16, min: u16) {
-    if *val > min {
-        *val -= 1;
-    }
-}
-
-fn main() -> Result<(), io::Error> {
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    let table_content = TableContent{
-        cells: vec![
-            vec![TableCell::String("Value".to_string()), TableCell::Value(10), TableCell::Value(10)],
-            vec![TableCell::String("Value".to_string()), TableCell::Value(20), TableCell::Value(10)],
-            vec![TableCell::String("Value".to_string()), TableCell::Empty, TableCell::Value(10)],
-            vec![TableCell::String("Value".to_string()), TableCell::Value(20), TableCell::Value(10)],
-        ],
-        col_widths: vec![10, 5],
-        row_heights: vec![1, 2],
-        selection: Selection {
-            row: 0,
-            col: 0,
-            rows: 1,
-            cols: 1,
-        },
-    };
-
-    let mut state = AppState {
-        table_content,
-        mode: AppMode::Normal,
-    };
-
-    loop {
-        terminal.draw(|f| ui(f, &state))?;
-
-        // Wait up to 1s for another event
-        if crossterm::event::poll(Duration::from_millis(1_000))? {
-            // It's guaranteed that read() won't block if `poll` returns `Ok(true)`
-            let event = crossterm::event::read()?;
-
-            if state.mode == AppMode::Normal {
-                if event == Event::Key(KeyCode::Char('j').into()) {
-                    add_clamp(&mut state.table_content.selection.row);
-                }
-                if event == Event::Key(KeyCode::Char('k').into()) {
-                    sub_clamp(&mut state.table_content.selection.row, 0);
-                }
-                if event == Event::Key(KeyCode::Char('l').into()) {
-                    add_clamp(&mut state.table_content.selection.col);
-                }
-                if event == Event::Key(KeyCode::Char('h').into()) {
-                    sub_clamp(&mut state.table_content.selection.col, 0);
-                }
-            } else if state.mode == AppMode::Visual {
-                if event == Event::Key(KeyCode::Char('j').into()) {
-                    add_clamp(&mut state.table_content.selection.rows);
-                }
-                if event == Event::Key(KeyCode::Char('k').into()) {
-                    sub_clamp(&mut state.table_content.selection.rows, 1);
-                }
-                if event == Event::Key(KeyCode::Char('l').into()) {
-                    add_clamp(&mut state.table_content.selection.cols);
-                }
-                if event == Event::Key(KeyCode::Char('h').into()) {
-                    sub_clamp(&mut state.table_content.selection.cols, 1);
-                }
-            }
-
-            if event == Event::Key(KeyCode::Esc.into()) {
-                state.mode = AppMode::Normal;
-                state.table_content.selection.set_single();
-            }
-            if event == Event::Key(KeyCode::Char('v').into()) {
-                state.mode = AppMode::Visual;
-            }
-
-            if event == Event::Key(KeyCode::Char('q').into()) {
-                break;
-            }
+use std::path::Path;
+
+use visp::{AppState, Message, VispError};
+use visp::grid::TableContent;
+use visp::theme::{Theme, ColorSupport};
+
+fn main() -> Result<(), VispError> {
+    let mut stream = false;
+    let mut noconfig = false;
+    let mut readonly = false;
+    let mut batch = None;
+    let mut output = None;
+    let mut merge = None;
+    let mut diff = None;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| VispError::Command(format!("{} needs a file", arg)));
+        match arg.as_str() {
+            // Append CSV rows from stdin while running, e.g. from tail -f
+            "--stream" => stream = true,
+            // Run the commands in a file without a terminal
+            "--batch" => batch = Some(value()?),
+            "-o" | "--output" => output = Some(value()?),
+            // Three-way merge of base, ours and theirs, see merge.rs
+            "--merge" => merge = Some([value()?, value()?, value()?]),
+            // The old file left of the new one, see diff.rs
+            "--diff" => diff = Some([value()?, value()?]),
+            // Leave out the config file and init.rhai, e.g. for a batch which
+            // should do the same everywhere
+            "--noconfig" => noconfig = true,
+            // Like vim -R, :set noreadonly allows edits again
+            "-R" => readonly = true,
+            _ if arg.starts_with('-') => return Err(VispError::Command(format!("Unknown argument: {}", arg))),
+            // Each file goes into a sheet of its own
+            _ => files.push(arg),
         }
     }
 
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
-    Ok(())
-}
-
-struct AppState {
-    table_content: TableContent,
-    mode: AppMode,
-}
-
-#[derive(PartialEq)]
-enum AppMode {
-    Normal,
-    Visual
-}
-
-enum TableCell {
-    Empty,
-    String(String),
-    Value(i32),
-}
+    let mut state = AppState::new(TableContent::default());
+    let log_file = std::env::var_os("VISP_LOG").map(std::path::PathBuf::from);
+    visp::logging::init(&state.log, log_file.as_deref())?;
+    tracing::info!("visp {} started", env!("CARGO_PKG_VERSION"));
 
-impl TableCell {
-    fn format_string(&self) -> String {
-        match self {
-            Self::Empty => "".to_string(),
-            Self::String(s) => s.clone(),
-            Self::Value(v) => format!("{}", v),
+    if let Some(script) = batch {
+        // Unlike on screen a broken config stops the batch, its commands may
+        // rely on it
+        if !noconfig {
+            visp::config::load(&mut state)?;
         }
+        state.options.readonly |= readonly;
+        if files.len() > 1 {
+            return Err(VispError::Command("--batch takes a single input file".to_string()));
+        }
+        let result = visp::batch::run(&mut state, Path::new(&script), files.first().map(String::as_str), output.as_deref());
+        visp::lock::release_all(&mut state);
+        return result;
     }
-}
-
-#[derive(Default)]
-struct Selection {
-    row: u16,
-    col: u16,
-    rows: u16,
-    cols: u16,
-}
-
-impl Selection {
-    fn set_single(&mut self) {
-        self.rows = 1;
-        self.cols = 1;
-    }
-
-    fn row_selected(&self, row: u16) -> bool {
-        row >= self.row && row < self.row + self.rows
+    if output.is_some() && merge.is_none() {
+        return Err(VispError::Command("-o only works with --batch and --merge".to_string()));
     }
-
-    fn col_selected(&self, col: u16) -> bool {
-        col >= self.col && col < self.col + self.cols
+    if merge.is_some() && !files.is_empty() {
+        return Err(VispError::Command("--merge takes no other files, -o gives the result".to_string()));
     }
-
-    fn selected(&self, row: u16, col: u16) -> bool {
-        self.row_selected(row) && self.col_selected(col)
+    if diff.is_some() && (merge.is_some() || !files.is_empty()) {
+        return Err(VispError::Command("--diff takes no other files".to_string()));
     }
-}
-
-struct TableContent {
-    cells: Vec<Vec<TableCell>>, // row major
-    col_widths: Vec<u16>,
-    row_heights: Vec<u16>,
-    selection: Selection
-}
-
-struct Table<'a> {
-    content: &'a TableContent,
-}
-
-impl<'a> Widget for Table<'a> {
-    fn render(self, area: Rect, buf: &mut Buffer) {
-        let column_style = Style::default();
-        let selected_column_style = Style::default().fg(Color::White).bg(Color::Black);
-
-        let header_style = column_style.add_modifier(Modifier::BOLD);
-        let selected_header_style = selected_column_style.add_modifier(Modifier::BOLD);
-
-        let draw_cell = |buf: &mut Buffer, cell: Option<&TableCell>, rect: Rect, selected: bool| {
-            let style = if selected {
-                selected_column_style
-            } else {
-                column_style
-            };
-            for x in rect.x..rect.x + rect.width {
-                for y in rect.y..rect.y + rect.height {
-                    buf.get_mut(x, y).set_char(' ').set_style(style);
-                }
-            }
-            if let Some(c) = cell {
-                buf.set_stringn(rect.x, rect.y, c.format_string(), rect.width as usize, style);
-            }
-        };
 
-        let mut row = 0; 
-        let mut y = area.y; //Buffer position
-
-        while y < area.y + area.height {
-            let table_row = if row == 0 { None } else { Some(row - 1) };
-            let row_height : u16 = table_row.and_then(|r| self.content.row_heights.get(r)).map(|h| *h).unwrap_or(1);
-
-            let mut col = 0;
-            let mut x = area.x;
-            while x < area.x + area.width {
-                let table_col = if col == 0 { None } else { Some(col - 1) };
-                let col_width : u16 = table_col.and_then(|c| self.content.col_widths.get(c)).map(|c| *c).unwrap_or(4);
-
-                if let Some(table_row) = table_row {
-                    if let Some(table_col) = table_col {
-                        // Table content
-                        let cell : Option<&TableCell> = self.content.cells.get(table_row).and_then(|r| r.get(table_col));
-                        let selected = self.content.selection.selected(table_row as u16, table_col as u16);
-                        draw_cell(buf, cell, Rect::new(x, y, col_width, row_height).intersection(area), selected);
-                    } else {
-                        // Header column
-                        let style = if self.content.selection.row_selected(table_row as u16) {
-                            selected_header_style
-                        } else {
-                            header_style
-                        };
-                        buf.set_string(x, y, format!("{}", row), style);
-                    }
-
-                } else {
-                    // Header row
-                    if let Some(table_col) = table_col {
-                        let style = if self.content.selection.col_selected(table_col as u16) {
-                            selected_header_style
-                        } else {
-                            header_style
-                        };
-                        buf.set_string(x, y, col_nr_to_label(table_col as u16), style);
-                    } else {
-                        buf.set_string(x, y, "**", header_style);
-                    }
-                }
-
-                x += col_width;
-                col += 1;
-            }
-
-            row += 1;
-            y += row_height;
+    let mut terminal = visp::io::setup_terminal()?;
+    state.theme = Theme::new(ColorSupport::detect());
+    let config = if noconfig { Ok(()) } else { visp::config::load(&mut state) };
+    state.options.readonly |= readonly;
+    state.oldfiles = Some(visp::oldfiles::load());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    state.tasks.sender = Some(sender.clone());
+
+    if let Some([base, ours, theirs]) = &merge {
+        // Like git merge-file the result goes into ours without -o
+        let result = output.as_ref().unwrap_or(ours);
+        if let Err(e) = visp::merge::start(&mut state, Path::new(base), Path::new(ours), Path::new(theirs), Path::new(result)) {
+            state.message = Some(Message::Error(e.to_string()));
+        }
+    } else if let Some([old, new]) = &diff {
+        if let Err(e) = visp::diff::start(&mut state, Path::new(old), Path::new(new)) {
+            state.message = Some(Message::Error(e.to_string()));
         }
+    } else if !files.is_empty() {
+        visp::commands::open_files(&mut state, &files);
+    } else if !stream {
+        visp::commands::dispatch(&mut state, "intro");
     }
-}
-
-
-fn ui<B: Backend>(f: &mut Frame<B>, state: &AppState) {
-   let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .margin(0)
-        .constraints(
-            [
-                Constraint::Max(10000),
-                Constraint::Length(1),
-            ].as_ref()
-        )
-        .split(f.size());
-
-    let table = Table {content: &state.table_content};
-    f.render_widget(table, chunks[0]);
-
-    let command_line = Paragraph::new("Command");
-    f.render_widget(command_line, chunks[1]);
+    if let Some(Message::Error(e)) = &state.message {
+        tracing::warn!("{}", e);
+    }
+    // A broken config shouldn't keep the file from opening, so it is only reported
+    if let Err(e) = config {
+        tracing::warn!("{}", e);
+        state.message = Some(Message::Error(e.to_string()));
+    }
+    visp::io::run(&mut terminal, &mut state, sender, receiver, stream)
 }
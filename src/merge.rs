@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::{csv, edit, lock, sheet, window, AppState, Message, Result, VispError};
+use crate::grid::{cell_name, Selection, TableCell, TableContent};
+use crate::sheet::Sheet;
+use crate::task::Progress;
+
+// visp --merge base.csv ours.csv theirs.csv [-o merged.csv], e.g. as the
+// mergetool of git:
+//   [mergetool "visp"]
+//       cmd = visp --merge "$BASE" "$LOCAL" "$REMOTE" -o "$MERGED"
+// Cells are merged by their position. Those changed on one side get that
+// change, those changed differently on both sides are conflicts which keep
+// ours until co, ct or :diffget takes a side or the cell is edited. The rows
+// have to line up: rows added at the end are fine, but a row inserted in
+// between looks like a change of every cell below it.
+pub struct Merge {
+    pub sheet: String, // Name of the sheet with the result
+    conflicts: BTreeMap<(u16, u16), Conflict>,
+}
+
+pub struct Conflict {
+    pub base: TableCell,
+    pub ours: TableCell,
+    pub theirs: TableCell,
+    taken: bool, // Ours was taken with co or :diffget
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Base,
+    Ours,
+    Theirs,
+}
+
+impl Side {
+    // Like the buffers of vim's :diffget in git's mergetool
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "base" => Some(Self::Base),
+            "ours" | "local" => Some(Self::Ours),
+            "theirs" | "remote" => Some(Self::Theirs),
+            _ => None,
+        }
+    }
+}
+
+impl Merge {
+    // Still holds ours and wasn't taken
+    fn open(&self, content: &TableContent, position: (u16, u16)) -> Option<&Conflict> {
+        let conflict = self.conflicts.get(&position)?;
+        let cell = content.get_cell(position.0, position.1).unwrap_or(&TableCell::Empty);
+        (!conflict.taken && cell.same_source(&conflict.ours)).then_some(conflict)
+    }
+
+    // For drawing
+    pub fn is_open(&self, content: &TableContent, position: (u16, u16)) -> bool {
+        self.open(content, position).is_some()
+    }
+}
+
+// Reads the three files into sheets of their own and puts the result into the
+// current one, which :w writes to `output`
+pub fn start(state: &mut AppState, base: &Path, ours: &Path, theirs: &Path, output: &Path) -> Result<()> {
+    let (delimiter, locale) = (state.options.delimiter(), state.options.locale());
+    let read = |path: &Path| {
+        csv::read(path, delimiter, locale, &Progress::default())
+            .map_err(|e| VispError::Command(format!("\"{}\" not read: {}", path.display(), e)))
+    };
+    let (base, _) = read(base)?;
+    let (ours, encoding) = read(ours)?;
+    let (theirs, _) = read(theirs)?;
+    let sides = [base, ours, theirs].map(TableContent::from_rows);
+
+    let positions: BTreeSet<(u16, u16)> = sides.iter().flat_map(|side| side.iter().map(|(position, _)| position)).collect();
+    let mut cells = Vec::new();
+    let mut conflicts = BTreeMap::new();
+    let mut taken = 0;
+    for position in positions {
+        let [base, ours, theirs] = sides.each_ref().map(|side| side.get_cell(position.0, position.1).cloned().unwrap_or(TableCell::Empty));
+        if ours.same_source(&theirs) || theirs.same_source(&base) {
+            cells.push((position, ours));
+        } else if ours.same_source(&base) {
+            taken += 1;
+            cells.push((position, theirs));
+        } else {
+            cells.push((position, ours.clone()));
+            conflicts.insert(position, Conflict { base, ours, theirs, taken: false });
+        }
+    }
+
+    let mut result = TableContent::default();
+    result.set_cells(cells);
+    state.table_content = result;
+    state.options.fileencoding = encoding.to_string();
+    state.file = Some(output.to_path_buf());
+    // Nothing is written until :w
+    state.saved_revision = 0;
+    state.undo.clear();
+    let name = sheet::usable_name(state, "Merged");
+    sheet::rename(state, &name)?;
+    for (name, content) in ["Base", "Ours", "Theirs"].into_iter().zip(sides) {
+        let mut sheet = Sheet::new(&sheet::usable_name(state, name));
+        sheet.saved_revision = content.revision;
+        sheet.content = content;
+        state.sheets.push(sheet);
+    }
+    window::clamp(state);
+    sheet::link(state);
+    lock::update(state);
+
+    let count = conflicts.len();
+    state.merge = Some(Merge { sheet: name, conflicts });
+    state.message = Some(Message::Info(format!(
+        "{} cells taken from theirs, {} conflicts, ]c goes to the next, co and ct take ours or theirs",
+        taken, count
+    )));
+    if count > 0 {
+        next(state, true, None);
+    }
+    Ok(())
+}
+
+// The merge, if the current sheet holds its result
+pub fn current(state: &AppState) -> Option<&Merge> {
+    state.merge.as_ref().filter(|merge| state.sheets[state.sheet].name == merge.sheet)
+}
+
+// ]c and [c, like in vim's diff mode, to the next conflict which is still open
+pub fn next(state: &mut AppState, forward: bool, count: Option<u32>) {
+    let Some(merge) = current(state) else {
+        state.message = Some(Message::Error("Nothing to compare, see visp --merge and visp --diff".to_string()));
+        return;
+    };
+    let content = &state.table_content;
+    let cursor = content.selection.cursor();
+    let open = merge.conflicts.keys().filter(|&&position| merge.is_open(content, position));
+    let count = count.unwrap_or(1).max(1) as usize;
+    let found = if forward {
+        open.filter(|&&position| position > cursor).nth(count - 1)
+    } else {
+        open.rev().filter(|&&position| position < cursor).nth(count - 1)
+    };
+    match found.copied() {
+        Some((row, col)) => state.table_content.selection.set_cursor(row, col),
+        None => state.message = Some(Message::Error("No more conflicts".to_string())),
+    }
+}
+
+// The conflict under the cursor, shown like a note
+pub fn describe(state: &AppState) -> Option<String> {
+    let merge = current(state)?;
+    let conflict = merge.open(&state.table_content, state.table_content.selection.cursor())?;
+    Some(format!(
+        "Conflict: ours {}, theirs {}, base {}",
+        shown_value(&conflict.ours),
+        shown_value(&conflict.theirs),
+        shown_value(&conflict.base)
+    ))
+}
+
+fn shown_value(cell: &TableCell) -> String {
+    match cell {
+        TableCell::Empty => "empty".to_string(),
+        cell => format!("\"{}\"", cell.source_string()),
+    }
+}
+
+// Whether co and ct take a side at `position`
+pub fn is_open(state: &AppState, position: (u16, u16)) -> bool {
+    current(state).is_some_and(|merge| merge.is_open(&state.table_content, position))
+}
+
+// co, ct and :diffget. Every open conflict in `range` gets the cell of `side`,
+// as one change which can be undone.
+pub fn take(state: &mut AppState, side: Side, range: Selection) -> Result<()> {
+    let merge = current(state).ok_or_else(|| VispError::Command("Not merging, see visp --merge".to_string()))?;
+    let content = &state.table_content;
+    let (top, left, bottom, right) = range.bounds();
+    let positions: Vec<(u16, u16)> = merge.conflicts.range((top, 0)..=(bottom, u16::MAX))
+        .map(|(&position, _)| position)
+        .filter(|&(_, col)| (left..=right).contains(&col))
+        .filter(|&position| merge.is_open(content, position))
+        .collect();
+    if positions.is_empty() {
+        return Err(VispError::Command(format!("No conflict in {}", range.name())));
+    }
+    let cells: Vec<_> = positions.iter().map(|position| {
+        let conflict = &merge.conflicts[position];
+        let cell = match side {
+            Side::Base => conflict.base.clone(),
+            Side::Ours => conflict.ours.clone(),
+            Side::Theirs => conflict.theirs.clone(),
+        };
+        (*position, cell)
+    }).collect();
+    // Ours is in the cells already, it is only marked as resolved
+    if side == Side::Ours {
+        edit::check_writable(&state.options)?;
+        let merge = state.merge.as_mut().unwrap();
+        for position in &positions {
+            merge.conflicts.get_mut(position).unwrap().taken = true;
+        }
+    } else {
+        edit::replace_cells(state, cells)?;
+    }
+    let remaining = current(state).map_or(0, |merge| merge.conflicts.keys().filter(|&&p| merge.is_open(&state.table_content, p)).count());
+    state.message = Some(Message::Info(match (positions.as_slice(), remaining) {
+        ([(row, col)], 0) => format!("{} resolved, no conflicts left, :w writes the result", cell_name(*row, *col)),
+        (_, 0) => format!("{} conflicts resolved, none left, :w writes the result", positions.len()),
+        ([(row, col)], remaining) => format!("{} resolved, {} conflicts left", cell_name(*row, *col), remaining),
+        (_, remaining) => format!("{} conflicts resolved, {} left", positions.len(), remaining),
+    }));
+    Ok(())
+}
+
+// :diffget ours|theirs|base
+pub fn diffget(state: &mut AppState, range: Selection, side: &str) -> Result<()> {
+    let side = Side::parse(side.trim()).ok_or_else(|| VispError::Command("Usage: diffget ours|theirs|base".to_string()))?;
+    take(state, side, range)
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::AppState;
+
+// Files kept in the list, like the default of vim's viminfo
+const SIZE: usize = 100;
+
+// $XDG_STATE_HOME/visp/oldfiles with ~/.local/state as the default, one path
+// per line, newest first
+pub fn path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))?;
+    Some(dir.join("visp").join("oldfiles"))
+}
+
+// The list as it was left by the last visp, empty if there is none yet
+pub fn load() -> Vec<PathBuf> {
+    let text = path().and_then(|path| fs::read_to_string(path).ok()).unwrap_or_default();
+    text.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect()
+}
+
+// Puts a file which was opened at the top of the list. The list is read
+// again first, so files opened by other visps running at the same time
+// aren't lost. Nothing is kept while state.oldfiles is None, e.g. in batch
+// mode.
+pub fn remember(state: &mut AppState, file: &Path) {
+    if state.oldfiles.is_none() {
+        return;
+    }
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    let mut files = load();
+    files.retain(|other| *other != file);
+    files.insert(0, file);
+    files.truncate(SIZE);
+    if let Some(path) = path() {
+        let text: String = files.iter().map(|file| format!("{}\n", file.display())).collect();
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(&path, text));
+        if let Err(e) = written {
+            tracing::warn!("{} not written: {}", path.display(), e);
+        }
+    }
+    state.oldfiles = Some(files);
+}
+
+// The recent files which still exist, for the start screen
+pub fn existing(state: &AppState, count: usize) -> Vec<PathBuf> {
+    state.oldfiles.iter().flatten().filter(|file| file.exists()).take(count).cloned().collect()
+}
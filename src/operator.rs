@@ -0,0 +1,169 @@
+use crate::{edit, input, merge, register, AppState, Message};
+use crate::grid::{Axis, Selection};
+use crate::keymap::{Action, Operator};
+use crate::register::BlockKind;
+
+// d, y and c wait for a motion and work on the cells it goes over, like in
+// vim: d3j deletes the row and the three below, y$ yanks the rest of the row
+// and cw empties the cells up to the next block and edits the first one.
+// Motions up and down take whole rows, the others cells in the row, and like
+// in vim h, l, w, b and 0 stop before the cell they go to. Typed twice, dd
+// and yy take count rows and cc changes the cell like s. After d and y, r
+// and c take count whole rows or columns, so dr and dc still delete them.
+// On a conflict of visp --merge co and ct take ours or theirs, like
+// conflict-marker.vim does.
+#[derive(Clone, Copy)]
+pub struct Pending {
+    operator: Operator,
+    count: Option<u32>,
+    start: (u16, u16), // The cursor when the operator was typed
+}
+
+pub fn start(state: &mut AppState, operator: Operator, count: Option<u32>) {
+    let start = state.table_content.selection.cursor();
+    state.operator = Some(Pending { operator, count, start });
+}
+
+// Counts before the operator and before the motion multiply, 2d3j is d6j
+fn total(pending: &Pending, count: Option<u32>) -> Option<u32> {
+    match (pending.count, count) {
+        (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn lines(count: Option<u32>) -> u16 {
+    count.unwrap_or(1).clamp(1, u16::MAX as u32) as u16
+}
+
+// Called with the action of the keys typed after the operator. Anything but a
+// motion cancels it, like in vim.
+pub fn motion(state: &mut AppState, pending: Pending, action: Action, count: Option<u32>) {
+    let count = total(&pending, count);
+    let (row, _) = pending.start;
+    let (rows, inclusive) = match action {
+        Action::Operator(Operator::Change) if pending.operator == Operator::Change => {
+            return edit::start_insert(state, true);
+        }
+        Action::Operator(operator) if operator == pending.operator => {
+            let selection = Selection { row, rows: lines(count), cols: u16::MAX, ..Selection::default() };
+            return operate(state, pending.operator, BlockKind::Rows, selection);
+        }
+        // The character comes with the next key, see after_find
+        Action::Find(_) => {
+            input::perform(state, action, count);
+            state.operator = Some(pending);
+            return;
+        }
+        Action::RepeatFind | Action::RepeatFindReverse => {
+            let vertical = state.last_find.is_some_and(|(find, _)| find.vertical);
+            input::perform(state, action, count);
+            return after_find(state, pending, vertical);
+        }
+        Action::MoveUp | Action::MoveDown | Action::GotoRow { .. } => (true, true),
+        Action::NextBlock { vertical: true, .. } => (true, false),
+        Action::MoveLeft | Action::MoveRight | Action::GotoCol { last: false } | Action::NextBlock { vertical: false, .. } => (false, false),
+        Action::GotoCol { last: true } => (false, true),
+        _ => return,
+    };
+    input::perform(state, action, count);
+    over(state, pending, rows, inclusive);
+}
+
+// After the character of f or t, or after ; and ,. They didn't find anything
+// if the cursor is still where it was.
+pub fn after_find(state: &mut AppState, pending: Pending, vertical: bool) {
+    if state.table_content.selection.cursor() != pending.start {
+        over(state, pending, vertical, true);
+    }
+}
+
+// r and c typed right after d or y, or o and t after c on a conflict, before
+// they are looked up in the keymap. Returns whether the key was one of them.
+pub fn axis_key(state: &mut AppState, pending: Pending, c: char) -> bool {
+    let (row, col) = pending.start;
+    if pending.operator == Operator::Change && matches!(c, 'o' | 't') && merge::is_open(state, pending.start) {
+        state.operator = None;
+        state.count = None;
+        let side = if c == 'o' { merge::Side::Ours } else { merge::Side::Theirs };
+        if let Err(e) = merge::take(state, side, Selection { row, col, ..Selection::default() }) {
+            state.message = Some(Message::Error(e.to_string()));
+        }
+        return true;
+    }
+    let count = lines(total(&pending, state.count));
+    let (kind, selection) = match (pending.operator, c) {
+        (Operator::Delete | Operator::Yank, 'r') => {
+            (BlockKind::Rows, Selection { row, rows: count, cols: u16::MAX, ..Selection::default() })
+        }
+        (Operator::Delete | Operator::Yank, 'c') => {
+            (BlockKind::Columns, Selection { col, cols: count, rows: u16::MAX, ..Selection::default() })
+        }
+        _ => return false,
+    };
+    state.operator = None;
+    state.count = None;
+    operate(state, pending.operator, kind, selection);
+    state.register = None;
+    true
+}
+
+// Works on the rows or cells in the row between where the operator was typed
+// and where the motion went
+fn over(state: &mut AppState, pending: Pending, rows: bool, inclusive: bool) {
+    let (row, col) = state.table_content.selection.cursor();
+    let (start_row, start_col) = pending.start;
+    let (from, to) = if rows { (start_row, row) } else { (start_col, col) };
+    let (first, mut last) = (from.min(to), from.max(to));
+    if !inclusive {
+        if from == to {
+            return;
+        }
+        last -= 1;
+    }
+    let selection = if rows {
+        Selection { row: first, rows: last - first + 1, cols: u16::MAX, ..Selection::default() }
+    } else {
+        Selection { row: start_row, col: first, cols: last - first + 1, ..Selection::default() }
+    };
+    operate(state, pending.operator, if rows { BlockKind::Rows } else { BlockKind::Cells }, selection);
+}
+
+// The cursor ends up at the start of what was worked on
+fn operate(state: &mut AppState, operator: Operator, kind: BlockKind, selection: Selection) {
+    let (row, col) = state.table_content.selection.cursor();
+    let (row, col) = match kind {
+        BlockKind::Rows => (selection.row, col),
+        BlockKind::Columns => (row, selection.col),
+        BlockKind::Cells => (selection.row, selection.col),
+    };
+    state.table_content.selection.set_cursor(row, col);
+    let result = match (operator, kind) {
+        (Operator::Yank, _) => {
+            register::yank_block(state, kind, selection, false);
+            Ok(())
+        }
+        (Operator::Delete, BlockKind::Rows) => {
+            register::delete_lines(state, Axis::Rows, selection.row, selection.rows);
+            Ok(())
+        }
+        (Operator::Delete, BlockKind::Columns) => {
+            register::delete_lines(state, Axis::Cols, selection.col, selection.cols);
+            Ok(())
+        }
+        (Operator::Delete, BlockKind::Cells) => register::delete_cells(state, selection),
+        // Rows keep their place and only lose their content
+        (Operator::Change, _) => {
+            let cols = state.table_content.used_cols();
+            let selection = if kind == BlockKind::Rows { Selection { col: 0, cols, ..selection } } else { selection };
+            let result = register::delete_cells(state, selection);
+            if result.is_ok() {
+                edit::start_insert(state, true);
+            }
+            result
+        }
+    };
+    if let Err(e) = result {
+        state.message = Some(Message::Error(e.to_string()));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{autosave, encoding, fetch, format, Result, VispError};
+use crate::format::Locale;
+use crate::grid::DEFAULT_COL_WIDTH;
+
+// Settings changed with :set
+pub struct Options {
+    pub number: bool,
+    pub relativenumber: bool,
+    pub header: bool, // The last row of the header block holds the column names
+    pub headerrows: u16, // Rows at the top which are styled as a header block
+    pub colwidth: u16, // Of columns which weren't resized
+    pub undolevels: u16, // Number of changes which can be undone, like in vim
+    pub undomemory: u16, // MiB the undo history may use before old changes are forgotten
+    pub freezeheader: bool, // Keep the header block on screen when scrolling
+    pub wholecell: bool, // * and # only find cells with exactly the same text
+    pub protect: bool, // Refuse edits to ranges marked with :protect
+    pub readonly: bool, // Refuse all edits and ask before :w! replaces the file, set by -R
+    pub trackchanges: bool, // Highlight cells changed since the option was set
+    // Placeholders: %mode %file %cell %sel-sum and %% for a literal %
+    pub statusline: String,
+    pub delimiter: String, // Between the fields of CSV files, a single character or "tab"
+    pub csvquote: String, // Which fields of CSV files are quoted: minimal, always or never
+    pub fileformat: String, // Line breaks of written CSV files, unix for LF or dos for CRLF
+    pub endofline: bool, // Write a line break after the last row of CSV files
+    pub fileencoding: String, // Of written CSV files, set to what a file was read in, see encoding::ENCODINGS
+    pub lazycalc: bool, // Only calculate formulas on screen and what they read, the rest when needed
+    pub autosave: String, // When to write the file without :w, see autosave::EVENTS
+    pub autosaveinterval: u16, // Seconds a change waits for autosave=interval
+    pub refresh: String, // How often :fetch runs again, e.g. 60s, 5m or 1h, empty for never
+    pub locale: String, // Of numbers in CSV files, see format::LOCALES, empty for 1234.56
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            number: true,
+            relativenumber: false,
+            header: false,
+            headerrows: 0,
+            colwidth: DEFAULT_COL_WIDTH,
+            undolevels: 1000,
+            undomemory: 256,
+            freezeheader: false,
+            wholecell: true,
+            protect: true,
+            readonly: false,
+            trackchanges: false,
+            statusline: "%mode  %file  %cell %sel-size  %content  %sel-sum".to_string(),
+            delimiter: ",".to_string(),
+            csvquote: "minimal".to_string(),
+            fileformat: "unix".to_string(),
+            endofline: true,
+            fileencoding: "utf-8".to_string(),
+            lazycalc: false,
+            autosave: String::new(),
+            autosaveinterval: 30,
+            refresh: String::new(),
+            locale: String::new(),
+        }
+    }
+}
+
+impl Options {
+    // Size of the header block, the column names count as one row of it
+    pub fn header_rows(&self) -> u16 {
+        if self.header { self.headerrows.max(1) } else { self.headerrows }
+    }
+
+    pub fn delimiter(&self) -> char {
+        parse_delimiter(&self.delimiter).unwrap_or(',')
+    }
+
+    pub fn locale(&self) -> Option<&'static Locale> {
+        Locale::find(&self.locale)
+    }
+
+    pub fn line_break(&self) -> &'static str {
+        if self.fileformat == "dos" { "\r\n" } else { "\n" }
+    }
+
+    // Handles one argument of :set like vim does: "name", "noname", "name!",
+    // "name?" or "name=value". Returns text to show, if any.
+    pub fn set(&mut self, argument: &str) -> Result<Option<String>> {
+        if let Some(name) = argument.strip_suffix('?') {
+            return Ok(Some(self.show(name)?));
+        }
+        if let Some((name, value)) = argument.split_once('=') {
+            return self.set_value(name, value).map(|_| None);
+        }
+        // Values of other options are shown when they are named alone
+        if self.string_option(argument).is_some() || self.number_option(argument).is_some() {
+            return Ok(Some(self.show(argument)?));
+        }
+        if let Some(name) = argument.strip_suffix('!') {
+            let option = self.bool_option(name)?;
+            *option = !*option;
+        } else if let Some(option) = argument.strip_prefix("no").and_then(|name| self.bool_option(name).ok()) {
+            *option = false;
+        } else {
+            *self.bool_option(argument)? = true;
+        }
+        Ok(None)
+    }
+
+    fn bool_option(&mut self, name: &str) -> Result<&mut bool> {
+        match name {
+            "nu" | "number" => Ok(&mut self.number),
+            "rnu" | "relativenumber" => Ok(&mut self.relativenumber),
+            "header" => Ok(&mut self.header),
+            "fh" | "freezeheader" => Ok(&mut self.freezeheader),
+            "wc" | "wholecell" => Ok(&mut self.wholecell),
+            "prot" | "protect" => Ok(&mut self.protect),
+            "ro" | "readonly" => Ok(&mut self.readonly),
+            "tc" | "trackchanges" => Ok(&mut self.trackchanges),
+            "eol" | "endofline" => Ok(&mut self.endofline),
+            "lc" | "lazycalc" => Ok(&mut self.lazycalc),
+            _ => Err(unknown_option(name)),
+        }
+    }
+
+    fn number_option(&mut self, name: &str) -> Option<&mut u16> {
+        match name {
+            "hr" | "headerrows" => Some(&mut self.headerrows),
+            "cw" | "colwidth" => Some(&mut self.colwidth),
+            "ul" | "undolevels" => Some(&mut self.undolevels),
+            "um" | "undomemory" => Some(&mut self.undomemory),
+            "asi" | "autosaveinterval" => Some(&mut self.autosaveinterval),
+            _ => None,
+        }
+    }
+
+    fn string_option(&mut self, name: &str) -> Option<&mut String> {
+        match name {
+            "stl" | "statusline" => Some(&mut self.statusline),
+            "delim" | "delimiter" => Some(&mut self.delimiter),
+            "cq" | "csvquote" => Some(&mut self.csvquote),
+            "ff" | "fileformat" => Some(&mut self.fileformat),
+            "as" | "autosave" => Some(&mut self.autosave),
+            "fenc" | "fileencoding" => Some(&mut self.fileencoding),
+            "rf" | "refresh" => Some(&mut self.refresh),
+            "loc" | "locale" => Some(&mut self.locale),
+            _ => None,
+        }
+    }
+
+    fn set_value(&mut self, name: &str, value: &str) -> Result<()> {
+        if matches!(name, "delim" | "delimiter") {
+            parse_delimiter(value)?;
+        }
+        if matches!(name, "cq" | "csvquote") && !matches!(value, "minimal" | "always" | "never") {
+            return Err(VispError::Parse(format!("csvquote must be minimal, always or never: {}", value)));
+        }
+        if matches!(name, "ff" | "fileformat") && !matches!(value, "unix" | "dos") {
+            return Err(VispError::Parse(format!("fileformat must be unix or dos: {}", value)));
+        }
+        if matches!(name, "as" | "autosave") && !value.is_empty() {
+            if let Some(event) = value.split(',').find(|e| !autosave::EVENTS.contains(e)) {
+                return Err(VispError::Parse(format!("autosave takes interval, modechange and focus: {}", event)));
+            }
+        }
+        if matches!(name, "fenc" | "fileencoding") && !encoding::ENCODINGS.contains(&value) {
+            return Err(VispError::Parse(format!("fileencoding must be one of {}: {}", encoding::ENCODINGS.join(", "), value)));
+        }
+        if matches!(name, "loc" | "locale") && !value.is_empty() && Locale::find(value).is_none() {
+            let names: Vec<&str> = format::LOCALES.iter().map(|locale| locale.name).collect();
+            return Err(VispError::Parse(format!("locale must be one of {}: {}", names.join(", "), value)));
+        }
+        if matches!(name, "rf" | "refresh") {
+            fetch::interval(value)?;
+        }
+        if matches!(name, "cw" | "colwidth") && value == "0" {
+            return Err(VispError::Command("Column width must be at least 1".to_string()));
+        }
+        if let Some(option) = self.string_option(name) {
+            *option = value.to_string();
+            return Ok(());
+        }
+        if let Some(option) = self.number_option(name) {
+            *option = value.parse().map_err(|_| VispError::Parse(format!("Number required after =: {}", value)))?;
+            return Ok(());
+        }
+        match self.bool_option(name) {
+            Ok(_) => Err(VispError::Command(format!("Option takes no value: {}", name))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn show(&mut self, name: &str) -> Result<String> {
+        if let Some(value) = self.string_option(name) {
+            return Ok(format!("{}={}", name, value));
+        }
+        if let Some(value) = self.number_option(name) {
+            return Ok(format!("{}={}", name, value));
+        }
+        let value = *self.bool_option(name)?;
+        Ok(format!("{}{}", if value { "" } else { "no" }, name))
+    }
+}
+
+fn parse_delimiter(value: &str) -> Result<char> {
+    let mut chars = value.chars();
+    match (value, chars.next(), chars.next()) {
+        ("tab", _, _) => Ok('\t'),
+        (_, Some(c), None) if c != '"' && c != '\n' => Ok(c),
+        _ => Err(VispError::Parse(format!("Delimiter must be a single character or tab: {}", value))),
+    }
+}
+
+fn unknown_option(name: &str) -> VispError {
+    VispError::Command(format!("Unknown option: {}", name))
+}
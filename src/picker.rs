@@ -0,0 +1,106 @@
+use crate::AppMode;
+use crate::grid::Selection;
+
+// List to choose an entry from, narrowed down by fuzzy matching what is typed
+pub struct Picker {
+    pub title: String,
+    pub items: Vec<String>,
+    pub query: String,
+    pub matches: Vec<usize>, // Items matching the query, best first
+    pub index: usize, // Highlighted entry in `matches`
+    pub kind: PickerKind,
+}
+
+// What happens with the chosen entry
+pub enum PickerKind {
+    Selection(Vec<(AppMode, Selection)>),
+    Command,
+    File(Vec<std::path::PathBuf>), // Opened with :e
+    Directory(std::path::PathBuf, Vec<std::path::PathBuf>), // Like File, kept up to date with the files in it
+}
+
+impl Picker {
+    pub fn new(title: &str, items: Vec<String>, kind: PickerKind) -> Self {
+        let mut picker = Self {
+            title: title.to_string(),
+            items,
+            query: String::new(),
+            matches: Vec::new(),
+            index: 0,
+            kind,
+        };
+        picker.update_matches();
+        picker
+    }
+
+    pub fn push_query(&mut self, c: char) {
+        self.query.push(c);
+        self.update_matches();
+    }
+
+    pub fn pop_query(&mut self) {
+        self.query.pop();
+        self.update_matches();
+    }
+
+    pub fn next(&mut self) {
+        self.index = (self.index + 1).min(self.matches.len().saturating_sub(1));
+    }
+
+    pub fn previous(&mut self) {
+        self.index = self.index.saturating_sub(1);
+    }
+
+    // Keeps the query and the highlighted entry, if it is still there
+    pub fn set_items(&mut self, items: Vec<String>) {
+        let selected = self.selected().map(|i| self.items[i].clone());
+        self.items = items;
+        self.update_matches();
+        self.index = self.matches.iter().position(|&i| Some(&self.items[i]) == selected.as_ref()).unwrap_or(0);
+    }
+
+    // Index into `items` of the highlighted entry
+    pub fn selected(&self) -> Option<usize> {
+        self.matches.get(self.index).copied()
+    }
+
+    fn update_matches(&mut self) {
+        let mut scored: Vec<(i32, usize)> = self.items.iter().enumerate()
+            .filter_map(|(i, item)| fuzzy_score(&self.query, item).map(|score| (score, i)))
+            .collect();
+        // Stable, so equally good matches keep their original order
+        scored.sort_by_key(|(score, _)| -score);
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.index = 0;
+    }
+}
+
+// Scores how well `pattern` matches `text` when its characters appear in
+// order, but not necessarily next to each other. Consecutive characters and
+// matches at word starts score higher. None if it doesn't match at all.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<i32> {
+    let mut score = 0;
+    let mut text_chars = text.chars().enumerate().peekable();
+    let mut last_match: Option<usize> = None;
+    let mut previous_char = ' ';
+
+    for p in pattern.chars().filter(|c| !c.is_whitespace()) {
+        loop {
+            let (i, c) = text_chars.next()?;
+            let word_start = !previous_char.is_alphanumeric();
+            previous_char = c;
+            if c.to_lowercase().eq(p.to_lowercase()) {
+                score += 1;
+                if last_match.map(|l| l + 1 == i).unwrap_or(false) {
+                    score += 5;
+                }
+                if word_start {
+                    score += 3;
+                }
+                last_match = Some(i);
+                break;
+            }
+        }
+    }
+    Some(score)
+}
@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::Path;
+
+use tui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+use crate::{AppState, Result};
+use crate::render::{Table, Viewport};
+
+// Lines per page, including the page header
+const PAGE_LINES: u16 = 66;
+
+// Writes the used part of the table as plain text, laid out the same way as on
+// screen and split into pages separated by form feeds
+pub fn print(state: &AppState, path: &Path) -> Result<usize> {
+    let content = &state.table_content;
+    let mut viewport = Viewport::default();
+    let width = (0..content.used_cols())
+        .map(|col| viewport.col_width(content, col) as u32)
+        .sum::<u32>()
+        .saturating_add(viewport.header_width() as u32)
+        .min(u16::MAX as u32) as u16;
+
+    // Page header, column header and at least one row
+    let rows_height = PAGE_LINES - 2;
+    let mut pages = Vec::new();
+    while viewport.row < content.used_rows() {
+        let mut end = viewport.row;
+        let mut height = 0;
+        while end < content.used_rows() && (end == viewport.row || height + content.row_height(end) <= rows_height) {
+            height += content.row_height(end);
+            end += 1;
+        }
+        // The column header takes one line
+        let area = Rect::new(0, 0, width, height + 1);
+        let mut buffer = Buffer::empty(area);
+        let table = Table {
+            content,
+            viewport: &viewport,
+            options: &state.options,
+            theme: &state.theme,
+            baseline: None,
+            search: None,
+            edit: None,
+            references: &[],
+            peer_cursor: None,
+            merge: None,
+            diff: None,
+        };
+        table.render(area, &mut buffer);
+        pages.push(buffer_lines(&buffer));
+        viewport.row = end;
+    }
+
+    let page_count = pages.len();
+    let mut text = String::new();
+    for (i, lines) in pages.into_iter().enumerate() {
+        if i > 0 {
+            text.push('\x0c');
+        }
+        text += &format!("{:>width$}\n", format!("Page {} of {}", i + 1, page_count), width = width as usize);
+        for line in lines {
+            text += &line;
+            text.push('\n');
+        }
+    }
+    fs::write(path, text)?;
+    Ok(page_count)
+}
+
+fn buffer_lines(buffer: &Buffer) -> Vec<String> {
+    let area = buffer.area;
+    (area.y..area.bottom())
+        .map(|y| {
+            let line: String = (area.x..area.right()).map(|x| buffer.get(x, y).symbol.as_str()).collect();
+            line.trim_end().to_string()
+        })
+        .collect()
+}
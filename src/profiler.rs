@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Collects how long drawing, input handling and commands take, see :profile
+#[derive(Default)]
+pub struct Profiler {
+    pub running: bool,
+    timings: BTreeMap<&'static str, Timing>,
+}
+
+#[derive(Default)]
+struct Timing {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl Profiler {
+    pub fn start(&mut self) {
+        self.timings.clear();
+        self.running = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn record(&mut self, what: &'static str, duration: Duration) {
+        if !self.running {
+            return;
+        }
+        let timing = self.timings.entry(what).or_default();
+        timing.count += 1;
+        timing.total += duration;
+        timing.max = timing.max.max(duration);
+    }
+
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![format!("{:<10} {:>8} {:>12} {:>12} {:>12}", "", "count", "total ms", "mean ms", "max ms")];
+        for (what, timing) in &self.timings {
+            let mean = timing.total / timing.count.max(1);
+            lines.push(format!(
+                "{:<10} {:>8} {:>12.3} {:>12.3} {:>12.3}",
+                what,
+                timing.count,
+                timing.total.as_secs_f64() * 1000.0,
+                mean.as_secs_f64() * 1000.0,
+                timing.max.as_secs_f64() * 1000.0,
+            ));
+        }
+        if self.timings.is_empty() {
+            lines.push("Nothing measured, start with :profile start".to_string());
+        }
+        lines
+    }
+}
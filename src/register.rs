@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use crate::{edit, formula, structure, AppState, AppMode, Message, Result, VispError};
+use crate::format::CellFormat;
+use crate::grid::{Axis, CellStyle, Selection, TableCell, TableContent};
+use crate::keymap::{key_names, KeyPress};
+
+// Where a block was copied from, which decides where p puts it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockKind {
+    Cells,
+    Rows, // From VisualRow, pasted below the cursor's row
+    Columns, // From VisualColumn, pasted right of the cursor's column
+}
+
+// Cells copied with y or d
+#[derive(Clone)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub cells: Vec<Vec<TableCell>>, // Row major, all rows are equally long
+    // By position in cells, only put by :pastespecial
+    pub formats: HashMap<(usize, usize), CellFormat>,
+    pub styles: HashMap<(usize, usize), CellStyle>,
+}
+
+impl Block {
+    fn size(&self) -> (usize, usize) {
+        (self.cells.len(), self.cells.first().map_or(0, Vec::len))
+    }
+
+    // Rows become columns and the other way around
+    fn transpose(self) -> Block {
+        let (rows, cols) = self.size();
+        let mut cells: Vec<Vec<TableCell>> = (0..cols).map(|_| Vec::with_capacity(rows)).collect();
+        for row in self.cells {
+            for (col, cell) in row.into_iter().enumerate() {
+                cells[col].push(cell);
+            }
+        }
+        let kind = match self.kind {
+            BlockKind::Cells => BlockKind::Cells,
+            BlockKind::Rows => BlockKind::Columns,
+            BlockKind::Columns => BlockKind::Rows,
+        };
+        Block {
+            kind,
+            cells,
+            formats: self.formats.into_iter().map(|((row, col), format)| ((col, row), format)).collect(),
+            styles: self.styles.into_iter().map(|((row, col), style)| ((col, row), style)).collect(),
+        }
+    }
+}
+
+// What :pastespecial takes from a block
+pub struct PasteSpecial {
+    pub values: bool, // Formulas as what they evaluated to when yanked
+    pub cells: bool, // Without it only the formats are put
+    pub formats: bool, // The formats and styles of the cells come along
+    pub transpose: bool,
+}
+
+// The named registers "a to "z and the unnamed one, which always holds the
+// last yanked or deleted block like in vim. Macros recorded with q have
+// registers of their own, so recording doesn't throw away copied cells.
+#[derive(Default)]
+pub struct Registers {
+    unnamed: Option<Block>,
+    named: HashMap<char, Block>,
+    macros: HashMap<char, Vec<KeyPress>>,
+    pub last_macro: Option<char>, // Played again with @@
+}
+
+impl Registers {
+    pub fn get(&self, name: Option<char>) -> Option<&Block> {
+        match name {
+            Some(name) if name != '"' => self.named.get(&name),
+            _ => self.unnamed.as_ref(),
+        }
+    }
+
+    fn set(&mut self, name: Option<char>, block: Block) {
+        if let Some(name) = name.filter(|&name| name != '"') {
+            self.named.insert(name, block.clone());
+        }
+        self.unnamed = Some(block);
+    }
+
+    // Text deleted or yanked in the cell editor, kept as a cell so p puts it
+    // into the table as well
+    pub fn set_text(&mut self, name: Option<char>, text: String) {
+        let cell = TableCell::String(text);
+        self.set(name, Block { kind: BlockKind::Cells, cells: vec![vec![cell]], formats: HashMap::new(), styles: HashMap::new() });
+    }
+
+    // For p in the cell editor, the first cell of a block as it is edited
+    pub fn text(&self, name: Option<char>) -> Option<String> {
+        self.get(name)?.cells.first()?.first().map(TableCell::source_string)
+    }
+
+    pub fn get_macro(&self, name: char) -> Option<&Vec<KeyPress>> {
+        self.macros.get(&name)
+    }
+
+    pub fn set_macro(&mut self, name: char, keys: Vec<KeyPress>) {
+        self.macros.insert(name, keys);
+    }
+
+    // For :registers, the unnamed one first and macros last
+    pub fn lines(&self) -> Vec<String> {
+        let mut names: Vec<char> = self.named.keys().copied().collect();
+        names.sort();
+        let describe = |name: char, block: &Block| {
+            let (rows, cols) = block.size();
+            let first = block.cells.first().and_then(|r| r.first()).map(TableCell::source_string).unwrap_or_default();
+            format!("\"{}  {:?} {}x{}  {}", name, block.kind, rows, cols, first)
+        };
+        let mut macros: Vec<char> = self.macros.keys().copied().collect();
+        macros.sort();
+        self.unnamed.iter().map(|block| describe('"', block))
+            .chain(names.into_iter().map(|name| describe(name, &self.named[&name])))
+            .chain(macros.into_iter().map(|name| format!("@{}  Macro  {}", name, key_names(&self.macros[&name]))))
+            .collect()
+    }
+}
+
+pub fn is_register_name(c: char) -> bool {
+    c == '"' || c.is_ascii_lowercase()
+}
+
+// What y and d copy in the mode
+fn selected_kind(mode: AppMode) -> BlockKind {
+    match mode {
+        AppMode::VisualRow => BlockKind::Rows,
+        AppMode::VisualColumn => BlockKind::Columns,
+        _ => BlockKind::Cells,
+    }
+}
+
+// Whole rows and columns end at the last cell in use
+fn block(content: &TableContent, kind: BlockKind, selection: Selection) -> Block {
+    let bottom = if kind == BlockKind::Columns { selection.bottom().min(content.used_rows() - 1) } else { selection.bottom() };
+    let right = if kind == BlockKind::Rows { selection.right().min(content.used_cols() - 1) } else { selection.right() };
+    let cells = (selection.row..=bottom)
+        .map(|row| (selection.col..=right).map(|col| content.get_cell(row, col).cloned().unwrap_or(TableCell::Empty)).collect())
+        .collect();
+    let inside = |&(row, col): &(u16, u16)| row >= selection.row && row <= bottom && col >= selection.col && col <= right;
+    let offset = |(row, col): (u16, u16)| ((row - selection.row) as usize, (col - selection.col) as usize);
+    Block {
+        kind,
+        cells,
+        formats: content.formats.iter().filter(|(p, _)| inside(p)).map(|(&p, format)| (offset(p), format.clone())).collect(),
+        styles: content.styles.iter().filter(|(p, _)| inside(p)).map(|(&p, &style)| (offset(p), style)).collect(),
+    }
+}
+
+// Like after y in vim the cursor goes to the start of the selection
+fn leave_visual(state: &mut AppState, row: u16, col: u16) {
+    state.remember_visual();
+    state.mode = AppMode::Normal;
+    state.table_content.selection.set_cursor(row, col);
+}
+
+// With `values` formulas are copied as their results, so p doesn't
+// calculate them again somewhere else
+pub fn yank(state: &mut AppState, values: bool) {
+    let selection = state.table_content.selection;
+    yank_block(state, selected_kind(state.mode), selection, values);
+    leave_visual(state, selection.row, selection.col);
+}
+
+// Copies `selection` into the register, also for operators like y$
+pub fn yank_block(state: &mut AppState, kind: BlockKind, selection: Selection, values: bool) {
+    if values {
+        formula::refresh_all(&mut state.table_content);
+    }
+    let mut block = block(&state.table_content, kind, selection);
+    if values {
+        for cell in block.cells.iter_mut().flatten() {
+            *cell = cell.computed();
+        }
+    }
+    let (rows, cols) = block.size();
+    state.registers.set(state.register, block);
+    let what = if values { "values" } else { "cells" };
+    state.message = Some(Message::Info(format!("{} {} yanked", rows * cols, what)));
+}
+
+// Yanks the selection and empties its cells. Whole rows and columns are
+// deleted instead.
+pub fn delete(state: &mut AppState) {
+    let selection = state.table_content.selection;
+    match state.mode {
+        AppMode::VisualRow => return delete_lines(state, Axis::Rows, selection.row, selection.rows),
+        AppMode::VisualColumn => return delete_lines(state, Axis::Cols, selection.col, selection.cols),
+        _ => {}
+    }
+    if let Err(e) = delete_cells(state, selection) {
+        state.message = Some(Message::Error(e.to_string()));
+        return;
+    }
+    leave_visual(state, selection.row, selection.col);
+}
+
+// Yanks the cells of `selection` and empties them
+pub fn delete_cells(state: &mut AppState, selection: Selection) -> Result<()> {
+    let block = block(&state.table_content, BlockKind::Cells, selection);
+    let cells = state.table_content.cells_in(selection.bounds()).map(|(cell, _)| (cell, TableCell::Empty)).collect();
+    edit::replace_cells(state, cells)?;
+    state.registers.set(state.register, block);
+    Ok(())
+}
+
+// Yanks `count` rows or columns from `at` on and deletes them, like dd in vim
+pub fn delete_lines(state: &mut AppState, axis: Axis, at: u16, count: u16) {
+    let (kind, selection) = match axis {
+        Axis::Rows => (BlockKind::Rows, Selection { row: at, rows: count, cols: u16::MAX, ..Selection::default() }),
+        Axis::Cols => (BlockKind::Columns, Selection { col: at, cols: count, rows: u16::MAX, ..Selection::default() }),
+    };
+    let block = block(&state.table_content, kind, selection);
+    if let Err(e) = structure::delete(state, axis, at, count) {
+        state.message = Some(Message::Error(e.to_string()));
+        return;
+    }
+    state.registers.set(state.register, block);
+    state.remember_visual();
+    state.mode = AppMode::Normal;
+}
+
+// Pastes after the cursor, or before it with `before`: rows are inserted
+// below or above the cursor's row, columns right or left of its column and
+// other blocks start at the cursor either way. In a visual mode the block
+// replaces cells from the start of the selection on. The table grows to make
+// room, cells past its largest possible size are left out.
+pub fn put(state: &mut AppState, before: bool) {
+    let block = match registered(state, state.register) {
+        Ok(block) => block,
+        Err(e) => {
+            state.message = Some(Message::Error(e.to_string()));
+            return;
+        }
+    };
+    let selection = state.table_content.selection;
+    let over = state.mode.is_visual();
+    let at = if over { (selection.row, selection.col) } else { selection.cursor() };
+    match paste(state, block, at, before, over, true, false) {
+        Ok((top, left)) => leave_visual(state, top, left),
+        Err(e) => state.message = Some(Message::Error(e.to_string())),
+    }
+}
+
+// :pastespecial, like p at the start of `range`. A range of more than one
+// cell is pasted over like in a visual mode, and so are formats alone.
+pub fn put_special(state: &mut AppState, range: Selection, before: bool, register: Option<char>, special: &PasteSpecial) -> Result<()> {
+    let mut block = registered(state, register)?;
+    if special.values {
+        for cell in block.cells.iter_mut().flatten() {
+            *cell = cell.computed();
+        }
+    }
+    if special.transpose {
+        block = block.transpose();
+    }
+    let over = range.rows != 1 || range.cols != 1 || !special.cells;
+    let (top, left) = paste(state, block, (range.row, range.col), before, over, special.cells, special.formats)?;
+    state.table_content.selection.set_cursor(top, left);
+    Ok(())
+}
+
+fn registered(state: &AppState, register: Option<char>) -> Result<Block> {
+    state.registers.get(register).cloned()
+        .ok_or_else(|| VispError::Command(format!("Nothing in register {}", register.unwrap_or('"'))))
+}
+
+// Puts the block at `at` as described for put, its cells with `cells` and
+// with `formats` its formats and styles, which replace those of the cells it
+// covers. Returns the top left cell of where it went.
+fn paste(state: &mut AppState, block: Block, (row, col): (u16, u16), before: bool, over: bool, cells: bool, formats: bool) -> Result<(u16, u16)> {
+    edit::check_writable(&state.options)?;
+    let after = !before && !over;
+    let (top, left) = match block.kind {
+        BlockKind::Cells => (row, col),
+        BlockKind::Rows => (if after { row.saturating_add(1) } else { row }, 0),
+        BlockKind::Columns => (0, if after { col.saturating_add(1) } else { col }),
+    };
+    let position = |r: usize, c: usize| Some((top.checked_add(u16::try_from(r).ok()?)?, left.checked_add(u16::try_from(c).ok()?)?));
+
+    // Rows and columns are put into new ones, not over the ones there
+    let (rows, cols) = block.size();
+    let mut replaced = Vec::new();
+    if cells {
+        for (r, block_row) in block.cells.into_iter().enumerate() {
+            for (c, cell) in block_row.into_iter().enumerate() {
+                if let Some(position) = position(r, c) {
+                    replaced.push((position, cell));
+                }
+            }
+        }
+    }
+    match block.kind {
+        BlockKind::Rows if !over => structure::insert_filled(state, Axis::Rows, top, rows as u16, replaced)?,
+        BlockKind::Columns if !over => structure::insert_filled(state, Axis::Cols, left, cols as u16, replaced)?,
+        _ => {
+            edit::replace_cells(state, replaced)?;
+        }
+    }
+
+    if formats {
+        let content = &mut state.table_content;
+        for r in 0..rows {
+            for c in 0..cols {
+                let Some(position) = position(r, c) else { continue };
+                match block.formats.get(&(r, c)) {
+                    Some(format) => content.formats.insert(position, format.clone()),
+                    None => content.formats.remove(&position),
+                };
+                match block.styles.get(&(r, c)) {
+                    Some(&style) => content.styles.insert(position, style),
+                    None => content.styles.remove(&position),
+                };
+            }
+        }
+        content.changed();
+    }
+    Ok((top, left))
+}
@@ -0,0 +1,877 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+use tui::{
+    backend::Backend,
+    widgets::{Widget, Paragraph, Block, Borders, Clear, List, ListItem, ListState},
+    layout::{Layout, Constraint, Direction, Rect},
+    buffer::{Buffer},
+    style::Style,
+    text::{Span, Spans},
+    Frame,
+};
+
+use crate::{diff, format, formula, merge, share, task, window, AppState, AppMode, Message, Pager};
+use crate::format::Align;
+use crate::formula::Bounds;
+use crate::calc::Calc;
+use crate::merge::Merge;
+use crate::edit::EditBuffer;
+use crate::picker::Picker;
+use crate::options::Options;
+use crate::search::Search;
+use crate::theme::Theme;
+use crate::grid::{col_nr_to_label, ColumnType, Selection, Snapshot, TableCell, TableContent, DEFAULT_COL_WIDTH, DEFAULT_ROW_HEIGHT};
+
+// Width of the row header column and height of the column header row
+const HEADER_WIDTH: u16 = DEFAULT_COL_WIDTH;
+const HEADER_HEIGHT: u16 = DEFAULT_ROW_HEIGHT;
+
+// Top left table cell which is visible on screen
+pub struct Viewport {
+    pub row: u16,
+    pub col: u16,
+    pub area: Rect, // Screen area of the last drawn table, including headers
+    pub compact: bool, // Overview with one character per cell
+    pub row_header: bool, // Whether the column with row numbers is shown
+    pub frozen_rows: u16, // Rows at the top which are always shown
+    pub cache: FormatCache,
+    cursor: (u16, u16), // Cursor position at the last draw
+}
+
+// Formatted text of the visible cells, kept between draws so that redrawing
+// and scrolling don't format every cell again
+#[derive(Default)]
+pub struct FormatCache {
+    revision: u64, // TableContent::revision the strings belong to
+    strings: HashMap<(u16, u16), String>,
+}
+
+impl FormatCache {
+    fn update(&mut self, content: &TableContent, rows: Range<u16>, cols: Range<u16>) {
+        if content.revision != self.revision {
+            self.strings.clear();
+            self.revision = content.revision;
+        }
+        self.strings.retain(|(row, col), _| rows.contains(row) && cols.contains(col));
+        for row in rows {
+            for col in cols.clone() {
+                if let Some(cell) = content.get_cell(row, col) {
+                    self.strings.entry((row, col)).or_insert_with(|| cell.display(content.formats.get(&(row, col))));
+                }
+            }
+        }
+    }
+
+    // Approximate number of bytes used
+    pub fn memory_size(&self) -> usize {
+        self.strings.values().map(|s| s.capacity() + std::mem::size_of::<((u16, u16), String)>()).sum()
+    }
+
+    fn get(&self, row: u16, col: u16) -> Option<&str> {
+        self.strings.get(&(row, col)).map(String::as_str)
+    }
+
+    // For cells whose values changed without a new revision
+    fn forget(&mut self, cells: &BTreeSet<(u16, u16)>) {
+        for cell in cells {
+            self.strings.remove(cell);
+        }
+    }
+}
+
+// Part of the table at a screen position
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GridPosition {
+    Corner,
+    ColumnHeader(u16),
+    RowHeader(u16),
+    Cell(u16, u16),
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            row: 0,
+            col: 0,
+            area: Rect::default(),
+            compact: false,
+            row_header: true,
+            frozen_rows: 0,
+            cache: FormatCache::default(),
+            cursor: (0, 0),
+        }
+    }
+}
+
+impl Viewport {
+    pub fn header_width(&self) -> u16 {
+        if self.row_header { HEADER_WIDTH } else { 0 }
+    }
+
+    pub fn col_width(&self, content: &TableContent, col: u16) -> u16 {
+        // Hidden columns and rows stay hidden
+        if self.compact { content.col_width(col).min(1) } else { content.col_width(col) }
+    }
+
+    pub fn row_height(&self, content: &TableContent, row: u16) -> u16 {
+        if self.compact { content.row_height(row).min(1) } else { content.row_height(row) }
+    }
+
+    // Table row shown at `index` below the column header
+    pub fn table_row(&self, index: u16) -> Option<u16> {
+        if index < self.frozen_rows {
+            Some(index)
+        } else {
+            self.first_row().checked_add(index - self.frozen_rows)
+        }
+    }
+
+    // First row below the frozen ones
+    fn first_row(&self) -> u16 {
+        self.row.max(self.frozen_rows)
+    }
+
+    fn frozen_height(&self, content: &TableContent) -> u16 {
+        (0..self.frozen_rows).map(|r| self.row_height(content, r) as u32).sum::<u32>().min(u16::MAX as u32) as u16
+    }
+
+    // Shows `content` with the same cells at the top left as `other`, for the
+    // old file of visp --diff
+    pub fn follow(&mut self, other: &Viewport, content: &TableContent, area: Rect) {
+        self.row = other.row;
+        self.col = other.col;
+        self.compact = other.compact;
+        self.row_header = other.row_header;
+        self.frozen_rows = other.frozen_rows;
+        self.area = area;
+        self.cursor = content.selection.cursor();
+        let (rows, cols) = (self.visible_rows(content), self.visible_cols(content));
+        self.cache.update(content, rows, cols);
+    }
+
+    // For a new window showing the same cells
+    pub fn split(&self) -> Self {
+        Self { row: self.row, col: self.col, compact: self.compact, ..Self::default() }
+    }
+
+    pub fn set_compact(&mut self, compact: bool) {
+        self.compact = compact;
+        // All sizes change, so make the next update bring the cursor into view
+        self.area = Rect::default();
+    }
+
+    // Called before every draw. Only follows the cursor if it moved or the
+    // table area changed, so scrolling without moving the cursor is possible.
+    pub fn update(&mut self, content: &TableContent, area: Rect) {
+        let cursor = content.selection.cursor();
+        if area != self.area || cursor != self.cursor {
+            self.scroll_to_selection(content, area);
+        }
+        self.area = area;
+        self.cursor = cursor;
+
+        let (rows, cols) = (self.visible_rows(content), self.visible_cols(content));
+        self.cache.update(content, rows, cols);
+    }
+
+    // Rows shown at the last draw
+    pub fn visible_rows(&self, content: &TableContent) -> Range<u16> {
+        let height = self.area.height.saturating_sub(HEADER_HEIGHT).saturating_sub(self.frozen_height(content));
+        visible(self.first_row(), height, |r| self.row_height(content, r))
+    }
+
+    pub fn visible_cols(&self, content: &TableContent) -> Range<u16> {
+        visible(self.col, self.area.width.saturating_sub(self.header_width()), |c| self.col_width(content, c))
+    }
+
+    pub fn scroll_rows(&mut self, delta: i32) {
+        self.row = (self.row as i32 + delta).clamp(0, u16::MAX as i32) as u16;
+    }
+
+    pub fn scroll_cols(&mut self, delta: i32) {
+        self.col = (self.col as i32 + delta).clamp(0, u16::MAX as i32) as u16;
+    }
+
+    // Maps a screen position to the table the same way Table lays it out
+    pub fn position_at(&self, content: &TableContent, x: u16, y: u16) -> Option<GridPosition> {
+        let area = self.area;
+        if x < area.x || x >= area.x + area.width || y < area.y || y >= area.y + area.height {
+            return None;
+        }
+
+        let frozen_height = self.frozen_height(content);
+        let row = if y < area.y + HEADER_HEIGHT {
+            None
+        } else if y < area.y + HEADER_HEIGHT + frozen_height {
+            Some(index_at(0, y - area.y - HEADER_HEIGHT, |r| self.row_height(content, r))?)
+        } else {
+            Some(index_at(self.first_row(), y - area.y - HEADER_HEIGHT - frozen_height, |r| self.row_height(content, r))?)
+        };
+        let col = if x < area.x + self.header_width() {
+            None
+        } else {
+            Some(index_at(self.col, x - area.x - self.header_width(), |c| self.col_width(content, c))?)
+        };
+
+        Some(match (row, col) {
+            (None, None) => GridPosition::Corner,
+            (None, Some(col)) => GridPosition::ColumnHeader(col),
+            (Some(row), None) => GridPosition::RowHeader(row),
+            (Some(row), Some(col)) => GridPosition::Cell(row, col),
+        })
+    }
+
+    // Screen area of a cell at the last draw, None if it is not visible
+    pub fn cell_rect(&self, content: &TableContent, row: u16, col: u16) -> Option<Rect> {
+        let area = self.area;
+        let mut y = area.y as u32 + HEADER_HEIGHT as u32;
+        let mut index = 0;
+        loop {
+            let r = self.table_row(index)?;
+            if y >= area.bottom() as u32 {
+                return None;
+            }
+            if r == row {
+                break;
+            }
+            y += self.row_height(content, r) as u32;
+            index += 1;
+        }
+        let x = self.col_x(content, col)?;
+        let rect = Rect::new(x, y as u16, self.col_width(content, col), self.row_height(content, row));
+        Some(rect.intersection(area))
+    }
+
+    // Screen column where `col` starts at the last draw, None if it is not
+    // visible
+    pub fn col_x(&self, content: &TableContent, col: u16) -> Option<u16> {
+        if col < self.col {
+            return None;
+        }
+        let x = self.area.x as u32 + self.header_width() as u32
+            + (self.col..col).map(|c| self.col_width(content, c) as u32).sum::<u32>();
+        if x >= self.area.right() as u32 {
+            return None;
+        }
+        Some(x as u16)
+    }
+
+    // Moves the viewport as little as possible so that the cursor is visible
+    // in a table widget of the given size
+    pub fn scroll_to_selection(&mut self, content: &TableContent, area: Rect) {
+        let (row, col) = content.selection.cursor();
+
+        // Frozen rows are always visible
+        if row >= self.frozen_rows {
+            let height = area.height.saturating_sub(HEADER_HEIGHT).saturating_sub(self.frozen_height(content));
+            let top = first_fitting(row, height, |r| self.row_height(content, r));
+            self.row = self.first_row().clamp(top.max(self.frozen_rows), row);
+        }
+
+        let width = area.width.saturating_sub(self.header_width());
+        let left = first_fitting(col, width, |c| self.col_width(content, c));
+        self.col = self.col.clamp(left, col);
+    }
+}
+
+// Finds the row/column covering `offset` when laying them out from `first`
+fn index_at(first: u16, offset: u16, size: impl Fn(u16) -> u16) -> Option<u16> {
+    let mut index = first;
+    let mut end = size(index) as u32;
+    while end <= offset as u32 {
+        index = index.checked_add(1)?;
+        end += size(index) as u32;
+    }
+    Some(index)
+}
+
+// Rows/columns from `first` on which are at least partly inside `space`
+fn visible(first: u16, space: u16, size: impl Fn(u16) -> u16) -> Range<u16> {
+    let mut end = first;
+    let mut used = 0;
+    while used < space as u32 && end < u16::MAX {
+        used += size(end) as u32;
+        end += 1;
+    }
+    first..end
+}
+
+// Walks back from `last` and returns the lowest index such that all
+// rows/columns up to and including `last` fit into `space`
+fn first_fitting(last: u16, space: u16, size: impl Fn(u16) -> u16) -> u16 {
+    let mut first = last;
+    let mut used = size(last) as u32;
+    while first > 0 && used + size(first - 1) as u32 <= space as u32 {
+        first -= 1;
+        used += size(first) as u32;
+    }
+    first
+}
+
+// Column labels in compact mode are only drawn for every n-th column
+const COMPACT_LABEL_EVERY: u16 = 5;
+
+// Stands for a cell in compact mode
+fn overview_glyph(cell: &TableCell, theme: &Theme) -> (char, Style) {
+    match cell {
+        TableCell::Empty => (' ', Style::default()),
+        TableCell::String(_) | TableCell::Bool(_) => ('a', theme.overview_string),
+        _ => ('#', theme.overview_value),
+    }
+}
+
+pub struct Table<'a> {
+    pub content: &'a TableContent,
+    pub viewport: &'a Viewport,
+    pub options: &'a Options,
+    pub theme: &'a Theme,
+    pub baseline: Option<&'a Snapshot>, // Cells which differ from it are highlighted
+    pub search: Option<&'a Search>, // Matches are highlighted
+    pub edit: Option<&'a EditBuffer>, // Shown instead of the cell's content
+    pub references: &'a [Reference], // Of the formula being edited
+    pub peer_cursor: Option<(u16, u16)>, // Of the other visp editing the sheet
+    pub merge: Option<&'a Merge>, // Its open conflicts are highlighted
+    pub diff: Option<&'a TableContent>, // The other side of visp --diff, cells which differ are highlighted
+}
+
+// A reference in a formula being typed: the characters it takes up in the
+// text, the area it points to and the style both are shown in
+type Reference = (Range<usize>, Bounds, Style);
+
+impl<'a> Table<'a> {
+    // Label in the row header, absolute and/or relative to the cursor like
+    // vim's number and relativenumber
+    fn row_label(&self, row: u16) -> String {
+        let cursor = self.content.selection.cursor().0;
+        if self.options.relativenumber && !(self.options.number && row == cursor) {
+            format!("{}", row.abs_diff(cursor))
+        } else {
+            format!("{}", row as u32 + 1)
+        }
+    }
+
+    // The column's name from the first row with :set header, otherwise its letter
+    fn column_label(&self, col: u16) -> String {
+        let names_row = self.options.header_rows().saturating_sub(1);
+        let name = match self.content.get_cell(names_row, col) {
+            Some(_) if self.options.header => self.content.display(names_row, col),
+            _ => String::new(),
+        };
+        if name.is_empty() { col_nr_to_label(col) } else { name }
+    }
+
+    // The cell's own formatting, with selection, header block, changes and
+    // search matches on top
+    fn cell_style(&self, row: u16, col: u16, text: Option<&str>, header: bool) -> Style {
+        let mut style = self.theme.cell;
+        if let Some(cell_style) = self.content.styles.get(&(row, col)) {
+            style = style.patch(self.theme.cell_style(cell_style));
+        }
+        if header {
+            style = style.patch(self.theme.header);
+        }
+        if self.content.selection.selected(row, col) {
+            style = style.patch(self.theme.selected_cell);
+        }
+        if self.peer_cursor == Some((row, col)) {
+            style = style.patch(self.theme.peer_cursor);
+        }
+        let referenced = |&&(_, (top, left, bottom, right), _): &&Reference| (top..=bottom).contains(&row) && (left..=right).contains(&col);
+        if let Some(&(_, _, reference)) = self.references.iter().find(referenced) {
+            style = style.patch(reference);
+        }
+        if self.baseline.is_some_and(|b| b.cell_changed(self.content, row, col)) {
+            style = style.patch(self.theme.changed_cell);
+        }
+        if self.diff.is_some_and(|other| diff::differs(self.content, other, row, col)) {
+            style = style.patch(self.theme.changed_cell);
+        }
+        if self.merge.is_some_and(|merge| merge.is_open(self.content, (row, col))) {
+            style = style.patch(self.theme.conflict);
+        }
+        if text.is_some_and(|t| self.search.is_some_and(|s| s.matches(t))) {
+            // n and N go from the cursor, so the match there is the current one
+            let current = (row, col) == self.content.selection.cursor();
+            style = style.patch(if current { self.theme.current_match } else { self.theme.search_match });
+        }
+        style
+    }
+
+    // References in the text being edited, in the style of the cells they
+    // point to
+    fn color_references(&self, buf: &mut Buffer, edit: &EditBuffer, rect: Rect, style: Style) {
+        let first = edit.line.cursor - edit.line.window(rect.width).1 as usize;
+        for (chars, _, reference) in self.references {
+            for c in chars.clone().filter(|&c| c >= first && c - first < rect.width as usize) {
+                buf.get_mut(rect.x + (c - first) as u16, rect.y).set_style(style.patch(*reference));
+            }
+        }
+    }
+
+    // Width available to a label in the header block, which runs on up to the
+    // next non-empty cell
+    fn label_width(&self, rect: Rect, row: u16, col: u16, area: Rect) -> u16 {
+        let limit = area.right().saturating_sub(rect.x) as u32;
+        let mut width = rect.width as u32;
+        let mut next = col;
+        // Columns beyond the edge of the area don't matter
+        while let Some(c) = next.checked_add(1).filter(|&c| width < limit && c < self.content.used_cols() && self.content.is_empty(row, c)) {
+            width += self.viewport.col_width(self.content, c) as u32;
+            next = c;
+        }
+        width.min(limit) as u16
+    }
+
+    fn column_type_glyph(&self, col: u16) -> Option<(char, Style)> {
+        Some(match self.content.column_type(col, self.options.header_rows())? {
+            ColumnType::Number => ('#', self.theme.overview_value),
+            ColumnType::Text => ('a', self.theme.overview_string),
+            ColumnType::Mixed => ('*', Style::default()),
+        })
+    }
+}
+
+impl<'a> Widget for Table<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let header_style = self.theme.header;
+        let selected_header_style = self.theme.selected_header;
+        let header_rows = self.options.header_rows();
+
+        let draw_cell = |buf: &mut Buffer, cell: Option<&TableCell>, text: Option<&str>, align: Option<Align>, rect: Rect, text_width: u16, style: Style, has_note: bool, header: bool| {
+            // Labels in the header block run on into empty cells, to label
+            // groups of columns
+            let keep_text = header && text.is_none_or(str::is_empty);
+            for x in rect.x..rect.x + rect.width {
+                for y in rect.y..rect.y + rect.height {
+                    let buf_cell = buf.get_mut(x, y);
+                    if !keep_text {
+                        buf_cell.set_char(' ');
+                    }
+                    buf_cell.set_style(style);
+                }
+            }
+            if self.viewport.compact {
+                if let Some(c) = cell.filter(|_| rect.width > 0) {
+                    let (glyph, glyph_style) = overview_glyph(c, self.theme);
+                    buf.get_mut(rect.x, rect.y).set_char(glyph).set_style(glyph_style);
+                }
+            } else if let Some(text) = text {
+                let offset = align.map_or(0, |a| a.offset(text.chars().count(), rect.width));
+                buf.set_stringn(rect.x + offset, rect.y, text, text_width.saturating_sub(offset) as usize, style);
+            }
+            // Marker in the top right corner, like the red triangle in other spreadsheets
+            if has_note && rect.width > 0 {
+                buf.get_mut(rect.x + rect.width - 1, rect.y).set_char('◥').set_style(self.theme.note_marker);
+            }
+        };
+
+        let mut row = 0; 
+        let mut y = area.y; //Buffer position
+
+        while y < area.y + area.height {
+            let table_row = if row == 0 {
+                None
+            } else {
+                match self.viewport.table_row(row - 1) {
+                    Some(table_row) => Some(table_row),
+                    None => break,
+                }
+            };
+            let row_height : u16 = table_row.map(|r| self.viewport.row_height(self.content, r)).unwrap_or(HEADER_HEIGHT);
+            // Rows hidden by :filter take no space
+            if row_height == 0 {
+                row += 1;
+                continue;
+            }
+
+            let mut col = 0;
+            let mut x = area.x;
+            while x < area.x + area.width {
+                let table_col = if col == 0 { None } else { Some(self.viewport.col + col - 1) };
+                let col_width : u16 = table_col.map(|c| self.viewport.col_width(self.content, c)).unwrap_or(self.viewport.header_width());
+
+                if let Some(table_row) = table_row {
+                    if let Some(table_col) = table_col {
+                        // Table content
+                        let cell : Option<&TableCell> = self.content.get_cell(table_row, table_col);
+                        let has_note = self.content.notes.contains_key(&(table_row, table_col));
+                        // Cells outside of the area the cache was filled for are formatted here
+                        let rect = Rect::new(x, y, col_width, row_height).intersection(area);
+                        let formatted;
+                        let edited = self.edit.filter(|e| e.cell == (table_row, table_col));
+                        let text = match self.viewport.cache.get(table_row, table_col) {
+                            _ if edited.is_some() => {
+                                formatted = edited.map(|e| e.line.window(rect.width).0);
+                                formatted.as_deref()
+                            }
+                            Some(text) => Some(text),
+                            None => {
+                                formatted = cell.map(|c| c.display(self.content.formats.get(&(table_row, table_col))));
+                                formatted.as_deref()
+                            }
+                        };
+                        // Text being edited stays at the left, like the cursor in it
+                        let align = self.content.formats.get(&(table_row, table_col))
+                            .and_then(|f| f.align)
+                            .filter(|_| edited.is_none());
+                        let header = table_row < header_rows;
+                        let text_width = if header { self.label_width(rect, table_row, table_col, area) } else { rect.width };
+                        let style = self.cell_style(table_row, table_col, text, header);
+                        draw_cell(buf, cell, text, align, rect, text_width, style, has_note, header);
+                        if let Some(edit) = edited {
+                            self.color_references(buf, edit, rect, style);
+                        }
+                    } else {
+                        // Header column
+                        let style = if self.content.selection.row_selected(table_row) {
+                            selected_header_style
+                        } else {
+                            header_style
+                        };
+                        if col_width > 0 {
+                            buf.set_string(x, y, self.row_label(table_row), style);
+                        }
+                    }
+
+                } else {
+                    // Header row
+                    if let Some(table_col) = table_col {
+                        let style = if self.content.selection.col_selected(table_col) {
+                            selected_header_style
+                        } else {
+                            header_style
+                        };
+                        // Labels don't fit on single character columns
+                        let label = self.column_label(table_col);
+                        if self.viewport.compact {
+                            if table_col % COMPACT_LABEL_EVERY == 0 && col_width > 0 {
+                                buf.set_string(x, y, col_nr_to_label(table_col), style);
+                            }
+                        } else {
+                            // Leave a space to the next label, names can be long
+                            let max_width = if self.options.header { col_width.saturating_sub(1) } else { col_width };
+                            buf.set_stringn(x, y, &label, max_width as usize, style);
+                        }
+                        // Type of the column at the right end of its label
+                        let label_width = label.chars().count() as u16;
+                        if !self.viewport.compact && col_width > label_width + 1 && x + col_width <= area.right() {
+                            if let Some((glyph, glyph_style)) = self.column_type_glyph(table_col) {
+                                buf.get_mut(x + col_width - 1, y).set_char(glyph).set_style(style.patch(glyph_style));
+                            }
+                        }
+                    } else if col_width > 0 {
+                        buf.set_string(x, y, "**", header_style);
+                    }
+                }
+
+                x += col_width;
+                col += 1;
+            }
+
+            row += 1;
+            y += row_height;
+        }
+    }
+}
+
+
+pub fn ui<B: Backend>(f: &mut Frame<B>, state: &mut AppState) {
+    // Like vim's tab line the sheet tabs are only shown if there are several
+    let tab_height = if state.sheets.len() > 1 { 1 } else { 0 };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(0)
+        .constraints(
+            [
+                Constraint::Length(tab_height),
+                Constraint::Max(10000),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ].as_ref()
+        )
+        .split(f.size());
+    let (tabs, chunks) = (chunks[0], &chunks[1..]);
+    if tab_height > 0 {
+        f.render_widget(Paragraph::new(sheet_tabs(state)).style(state.theme.sheet_tab), tabs);
+    }
+
+    // With visp --diff the old file takes the left half
+    let mut table_area = chunks[0];
+    let mut old_area = None;
+    if diff::current(state).is_some() {
+        let width = table_area.width.saturating_sub(1) / 2;
+        old_area = Some(Rect { width, ..table_area });
+        table_area = Rect { x: table_area.x + width + 1, width: table_area.width - width - 1, ..table_area };
+    }
+    let mut windows = Vec::new();
+    let mut lines = Vec::new();
+    state.layout.areas(table_area, &mut windows, &mut lines);
+    for (index, area) in windows {
+        // The other windows are swapped in while they are drawn
+        let current = index == state.window;
+        if !current {
+            window::swap(state, index);
+        }
+        render_table(f, state, area);
+        if !current {
+            window::swap(state, index);
+        }
+    }
+    if let Some(area) = old_area {
+        render_old(f, state, area);
+        lines.push((Rect { x: area.right(), width: 1, ..area }, true));
+    }
+    for (area, vertical) in lines {
+        let line = if vertical { vec!["│"; area.height as usize].join("\n") } else { "─".repeat(area.width as usize) };
+        f.render_widget(Paragraph::new(line).style(state.theme.border), area);
+    }
+
+    if let Some(pager) = &state.pager {
+        render_pager(f, pager, &state.theme, chunks[0]);
+    }
+    if let Some(picker) = &state.picker {
+        render_picker(f, picker, &state.theme, chunks[0]);
+    }
+    if state.calc.open {
+        render_calc(f, &state.calc, &state.theme, chunks[0]);
+    }
+
+    let status = Paragraph::new(status_line(state)).style(state.theme.status_line);
+    f.render_widget(status, chunks[1]);
+
+    if state.mode == AppMode::Command {
+        let (text, offset) = state.command_line.line.window(chunks[2].width.saturating_sub(1));
+        f.set_cursor(chunks[2].x + 1 + offset, chunks[2].y);
+        let prompt = state.command_line.prompt.symbol();
+        f.render_widget(Paragraph::new(format!("{}{}", prompt, text)).style(state.theme.command_line), chunks[2]);
+        return;
+    }
+
+    if let Some(edit) = &state.edit {
+        let (row, col) = edit.cell;
+        if let Some(rect) = state.viewport.cell_rect(&state.table_content, row, col) {
+            let offset = edit.line.window(rect.width).1;
+            if offset < rect.width {
+                f.set_cursor(rect.x + offset, rect.y);
+            }
+        }
+    }
+
+    if let Some(task) = &state.tasks.running {
+        f.render_widget(Paragraph::new(task::status(task)).style(state.theme.message), chunks[2]);
+        return;
+    }
+    let command_line = match &state.message {
+        None if state.edit.as_ref().is_some_and(|e| e.point.is_some()) => Paragraph::new("-- INSERT -- (point)").style(state.theme.message),
+        None if state.edit.as_ref().is_some_and(|e| e.normal.active) => Paragraph::new("-- INSERT -- (normal)").style(state.theme.message),
+        None if state.entry.is_some() => Paragraph::new("-- INSERT -- (entry)").style(state.theme.message),
+        None if state.mode == AppMode::Insert => Paragraph::new("-- INSERT --").style(state.theme.message),
+        Some(Message::Info(text)) => Paragraph::new(text.as_str()).style(state.theme.message),
+        Some(Message::Error(text)) => Paragraph::new(text.as_str()).style(state.theme.error),
+        // Notes and conflicts are shown while the cursor is on their cell
+        None => match (state.table_content.notes.get(&state.table_content.selection.cursor()), merge::describe(state)) {
+            (_, Some(conflict)) => Paragraph::new(format!("{}  (co, ct or :diffget take a side)", conflict)).style(state.theme.note),
+            (Some(note), None) => Paragraph::new(format!("Note: {}", note)).style(state.theme.note),
+            (None, None) => Paragraph::new(""),
+        },
+    };
+    f.render_widget(command_line, chunks[2]);
+}
+
+fn render_table<B: Backend>(f: &mut Frame<B>, state: &mut AppState, area: Rect) {
+    // The table area changes with the terminal size, so the viewport is
+    // clamped before every draw
+    state.viewport.row_header = state.options.number || state.options.relativenumber;
+    state.viewport.frozen_rows = if state.options.freezeheader { state.options.header_rows() } else { 0 };
+    state.viewport.update(&state.table_content, area);
+    if state.table_content.lazy {
+        let areas = shown(state);
+        let calculated = formula::refresh(&mut state.table_content, &areas);
+        state.viewport.cache.forget(&calculated);
+    }
+
+    let references = typed_references(state.edit.as_ref(), &state.theme);
+    let table = Table {content: &state.table_content, viewport: &state.viewport, options: &state.options, theme: &state.theme, baseline: state.change_baseline.as_ref(), search: state.search.as_ref(), edit: state.edit.as_ref(), references: &references, peer_cursor: share::peer_cursor(state), merge: merge::current(state), diff: diff::current(state).map(|diff| &diff.old)};
+    f.render_widget(table, area);
+}
+
+// The old file of visp --diff with the cursor and the cells at the top left of
+// the current window
+fn render_old<B: Backend>(f: &mut Frame<B>, state: &mut AppState, area: Rect) {
+    let Some(diff) = &mut state.diff else {
+        return;
+    };
+    diff.old.selection = state.table_content.selection;
+    diff.viewport.follow(&state.viewport, &diff.old, area);
+    let table = Table {content: &diff.old, viewport: &diff.viewport, options: &state.options, theme: &state.theme, baseline: None, search: state.search.as_ref(), edit: None, references: &[], peer_cursor: None, merge: None, diff: Some(&state.table_content)};
+    f.render_widget(table, area);
+}
+
+// Cells whose values are drawn or summed in the status line, which lazycalc
+// calculates first: the visible ones, the header block and the selection
+fn shown(state: &AppState) -> Vec<Bounds> {
+    let (viewport, content) = (&state.viewport, &state.table_content);
+    let (rows, cols) = (viewport.visible_rows(content), viewport.visible_cols(content));
+    let mut areas = vec![content.selection.bounds()];
+    if !cols.is_empty() {
+        let top = state.options.header_rows().max(viewport.frozen_rows);
+        if top > 0 {
+            areas.push((0, cols.start, top - 1, cols.end - 1));
+        }
+        if !rows.is_empty() {
+            areas.push((rows.start, cols.start, rows.end - 1, cols.end - 1));
+        }
+    }
+    areas
+}
+
+// The references of a formula being typed, each area in a style of its own
+// as far as the theme has enough
+fn typed_references(edit: Option<&EditBuffer>, theme: &Theme) -> Vec<Reference> {
+    let Some(source) = edit.and_then(|e| e.line.text.strip_prefix('=')) else {
+        return Vec::new();
+    };
+    let mut areas = Vec::new();
+    formula::references(source).into_iter().map(|(chars, area)| {
+        let index = areas.iter().position(|&a| a == area).unwrap_or_else(|| {
+            areas.push(area);
+            areas.len() - 1
+        });
+        // The text starts with the =
+        (chars.start + 1..chars.end + 1, area, theme.references[index % theme.references.len()])
+    }).collect()
+}
+
+fn sheet_tabs(state: &AppState) -> Spans<'_> {
+    let spans = state.sheets.iter().enumerate().map(|(i, sheet)| {
+        let style = if i == state.sheet { state.theme.current_sheet_tab } else { state.theme.sheet_tab };
+        Span::styled(format!(" {} ", sheet.name), style)
+    });
+    Spans::from(spans.collect::<Vec<_>>())
+}
+
+// Fills in the placeholders of the statusline option
+fn status_line(state: &AppState) -> String {
+    let format = &state.options.statusline;
+    let mut line = String::new();
+    let mut rest = format.as_str();
+    while let Some(start) = rest.find('%') {
+        line.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-')).unwrap_or(rest.len());
+        let selection = &state.table_content.selection;
+        match &rest[..end] {
+            "" if rest.starts_with('%') => {
+                line.push('%');
+                rest = &rest[1..];
+                continue;
+            }
+            "mode" => {
+                line.push_str(state.mode.name());
+                // Like vim, so it isn't forgotten
+                if let Some((register, _)) = &state.recording {
+                    line.push_str(&format!(" recording @{}", register));
+                }
+            }
+            "file" => {
+                match &state.file {
+                    Some(path) => line.push_str(&path.to_string_lossy()),
+                    None => line.push_str("[No Name]"),
+                }
+                if state.is_modified() {
+                    line.push_str(" [+]");
+                }
+                if state.options.readonly {
+                    line.push_str(" [RO]");
+                }
+            }
+            "cell" => line.push_str(&selection.name()),
+            // As typed, so formulas show their source instead of the value
+            "content" => {
+                let (row, col) = selection.cursor();
+                if let Some(cell) = state.table_content.get_cell(row, col) {
+                    line.push_str(&cell.source_string());
+                }
+            }
+            "sel-size" if state.mode.is_visual() => line.push_str(&selection_size(selection)),
+            "sel-size" => {}
+            "sel-sum" => line.push_str(&format!("Sum: {}", format::format_number(state.table_content.sum(selection)))),
+            // Unknown placeholders are shown as they are
+            other => {
+                line.push('%');
+                line.push_str(other);
+            }
+        }
+        rest = &rest[end..];
+    }
+    line.push_str(rest);
+    line
+}
+
+// Rows by columns, e.g. 3x2, or the number of whole rows or columns
+fn selection_size(selection: &Selection) -> String {
+    let plural = |n: u16| if n == 1 { "" } else { "s" };
+    if selection.cols == u16::MAX {
+        format!("{} row{}", selection.rows, plural(selection.rows))
+    } else if selection.rows == u16::MAX {
+        format!("{} column{}", selection.cols, plural(selection.cols))
+    } else {
+        format!("{}x{}", selection.rows, selection.cols)
+    }
+}
+
+fn render_pager<B: Backend>(f: &mut Frame<B>, pager: &Pager, theme: &Theme, area: Rect) {
+    // Show the end of the text, like a terminal would
+    let visible = area.height.saturating_sub(2) as usize;
+    let first = pager.lines.len().saturating_sub(visible);
+    let text = pager.lines[first..].join("\n");
+
+    let block = Block::default().borders(Borders::ALL).border_style(theme.border).title(pager.title.as_str());
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(text).block(block), area);
+}
+
+// At the bottom of the table, so the cells above stay in view
+fn render_calc<B: Backend>(f: &mut Frame<B>, calc: &Calc, theme: &Theme, area: Rect) {
+    let height = (calc.entries.len() as u16 + 3).clamp(4, 12).min(area.height);
+    let area = Rect { y: area.bottom() - height, height, ..area };
+    let block = Block::default().borders(Borders::ALL).border_style(theme.border).title("Calc (Esc closes)");
+    let inner = block.inner(area);
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+    if inner.height == 0 {
+        return;
+    }
+
+    let shown = inner.height as usize - 1;
+    let first = calc.entries.len().saturating_sub(shown);
+    let lines: Vec<String> = calc.entries[first..].iter().map(|(expression, result)| format!("  {} = {}", expression, result)).collect();
+    f.render_widget(Paragraph::new(lines.join("\n")), Rect { height: inner.height - 1, ..inner });
+
+    let (text, offset) = calc.line.window(inner.width.saturating_sub(2));
+    let input = Rect { y: inner.bottom() - 1, height: 1, ..inner };
+    f.set_cursor(input.x + 2 + offset, input.y);
+    f.render_widget(Paragraph::new(format!("> {}", text)), input);
+}
+
+fn render_picker<B: Backend>(f: &mut Frame<B>, picker: &Picker, theme: &Theme, area: Rect) {
+    let block = Block::default().borders(Borders::ALL).border_style(theme.border).title(picker.title.as_str());
+    let inner = block.inner(area);
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+    if inner.height == 0 {
+        return;
+    }
+
+    let query = format!("> {}", picker.query);
+    f.set_cursor(inner.x + query.chars().count() as u16, inner.y);
+    f.render_widget(Paragraph::new(query), Rect { height: 1, ..inner });
+
+    let items: Vec<ListItem> = picker.matches.iter().map(|&i| ListItem::new(picker.items[i].as_str())).collect();
+    let list = List::new(items).highlight_style(theme.picker_highlight);
+    let mut list_state = ListState::default();
+    list_state.select(Some(picker.index));
+    let list_area = Rect { y: inner.y + 1, height: inner.height - 1, ..inner };
+    f.render_stateful_widget(list, list_area, &mut list_state);
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Scope, AST};
+
+use crate::{commands, edit, formula, sheet, AppState, Message, Result, VispError};
+use crate::formula::FormulaError;
+use crate::grid::{cell_name, parse_cell_name, Selection, TableCell, TableContent};
+
+// Rhai script which adds formula functions and commands, read from init.rhai
+// next to the config file or with :source. For example:
+//
+//   fn double(values) { values[0] * 2 }
+//   register_function("DOUBLE", "double");   // =DOUBLE(A1)
+//
+//   fn upper(args) {
+//       for name in selection() { set(name, get(name).to_upper()) }
+//   }
+//   register_command("upper", "upper");      // :upper
+//
+// Functions get the numbers of their arguments as an array, ranges skip text
+// like for SUM. Commands get the text after their name, and get(), set(),
+// selection(), cursor() and message() work on the current sheet.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    registered: Rc<RefCell<Registered>>,
+    context: Rc<RefCell<Option<Context>>>, // Only while a command runs
+}
+
+#[derive(Default)]
+struct Registered {
+    functions: HashMap<String, String>, // Upper case formula name to script function
+    commands: HashMap<String, String>,
+}
+
+// What a command works on. The table is moved here while the command runs
+// and the cells it sets are only written afterwards, as one undo step.
+struct Context {
+    content: TableContent,
+    range: Selection,
+    writes: BTreeMap<(u16, u16), TableCell>,
+    message: Option<String>,
+}
+
+// An endless loop ends with an error instead of hanging the editor
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+impl Script {
+    pub fn new(text: &str) -> Result<Self> {
+        let registered = Rc::new(RefCell::new(Registered::default()));
+        let context = Rc::new(RefCell::new(None));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        // The terminal belongs to the table, so output goes to the log
+        engine.on_print(|text| tracing::info!("script: {}", text));
+        engine.on_debug(|text, _, position| tracing::debug!("script {}: {}", position, text));
+        register_api(&mut engine, &registered, &context);
+
+        let ast = engine.compile(text).map_err(|e| VispError::Script(e.to_string()))?;
+        engine.run_ast(&ast).map_err(script_error)?;
+        let script = Self { engine, ast, registered, context };
+        script.check_registered()?;
+        Ok(script)
+    }
+
+    // Registered names have to point to functions taking one argument
+    fn check_registered(&self) -> Result<()> {
+        let registered = self.registered.borrow();
+        for function in registered.functions.values().chain(registered.commands.values()) {
+            if !self.ast.iter_functions().any(|f| f.name == function && f.params.len() == 1) {
+                return Err(VispError::Script(format!("No function {}(_) in the script", function)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn counts(&self) -> (usize, usize) {
+        let registered = self.registered.borrow();
+        (registered.functions.len(), registered.commands.len())
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.registered.borrow().commands.contains_key(name)
+    }
+
+    // Calculates =NAME(...) in a formula. Script errors go to the log, the
+    // cell only shows #VALUE!.
+    pub fn call_function(&self, name: &str, numbers: Vec<f64>) -> std::result::Result<f64, FormulaError> {
+        let function = self.registered.borrow().functions.get(name).cloned().ok_or(FormulaError::Name)?;
+        let values: Array = numbers.into_iter().map(Dynamic::from_float).collect();
+        let result = self.call(&function, (values,)).map_err(|e| {
+            tracing::warn!("{}: {}", name, e);
+            FormulaError::Value
+        })?;
+        number(&result).ok_or(FormulaError::Value)
+    }
+
+    fn call(&self, function: &str, args: impl FuncArgs) -> ScriptResult<Dynamic> {
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine.call_fn_with_options(options, &mut Scope::new(), &self.ast, function, args)
+    }
+}
+
+// Reads a script and makes its functions and commands available, replacing
+// those of the script before
+pub fn load(state: &mut AppState, path: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let script = Script::new(&text).map_err(|e| match e {
+        VispError::Script(message) => VispError::Script(format!("{}: {}", path.display(), message)),
+        e => e,
+    })?;
+    state.script = Some(Rc::new(script));
+    // Formulas may use the new functions
+    sheet::link(state);
+    tracing::info!("Read script {}", path.display());
+    Ok(())
+}
+
+// Runs the command `name` registered by the script
+pub fn run_command(state: &mut AppState, script: &Script, name: &str, range: Selection, args: &str) -> Result<()> {
+    let function = script.registered.borrow().commands.get(name).cloned()
+        .ok_or_else(|| VispError::Command(format!("Not an editor command: {}", name)))?;
+    let content = std::mem::take(&mut state.table_content);
+    *script.context.borrow_mut() = Some(Context { content, range, writes: BTreeMap::new(), message: None });
+    let result = script.call(&function, (args.to_string(),)).map(|_| ());
+    let context = script.context.borrow_mut().take().expect("context is set while the command runs");
+    state.table_content = context.content;
+
+    result.map_err(script_error)?;
+    let changed = edit::replace_cells(state, context.writes.into_iter().collect())?;
+    let message = context.message.or_else(|| (changed > 0).then(|| format!("{} cells changed", changed)));
+    if let Some(message) = message {
+        state.message = Some(Message::Info(message));
+    }
+    Ok(())
+}
+
+fn register_api(engine: &mut Engine, registered: &Rc<RefCell<Registered>>, context: &Rc<RefCell<Option<Context>>>) {
+    let function_names = registered.clone();
+    engine.register_fn("register_function", move |name: &str, function: &str| -> ScriptResult<()> {
+        if name.is_empty() || !name.chars().all(formula::is_name_char) || parse_cell_name(name).is_some() {
+            return Err(format!("Not a function name: {}", name).into());
+        }
+        if formula::is_builtin(name) {
+            return Err(format!("Formulas already have a function {}", name).into());
+        }
+        function_names.borrow_mut().functions.insert(name.to_ascii_uppercase(), function.to_string());
+        Ok(())
+    });
+    let command_names = registered.clone();
+    engine.register_fn("register_command", move |name: &str, function: &str| -> ScriptResult<()> {
+        if name.is_empty() || !name.chars().all(char::is_alphanumeric) {
+            return Err(format!("Not a command name: {}", name).into());
+        }
+        if commands::find(name).is_some() {
+            return Err(format!("There is already a command {}", name).into());
+        }
+        command_names.borrow_mut().commands.insert(name.to_string(), function.to_string());
+        Ok(())
+    });
+
+    let get = context.clone();
+    engine.register_fn("get", move |name: &str| -> ScriptResult<Dynamic> {
+        with_context(&get, |context| {
+            let cell = parse_name(name)?;
+            let value = context.writes.get(&cell).or_else(|| context.content.get_cell(cell.0, cell.1));
+            Ok(to_dynamic(value))
+        })
+    });
+    let set = context.clone();
+    engine.register_fn("set", move |name: &str, value: Dynamic| -> ScriptResult<()> {
+        with_context(&set, |context| {
+            let cell = parse_name(name)?;
+            context.writes.insert(cell, from_dynamic(value)?);
+            Ok(())
+        })
+    });
+    // Cell names row by row, whole rows and columns end at the last cell in use
+    let selection = context.clone();
+    engine.register_fn("selection", move || -> ScriptResult<Array> {
+        with_context(&selection, |context| {
+            let range = context.range;
+            let bottom = range.bottom().min(context.content.used_rows().saturating_sub(1)).max(range.row);
+            let right = range.right().min(context.content.used_cols().saturating_sub(1)).max(range.col);
+            Ok((range.row..=bottom)
+                .flat_map(|row| (range.col..=right).map(move |col| cell_name(row, col).into()))
+                .collect())
+        })
+    });
+    let cursor = context.clone();
+    engine.register_fn("cursor", move || -> ScriptResult<String> {
+        with_context(&cursor, |context| {
+            let (row, col) = context.content.selection.cursor();
+            Ok(cell_name(row, col))
+        })
+    });
+    let message = context.clone();
+    engine.register_fn("message", move |text: &str| -> ScriptResult<()> {
+        with_context(&message, |context| {
+            context.message = Some(text.to_string());
+            Ok(())
+        })
+    });
+}
+
+// Formula functions only see their arguments, so that formulas are
+// recalculated whenever what they read changes
+fn with_context<T>(context: &RefCell<Option<Context>>, f: impl FnOnce(&mut Context) -> ScriptResult<T>) -> ScriptResult<T> {
+    match context.borrow_mut().as_mut() {
+        Some(context) => f(context),
+        None => Err("Cells can only be used in commands".into()),
+    }
+}
+
+fn parse_name(name: &str) -> ScriptResult<(u16, u16)> {
+    parse_cell_name(name).ok_or_else(|| format!("Not a cell: {}", name).into())
+}
+
+// Numbers of formulas are their values, dates and other values their text
+fn to_dynamic(cell: Option<&TableCell>) -> Dynamic {
+    match cell {
+        None | Some(TableCell::Empty) => Dynamic::UNIT,
+        Some(TableCell::Value(v)) => Dynamic::from_int(*v as i64),
+        Some(TableCell::Bool(b)) => Dynamic::from_bool(*b),
+        Some(TableCell::String(s)) => s.clone().into(),
+        Some(TableCell::Formula(formula)) => match formula.value {
+            Ok(value) => Dynamic::from_float(value),
+            Err(e) => e.to_string().into(),
+        },
+        Some(cell) => cell.number().map(Dynamic::from_float).unwrap_or_else(|| cell.format_string().into()),
+    }
+}
+
+// Text is parsed like typed text, so "=A1*2" becomes a formula
+fn from_dynamic(value: Dynamic) -> ScriptResult<TableCell> {
+    if value.is_unit() {
+        return Ok(TableCell::Empty);
+    }
+    if let Some(n) = number(&value).filter(|_| !value.is_bool()) {
+        return if n.is_finite() { Ok(TableCell::from_number(n)) } else { Err("Not a finite number".into()) };
+    }
+    if let Ok(b) = value.as_bool() {
+        return Ok(TableCell::Bool(b));
+    }
+    let type_name = value.type_name();
+    match value.into_string() {
+        Ok(text) => Ok(edit::parse_cell(&text)),
+        Err(_) => Err(format!("Can't put a {} into a cell", type_name).into()),
+    }
+}
+
+// true counts as 1, like in formulas
+fn number(value: &Dynamic) -> Option<f64> {
+    value.as_float().ok()
+        .or_else(|| value.as_int().ok().map(|n| n as f64))
+        .or_else(|| value.as_bool().ok().map(|b| if b { 1.0 } else { 0.0 }))
+}
+
+fn script_error(e: Box<EvalAltResult>) -> VispError {
+    VispError::Script(e.to_string())
+}
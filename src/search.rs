@@ -0,0 +1,241 @@
+use std::collections::BTreeSet;
+
+use regex::Regex;
+
+use crate::{Result, VispError};
+use crate::grid::{TableCell, TableContent};
+
+// Last search, repeated with n and N
+pub struct Search {
+    pub pattern: String, // As it was typed
+    regex: Regex,
+    literal: Option<String>, // Text every match contains, for the index
+    pub forward: bool,
+}
+
+impl Search {
+    // A regular expression typed after / or ?, cells containing a match are found
+    pub fn new(pattern: &str, forward: bool) -> Result<Self> {
+        Ok(Self { pattern: pattern.to_string(), regex: compile(pattern)?, literal: literal(pattern), forward })
+    }
+
+    // The text of a cell for * and #, only cells with exactly that text are
+    // found with `whole_cell`
+    pub fn literal(text: &str, forward: bool, whole_cell: bool) -> Result<Self> {
+        let escaped = regex::escape(text);
+        let pattern = if whole_cell { format!("^{}$", escaped) } else { escaped };
+        Ok(Self { pattern: text.to_string(), regex: compile(&pattern)?, literal: Some(text.to_string()), forward })
+    }
+
+    // `text` is the cell as it is shown
+    pub fn matches(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    // Next matching cell after `from`, row by row and wrapping around the end.
+    // Rows which the index rules out are skipped.
+    pub fn next(&self, content: &TableContent, from: (u16, u16), forward: bool) -> Option<(u16, u16)> {
+        let candidate = candidate_rows(content, self.literal.as_deref());
+        // `from` itself comes last, after wrapping around
+        let at = content.get_cell(from.0, from.1).map(|cell| (from, cell));
+        let after = content.iter_from(from).filter(|&(cell, _)| cell != from);
+        let before = content.iter_before(from);
+        let mut cells: Box<dyn Iterator<Item = ((u16, u16), &TableCell)>> = if forward {
+            Box::new(after.chain(before).chain(at))
+        } else {
+            Box::new(before.rev().chain(after.rev()).chain(at))
+        };
+        cells.find(|&((row, col), _)| candidate(row) && self.matches(&content.display(row, col))).map(|(cell, _)| cell)
+    }
+}
+
+// The text a pattern matches if it has no special characters apart from
+// anchors, matches of other patterns can't be looked up in the index
+pub fn literal(pattern: &str) -> Option<String> {
+    let text = pattern.strip_prefix('^').unwrap_or(pattern);
+    let text = text.strip_suffix('$').filter(|t| !t.ends_with('\\')).unwrap_or(text);
+    Some(text.to_string()).filter(|t| regex::escape(t) == *t)
+}
+
+// Whether a row can hold a cell containing `literal`, after bringing the
+// index up to date. All rows can without a literal.
+pub fn candidate_rows(content: &TableContent, literal: Option<&str>) -> impl Fn(u16) -> bool {
+    let mut index = content.search_index.borrow_mut();
+    index.update(content);
+    let blocks = literal.and_then(|literal| index.candidates(literal));
+    move |row| blocks.as_ref().is_none_or(|b| b.get((row / BLOCK_ROWS) as usize).copied().unwrap_or(true))
+}
+
+// Rows are indexed in blocks of this many
+const BLOCK_ROWS: u16 = 64;
+
+// Bits per block, each sequence of three bytes sets one of them
+const BLOCK_BITS: usize = 8192;
+
+// Which sequences of three bytes the cells in each block of rows contain, so
+// that searches skip the blocks which can't hold a match. Formulas are left
+// out because their values change without them being written, blocks with
+// formulas are always searched. Kept in TableContent and brought up to date
+// before each search, only the rows given to TableContent::changed_rows are
+// indexed again, any other change indexes the whole table.
+#[derive(Default)]
+pub struct Index {
+    revision: Option<u64>, // Of the table, None before the first search
+    dirty: BTreeSet<u16>, // Blocks changed since then
+    blocks: Vec<Block>,
+}
+
+#[derive(Clone)]
+struct Block {
+    bits: Vec<u64>,
+    formulas: bool,
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self { bits: vec![0; BLOCK_BITS / 64], formulas: false }
+    }
+}
+
+impl Index {
+    // Called before the table's revision is increased for a change to the
+    // text of cells in `rows`
+    pub fn touch(&mut self, revision: u64, rows: impl IntoIterator<Item = u16>) {
+        if self.revision == Some(revision) {
+            self.dirty.extend(rows.into_iter().map(|row| row / BLOCK_ROWS));
+            self.revision = Some(revision + 1);
+        }
+    }
+
+    pub fn update(&mut self, content: &TableContent) {
+        let blocks = content.used_rows().div_ceil(BLOCK_ROWS);
+        if self.revision != Some(content.revision) {
+            self.blocks.clear();
+            self.dirty = (0..blocks).collect();
+        }
+        self.blocks.resize(blocks as usize, Block::default());
+        for block in std::mem::take(&mut self.dirty).into_iter().filter(|&b| b < blocks) {
+            let mut indexed = Block::default();
+            let start = block * BLOCK_ROWS;
+            let end = start as u32 + BLOCK_ROWS as u32;
+            for ((row, col), cell) in content.iter_from((start, 0)).take_while(|&((row, _), _)| (row as u32) < end) {
+                if matches!(cell, TableCell::Formula(_)) {
+                    indexed.formulas = true;
+                } else {
+                    for gram in content.display(row, col).as_bytes().windows(3) {
+                        let bit = gram_bit(gram);
+                        indexed.bits[bit / 64] |= 1 << (bit % 64);
+                    }
+                }
+            }
+            self.blocks[block as usize] = indexed;
+        }
+        self.revision = Some(content.revision);
+    }
+
+    // For each block, whether it can contain `literal`. None if the index
+    // doesn't help, i.e. `literal` is shorter than three bytes.
+    pub fn candidates(&self, literal: &str) -> Option<Vec<bool>> {
+        let bits: Vec<usize> = literal.as_bytes().windows(3).map(gram_bit).collect();
+        if bits.is_empty() {
+            return None;
+        }
+        Some(self.blocks.iter().map(|block| {
+            block.formulas || bits.iter().all(|&bit| block.bits[bit / 64] & (1 << (bit % 64)) != 0)
+        }).collect())
+    }
+}
+
+fn gram_bit(gram: &[u8]) -> usize {
+    let hash = u32::from_le_bytes([gram[0], gram[1], gram[2], 0]).wrapping_mul(0x9E37_79B1);
+    (hash >> (32 - BLOCK_BITS.trailing_zeros())) as usize
+}
+
+// The regex crate explains errors over several lines, the last one says what is wrong
+pub fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|e| {
+        let error = e.to_string();
+        let reason = error.lines().last().unwrap_or_default().trim_start_matches("error: ");
+        VispError::Parse(format!("Invalid pattern {}: {}", pattern, reason))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::CellFormat;
+    use crate::formula::Formula;
+    use crate::grid::{Axis, Shift};
+
+    // 300 rows of "item <row>" and the row times 10
+    fn table() -> TableContent {
+        TableContent::from_rows((0..300).map(|row| vec![TableCell::String(format!("item {}", row)), TableCell::Value(row * 10)]).collect())
+    }
+
+    // All matches from the top, by following next around the table
+    fn matches(content: &TableContent, pattern: &str) -> Vec<(u16, u16)> {
+        let search = Search::new(pattern, true).unwrap();
+        let mut found = Vec::new();
+        let mut at = (u16::MAX, u16::MAX);
+        while let Some(cell) = search.next(content, at, true).filter(|cell| !found.contains(cell)) {
+            found.push(cell);
+            at = cell;
+        }
+        found.sort();
+        found
+    }
+
+    // What a search without the index finds
+    fn scan(content: &TableContent, pattern: &str) -> Vec<(u16, u16)> {
+        let regex = compile(pattern).unwrap();
+        content.iter().map(|(cell, _)| cell).filter(|&(row, col)| regex.is_match(&content.display(row, col))).collect()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(literal("item 7").as_deref(), Some("item 7"));
+        assert_eq!(literal("^item 7$").as_deref(), Some("item 7"));
+        assert_eq!(literal("item.7"), None);
+        assert_eq!(literal("item\\$"), None);
+    }
+
+    #[test]
+    fn index_finds_what_a_scan_finds() {
+        let content = table();
+        for pattern in ["item 25", "^item 250$", "2990", "item", "29", "item 2.9", "nothing"] {
+            assert_eq!(matches(&content, pattern), scan(&content, pattern), "{}", pattern);
+        }
+        let candidates = content.search_index.borrow().candidates("item 250").unwrap();
+        assert!(candidates.iter().filter(|&&c| c).count() < candidates.len());
+        let backward = Search::new("item 25", false).unwrap();
+        assert_eq!(backward.next(&content, (10, 0), false), Some((259, 0)));
+    }
+
+    #[test]
+    fn index_follows_changes() {
+        let mut content = table();
+        assert_eq!(matches(&content, "new text"), []);
+        content.set_cell(200, 1, TableCell::String("new text".to_string()));
+        assert_eq!(matches(&content, "new text"), [(200, 1)]);
+        content.set_cell(200, 1, TableCell::Empty);
+        assert_eq!(matches(&content, "new text"), []);
+
+        content.formats.insert((150, 1), CellFormat { spec: Some("%,.2f".to_string()), align: None });
+        content.changed_rows([150]);
+        assert_eq!(matches(&content, "1,500.00"), [(150, 1)]);
+
+        content.shift(Shift { axis: Axis::Rows, at: 0, count: 100, insert: true });
+        assert_eq!(matches(&content, "1,500.00"), [(250, 1)]);
+        assert_eq!(matches(&content, "^item 0$"), [(100, 0)]);
+    }
+
+    #[test]
+    fn formulas_are_searched() {
+        let mut content = table();
+        content.set_cell(250, 2, TableCell::Formula(Box::new(Formula::new("A1*1000"))));
+        content.set_cell(0, 0, TableCell::Value(7));
+        assert_eq!(matches(&content, "7000"), [(250, 2)]);
+        content.set_cell(0, 0, TableCell::Value(8));
+        assert_eq!(matches(&content, "8000"), [(250, 2)]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::Result;
+use crate::grid::{col_nr_to_label, TableContent};
+
+// Seconds between reloads of the page in the browser
+const REFRESH_SECONDS: u32 = 2;
+
+// Read-only view of the table as a web page, see :serve
+pub struct Server {
+    pub address: SocketAddr,
+    page: Arc<Mutex<String>>,
+    revision: Option<u64>, // TableContent::revision the page shows
+}
+
+impl Server {
+    // Listens on `address` in a background thread until visp exits
+    pub fn start(address: &str) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let page = Arc::new(Mutex::new(String::new()));
+        let server = Self {
+            address: listener.local_addr()?,
+            page: page.clone(),
+            revision: None,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = respond(stream, &page.lock().unwrap()) {
+                    tracing::debug!("serving page failed: {}", e);
+                }
+            }
+        });
+        tracing::info!("serving the table at http://{}", server.address);
+        Ok(server)
+    }
+
+    // Called after every change so the page stays up to date
+    pub fn update(&mut self, content: &TableContent) {
+        if self.revision != Some(content.revision) {
+            *self.page.lock().unwrap() = html(content);
+            self.revision = Some(content.revision);
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, page: &str) -> std::io::Result<()> {
+    // Every request gets the page, so the request itself is not looked at
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+        page.len(),
+        page
+    )
+}
+
+fn html(content: &TableContent) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{}\"><title>VISP</title></head><body>\n<table border=\"1\">\n<tr><th></th>",
+        REFRESH_SECONDS
+    );
+    for col in 0..content.used_cols() {
+        page += &format!("<th>{}</th>", col_nr_to_label(col));
+    }
+    page += "</tr>\n";
+    for row in 0..content.used_rows() {
+        page += &format!("<tr><th>{}</th>", row as u32 + 1);
+        for col in 0..content.used_cols() {
+            let text = content.display(row, col);
+            page += &format!("<td>{}</td>", escape(&text));
+        }
+        page += "</tr>\n";
+    }
+    page += "</table>\n</body></html>\n";
+    page
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
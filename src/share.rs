@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{csv, sheet, AppState, Message, Result, VispError};
+use crate::grid::{TableCell, TableContent};
+use crate::io::AppEvent;
+
+// How long a peer may keep us waiting before the connection counts as lost
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the thread taking connections checks whether :leave was given
+const POLL: Duration = Duration::from_millis(100);
+
+// Ids of connections, so events of one which was closed are ignored
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+// A sheet edited by two visps at once, see :host and :join. Both send the
+// cells which changed since they last sent or got them, and the cursor,
+// as CSV records over TCP:
+//   hello,<user>        first thing on a connection
+//   cell,<row>,<col>,<value as in a CSV file>
+//   cursor,<row>,<col>
+//   busy                the host already has a guest
+// The host sends the cells it gets back to the guest, so when both change a
+// cell at once the one the host got last wins on both sides. Only the cells
+// are shared, not formats, notes or widths.
+pub struct Share {
+    pub host: bool,
+    pub address: SocketAddr, // Where the host takes connections
+    pub sheet: String, // Name of the shared sheet
+    pub peer: Option<Peer>,
+    shared: BTreeMap<(u16, u16), TableCell>, // The sheet as sent to or got from the peer
+    revision: Option<u64>, // TableContent::revision `shared` was compared at
+    cursor: Option<(u16, u16)>, // The last one sent
+    stopped: Arc<AtomicBool>, // Ends the thread taking connections
+}
+
+pub struct Peer {
+    id: u64,
+    stream: TcpStream, // Written here, read by a thread of its own
+    pub user: String,
+    pub cursor: Option<(u16, u16)>,
+}
+
+pub enum ShareEvent {
+    Connected(u64, TcpStream), // A guest, for writing
+    Lines(u64, Vec<String>), // The records read so far
+    Closed(u64),
+}
+
+fn user() -> String {
+    std::env::var("USER").unwrap_or_else(|_| "someone".to_string())
+}
+
+// :host, takes one guest at a time for the current sheet
+pub fn host(state: &mut AppState, address: &str) -> Result<()> {
+    let sender = start(state)?;
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let share = Share {
+        host: true,
+        address: listener.local_addr()?,
+        sheet: state.sheets[state.sheet].name.clone(),
+        peer: None,
+        shared: BTreeMap::new(),
+        revision: None,
+        cursor: None,
+        stopped: stopped.clone(),
+    };
+    thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let id = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                    let writer = stream.set_nonblocking(false).and_then(|_| stream.try_clone());
+                    match writer {
+                        Ok(writer) => {
+                            if sender.send(AppEvent::Share(ShareEvent::Connected(id, writer))).is_err() {
+                                break;
+                            }
+                            spawn_reader(id, stream, sender.clone());
+                        }
+                        Err(e) => tracing::debug!("connection not taken: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL),
+                Err(e) => {
+                    tracing::warn!("no more connections taken: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    tracing::info!("sharing sheet {} at {}", share.sheet, share.address);
+    state.message = Some(Message::Info(format!("Sharing {} at {}, others can :join it", share.sheet, share.address)));
+    state.share = Some(share);
+    Ok(())
+}
+
+// :join, the sheet of the host is put into a new one
+pub fn join(state: &mut AppState, address: &str) -> Result<()> {
+    let sender = start(state)?;
+    let stream = TcpStream::connect(address)?;
+    let id = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    spawn_reader(id, stream.try_clone()?, sender);
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let name = sheet::usable_name(state, "Shared");
+    sheet::add(state, Some(&name))?;
+    let mut share = Share {
+        host: false,
+        address: stream.peer_addr()?,
+        sheet: name,
+        peer: Some(Peer { id, stream, user: address.to_string(), cursor: None }),
+        shared: BTreeMap::new(),
+        revision: Some(state.table_content.revision),
+        cursor: None,
+        stopped: Arc::new(AtomicBool::new(true)),
+    };
+    send(&mut share, &format!("hello,{}\n", csv::quote(&user(), ',')))?;
+    state.message = Some(Message::Info(format!("Joining {}", share.address)));
+    state.share = Some(share);
+    Ok(())
+}
+
+fn start(state: &AppState) -> Result<Sender<AppEvent>> {
+    if let Some(share) = &state.share {
+        return Err(VispError::Command(format!("Already sharing {} with {}, see :leave", share.sheet, share.address)));
+    }
+    // Batch mode has no main loop to take what the peer sends
+    state.tasks.sender.clone().ok_or_else(|| VispError::Command("Sharing needs the terminal".to_string()))
+}
+
+// :leave, the sheet stays as it is
+pub fn leave(state: &mut AppState) -> Result<()> {
+    let share = state.share.take().ok_or_else(|| VispError::Command("Not sharing".to_string()))?;
+    share.stopped.store(true, Ordering::Relaxed);
+    if let Some(peer) = &share.peer {
+        let _ = peer.stream.shutdown(Shutdown::Both);
+    }
+    state.message = Some(Message::Info(format!("Stopped sharing {}", share.sheet)));
+    Ok(())
+}
+
+// Sends every line which arrives on `stream`. The records which are there
+// already go together, so a whole table is put in at once.
+fn spawn_reader(id: u64, stream: TcpStream, sender: Sender<AppEvent>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+            // A quoted line break, the record goes on in the next line
+            if line.matches('"').count() % 2 == 1 {
+                continue;
+            }
+            lines.push(std::mem::take(&mut line));
+            if reader.buffer().is_empty() && sender.send(AppEvent::Share(ShareEvent::Lines(id, std::mem::take(&mut lines)))).is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(AppEvent::Share(ShareEvent::Closed(id)));
+    });
+}
+
+fn send(share: &mut Share, text: &str) -> Result<()> {
+    match &mut share.peer {
+        Some(peer) => Ok(peer.stream.write_all(text.as_bytes())?),
+        None => Ok(()),
+    }
+}
+
+fn cell_record((row, col): (u16, u16), cell: &TableCell) -> String {
+    format!("cell,{},{},{}\n", row, col, csv::quote(&csv::field(cell), ','))
+}
+
+fn sheet_index(state: &AppState, share: &Share) -> Option<usize> {
+    sheet::find(state, &share.sheet)
+}
+
+// Called by the main loop before drawing. Sends the changes of the shared
+// sheet and the cursor on it.
+pub fn update(state: &mut AppState) {
+    let Some(share) = &mut state.share else {
+        return;
+    };
+    if share.peer.is_none() || state.sheets[state.sheet].name != share.sheet {
+        return;
+    }
+    let content = &state.table_content;
+    let mut text = String::new();
+    if share.revision != Some(content.revision) {
+        for (position, cell) in changes(&share.shared, content) {
+            text += &cell_record(position, &cell);
+            store(&mut share.shared, position, cell);
+        }
+        share.revision = Some(content.revision);
+    }
+    let cursor = content.selection.cursor();
+    if share.cursor != Some(cursor) {
+        text += &format!("cursor,{},{}\n", cursor.0, cursor.1);
+        share.cursor = Some(cursor);
+    }
+    if !text.is_empty() && send(share, &text).is_err() {
+        lost(state);
+    }
+}
+
+// Cells of `content` which differ from `shared`, Empty where they are gone
+fn changes(shared: &BTreeMap<(u16, u16), TableCell>, content: &TableContent) -> Vec<((u16, u16), TableCell)> {
+    let mut changes = Vec::new();
+    let mut old = shared.iter().peekable();
+    let mut new = content.iter().peekable();
+    loop {
+        let (a, b) = (old.peek().map(|(&position, _)| position), new.peek().map(|&(position, _)| position));
+        match (a, b) {
+            (None, None) => break,
+            (Some(a), b) if b.is_none_or(|b| a < b) => {
+                old.next();
+                changes.push((a, TableCell::Empty));
+            }
+            (a, Some(b)) if a.is_none_or(|a| b < a) => {
+                let (_, cell) = new.next().unwrap();
+                changes.push((b, cell.clone()));
+            }
+            _ => {
+                let ((&position, before), (_, after)) = (old.next().unwrap(), new.next().unwrap());
+                if !before.same_source(after) {
+                    changes.push((position, after.clone()));
+                }
+            }
+        }
+    }
+    changes
+}
+
+fn store(shared: &mut BTreeMap<(u16, u16), TableCell>, position: (u16, u16), cell: TableCell) {
+    match cell {
+        TableCell::Empty => shared.remove(&position),
+        cell => shared.insert(position, cell),
+    };
+}
+
+// Called by the main loop with what the threads of the connection found
+pub fn handle(state: &mut AppState, event: ShareEvent) {
+    match event {
+        ShareEvent::Connected(id, stream) => connected(state, id, stream),
+        ShareEvent::Lines(id, lines) if is_peer(state, id) => received(state, lines),
+        ShareEvent::Closed(id) if is_peer(state, id) => lost(state),
+        _ => {}
+    }
+}
+
+fn is_peer(state: &AppState, id: u64) -> bool {
+    state.share.as_ref().and_then(|share| share.peer.as_ref()).is_some_and(|peer| peer.id == id)
+}
+
+// A guest gets the whole sheet
+fn connected(state: &mut AppState, id: u64, mut stream: TcpStream) {
+    let Some(index) = state.share.as_ref().and_then(|share| sheet_index(state, share)) else {
+        return;
+    };
+    let share = state.share.as_mut().unwrap();
+    if share.peer.is_some() {
+        let _ = stream.write_all(b"busy\n");
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+    let content = if index == state.sheet { &state.table_content } else { &state.sheets[index].content };
+    share.shared = content.iter().map(|(position, cell)| (position, cell.clone())).collect();
+    share.revision = Some(content.revision);
+    share.cursor = None;
+    let mut text = format!("hello,{}\n", csv::quote(&user(), ','));
+    for (&position, cell) in &share.shared {
+        text += &cell_record(position, cell);
+    }
+    share.peer = Some(Peer { id, stream, user: "guest".to_string(), cursor: None });
+    if send(share, &text).is_err() {
+        lost(state);
+    }
+}
+
+fn received(state: &mut AppState, lines: Vec<String>) {
+    let Some(index) = state.share.as_ref().and_then(|share| sheet_index(state, share)) else {
+        return lost(state);
+    };
+    let share = state.share.as_mut().unwrap();
+    let peer = share.peer.as_mut().unwrap();
+    let mut cells = Vec::new();
+    for line in &lines {
+        let record = csv::records(line, ',').into_iter().next().unwrap_or_default();
+        let number = |i: usize| record.get(i).and_then(|field| field.parse::<u16>().ok());
+        match record.first().map(String::as_str) {
+            Some("hello") => {
+                peer.user = record.get(1).cloned().unwrap_or_default();
+                let text = if share.host { format!("{} joined {}", peer.user, share.sheet) } else { format!("Editing the sheet of {} in {}", peer.user, share.sheet) };
+                state.message = Some(Message::Info(text));
+            }
+            Some("cell") => {
+                if let (Some(row), Some(col)) = (number(1), number(2)) {
+                    let field = record.get(3).cloned().unwrap_or_default();
+                    cells.push(((row, col), csv::parse_field(field, None)));
+                }
+            }
+            Some("cursor") => peer.cursor = number(1).zip(number(2)),
+            Some("busy") => {
+                state.message = Some(Message::Error(format!("{} already has a guest", share.address)));
+                state.share = None;
+                return;
+            }
+            _ => tracing::debug!("unknown record from {}: {}", peer.user, line.trim_end()),
+        }
+    }
+    if cells.is_empty() {
+        return;
+    }
+
+    // Changes made here which weren't sent yet are still found by update
+    let content = if index == state.sheet { &mut state.table_content } else { &mut state.sheets[index].content };
+    let share = state.share.as_mut().unwrap();
+    let pending = share.revision != Some(content.revision);
+    let user = share.peer.as_ref().unwrap().user.clone();
+    let mut echo = String::new();
+    for (position, cell) in &cells {
+        let old = content.get_cell(position.0, position.1).cloned().unwrap_or(TableCell::Empty);
+        if !old.same_source(cell) {
+            state.edit_log.record_by(&user, *position, &old, cell);
+        }
+        if share.host {
+            echo += &cell_record(*position, cell);
+        }
+        store(&mut share.shared, *position, cell.clone());
+    }
+    content.set_cells(cells);
+    if !pending {
+        share.revision = Some(content.revision);
+    }
+    if send(share, &echo).is_err() {
+        lost(state);
+    }
+}
+
+// The peer left or can't be reached. The host waits for another guest.
+fn lost(state: &mut AppState) {
+    let Some(index) = state.share.as_ref().map(|share| sheet_index(state, share)) else {
+        return;
+    };
+    let share = state.share.as_mut().unwrap();
+    let user = share.peer.take().map_or_else(String::new, |peer| peer.user);
+    if share.host && index.is_some() {
+        state.message = Some(Message::Info(format!("{} left {}", user, share.sheet)));
+    } else {
+        state.message = Some(Message::Error(format!("Stopped sharing {} with {}", share.sheet, user)));
+        share.stopped.store(true, Ordering::Relaxed);
+        state.share = None;
+    }
+}
+
+// Where the peer's cursor is drawn, if it is on the current sheet
+pub fn peer_cursor(state: &AppState) -> Option<(u16, u16)> {
+    let share = state.share.as_ref()?;
+    if state.sheets[state.sheet].name != share.sheet {
+        return None;
+    }
+    share.peer.as_ref()?.cursor
+}
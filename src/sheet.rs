@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{formula, window, AppState, AppMode, Result, VispError};
+use crate::fetch::Source;
+use crate::formula::Linked;
+use crate::grid::{Selection, Snapshot, TableContent};
+use crate::render::{FormatCache, Viewport};
+use crate::undo::UndoHistory;
+
+// A tab of the workbook. The current sheet lives in the fields of AppState
+// with the same names, its entry here only keeps the name and the source.
+#[derive(Default)]
+pub struct Sheet {
+    pub name: String,
+    pub content: TableContent,
+    pub viewport: Viewport,
+    pub undo: UndoHistory,
+    pub last_visual: Option<(AppMode, Selection)>,
+    pub change_baseline: Option<Snapshot>,
+    pub file: Option<PathBuf>,
+    pub saved_revision: u64,
+    pub source: Option<Source>, // Command the data comes from, see fetch.rs
+}
+
+impl Sheet {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), ..Self::default() }
+    }
+
+    // Trades everything but the name and the source with the current sheet
+    fn swap(&mut self, state: &mut AppState) {
+        std::mem::swap(&mut self.content, &mut state.table_content);
+        std::mem::swap(&mut self.viewport, &mut state.viewport);
+        std::mem::swap(&mut self.undo, &mut state.undo);
+        std::mem::swap(&mut self.last_visual, &mut state.last_visual);
+        std::mem::swap(&mut self.change_baseline, &mut state.change_baseline);
+        std::mem::swap(&mut self.file, &mut state.file);
+        std::mem::swap(&mut self.saved_revision, &mut state.saved_revision);
+    }
+}
+
+// Makes the sheet at `index` the current one
+pub fn show(state: &mut AppState, index: usize) {
+    if index == state.sheet || index >= state.sheets.len() {
+        return;
+    }
+    swap_in(state, state.sheet);
+    swap_in(state, index);
+    state.sheet = index;
+    window::clamp(state);
+    if state.options.trackchanges && state.change_baseline.is_none() {
+        state.change_baseline = Some(state.table_content.snapshot());
+    }
+    link(state);
+}
+
+fn swap_in(state: &mut AppState, index: usize) {
+    let mut sheet = std::mem::take(&mut state.sheets[index]);
+    sheet.swap(state);
+    state.sheets[index] = sheet;
+}
+
+// gt and gT. With a count gt goes to that sheet and gT goes back that many,
+// like vim's tabs.
+pub fn next(state: &mut AppState, forward: bool, count: Option<u32>) {
+    let len = state.sheets.len();
+    let index = match (forward, count) {
+        (true, Some(n)) => (n.max(1) as usize - 1).min(len - 1),
+        (true, None) => (state.sheet + 1) % len,
+        (false, n) => {
+            let back = n.unwrap_or(1) as usize % len;
+            (state.sheet + len - back) % len
+        }
+    };
+    show(state, index);
+}
+
+// The sheet called `name`, or at a position counted from 1
+pub fn find(state: &AppState, name: &str) -> Option<usize> {
+    if let Ok(n) = name.parse::<usize>() {
+        return n.checked_sub(1).filter(|&i| i < state.sheets.len());
+    }
+    state.sheets.iter().position(|sheet| sheet.name.eq_ignore_ascii_case(name))
+}
+
+// Adds an empty sheet after the current one and shows it. Without a name it
+// is called SheetN with the first free N.
+pub fn add(state: &mut AppState, name: Option<&str>) -> Result<()> {
+    let name = match name {
+        Some(name) => {
+            check_name(state, name)?;
+            name.to_string()
+        }
+        None => (1..).map(|n| format!("Sheet{}", n)).find(|name| find(state, name).is_none()).unwrap(),
+    };
+    let index = state.sheet + 1;
+    state.sheets.insert(index, Sheet::new(&name));
+    show(state, index);
+    Ok(())
+}
+
+// Replaces all sheets with the sheets of a workbook read from `file`, the
+// first one is shown
+pub fn replace_all(state: &mut AppState, mut sheets: Vec<(String, TableContent)>, file: &Path) {
+    if sheets.is_empty() {
+        sheets.push(("Sheet1".to_string(), TableContent::default()));
+    }
+    state.sheets.clear();
+    for (name, content) in sheets {
+        let mut sheet = Sheet::new(&usable_name(state, &name));
+        sheet.saved_revision = content.revision;
+        sheet.content = content;
+        sheet.file = Some(file.to_path_buf());
+        state.sheets.push(sheet);
+    }
+    // What was shown before goes away with the sheet it is swapped into
+    let mut first = std::mem::take(&mut state.sheets[0]);
+    first.swap(state);
+    state.sheets[0] = Sheet::new(&first.name);
+    state.sheet = 0;
+    window::clamp(state);
+    link(state);
+    // After link calculated the formulas
+    if state.options.trackchanges {
+        formula::refresh_all(&mut state.table_content);
+        state.change_baseline = Some(state.table_content.snapshot());
+        for sheet in state.sheets.iter_mut().skip(1) {
+            sheet.change_baseline = Some(sheet.content.snapshot());
+        }
+    }
+}
+
+// Other spreadsheets allow names which can't be used in our formulas, like
+// "Q1 2024". Those get the characters we can't read replaced.
+pub fn usable_name(state: &AppState, name: &str) -> String {
+    let mut usable: String = name.chars().map(|c| if formula::is_name_char(c) { c } else { '_' }).collect();
+    if !usable.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        usable = format!("Sheet{}", usable);
+    }
+    let mut candidate = usable.clone();
+    let mut n = 1;
+    while check_name(state, &candidate).is_err() {
+        n += 1;
+        candidate = format!("{}_{}", usable, n);
+    }
+    candidate
+}
+
+// References to the old name from other sheets become #REF!, until a sheet
+// is called that again
+pub fn rename(state: &mut AppState, name: &str) -> Result<()> {
+    if !state.sheets[state.sheet].name.eq_ignore_ascii_case(name) {
+        check_name(state, name)?;
+    }
+    if let Some(share) = state.share.as_mut().filter(|share| share.sheet == state.sheets[state.sheet].name) {
+        share.sheet = name.to_string();
+    }
+    state.sheets[state.sheet].name = name.to_string();
+    link(state);
+    Ok(())
+}
+
+// Deletes the current sheet, the next one is shown instead
+pub fn delete(state: &mut AppState, force: bool) -> Result<()> {
+    if state.sheets.len() == 1 {
+        return Err(VispError::Command("Cannot delete the last sheet".to_string()));
+    }
+    if state.is_modified() && !force {
+        return Err(VispError::Command("No write since last change (add ! to override)".to_string()));
+    }
+    state.sheets.remove(state.sheet);
+    let index = state.sheet.min(state.sheets.len() - 1);
+    let mut sheet = std::mem::take(&mut state.sheets[index]);
+    sheet.swap(state);
+    state.sheets[index] = Sheet { name: sheet.name, source: sheet.source, ..Sheet::default() };
+    state.sheet = index;
+    window::clamp(state);
+    link(state);
+    Ok(())
+}
+
+// Names are used in formulas like Name!A1, so they are a single word which
+// doesn't look like a number or a :sheet subcommand
+fn check_name(state: &AppState, name: &str) -> Result<()> {
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || !name.chars().all(formula::is_name_char) {
+        return Err(VispError::Command(format!("Invalid sheet name: {}, use letters, digits and _", name)));
+    }
+    if ["new", "rename", "delete"].iter().any(|command| command.eq_ignore_ascii_case(name)) {
+        return Err(VispError::Command(format!("Invalid sheet name: {}", name)));
+    }
+    if find(state, name).is_some() {
+        return Err(VispError::Command(format!("There already is a sheet called {}", name)));
+    }
+    Ok(())
+}
+
+// The sheet which was read from or is written to `file`
+pub fn with_file(state: &AppState, file: &Path) -> Option<usize> {
+    let same = |other: &Path| other == file || other.canonicalize().ok().is_some_and(|other| file.canonicalize().ok() == Some(other));
+    (0..state.sheets.len()).find(|&i| {
+        let other = if i == state.sheet { &state.file } else { &state.sheets[i].file };
+        other.as_deref().is_some_and(same)
+    })
+}
+
+// For :ls, like vim's buffer list: the number, % for the current sheet, +
+// if it has changes which aren't written, the file and the name
+pub fn list(state: &AppState) -> Vec<String> {
+    (0..state.sheets.len()).map(|i| {
+        let (file, modified) = if i == state.sheet {
+            (&state.file, state.is_modified())
+        } else {
+            let sheet = &state.sheets[i];
+            (&sheet.file, sheet.content.revision != sheet.saved_revision)
+        };
+        let file = file.as_ref().map_or("[No Name]".to_string(), |file| format!("\"{}\"", file.display()));
+        format!(
+            "{:>3} {}{} {:<30} {}",
+            i + 1,
+            if i == state.sheet { '%' } else { ' ' },
+            if modified { '+' } else { ' ' },
+            file,
+            state.sheets[i].name
+        )
+    }).collect()
+}
+
+// The first sheet besides the current one with changes which aren't written
+pub fn unsaved(state: &AppState) -> Option<&str> {
+    state.sheets.iter().enumerate()
+        .find(|(i, sheet)| *i != state.sheet && sheet.content.revision != sheet.saved_revision)
+        .map(|(_, sheet)| sheet.name.as_str())
+}
+
+fn content(state: &AppState, index: usize) -> &TableContent {
+    if index == state.sheet { &state.table_content } else { &state.sheets[index].content }
+}
+
+// Gives every sheet the values of the others for references like Sheet2!A1
+// and the colwidth and lazycalc options. Called whenever another sheet is
+// shown, as only the current one can change. Only sheets whose name, script
+// or values of other sheets changed are recalculated, again while the values
+// of others change through them, until they settle or there was a round per
+// sheet. Only the current sheet leaves formulas for later with lazycalc, the
+// values of the others are read as a whole.
+pub fn link(state: &mut AppState) {
+    let len = state.sheets.len();
+    for i in 0..len {
+        let table = if i == state.sheet { &mut state.table_content } else { &mut state.sheets[i].content };
+        table.default_col_width = Some(state.options.colwidth);
+        table.lazy = state.options.lazycalc && i == state.sheet;
+        if len > 1 {
+            formula::refresh_all(table);
+        }
+    }
+    let mut values: Vec<Rc<formula::Values>> = if len == 1 {
+        Vec::new()
+    } else {
+        (0..len).map(|i| Rc::new(formula::values(content(state, i)))).collect()
+    };
+    let names: Vec<String> = state.sheets.iter().map(|sheet| sheet.name.to_ascii_lowercase()).collect();
+    let others = |values: &[Rc<formula::Values>], i: usize| -> HashMap<String, Rc<formula::Values>> {
+        values.iter().enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(j, values)| (names[j].clone(), values.clone()))
+            .collect()
+    };
+    let mut stale: Vec<bool> = (0..len).map(|i| {
+        let linked = &content(state, i).linked;
+        let same_script = match (&linked.script, &state.script) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        linked.name != state.sheets[i].name || !same_script || linked.sheets != others(&values, i)
+    }).collect();
+
+    for _ in 0..len {
+        if !stale.contains(&true) {
+            break;
+        }
+        for i in 0..len {
+            if !stale[i] {
+                continue;
+            }
+            stale[i] = false;
+            let linked = Linked { name: state.sheets[i].name.clone(), sheets: others(&values, i), script: state.script.clone() };
+            let table = if i == state.sheet { &mut state.table_content } else { &mut state.sheets[i].content };
+            table.linked = linked;
+            formula::recalculate_all(table);
+            if len > 1 {
+                formula::refresh_all(table);
+                let new = formula::values(table);
+                if new != *values[i] {
+                    values[i] = Rc::new(new);
+                    // Sheets without formulas can't read it
+                    for (j, stale) in stale.iter_mut().enumerate() {
+                        *stale |= j != i && content(state, j).dependencies.has_formulas();
+                    }
+                }
+            }
+        }
+    }
+    // Tables which were read, or left lazycalc, and weren't recalculated
+    for i in 0..len {
+        let table = if i == state.sheet { &mut state.table_content } else { &mut state.sheets[i].content };
+        if !table.lazy {
+            formula::refresh_all(table);
+        }
+    }
+    // Values may have changed without a new revision
+    state.viewport.cache = FormatCache::default();
+}
@@ -0,0 +1,163 @@
+use std::cmp::Ordering;
+
+use regex::Regex;
+
+use crate::{edit, search, AppState, Result, VispError};
+use crate::formula::{offset_references, Formula};
+use crate::grid::{col_label_to_nr, col_nr_to_label, Selection, TableCell, TableContent};
+use crate::undo::Change;
+
+// Rows a sort or filter works on and the columns which move along. A range
+// of a single row stands for the whole table below the header block.
+fn rows_of(state: &AppState, range: Selection) -> (Vec<u16>, u16, u16) {
+    let content = &state.table_content;
+    let last_row = content.used_rows() - 1;
+    let last_col = content.used_cols() - 1;
+    let (top, bottom, left, right) = if range.rows == 1 {
+        (state.options.header_rows(), last_row, 0, last_col)
+    } else {
+        (range.row, range.bottom().min(last_row), range.col, range.right().min(last_col))
+    };
+    // Rows hidden by a filter keep their place
+    let rows = (top..=bottom).filter(|row| !content.hidden_rows.contains(row)).collect();
+    (rows, left, right)
+}
+
+// Numbers are compared as numbers and come before text, empty cells are
+// always last
+#[derive(PartialEq)]
+enum Key {
+    Number(f64),
+    Text(String),
+    Empty,
+}
+
+fn key(cell: Option<&TableCell>) -> Key {
+    match cell {
+        None | Some(TableCell::Empty) => Key::Empty,
+        Some(cell) => match cell.number() {
+            Some(n) => Key::Number(n),
+            // Dates are written year first, so their text sorts by time
+            None => Key::Text(cell.format_string().to_lowercase()),
+        },
+    }
+}
+
+fn compare(a: &Key, b: &Key, descending: bool) -> Ordering {
+    let ordering = match (a, b) {
+        (Key::Empty, Key::Empty) => return Ordering::Equal,
+        (Key::Empty, _) => return Ordering::Greater,
+        (_, Key::Empty) => return Ordering::Less,
+        (Key::Number(a), Key::Number(b)) => a.total_cmp(b),
+        (Key::Number(_), Key::Text(_)) => Ordering::Less,
+        (Key::Text(_), Key::Number(_)) => Ordering::Greater,
+        (Key::Text(a), Key::Text(b)) => a.cmp(b),
+    };
+    if descending { ordering.reverse() } else { ordering }
+}
+
+// Sorts rows by the values in column `col`, keeping the order of equal ones.
+// Notes, styles and formats move with their row. Formulas do too, with their
+// references moving along like with fill, so =B2*2 in row 2 is =B5*2 in row
+// 5. Returns how many rows moved.
+pub fn sort(state: &mut AppState, range: Selection, col: u16, descending: bool) -> Result<usize> {
+    let (rows, left, right) = rows_of(state, range);
+    if !(left..=right).contains(&col) {
+        return Err(VispError::Command(format!("Column {} is outside the range", col_nr_to_label(col))));
+    }
+    let content = &state.table_content;
+    let mut sorted: Vec<(u16, Key)> = rows.iter().map(|&row| (row, key(content.get_cell(row, col)))).collect();
+    sorted.sort_by(|(_, a), (_, b)| compare(a, b, descending));
+
+    let mut cells = Vec::new();
+    let mut moves = Vec::new();
+    for (&to, &(from, _)) in rows.iter().zip(&sorted) {
+        if from == to {
+            continue;
+        }
+        moves.push((from, to));
+        for c in left..=right {
+            let cell = match content.get_cell(from, c) {
+                Some(TableCell::Formula(f)) => TableCell::Formula(Box::new(Formula::new(&offset_references(&f.source, to as i32 - from as i32, 0)))),
+                cell => cell.cloned().unwrap_or(TableCell::Empty),
+            };
+            cells.push(((to, c), cell));
+        }
+    }
+    let moved = moves.len();
+    state.undo.begin_group();
+    let result = edit::replace_cells(state, cells);
+    if result.is_ok() && moved > 0 {
+        state.table_content.move_rows(&moves, left, right);
+        state.undo.record(Change::MoveRows { moves, left, right }, &state.options);
+    }
+    state.undo.end_group(&state.options);
+    result.map(|_| moved)
+}
+
+// Comparison of :filter, e.g. "B > 10" or "C ~ ^a"
+struct Predicate {
+    col: u16,
+    operator: &'static str,
+    value: String,
+    number: Option<f64>,
+    regex: Option<Regex>,
+    literal: Option<String>, // What the regex matches if it is plain text, see search::literal
+}
+
+const OPERATORS: &[&str] = &["<=", ">=", "!=", "=", "<", ">", "~"];
+
+impl Predicate {
+    fn parse(text: &str) -> Result<Self> {
+        let usage = || VispError::Command("Usage: filter column =|!=|<|<=|>|>=|~ value".to_string());
+        let split = text.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(text.len());
+        let col = col_label_to_nr(&text[..split].to_ascii_uppercase()).ok_or_else(usage)?;
+        let rest = text[split..].trim_start();
+        let operator = OPERATORS.iter().find(|op| rest.starts_with(*op)).ok_or_else(usage)?;
+        let value = rest[operator.len()..].trim().to_string();
+        let regex = match *operator {
+            "~" => Some(Regex::new(&value).map_err(|e| VispError::Parse(e.to_string()))?),
+            _ => None,
+        };
+        let literal = regex.as_ref().and_then(|_| search::literal(&value));
+        Ok(Self { col, operator, number: value.parse().ok(), value, regex, literal })
+    }
+
+    // Numbers compare as numbers. Against a number, text only matches !=.
+    fn matches(&self, content: &TableContent, row: u16) -> bool {
+        let cell = content.get_cell(row, self.col);
+        if let Some(regex) = &self.regex {
+            return regex.is_match(&content.display(row, self.col));
+        }
+        let ordering = match (self.number, cell.and_then(TableCell::number)) {
+            (Some(value), Some(n)) => n.total_cmp(&value),
+            (Some(_), None) => return self.operator == "!=",
+            (None, _) => content.display(row, self.col).to_lowercase().cmp(&self.value.to_lowercase()),
+        };
+        match self.operator {
+            "=" => ordering == Ordering::Equal,
+            "!=" => ordering != Ordering::Equal,
+            "<" => ordering == Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            ">" => ordering == Ordering::Greater,
+            _ => ordering != Ordering::Less,
+        }
+    }
+}
+
+// Hides the rows which don't match `predicate`, or those which do if
+// `invert` is set. Rows hidden before stay hidden. Returns how many rows are
+// hidden now.
+pub fn filter(state: &mut AppState, range: Selection, predicate: &str, invert: bool) -> Result<usize> {
+    let predicate = Predicate::parse(predicate)?;
+    let (rows, _, _) = rows_of(state, range);
+    let content = &mut state.table_content;
+    let candidate = search::candidate_rows(content, predicate.literal.as_deref());
+    let hide: Vec<u16> = rows.into_iter().filter(|&row| (candidate(row) && predicate.matches(content, row)) == invert).collect();
+    content.hidden_rows.extend(hide);
+
+    let (row, col) = content.selection.cursor();
+    let row = content.visible_row(row);
+    content.selection.set_cursor(row, col);
+    Ok(content.hidden_rows.len())
+}
@@ -0,0 +1,44 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use crate::{csv, AppState};
+use crate::io::AppEvent;
+
+// Reads comma separated rows from stdin for --stream, until it is closed
+pub fn spawn_stdin_reader(sender: Sender<AppEvent>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let event = match line {
+                Ok(line) => AppEvent::StreamLine(line),
+                Err(e) => AppEvent::InputError(e),
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(AppEvent::StreamClosed);
+    });
+}
+
+// Adds a row to the end of the table. A cursor in the last row moves along,
+// like tail -f.
+pub fn append_line(state: &mut AppState, line: &str) {
+    let (delimiter, locale) = (state.options.delimiter(), state.options.locale());
+    let content = &mut state.table_content;
+    let row = if content.iter().next().is_none() { 0 } else { content.used_rows() };
+    let following = content.selection.cursor().0 + 1 == row && !state.mode.is_visual();
+    if row == u16::MAX {
+        return;
+    }
+
+    let cells = csv::parse_record(line, delimiter, locale).into_iter().enumerate().take(u16::MAX as usize + 1)
+        .map(|(col, cell)| ((row, col as u16), cell))
+        .collect();
+    // Formulas may read the new row, e.g. a running total over a column
+    content.set_cells(cells);
+    if following {
+        let (_, col) = content.selection.cursor();
+        content.selection.set_cursor(row, col);
+    }
+}
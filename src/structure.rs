@@ -0,0 +1,127 @@
+use crate::{edit, window, AppState, Message, Result, VispError};
+use crate::grid::{Axis, Removed, Shift, TableCell, DEFAULT_COL_WIDTH};
+use crate::undo::{CellChange, Change};
+
+// Inserts `count` empty rows or columns before `at`, the cursor goes to the
+// first of them
+pub fn insert(state: &mut AppState, axis: Axis, at: u16, count: u16) -> Result<()> {
+    edit::check_writable(&state.options)?;
+    let shift = Shift { axis, at, count, insert: true };
+    let removed = apply(state, shift);
+    state.undo.record(Change::Shift { shift, removed }, &state.options);
+    state.message = Some(Message::Info(format!("{} inserted", describe(axis, count))));
+    Ok(())
+}
+
+// Like insert and then replacing cells of the new rows or columns, for
+// pasting. It is a single step to undo and formulas are only recalculated
+// once, so large blocks go in quickly.
+pub fn insert_filled(state: &mut AppState, axis: Axis, at: u16, count: u16, cells: Vec<((u16, u16), TableCell)>) -> Result<()> {
+    edit::check_writable(&state.options)?;
+    let shift = Shift { axis, at, count, insert: true };
+    let cells: Vec<((u16, u16), TableCell)> = cells.into_iter().filter(|(_, cell)| *cell != TableCell::Empty).collect();
+    let mut changes = Vec::with_capacity(cells.len());
+    for (cell, new) in &cells {
+        state.edit_log.record(*cell, &TableCell::Empty, new);
+        changes.push(CellChange { cell: *cell, old: TableCell::Empty, new: new.clone() });
+    }
+    let removed = state.table_content.insert_filled(shift, cells);
+    follow(state, shift);
+    state.undo.record(Change::Group(vec![Change::Shift { shift, removed }, Change::Cells(changes)]), &state.options);
+    state.message = Some(Message::Info(format!("{} inserted", describe(axis, count))));
+    Ok(())
+}
+
+// Deletes `count` rows or columns from `at` on, the ones after them move up
+// or left. Nothing is deleted if a protected range is in the way.
+pub fn delete(state: &mut AppState, axis: Axis, at: u16, count: u16) -> Result<()> {
+    edit::check_writable(&state.options)?;
+    let shift = Shift { axis, at, count, insert: false };
+    let last = at as u32 + count as u32 - 1;
+    if state.options.protect {
+        let deleted = |start: u16, end: u16| start as u32 <= last && end >= at;
+        let protected = state.table_content.protected.iter().find(|range| match axis {
+            Axis::Rows => deleted(range.row, range.bottom()),
+            Axis::Cols => deleted(range.col, range.right()),
+        });
+        if let Some(range) = protected {
+            return Err(VispError::Command(format!("{} is protected, see :set noprotect", range.name())));
+        }
+    }
+    let removed = apply(state, shift);
+    state.undo.record(Change::Shift { shift, removed }, &state.options);
+    state.message = Some(Message::Info(format!("{} deleted", describe(axis, count))));
+    Ok(())
+}
+
+// Shifts the table and everything else which points at cells
+pub fn apply(state: &mut AppState, shift: Shift) -> Removed {
+    let removed = state.table_content.shift(shift);
+    follow(state, shift);
+    removed
+}
+
+// Reverts apply, for undo
+pub fn revert(state: &mut AppState, shift: Shift, removed: &Removed) {
+    state.table_content.unshift(shift, removed);
+    follow(state, shift.inverse());
+}
+
+// Selections which were remembered and other windows move with the cells,
+// the cursor goes to the first row or column which was inserted or deleted
+fn follow(state: &mut AppState, shift: Shift) {
+    state.last_visual = state.last_visual.and_then(|(mode, s)| Some((mode, shift.selection(&s)?)));
+    state.selection_history = state.selection_history.iter()
+        .filter_map(|&(mode, s)| Some((mode, shift.selection(&s)?)))
+        .collect();
+    window::shift(state, shift);
+    let selection = &mut state.table_content.selection;
+    let (row, col) = selection.cursor();
+    match shift.axis {
+        Axis::Rows => selection.set_cursor(shift.at, col),
+        Axis::Cols => selection.set_cursor(row, shift.at),
+    }
+}
+
+// Makes columns wider or narrower by `delta`, at least 1 wide
+pub fn resize_cols(state: &mut AppState, left: u16, right: u16, delta: i32) -> Result<()> {
+    let content = &state.table_content;
+    let sizes: Vec<(u16, Option<u16>)> = (left..=right).map(|col| {
+        let width = content.col_widths.get(&col).copied().or(content.default_col_width).unwrap_or(DEFAULT_COL_WIDTH) as i32;
+        (col, Some((width + delta).clamp(1, u16::MAX as i32) as u16))
+    }).collect();
+    let width = sizes[sizes.len() - 1].1.unwrap_or(DEFAULT_COL_WIDTH);
+    resize(state, Axis::Cols, sizes)?;
+    state.message = Some(Message::Info(format!("colwidth={}", width)));
+    Ok(())
+}
+
+// Sets the widths of columns or heights of rows, None for the default, as a
+// change which can be undone. They are saved with the file, see
+// format::Sidecar.
+pub fn resize(state: &mut AppState, axis: Axis, sizes: Vec<(u16, Option<u16>)>) -> Result<()> {
+    let content = &state.table_content;
+    let map = match axis {
+        Axis::Cols => &content.col_widths,
+        Axis::Rows => &content.row_heights,
+    };
+    let sizes: Vec<(u16, Option<u16>, Option<u16>)> = sizes.into_iter()
+        .map(|(i, size)| (i, map.get(&i).copied(), size))
+        .filter(|(_, old, new)| old != new)
+        .collect();
+    let (Some(first), Some(last)) = (sizes.iter().map(|s| s.0).min(), sizes.iter().map(|s| s.0).max()) else {
+        return Ok(());
+    };
+    edit::check_lines(state, axis, first, last)?;
+    state.table_content.set_sizes(axis, sizes.iter().map(|&(i, _, new)| (i, new)));
+    state.undo.record(Change::Sizes { axis, sizes }, &state.options);
+    Ok(())
+}
+
+fn describe(axis: Axis, count: u16) -> String {
+    let noun = match axis {
+        Axis::Rows => "row",
+        Axis::Cols => "column",
+    };
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{csv, sheet, window, workbook, AppState, Message, Result, VispError};
+use crate::grid::TableContent;
+use crate::task::Progress;
+
+// Shortest time between two writes of a swap file, like vim's updatetime
+const INTERVAL: Duration = Duration::from_secs(4);
+
+// Copies of CSV files with their unsaved changes, kept next to them as
+// .name.csv.swp like in vim. They are written while visp runs and removed
+// when the changes are written or thrown away, so one which is found on
+// opening a file is left from a crash. Workbooks don't get one.
+#[derive(Default)]
+pub struct SwapFiles {
+    written: HashMap<PathBuf, u64>, // Swap files and the revision of the table in them
+    last_write: Option<Instant>,
+}
+
+pub fn path(file: &Path) -> PathBuf {
+    let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    file.with_file_name(format!(".{}.swp", name))
+}
+
+// Called every second by the main loop. Writes the swap files of sheets with
+// new changes and removes those of sheets without changes. Returns whether
+// an error is to be shown.
+pub fn update(state: &mut AppState) -> bool {
+    let mut modified: HashMap<PathBuf, (&TableContent, u64)> = HashMap::new();
+    for i in 0..state.sheets.len() {
+        let (file, content, saved) = if i == state.sheet {
+            (&state.file, &state.table_content, state.saved_revision)
+        } else {
+            let sheet = &state.sheets[i];
+            (&sheet.file, &sheet.content, sheet.saved_revision)
+        };
+        match file {
+            Some(file) if content.revision != saved && !workbook::is_workbook(file) => {
+                modified.insert(path(file), (content, content.revision));
+            }
+            _ => {}
+        }
+    }
+
+    let mut failed = false;
+    let swap = &mut state.swap;
+    swap.written.retain(|path, _| {
+        let keep = modified.contains_key(path);
+        if !keep {
+            let _ = fs::remove_file(path);
+        }
+        keep
+    });
+    if swap.last_write.is_some_and(|last| last.elapsed() < INTERVAL) {
+        return false;
+    }
+    for (path, (content, revision)) in modified {
+        if swap.written.get(&path) == Some(&revision) {
+            continue;
+        }
+        // Not tried again until the next change, so a failure is shown once
+        swap.written.insert(path.clone(), revision);
+        swap.last_write = Some(Instant::now());
+        // Always in UTF-8, which has every character
+        let (text, _) = csv::to_text(content, &state.options);
+        if let Err(e) = fs::write(&path, text) {
+            tracing::warn!("swap file {} not written: {}", path.display(), e);
+            state.message = Some(Message::Error(format!("Swap file \"{}\" not written: {}", path.display(), e)));
+            failed = true;
+        }
+    }
+    failed
+}
+
+// When visp quits
+pub fn remove_all(state: &mut AppState) {
+    for path in state.swap.written.keys() {
+        let _ = fs::remove_file(path);
+    }
+    state.swap.written.clear();
+}
+
+// After a CSV file is opened, asks to recover it if a swap file was left
+// for it and isn't one of ours
+pub fn check(state: &mut AppState, file: &Path) {
+    let swap = path(file);
+    if !swap.exists() || state.swap.written.contains_key(&swap) {
+        return;
+    }
+    state.confirm = Some("recover".to_string());
+    state.message = Some(Message::Info(format!(
+        "Found swap file \"{}\", visp may have crashed. Recover the unsaved changes? (y/n)",
+        swap.display()
+    )));
+}
+
+// :recover puts the table from the swap file of the current file in place of
+// the one read from the file. It counts as changed until it is written.
+pub fn recover(state: &mut AppState) -> Result<()> {
+    let file = state.file.clone().ok_or_else(|| VispError::Command("No file name".to_string()))?;
+    let swap = path(&file);
+    if !swap.exists() {
+        return Err(VispError::Command(format!("No swap file found for \"{}\"", file.display())));
+    }
+    let (cells, _) = csv::read(&swap, state.options.delimiter(), state.options.locale(), &Progress::default())?;
+    let formats = std::mem::take(&mut state.table_content.formats);
+    state.table_content = TableContent::from_rows(cells);
+    state.table_content.formats = formats;
+    state.saved_revision = state.table_content.revision;
+    state.table_content.changed();
+    window::clamp(state);
+    state.undo.clear();
+    sheet::link(state);
+    state.message = Some(Message::Info(format!(
+        "Recovered \"{}\" from \"{}\", :w to keep the changes",
+        file.display(),
+        swap.display()
+    )));
+    Ok(())
+}
+
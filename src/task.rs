@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::io::AppEvent;
+use crate::{AppState, Message, Result, VispError};
+
+// How often the status of a running task is drawn
+const UPDATE: Duration = Duration::from_millis(100);
+
+const SPINNER: &[char] = &['|', '/', '-', '\\'];
+
+// Work which takes seconds on big sheets, like reading a file with :e, runs on
+// a thread of its own while the main loop keeps drawing how far it got. Its
+// result is put into the table back on the main thread. Meanwhile only
+// Ctrl-C is taken, which cancels it, other keys would work on the table it
+// is about to replace. Without the main loop, e.g. in batch mode, tasks run
+// right away and their errors are returned like those of other commands.
+#[derive(Default)]
+pub struct Tasks {
+    pub sender: Option<Sender<AppEvent>>, // Set while the main loop runs
+    pub running: Option<Task>,
+    started: u64, // Tasks started so far, the id of the last one
+}
+
+pub struct Task {
+    id: u64,
+    title: String, // E.g. Reading "big.csv"
+    progress: Arc<Progress>,
+    start: Instant,
+}
+
+// Shared by a task and the thread doing its work
+#[derive(Default)]
+pub struct Progress {
+    done: AtomicU64,
+    total: AtomicU64, // 0 while it isn't known, a spinner is shown then
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    pub fn set(&self, done: u64, total: u64) {
+        self.done.store(done, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // For the work to stop early after Ctrl-C
+    pub fn check(&self) -> Result<()> {
+        if self.cancelled() {
+            return Err(VispError::Command("Cancelled".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// What a finished task does on the main thread
+pub type Finish = Box<dyn FnOnce(&mut AppState) + Send>;
+
+// Runs `work` and hands its result to `finish`. In the background errors of
+// either are shown as the message.
+pub fn run<T: Send + 'static>(
+    state: &mut AppState,
+    title: String,
+    work: impl FnOnce(&Progress) -> Result<T> + Send + 'static,
+    finish: impl FnOnce(&mut AppState, T) -> Result<()> + Send + 'static,
+) -> Result<()> {
+    let sender = match &state.tasks.sender {
+        Some(sender) if state.tasks.running.is_none() => sender.clone(),
+        _ => return finish(state, work(&Progress::default())?),
+    };
+    state.tasks.started += 1;
+    let id = state.tasks.started;
+    let progress = Arc::new(Progress::default());
+    state.tasks.running = Some(Task { id, title, progress: progress.clone(), start: Instant::now() });
+    thread::spawn(move || {
+        let worker = {
+            let progress = progress.clone();
+            thread::spawn(move || work(&progress))
+        };
+        while !worker.is_finished() && !progress.cancelled() {
+            thread::sleep(UPDATE);
+            if sender.send(AppEvent::TaskProgress).is_err() {
+                return;
+            }
+        }
+        let result = worker.join().unwrap_or_else(|_| Err(VispError::Command("The task failed".to_string())));
+        let finish: Finish = Box::new(move |state| {
+            if let Err(e) = result.and_then(|value| finish(state, value)) {
+                state.message = Some(Message::Error(e.to_string()));
+            }
+        });
+        let _ = sender.send(AppEvent::TaskDone(id, finish));
+    });
+    Ok(())
+}
+
+// Runs `f` with tasks done right away, for commands which need their results
+// before going on, like a macro or opening several files
+pub fn blocking<R>(state: &mut AppState, f: impl FnOnce(&mut AppState) -> R) -> R {
+    let sender = state.tasks.sender.take();
+    let result = f(state);
+    state.tasks.sender = sender;
+    result
+}
+
+// Called by the main loop with the result of task `id`. That of a cancelled
+// task is dropped.
+pub fn done(state: &mut AppState, id: u64, finish: Finish) {
+    if state.tasks.running.as_ref().is_some_and(|task| task.id == id) {
+        state.tasks.running = None;
+        finish(state);
+    }
+}
+
+// Ctrl-C. The table stays as it was, the thread stops at its next check.
+pub fn cancel(state: &mut AppState) {
+    if let Some(task) = state.tasks.running.take() {
+        task.progress.cancelled.store(true, Ordering::Relaxed);
+        state.message = Some(Message::Error(format!("{} cancelled", task.title)));
+    }
+}
+
+// For the command line, e.g. Reading "big.csv" [#####-----] 50% 3s
+pub fn status(task: &Task) -> String {
+    let elapsed = task.start.elapsed();
+    let (done, total) = (task.progress.done.load(Ordering::Relaxed), task.progress.total.load(Ordering::Relaxed));
+    let shown = match (done.min(total) * 100).checked_div(total) {
+        Some(percent) => {
+            let filled = percent as usize / 5;
+            format!("[{}{}] {}%", "#".repeat(filled), "-".repeat(20 - filled), percent)
+        }
+        None => SPINNER[(elapsed.as_millis() / UPDATE.as_millis()) as usize % SPINNER.len()].to_string(),
+    };
+    format!("{} {} {}s  Ctrl-C cancels", task.title, shown, elapsed.as_secs())
+}
@@ -0,0 +1,224 @@
+use std::env;
+
+use tui::style::{Color, Modifier, Style};
+
+use crate::grid::{CellColor, CellStyle};
+
+// How many colors the terminal can show
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorSupport {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    Monochrome,
+}
+
+impl ColorSupport {
+    // Guesses from the environment the same way most terminal programs do
+    pub fn detect() -> Self {
+        if env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            return Self::Monochrome;
+        }
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return Self::TrueColor;
+        }
+        match env::var("TERM").unwrap_or_default().as_str() {
+            "" | "dumb" => Self::Monochrome,
+            term if term.contains("256color") => Self::Ansi256,
+            _ => Self::Ansi16,
+        }
+    }
+}
+
+// Styles used to draw the table and the command line
+pub struct Theme {
+    pub name: &'static str,
+    pub support: ColorSupport,
+    pub cell: Style,
+    pub selected_cell: Style,
+    pub header: Style,
+    pub selected_header: Style,
+    pub status_line: Style,
+    pub sheet_tab: Style,
+    pub current_sheet_tab: Style,
+    pub changed_cell: Style,
+    pub search_match: Style,
+    pub current_match: Style, // The match under the cursor
+    pub peer_cursor: Style, // Of the other visp editing the sheet, see share.rs
+    pub conflict: Style, // Cells of visp --merge changed differently on both sides
+    pub note_marker: Style,
+    pub note: Style,
+    pub command_line: Style,
+    pub message: Style,
+    pub error: Style,
+    pub border: Style,
+    pub picker_highlight: Style,
+    pub overview_string: Style,
+    pub overview_value: Style,
+    pub references: Vec<Style>, // Cells read by the formula being typed, taken in turn
+}
+
+impl Theme {
+    pub fn new(support: ColorSupport) -> Self {
+        let cell = Style::default();
+        let header = Style::default().add_modifier(Modifier::BOLD);
+        // Without colors everything falls back to reverse video and bold
+        let (selected_cell, selected_header) = match support {
+            ColorSupport::TrueColor => (
+                cell.fg(Color::Rgb(0xff, 0xff, 0xff)).bg(Color::Rgb(0x26, 0x4f, 0x78)),
+                header.fg(Color::Rgb(0xff, 0xff, 0xff)).bg(Color::Rgb(0x1c, 0x3b, 0x5a)),
+            ),
+            ColorSupport::Ansi256 => (
+                cell.fg(Color::Indexed(255)).bg(Color::Indexed(24)),
+                header.fg(Color::Indexed(255)).bg(Color::Indexed(23)),
+            ),
+            ColorSupport::Ansi16 => (
+                cell.fg(Color::White).bg(Color::Blue),
+                header.fg(Color::White).bg(Color::Blue),
+            ),
+            ColorSupport::Monochrome => (
+                cell.add_modifier(Modifier::REVERSED),
+                header.add_modifier(Modifier::REVERSED),
+            ),
+        };
+        let fg = |color| match support {
+            ColorSupport::Monochrome => Style::default(),
+            _ => Style::default().fg(color),
+        };
+
+        Self {
+            name: "default",
+            support,
+            cell,
+            selected_cell,
+            header,
+            selected_header,
+            status_line: Style::default().add_modifier(Modifier::REVERSED),
+            sheet_tab: Style::default().add_modifier(Modifier::REVERSED),
+            current_sheet_tab: Style::default().add_modifier(Modifier::BOLD).remove_modifier(Modifier::REVERSED), // Drawn over sheet_tab
+            changed_cell: fg(Color::Magenta).add_modifier(Modifier::UNDERLINED),
+            search_match: match support {
+                ColorSupport::Monochrome => Style::default().add_modifier(Modifier::BOLD),
+                _ => Style::default().fg(Color::Black).bg(Color::Yellow),
+            },
+            current_match: match support {
+                ColorSupport::Monochrome => Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+                _ => Style::default().fg(Color::Black).bg(Color::Red),
+            },
+            peer_cursor: match support {
+                ColorSupport::Monochrome => Style::default().add_modifier(Modifier::UNDERLINED | Modifier::ITALIC),
+                _ => Style::default().fg(Color::Black).bg(Color::Green),
+            },
+            conflict: match support {
+                ColorSupport::Monochrome => Style::default().add_modifier(Modifier::BOLD | Modifier::ITALIC),
+                _ => Style::default().fg(Color::White).bg(Color::Magenta),
+            },
+            note_marker: fg(Color::Red),
+            note: fg(Color::Yellow),
+            command_line: Style::default(),
+            message: Style::default(),
+            error: match support {
+                ColorSupport::Monochrome => Style::default().add_modifier(Modifier::BOLD),
+                _ => Style::default().fg(Color::Red),
+            },
+            border: fg(Color::DarkGray),
+            picker_highlight: Style::default().add_modifier(Modifier::REVERSED),
+            overview_string: fg(Color::Cyan),
+            overview_value: fg(Color::Green),
+            // Like the colored boxes other spreadsheets draw, without colors
+            // they are all only underlined
+            references: [Color::Blue, Color::Red, Color::Magenta, Color::Green, Color::Cyan, Color::Yellow]
+                .into_iter()
+                .map(|color| fg(color).add_modifier(Modifier::UNDERLINED))
+                .collect(),
+        }
+    }
+}
+
+impl Theme {
+    // Terminal style for the formatting a user gave a cell. Colors are
+    // dropped without color support.
+    pub fn cell_style(&self, cell_style: &CellStyle) -> Style {
+        let mut style = Style::default();
+        if cell_style.bold {
+            style = style.add_modifier(Modifier::BOLD);
+        }
+        if cell_style.italic {
+            style = style.add_modifier(Modifier::ITALIC);
+        }
+        if cell_style.underline {
+            style = style.add_modifier(Modifier::UNDERLINED);
+        }
+        if self.support != ColorSupport::Monochrome {
+            if let Some(fg) = cell_style.fg {
+                style = style.fg(self.color(fg));
+            }
+            if let Some(bg) = cell_style.bg {
+                style = style.bg(self.color(bg));
+            }
+        }
+        style
+    }
+
+    fn color(&self, color: CellColor) -> Color {
+        match color {
+            CellColor::Black => Color::Black,
+            CellColor::Red => Color::Red,
+            CellColor::Green => Color::Green,
+            CellColor::Yellow => Color::Yellow,
+            CellColor::Blue => Color::Blue,
+            CellColor::Magenta => Color::Magenta,
+            CellColor::Cyan => Color::Cyan,
+            CellColor::White => Color::White,
+            CellColor::Rgb(r, g, b) => match self.support {
+                ColorSupport::TrueColor => Color::Rgb(r, g, b),
+                ColorSupport::Ansi256 => Color::Indexed(ansi256(r, g, b)),
+                _ => ansi16(r, g, b),
+            },
+        }
+    }
+}
+
+// Nearest color in the 6x6x6 cube of the 256 color palette
+fn ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+// Nearest basic color, each channel either on or off
+fn ansi16(r: u8, g: u8, b: u8) -> Color {
+    match (r >= 128, g >= 128, b >= 128) {
+        (false, false, false) => Color::Black,
+        (true, false, false) => Color::Red,
+        (false, true, false) => Color::Green,
+        (true, true, false) => Color::Yellow,
+        (false, false, true) => Color::Blue,
+        (true, false, true) => Color::Magenta,
+        (false, true, true) => Color::Cyan,
+        (true, true, true) => Color::White,
+    }
+}
+
+// Names for :colorscheme
+pub const COLOR_SCHEMES: &[&str] = &["default", "mono"];
+
+impl Theme {
+    // A scheme from COLOR_SCHEMES for the colors the terminal supports
+    pub fn by_name(name: &str, support: ColorSupport) -> Option<Self> {
+        match name {
+            "default" => Some(Self::new(support)),
+            "mono" => Some(Self {
+                name: "mono",
+                ..Self::new(ColorSupport::Monochrome)
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::new(ColorSupport::Ansi16)
+    }
+}
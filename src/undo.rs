@@ -0,0 +1,412 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use crate::{edit, structure, AppState, Message};
+use crate::format::CellFormat;
+use crate::grid::{cell_name, col_nr_to_label, Axis, CellStyle, Removed, Shift, Snapshot, TableCell};
+use crate::options::Options;
+
+// One step for u and Ctrl-R. Only what changed is kept, not a copy of the
+// table, so editing large tables stays cheap.
+pub enum Change {
+    Cells(Vec<CellChange>),
+    MoveCol { from: u16, to: u16, formulas: Vec<((u16, u16), TableCell)> }, // The formulas before their references were rewritten
+    Shift { shift: Shift, removed: Removed },
+    Restore { old: Box<Snapshot>, new: Box<Snapshot> }, // :snapshot restore, widths, notes, styles and formats too
+    MoveRows { moves: Vec<(u16, u16)>, left: u16, right: u16 }, // Notes, styles and formats moved by :sort
+    HiddenCols { old: BTreeSet<u16>, new: BTreeSet<u16> }, // :hide and :unhide
+    Sizes { axis: Axis, sizes: Vec<(u16, Option<u16>, Option<u16>)> }, // Column or row, old and new size
+    Styles(Vec<StyleChange>), // :style
+    Formats(Vec<FormatChange>), // :fmt
+    Note { cell: (u16, u16), old: Option<String>, new: Option<String> }, // :note and :delnote
+    Group(Vec<Change>), // Undone together, see UndoHistory::begin_group
+}
+
+impl Change {
+    fn describe(&self) -> String {
+        match self {
+            Change::Cells(cells) if cells.len() == 1 => format!("{} changed", cell_name(cells[0].cell.0, cells[0].cell.1)),
+            Change::Cells(cells) => format!("{} cells changed", cells.len()),
+            Change::MoveCol { from, to, .. } => format!("Column {} moved to {}", col_nr_to_label(*from), col_nr_to_label(*to)),
+            Change::Shift { shift, .. } => {
+                let (noun, at) = match shift.axis {
+                    Axis::Rows => ("row", (shift.at as u32 + 1).to_string()),
+                    Axis::Cols => ("column", col_nr_to_label(shift.at)),
+                };
+                let plural = if shift.count == 1 { "" } else { "s" };
+                let verb = if shift.insert { "inserted at" } else { "deleted from" };
+                format!("{} {}{} {} {}", shift.count, noun, plural, verb, at)
+            }
+            Change::Sizes { axis, sizes } => {
+                let noun = match axis {
+                    Axis::Rows => "row",
+                    Axis::Cols => "column",
+                };
+                format!("{} {}{} resized", sizes.len(), noun, if sizes.len() == 1 { "" } else { "s" })
+            }
+            Change::Styles(styles) => format!("{} cell{} styled", styles.len(), if styles.len() == 1 { "" } else { "s" }),
+            Change::Formats(formats) => format!("{} cell{} formatted", formats.len(), if formats.len() == 1 { "" } else { "s" }),
+            Change::Note { cell, old: None, .. } => format!("Note added to {}", cell_name(cell.0, cell.1)),
+            Change::Note { cell, new: None, .. } => format!("Note removed from {}", cell_name(cell.0, cell.1)),
+            Change::Note { cell, .. } => format!("Note on {} changed", cell_name(cell.0, cell.1)),
+            Change::Restore { .. } => "Snapshot restored".to_string(),
+            Change::MoveRows { moves, .. } => format!("{} rows sorted", moves.len()),
+            Change::HiddenCols { old, new } => {
+                let count = old.symmetric_difference(new).count();
+                let plural = if count == 1 { "" } else { "s" };
+                format!("{} column{} {}", count, plural, if new.len() > old.len() { "hidden" } else { "shown" })
+            }
+            Change::Group(changes) => changes.iter().map(Change::describe).collect::<Vec<_>>().join(", "),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        std::mem::size_of::<Change>() + match self {
+            Change::Cells(cells) => cells.iter()
+                .map(|c| std::mem::size_of::<CellChange>() + c.old.heap_size() + c.new.heap_size())
+                .sum(),
+            Change::MoveCol { formulas, .. } => formulas.iter().map(|(_, cell)| std::mem::size_of::<((u16, u16), TableCell)>() + cell.heap_size()).sum(),
+            Change::Shift { removed, .. } => removed.memory_size(),
+            Change::Restore { old, new } => old.memory_size() + new.memory_size(),
+            Change::MoveRows { moves, .. } => moves.capacity() * std::mem::size_of::<(u16, u16)>(),
+            Change::HiddenCols { old, new } => (old.len() + new.len()) * std::mem::size_of::<u16>(),
+            Change::Sizes { sizes, .. } => sizes.capacity() * std::mem::size_of::<(u16, Option<u16>, Option<u16>)>(),
+            Change::Styles(styles) => styles.capacity() * std::mem::size_of::<StyleChange>(),
+            Change::Formats(formats) => formats.iter()
+                .map(|c| std::mem::size_of::<FormatChange>() + c.old.iter().chain(&c.new).filter_map(|f| f.spec.as_ref()).map(String::capacity).sum::<usize>())
+                .sum(),
+            Change::Note { old, new, .. } => old.iter().chain(new).map(String::capacity).sum(),
+            Change::Group(changes) => changes.iter().map(Change::memory_size).sum(),
+        }
+    }
+}
+
+pub struct CellChange {
+    pub cell: (u16, u16),
+    pub old: TableCell,
+    pub new: TableCell,
+}
+
+// None is no style
+pub struct StyleChange {
+    pub cell: (u16, u16),
+    pub old: Option<CellStyle>,
+    pub new: Option<CellStyle>,
+}
+
+pub struct FormatChange {
+    pub cell: (u16, u16),
+    pub old: Option<CellFormat>,
+    pub new: Option<CellFormat>,
+}
+
+#[derive(Default)]
+pub struct UndoHistory {
+    undo: VecDeque<Change>, // Oldest first
+    redo: Vec<Change>, // Changes which were undone, the last one first
+    size: usize, // Approximate number of bytes used by both stacks
+    group: Option<Vec<Change>>, // Changes recorded since begin_group
+}
+
+impl UndoHistory {
+    // Called after every change, which makes the undone changes unreachable
+    pub fn record(&mut self, change: Change, options: &Options) {
+        if matches!(&change, Change::Cells(cells) if cells.is_empty()) {
+            return;
+        }
+        if let Some(group) = &mut self.group {
+            group.push(change);
+            return;
+        }
+        self.size -= self.redo.drain(..).map(|change| change.memory_size()).sum::<usize>();
+        self.size += change.memory_size();
+        self.undo.push_back(change);
+        self.prune(options);
+    }
+
+    // The changes recorded until end_group are undone and redone as one, e.g.
+    // rows inserted by p and the cells put into them
+    pub fn begin_group(&mut self) {
+        self.group.get_or_insert_with(Vec::new);
+    }
+
+    // What the changes since begin_group did to all cells together, e.g. "2
+    // rows deleted, 5 cells changed" for :g. None if nothing changed.
+    pub fn group_summary(&self) -> Option<String> {
+        fn count(change: &Change, totals: &mut [u32; 5]) {
+            match change {
+                Change::Cells(cells) => totals[0] += cells.len() as u32,
+                Change::Shift { shift, .. } => {
+                    let i = match (shift.axis, shift.insert) {
+                        (Axis::Rows, true) => 1,
+                        (Axis::Rows, false) => 2,
+                        (Axis::Cols, true) => 3,
+                        (Axis::Cols, false) => 4,
+                    };
+                    totals[i] += shift.count as u32;
+                }
+                Change::Group(changes) => changes.iter().for_each(|change| count(change, totals)),
+                _ => {}
+            }
+        }
+        let mut totals = [0; 5];
+        self.group.iter().flatten().for_each(|change| count(change, &mut totals));
+        let names = [("cell", "changed"), ("row", "inserted"), ("row", "deleted"), ("column", "inserted"), ("column", "deleted")];
+        let parts: Vec<String> = totals.iter().zip(names)
+            .filter(|(&total, _)| total > 0)
+            .map(|(&total, (noun, verb))| format!("{} {}{} {}", total, noun, if total == 1 { "" } else { "s" }, verb))
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    pub fn end_group(&mut self, options: &Options) {
+        let mut changes = self.group.take().unwrap_or_default();
+        match changes.len() {
+            0 => {}
+            1 => self.record(changes.remove(0), options),
+            _ => self.record(Change::Group(changes), options),
+        }
+    }
+
+    // Forgets the oldest changes beyond the undolevels and undomemory options.
+    // The newest change is kept even if it is larger than allowed.
+    pub fn prune(&mut self, options: &Options) {
+        let max_size = options.undomemory as usize * 1024 * 1024;
+        while self.undo.len() > options.undolevels as usize || (self.size > max_size && self.undo.len() > 1) {
+            match self.undo.pop_front() {
+                Some(change) => self.size -= change.memory_size(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.size = 0;
+    }
+
+    // For :undolist, oldest first. The changes after the current one were
+    // undone and can be redone.
+    pub fn list(&self) -> Vec<String> {
+        let undone = self.redo.iter().rev().map(|change| format!("   {}", change.describe()));
+        self.undo.iter().enumerate()
+            .map(|(i, change)| format!("{}  {}", if i + 1 == self.undo.len() { ">" } else { " " }, change.describe()))
+            .chain(undone)
+            .collect()
+    }
+}
+
+pub fn undo(state: &mut AppState, count: u32) {
+    if let Err(e) = edit::check_writable(&state.options) {
+        state.message = Some(Message::Error(e.to_string()));
+        return;
+    }
+    for _ in 0..count {
+        let change = match state.undo.undo.pop_back() {
+            Some(change) => change,
+            None => {
+                state.message = Some(Message::Info("Already at oldest change".to_string()));
+                break;
+            }
+        };
+        apply(state, &change, true);
+        state.undo.redo.push(change);
+    }
+}
+
+pub fn redo(state: &mut AppState, count: u32) {
+    if let Err(e) = edit::check_writable(&state.options) {
+        state.message = Some(Message::Error(e.to_string()));
+        return;
+    }
+    for _ in 0..count {
+        let change = match state.undo.redo.pop() {
+            Some(change) => change,
+            None => {
+                state.message = Some(Message::Info("Already at newest change".to_string()));
+                break;
+            }
+        };
+        apply(state, &change, false);
+        state.undo.undo.push_back(change);
+    }
+}
+
+// Does the change again, or reverts it. The cursor goes to where it happened.
+fn apply(state: &mut AppState, change: &Change, revert: bool) {
+    let content = &mut state.table_content;
+    match change {
+        Change::Cells(cells) => {
+            let mut replaced = Vec::with_capacity(cells.len());
+            for c in cells {
+                let (from, to) = if revert { (&c.new, &c.old) } else { (&c.old, &c.new) };
+                state.edit_log.record(c.cell, from, to);
+                replaced.push((c.cell, to.clone()));
+            }
+            if let Some((row, col)) = cells.first().map(|c| c.cell) {
+                content.selection.set_cursor(row, col);
+            }
+            content.set_cells(replaced);
+        }
+        Change::MoveCol { from, to, formulas } if revert => {
+            content.move_col(*to, *from, false);
+            content.set_cells(formulas.clone());
+            let (row, _) = content.selection.cursor();
+            content.selection.set_cursor(row, *from);
+        }
+        Change::MoveCol { from, to, .. } => {
+            content.move_col(*from, *to, true);
+            let (row, _) = content.selection.cursor();
+            content.selection.set_cursor(row, *to);
+        }
+        Change::Shift { shift, removed } if revert => structure::revert(state, *shift, removed),
+        Change::Shift { shift, .. } => {
+            structure::apply(state, *shift);
+        }
+        Change::MoveRows { moves, left, right } if revert => {
+            let moves: Vec<(u16, u16)> = moves.iter().map(|&(from, to)| (to, from)).collect();
+            content.move_rows(&moves, *left, *right);
+        }
+        Change::MoveRows { moves, left, right } => content.move_rows(moves, *left, *right),
+        Change::Sizes { axis, sizes } => content.set_sizes(*axis, sizes.iter().map(|&(i, old, new)| (i, if revert { old } else { new }))),
+        Change::HiddenCols { old, new } => {
+            content.hidden_cols = if revert { old.clone() } else { new.clone() };
+            content.changed();
+        }
+        Change::Styles(styles) => {
+            for change in styles {
+                match if revert { change.old } else { change.new } {
+                    Some(style) => content.styles.insert(change.cell, style),
+                    None => content.styles.remove(&change.cell),
+                };
+            }
+            content.changed();
+        }
+        Change::Formats(formats) => {
+            for change in formats {
+                match if revert { &change.old } else { &change.new } {
+                    Some(format) => content.formats.insert(change.cell, format.clone()),
+                    None => content.formats.remove(&change.cell),
+                };
+            }
+            content.changed_rows(formats.iter().map(|change| change.cell.0));
+        }
+        Change::Note { cell, old, new } => {
+            match if revert { old } else { new } {
+                Some(note) => content.notes.insert(*cell, note.clone()),
+                None => content.notes.remove(cell),
+            };
+            content.changed();
+        }
+        Change::Group(changes) if revert => {
+            for change in changes.iter().rev() {
+                apply(state, change, true);
+            }
+        }
+        Change::Group(changes) => {
+            for change in changes {
+                apply(state, change, false);
+            }
+        }
+        Change::Restore { old, new } => {
+            let to = if revert { old } else { new };
+            for (row, col) in to.changed_cells(content) {
+                let before = content.get_cell(row, col).cloned().unwrap_or(TableCell::Empty);
+                state.edit_log.record((row, col), &before, to.get(row, col).unwrap_or(&TableCell::Empty));
+            }
+            content.restore(to);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::{self, Formula};
+    use crate::grid::TableContent;
+
+    fn state() -> AppState {
+        let rows = (1..=3).map(|i| vec![TableCell::String(format!("row {}", i)), TableCell::Value(i)]).collect();
+        AppState::new(TableContent::from_rows(rows))
+    }
+
+    fn text(state: &AppState, row: u16, col: u16) -> String {
+        state.table_content.get_cell(row, col).map_or_else(String::new, TableCell::source_string)
+    }
+
+    fn column(state: &AppState, col: u16) -> Vec<String> {
+        (0..4).map(|row| text(state, row, col)).collect()
+    }
+
+    #[test]
+    fn cells() {
+        let mut state = state();
+        edit::replace_cells(&mut state, vec![((0, 1), TableCell::Value(10)), ((3, 0), TableCell::String("new".to_string()))]).unwrap();
+        edit::replace_cells(&mut state, vec![((0, 1), TableCell::Value(20))]).unwrap();
+        undo(&mut state, 1);
+        assert_eq!(text(&state, 0, 1), "10");
+        assert_eq!(text(&state, 3, 0), "new");
+        undo(&mut state, 1);
+        assert_eq!(text(&state, 0, 1), "1");
+        assert_eq!(text(&state, 3, 0), "");
+        undo(&mut state, 1);
+        assert!(matches!(&state.message, Some(Message::Info(text)) if text == "Already at oldest change"));
+        redo(&mut state, 2);
+        assert_eq!(text(&state, 0, 1), "20");
+        assert_eq!(text(&state, 3, 0), "new");
+        redo(&mut state, 1);
+        assert!(matches!(&state.message, Some(Message::Info(text)) if text == "Already at newest change"));
+    }
+
+    #[test]
+    fn a_change_after_undo_drops_redo() {
+        let mut state = state();
+        edit::replace_cells(&mut state, vec![((0, 1), TableCell::Value(10))]).unwrap();
+        undo(&mut state, 1);
+        edit::replace_cells(&mut state, vec![((1, 1), TableCell::Value(30))]).unwrap();
+        redo(&mut state, 1);
+        assert_eq!(text(&state, 0, 1), "1");
+        assert_eq!(state.undo.list(), vec![">  B2 changed"]);
+    }
+
+    #[test]
+    fn rows_and_formulas() {
+        let mut state = state();
+        edit::replace_cells(&mut state, vec![((3, 1), TableCell::Formula(Box::new(Formula::new("SUM(B1:B3)"))))]).unwrap();
+        structure::delete(&mut state, Axis::Rows, 1, 1).unwrap();
+        assert_eq!(column(&state, 0), ["row 1", "row 3", "", ""]);
+        assert_eq!(text(&state, 2, 1), "=SUM(B1:B2)");
+        structure::insert(&mut state, Axis::Rows, 0, 2).unwrap();
+        assert_eq!(column(&state, 0), ["", "", "row 1", "row 3"]);
+        undo(&mut state, 2);
+        assert_eq!(column(&state, 0), ["row 1", "row 2", "row 3", ""]);
+        assert_eq!(text(&state, 3, 1), "=SUM(B1:B3)");
+        formula::refresh_all(&mut state.table_content);
+        assert_eq!(state.table_content.display(3, 1), "6");
+        redo(&mut state, 1);
+        assert_eq!(column(&state, 0), ["row 1", "row 3", "", ""]);
+    }
+
+    #[test]
+    fn groups_and_levels() {
+        let mut state = state();
+        state.undo.begin_group();
+        for row in 0..3 {
+            edit::replace_cells(&mut state, vec![((row, 1), TableCell::Value(0))]).unwrap();
+        }
+        state.undo.end_group(&state.options);
+        undo(&mut state, 1);
+        assert_eq!(column(&state, 1), ["1", "2", "3", ""]);
+
+        state.options.set("undolevels=2").unwrap();
+        for value in 10..15 {
+            edit::replace_cells(&mut state, vec![((0, 1), TableCell::Value(value))]).unwrap();
+        }
+        undo(&mut state, 5);
+        assert_eq!(text(&state, 0, 1), "12");
+    }
+}
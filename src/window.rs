@@ -0,0 +1,271 @@
+use tui::layout::Rect;
+
+use crate::{AppState, AppMode, Result, VispError};
+use crate::grid::{Axis, Selection, Shift};
+use crate::render::Viewport;
+
+// A view of the current sheet next to others, made with Ctrl-W s and
+// Ctrl-W v. The current window lives in AppState::viewport and the selection
+// of the table, its entry here is left empty, like with sheets.
+#[derive(Default)]
+pub struct Window {
+    pub viewport: Viewport,
+    pub selection: Selection,
+}
+
+// How the table area is divided between the windows
+pub enum Pane {
+    Window(usize), // Index into AppState::windows
+    Split { vertical: bool, panes: Vec<Pane> }, // Side by side if vertical, otherwise stacked
+}
+
+impl Default for Pane {
+    fn default() -> Self {
+        Self::Window(0)
+    }
+}
+
+impl Pane {
+    // Window indices from the top left to the bottom right
+    pub fn windows(&self) -> Vec<usize> {
+        match self {
+            Self::Window(index) => vec![*index],
+            Self::Split { panes, .. } => panes.iter().flat_map(Pane::windows).collect(),
+        }
+    }
+
+    // Screen areas of the windows and of the lines between them, the lines
+    // with whether they are vertical. A line takes one row or column.
+    pub fn areas(&self, area: Rect, windows: &mut Vec<(usize, Rect)>, lines: &mut Vec<(Rect, bool)>) {
+        let (vertical, panes) = match self {
+            Self::Window(index) => return windows.push((*index, area)),
+            Self::Split { vertical, panes } => (*vertical, panes),
+        };
+        let count = panes.len() as u16;
+        let total = if vertical { area.width } else { area.height };
+        let space = total.saturating_sub(count - 1);
+        let mut offset = 0;
+        for (i, pane) in panes.iter().enumerate() {
+            let i = i as u16;
+            // The first panes get what is left over
+            let size = space / count + u16::from(i < space % count);
+            let part = |offset, size| match vertical {
+                true => Rect::new(area.x + offset, area.y, size, area.height).intersection(area),
+                false => Rect::new(area.x, area.y + offset, area.width, size).intersection(area),
+            };
+            pane.areas(part(offset, size), windows, lines);
+            offset += size;
+            if i + 1 < count {
+                lines.push((part(offset, 1), vertical));
+                offset += 1;
+            }
+        }
+    }
+
+    // Puts window `new` before `window`, in the space `window` had
+    fn split(&mut self, window: usize, new: usize, vertical: bool) -> bool {
+        match self {
+            Self::Window(index) if *index == window => {
+                *self = Self::Split { vertical, panes: vec![Self::Window(new), Self::Window(window)] };
+                true
+            }
+            Self::Window(_) => false,
+            Self::Split { vertical: v, panes } => {
+                // Another split the same way adds a pane instead of nesting
+                let position = panes.iter().position(|pane| matches!(pane, Self::Window(index) if *index == window));
+                match position {
+                    Some(i) if *v == vertical => {
+                        panes.insert(i, Self::Window(new));
+                        true
+                    }
+                    _ => panes.iter_mut().any(|pane| pane.split(window, new, vertical)),
+                }
+            }
+        }
+    }
+
+    // Takes `window` out, the windows after it move down one index
+    fn remove(&mut self, window: usize) {
+        if let Self::Split { panes, .. } = self {
+            panes.retain(|pane| !matches!(pane, Self::Window(index) if *index == window));
+            for pane in panes.iter_mut() {
+                pane.remove(window);
+            }
+            if panes.len() == 1 {
+                *self = panes.remove(0);
+            }
+        } else if let Self::Window(index) = self {
+            if *index > window {
+                *index -= 1;
+            }
+        }
+    }
+}
+
+// Trades the viewport and cursor of window `index` with the current ones.
+// Done twice it changes nothing, which is how the other windows are drawn.
+pub fn swap(state: &mut AppState, index: usize) {
+    let window = &mut state.windows[index];
+    std::mem::swap(&mut window.viewport, &mut state.viewport);
+    std::mem::swap(&mut window.selection, &mut state.table_content.selection);
+}
+
+// Makes window `index` the current one. A visual selection doesn't go
+// along.
+pub fn focus(state: &mut AppState, index: usize) {
+    if index == state.window || index >= state.windows.len() {
+        return;
+    }
+    if state.mode.is_visual() {
+        state.remember_visual();
+        state.mode = AppMode::Normal;
+        let (row, col) = state.table_content.selection.cursor();
+        state.table_content.selection.set_cursor(row, col);
+    }
+    swap(state, state.window);
+    swap(state, index);
+    state.window = index;
+}
+
+// Ctrl-W s and Ctrl-W v. The new window shows the same cells and becomes
+// the current one, above or left of the old one.
+pub fn split(state: &mut AppState, vertical: bool) {
+    let (row, col) = state.table_content.selection.cursor();
+    let mut selection = Selection::default();
+    selection.set_cursor(row, col);
+    let window = Window { viewport: state.viewport.split(), selection };
+    state.windows.push(window);
+    let new = state.windows.len() - 1;
+    state.layout.split(state.window, new, vertical);
+    focus(state, new);
+}
+
+// The other windows keep showing the same cells when rows or columns are
+// inserted or deleted. A cursor on deleted cells goes to the first one after
+// them.
+pub fn shift(state: &mut AppState, shift: Shift) {
+    for (index, window) in state.windows.iter_mut().enumerate() {
+        if index == state.window {
+            continue;
+        }
+        let (row, col) = window.selection.cursor();
+        window.selection = shift.selection(&window.selection).unwrap_or_else(|| {
+            let mut selection = Selection::default();
+            match shift.axis {
+                Axis::Rows => selection.set_cursor(shift.at, col),
+                Axis::Cols => selection.set_cursor(row, shift.at),
+            }
+            selection
+        });
+        let viewport = &mut window.viewport;
+        match shift.axis {
+            Axis::Rows => viewport.row = shift.index(viewport.row).unwrap_or(shift.at),
+            Axis::Cols => viewport.col = shift.index(viewport.col).unwrap_or(shift.at),
+        }
+    }
+}
+
+// All windows show the current sheet, so after another one is shown the
+// cursors of the other windows move into its cells
+pub fn clamp(state: &mut AppState) {
+    let content = &state.table_content;
+    let (last_row, last_col) = (content.used_rows() - 1, content.used_cols() - 1);
+    for (index, window) in state.windows.iter_mut().enumerate() {
+        if index == state.window {
+            continue;
+        }
+        let (row, col) = window.selection.cursor();
+        let (row, col) = (row.min(last_row), col.min(last_col));
+        window.selection.set_cursor(row, col);
+        window.viewport.row = window.viewport.row.min(row);
+        window.viewport.col = window.viewport.col.min(col);
+    }
+}
+
+// Ctrl-W c and :close
+pub fn close(state: &mut AppState) -> Result<()> {
+    let order = state.layout.windows();
+    if order.len() == 1 {
+        return Err(VispError::Command("Cannot close the last window".to_string()));
+    }
+    // Like in vim the window before takes over, or the one after for the first
+    let closing = state.window;
+    let position = order.iter().position(|&index| index == closing).unwrap_or(0);
+    let next = if position > 0 { order[position - 1] } else { order[1] };
+    focus(state, next);
+    state.windows.remove(closing);
+    state.layout.remove(closing);
+    if state.window > closing {
+        state.window -= 1;
+    }
+    Ok(())
+}
+
+// Ctrl-W o and :only
+pub fn only(state: &mut AppState) {
+    state.windows = vec![Window::default()];
+    state.window = 0;
+    state.layout = Pane::default();
+}
+
+// Ctrl-W w goes to the next window, or with a count to that window counted
+// from the top left
+pub fn next(state: &mut AppState, count: Option<u32>) {
+    let order = state.layout.windows();
+    let position = match count {
+        Some(n) => (n.max(1) as usize - 1).min(order.len() - 1),
+        None => {
+            let current = order.iter().position(|&index| index == state.window).unwrap_or(0);
+            (current + 1) % order.len()
+        }
+    };
+    focus(state, order[position]);
+}
+
+// Ctrl-W h, j, k and l go to the nearest window in that direction, using
+// where the windows were at the last draw. Of several windows the one next
+// to the cursor wins.
+pub fn focus_towards(state: &mut AppState, forward: bool, vertical: bool) {
+    let current = state.viewport.area;
+    let (row, col) = state.table_content.selection.cursor();
+    let cursor = state.viewport.cell_rect(&state.table_content, row, col).unwrap_or(current);
+    let nearest = state.windows.iter().enumerate()
+        .filter(|&(index, _)| index != state.window)
+        .filter_map(|(index, window)| {
+            let area = window.viewport.area;
+            let (gap, along) = match (vertical, forward) {
+                (false, true) if area.x >= current.right() => (area.x - current.right(), distance(cursor.y, area.y, area.bottom())),
+                (false, false) if area.right() <= current.x => (current.x - area.right(), distance(cursor.y, area.y, area.bottom())),
+                (true, true) if area.y >= current.bottom() => (area.y - current.bottom(), distance(cursor.x, area.x, area.right())),
+                (true, false) if area.bottom() <= current.y => (current.y - area.bottom(), distance(cursor.x, area.x, area.right())),
+                _ => return None,
+            };
+            Some(((gap, along), index))
+        })
+        .min();
+    if let Some((_, index)) = nearest {
+        focus(state, index);
+    }
+}
+
+// How far `point` is outside of start..end
+fn distance(point: u16, start: u16, end: u16) -> u16 {
+    if point < start {
+        start - point
+    } else if point >= end {
+        point + 1 - end
+    } else {
+        0
+    }
+}
+
+// The window at a screen position at the last draw
+pub fn at(state: &AppState, x: u16, y: u16) -> Option<usize> {
+    let contains = |area: Rect| x >= area.x && x < area.right() && y >= area.y && y < area.bottom();
+    if contains(state.viewport.area) {
+        return Some(state.window);
+    }
+    state.windows.iter().enumerate()
+        .find(|&(index, window)| index != state.window && contains(window.viewport.area))
+        .map(|(index, _)| index)
+}
@@ -0,0 +1,1394 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use calamine::{open_workbook_auto, Data, Reader};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, SubsecRound, Timelike};
+use quick_xml::events::{BytesStart, Event};
+use regex::{Captures, Regex};
+use rust_xlsxwriter::{Color, ExcelDateTime, Format, FormatAlign, FormatUnderline, Note, Workbook, XlsxError};
+use zip::write::SimpleFileOptions;
+use zip::result::ZipError;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{Result, VispError};
+use crate::format::{is_date_spec, Align, CellFormat, NumberSpec};
+use crate::formula::Formula;
+use crate::grid::{parse_cell_name, CellColor, CellStyle, TableCell, TableContent};
+use crate::task::Progress;
+
+// Files which hold several sheets, read with :e and written with :w like CSV
+pub fn is_workbook(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Xlsx,
+    Ods,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "xlsx" | "xlsm" => Some(Kind::Xlsx),
+        "ods" => Some(Kind::Ods),
+        _ => None,
+    }
+}
+
+// A sheet as read from a workbook, with its name as in the file
+pub struct Sheet {
+    pub name: String,
+    pub rows: Vec<Vec<TableCell>>,
+    pub styles: HashMap<(u16, u16), CellStyle>,
+    pub notes: HashMap<(u16, u16), String>,
+    pub formats: HashMap<(u16, u16), CellFormat>,
+}
+
+impl Sheet {
+    fn cell(&self, (row, col): (u16, u16)) -> &TableCell {
+        self.rows.get(row as usize).and_then(|cells| cells.get(col as usize)).unwrap_or(&TableCell::Empty)
+    }
+
+    pub fn into_content(self) -> (String, TableContent) {
+        let mut content = TableContent::from_rows(self.rows);
+        content.styles = self.styles;
+        content.notes = self.notes;
+        content.formats = self.formats;
+        (self.name, content)
+    }
+}
+
+// The sheets of a workbook in order. `progress` counts the sheets.
+pub fn read(path: &Path, progress: &Progress) -> Result<Vec<Sheet>> {
+    let mut workbook = open_workbook_auto(path).map_err(|e| VispError::Parse(format!("Cannot read {}: {}", path.display(), e)))?;
+    let ods = kind(path) == Some(Kind::Ods);
+    let mut sheets = Vec::new();
+    let names = workbook.sheet_names();
+    for (i, name) in names.iter().cloned().enumerate() {
+        progress.set(i as u64, names.len() as u64);
+        progress.check()?;
+        let invalid = |e: calamine::Error| VispError::Parse(format!("Cannot read sheet {}: {}", name, e));
+        let mut rows: Vec<Vec<TableCell>> = Vec::new();
+        let mut put = |row: usize, col: usize, cell: TableCell| {
+            if row > u16::MAX as usize || col > u16::MAX as usize {
+                return;
+            }
+            if rows.len() <= row {
+                rows.resize_with(row + 1, Vec::new);
+            }
+            if rows[row].len() <= col {
+                rows[row].resize(col + 1, TableCell::Empty);
+            }
+            rows[row][col] = cell;
+        };
+
+        let values = workbook.worksheet_range(&name).map_err(invalid)?;
+        let (top, left) = values.start().unwrap_or_default();
+        for (row, col, data) in values.used_cells() {
+            put(top as usize + row, left as usize + col, cell(data));
+        }
+        // Formulas replace their cached values
+        let formulas = workbook.worksheet_formula(&name).map_err(invalid)?;
+        let (top, left) = formulas.start().unwrap_or_default();
+        for (row, col, source) in formulas.used_cells().filter(|(_, _, source)| !source.is_empty()) {
+            let source = if ods { from_ods_formula(source) } else { source.replace('$', "") };
+            put(top as usize + row, left as usize + col, TableCell::Formula(Box::new(Formula::new(&source))));
+        }
+        sheets.push(Sheet { name, rows, styles: HashMap::new(), notes: HashMap::new(), formats: HashMap::new() });
+    }
+    // calamine only reads the cells
+    let invalid = |e: VispError| VispError::Parse(format!("Cannot read {}: {}", path.display(), e));
+    let mut zip = ZipArchive::new(File::open(path)?).map_err(|e| invalid(zip_error(e)))?;
+    if ods {
+        read_ods_content(&mut zip, &mut sheets).map_err(invalid)?;
+    } else {
+        read_xlsx_styles(&mut zip, &mut sheets).map_err(invalid)?;
+        read_xlsx_notes(&mut zip, &mut sheets).map_err(invalid)?;
+    }
+    Ok(sheets)
+}
+
+fn zip_error(e: ZipError) -> VispError {
+    VispError::Parse(e.to_string())
+}
+
+// A file in the archive, None if there is no such file
+fn zip_text(zip: &mut ZipArchive<File>, name: &str) -> Result<Option<String>> {
+    let mut file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(zip_error(e)),
+    };
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(Some(text))
+}
+
+// What parse_xml reads, elements by their name without the namespace
+enum Xml<'a> {
+    Open(&'a [u8], &'a BytesStart<'a>),
+    Close(&'a [u8]), // Also right after an element without content
+    Text(&'a str),
+}
+
+fn parse_xml(xml: &str, mut visit: impl FnMut(Xml)) -> Result<()> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let invalid = |e: quick_xml::Error| VispError::Parse(e.to_string());
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(element) => visit(Xml::Open(element.local_name().as_ref(), &element)),
+            Event::Empty(element) => {
+                visit(Xml::Open(element.local_name().as_ref(), &element));
+                visit(Xml::Close(element.local_name().as_ref()));
+            }
+            Event::End(element) => visit(Xml::Close(element.local_name().as_ref())),
+            Event::Text(text) => visit(Xml::Text(&text.decode().map_err(|e| invalid(e.into()))?)),
+            Event::CData(text) => visit(Xml::Text(&text.decode().map_err(|e| invalid(e.into()))?)),
+            // Like &amp; or &#10;, between the text around them
+            Event::GeneralRef(reference) => {
+                let name = reference.decode().map_err(|e| invalid(e.into()))?;
+                match reference.resolve_char_ref().map_err(invalid)? {
+                    Some(c) => visit(Xml::Text(c.encode_utf8(&mut [0; 4]))),
+                    None => visit(Xml::Text(quick_xml::escape::resolve_predefined_entity(&name).unwrap_or_default())),
+                }
+            }
+            Event::Eof => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+// The attribute without its namespace, e.g. "name" for style:name
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes().flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.decode_and_unescape_value(element.decoder()).ok())
+        .map(|value| value.into_owned())
+}
+
+// "#ff8000" in ODS, "FFFF8000" with the alpha first in XLSX
+fn parse_color(text: &str) -> Option<CellColor> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    let hex = hex.get(hex.len().checked_sub(6)?..)?;
+    u32::from_str_radix(hex, 16).ok().map(CellColor::from_rgb)
+}
+
+// The file a relationship points to, relative to the folder `dir` unless
+// it starts with /
+fn zip_path(dir: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = if target.starts_with('/') { Vec::new() } else { dir.split('/').collect() };
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop(); }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+// The relationships of a part of an XLSX file, from their ids to their type
+// and file
+fn xlsx_relationships(zip: &mut ZipArchive<File>, part: &str) -> Result<HashMap<String, (String, String)>> {
+    let (dir, name) = part.rsplit_once('/').unwrap_or(("", part));
+    let mut relationships = HashMap::new();
+    let Some(xml) = zip_text(zip, &format!("{}/_rels/{}.rels", dir, name))? else {
+        return Ok(relationships);
+    };
+    parse_xml(&xml, |xml| {
+        if let Xml::Open(b"Relationship", e) = xml {
+            if let (Some(id), Some(kind), Some(target)) = (attribute(e, "Id"), attribute(e, "Type"), attribute(e, "Target")) {
+                relationships.insert(id, (kind, zip_path(dir, &target)));
+            }
+        }
+    })?;
+    Ok(relationships)
+}
+
+// The parts of an XLSX file by sheet name, from the workbook and its
+// relationships
+fn xlsx_sheet_parts(zip: &mut ZipArchive<File>) -> Result<Vec<(String, String)>> {
+    let Some(workbook) = zip_text(zip, "xl/workbook.xml")? else {
+        return Ok(Vec::new());
+    };
+    let targets: HashMap<String, String> = xlsx_relationships(zip, "xl/workbook.xml")?.into_iter()
+        .map(|(id, (_, target))| (id, target))
+        .collect();
+    let mut parts = Vec::new();
+    parse_xml(&workbook, |xml| {
+        if let Xml::Open(b"sheet", e) = xml {
+            if let (Some(name), Some(target)) = (attribute(e, "name"), attribute(e, "id").and_then(|id| targets.get(&id))) {
+                parts.push((name, target.clone()));
+            }
+        }
+    })?;
+    Ok(parts)
+}
+
+// The styles and formats of the cells of each sheet. A cell's s attribute
+// is its index in cellXfs, which picks a font, a fill and a number format.
+fn read_xlsx_styles(zip: &mut ZipArchive<File>, sheets: &mut [Sheet]) -> Result<()> {
+    let formats = match zip_text(zip, "xl/styles.xml")? {
+        Some(xml) => xlsx_cell_formats(&xml)?,
+        None => return Ok(()),
+    };
+    for (name, part) in xlsx_sheet_parts(zip)? {
+        let (Some(sheet), Some(xml)) = (sheets.iter_mut().find(|sheet| sheet.name == name), zip_text(zip, &part)?) else {
+            continue;
+        };
+        // Rows and cells may leave out their position, then it follows the
+        // one before
+        let (mut row, mut next_row, mut col) = (0, 0, 0);
+        parse_xml(&xml, |xml| match xml {
+            Xml::Open(b"row", e) => {
+                row = attribute(e, "r").and_then(|r| r.parse::<u32>().ok()?.checked_sub(1)).unwrap_or(next_row);
+                next_row = row + 1;
+                col = 0;
+            }
+            Xml::Open(b"c", e) => {
+                let position = attribute(e, "r").and_then(|name| parse_cell_name(&name))
+                    .or_else(|| Some((u16::try_from(row).ok()?, u16::try_from(col).ok()?)));
+                let Some(position) = position else {
+                    return;
+                };
+                col = position.1 as u32 + 1;
+                let Some(format) = attribute(e, "s").and_then(|s| formats.get(s.parse::<usize>().ok()?)) else {
+                    return;
+                };
+                if format.style != CellStyle::default() {
+                    sheet.styles.insert(position, format.style);
+                }
+                let spec = format.number_format.as_deref().and_then(spec_from_excel);
+                if let Some(format) = read_format(sheet.cell(position), spec, format.align) {
+                    sheet.formats.insert(position, format);
+                }
+            }
+            _ => {}
+        })?;
+    }
+    Ok(())
+}
+
+// The notes of the cells of each sheet, which XLSX calls comments
+fn read_xlsx_notes(zip: &mut ZipArchive<File>, sheets: &mut [Sheet]) -> Result<()> {
+    for (name, part) in xlsx_sheet_parts(zip)? {
+        let Some(sheet) = sheets.iter_mut().find(|sheet| sheet.name == name) else {
+            continue;
+        };
+        let relationships = xlsx_relationships(zip, &part)?;
+        let comments = relationships.values().find(|(kind, _)| kind.ends_with("/comments")).map(|(_, target)| target);
+        let Some(xml) = comments.map(|comments| zip_text(zip, comments)).transpose()?.flatten() else {
+            continue;
+        };
+        // The comment being read, its runs of text and whether they are bold
+        let mut comment = None;
+        let mut runs: Vec<(bool, String)> = Vec::new();
+        let mut text = false;
+        parse_xml(&xml, |xml| match xml {
+            Xml::Open(b"comment", e) => {
+                comment = attribute(e, "ref").and_then(|name| parse_cell_name(&name));
+                runs = vec![(false, String::new())];
+            }
+            Xml::Open(b"r", _) => runs.push((false, String::new())),
+            Xml::Open(b"b", _) => {
+                if let Some(run) = runs.last_mut() {
+                    run.0 = true;
+                }
+            }
+            Xml::Open(b"t", _) => text = true,
+            Xml::Close(b"t") => text = false,
+            Xml::Text(t) if text => {
+                if let Some(run) = runs.last_mut() {
+                    run.1 += t;
+                }
+            }
+            Xml::Close(b"comment") => {
+                let Some(position) = comment.take() else {
+                    return;
+                };
+                let mut runs = std::mem::take(&mut runs);
+                runs.retain(|(_, text)| !text.is_empty());
+                // Excel starts the note with its author in bold, like
+                // "Ann:", and the text on the next line
+                if runs.len() > 1 && runs[0].0 && runs[0].1.ends_with(':') {
+                    runs.remove(0);
+                }
+                let note: String = runs.into_iter().map(|(_, text)| text).collect();
+                let note = note.trim_start_matches('\n');
+                if !note.is_empty() {
+                    sheet.notes.insert(position, note.to_string());
+                }
+            }
+            _ => {}
+        })?;
+    }
+    Ok(())
+}
+
+// An entry of cellXfs
+#[derive(Default)]
+struct XlsxCellFormat {
+    style: CellStyle,
+    number_format: Option<String>, // Excel's code, e.g. #,##0.00
+    align: Option<Align>,
+}
+
+fn xlsx_cell_formats(xml: &str) -> Result<Vec<XlsxCellFormat>> {
+    // Built in number formats which have no code in the file, the others
+    // are for dates which are read as such anyway or have no spec
+    let mut number_formats: HashMap<usize, String> = [(1, "0"), (2, "0.00"), (3, "#,##0"), (4, "#,##0.00")].into_iter()
+        .map(|(id, code)| (id, code.to_string()))
+        .collect();
+    let mut fonts: Vec<CellStyle> = Vec::new();
+    let mut fills: Vec<Option<CellColor>> = Vec::new();
+    let mut formats: Vec<XlsxCellFormat> = Vec::new();
+    let mut section = "";
+    let mut solid = false;
+    // <b/> is on, <b val="0"/> off
+    let on = |e: &BytesStart| !matches!(attribute(e, "val").as_deref(), Some("0" | "false" | "none"));
+    let index = |e: &BytesStart, name| attribute(e, name).and_then(|id| id.parse::<usize>().ok()).unwrap_or(0);
+    parse_xml(xml, |xml| match (section, xml) {
+        (_, Xml::Open(b"numFmts", _)) => section = "numFmts",
+        (_, Xml::Open(b"fonts", _)) => section = "fonts",
+        (_, Xml::Open(b"fills", _)) => section = "fills",
+        (_, Xml::Open(b"cellXfs", _)) => section = "cellXfs",
+        (_, Xml::Close(b"numFmts" | b"fonts" | b"fills" | b"cellXfs")) => section = "",
+        ("numFmts", Xml::Open(b"numFmt", e)) => {
+            if let Some(code) = attribute(e, "formatCode") {
+                number_formats.insert(index(e, "numFmtId"), code);
+            }
+        }
+        ("fonts", Xml::Open(b"font", _)) => fonts.push(CellStyle::default()),
+        ("fonts", Xml::Open(element @ (b"b" | b"i" | b"u" | b"color"), e)) => {
+            let Some(font) = fonts.last_mut() else {
+                return;
+            };
+            match element {
+                b"b" => font.bold = on(e),
+                b"i" => font.italic = on(e),
+                b"u" => font.underline = on(e),
+                _ => font.fg = attribute(e, "rgb").and_then(|rgb| parse_color(&rgb)),
+            }
+        }
+        ("fills", Xml::Open(b"fill", _)) => fills.push(None),
+        ("fills", Xml::Open(b"patternFill", e)) => solid = attribute(e, "patternType").as_deref() == Some("solid"),
+        ("fills", Xml::Open(b"fgColor", e)) if solid => {
+            if let Some(fill) = fills.last_mut() {
+                *fill = attribute(e, "rgb").and_then(|rgb| parse_color(&rgb));
+            }
+        }
+        ("cellXfs", Xml::Open(b"xf", e)) => {
+            let mut style = fonts.get(index(e, "fontId")).copied().unwrap_or_default();
+            style.bg = fills.get(index(e, "fillId")).copied().flatten();
+            let number_format = number_formats.get(&index(e, "numFmtId")).cloned();
+            formats.push(XlsxCellFormat { style, number_format, align: None });
+        }
+        ("cellXfs", Xml::Open(b"alignment", e)) => {
+            if let Some(format) = formats.last_mut() {
+                format.align = match attribute(e, "horizontal").as_deref() {
+                    Some("left") => Some(Align::Left),
+                    Some("center") => Some(Align::Center),
+                    Some("right") => Some(Align::Right),
+                    _ => None,
+                };
+            }
+        }
+        _ => {}
+    })?;
+    Ok(formats)
+}
+
+// A format read from a workbook, None if it has neither an alignment nor a
+// spec. Specs which show the cell like it is shown anyway are left out.
+fn read_format(cell: &TableCell, spec: Option<String>, align: Option<Align>) -> Option<CellFormat> {
+    let spec = spec.filter(|spec| Some(spec.as_str()) != default_spec(cell) && spec_applies(cell, spec));
+    Some(CellFormat { spec, align }).filter(|format| *format != CellFormat::default())
+}
+
+// How cells of a type are shown without a format, which they are written
+// with
+fn default_spec(cell: &TableCell) -> Option<&'static str> {
+    match cell {
+        TableCell::Date(_) => Some("%Y-%m-%d"),
+        TableCell::DateTime(_) => Some("%Y-%m-%d %H:%M:%S"),
+        TableCell::Time(_) => Some("%H:%M:%S"),
+        _ => None,
+    }
+}
+
+// A cell style of an ODS file
+#[derive(Clone, Default)]
+struct OdsCellStyle {
+    style: CellStyle,
+    data_style: Option<String>, // The name of its number, date or time style
+    align: Option<Align>,
+}
+
+// The styles and formats of the cells of each sheet from the automatic
+// styles in content.xml, and their notes which ODS calls annotations
+fn read_ods_content(zip: &mut ZipArchive<File>, sheets: &mut [Sheet]) -> Result<()> {
+    let Some(xml) = zip_text(zip, "content.xml")? else {
+        return Ok(());
+    };
+    let mut styles: HashMap<String, OdsCellStyle> = HashMap::new();
+    let mut style: Option<(String, OdsCellStyle)> = None; // The one being read
+    // Data styles as specs of :fmt, and the one being read, None in it once
+    // it has a part without a spec
+    let mut data_styles: HashMap<String, String> = HashMap::new();
+    let mut data_style: Option<(String, Option<String>)> = None;
+    let mut literal = false; // In the text of a data style
+    let mut sheet = None;
+    let (mut row, mut col, mut rows_repeated) = (0u32, 0u32, 1u32);
+    let mut used = (0u32, 0u32); // Rows and columns with cells in the sheet
+    let mut cell = (0u32, 0u32); // Where the cell being read is
+    let mut note: Option<Vec<String>> = None; // The paragraphs of its note
+    let mut paragraph = false;
+    let repeated = |e: &BytesStart, name| attribute(e, name).and_then(|n| n.parse::<u32>().ok()).unwrap_or(1);
+    let long = |e: &BytesStart| attribute(e, "style").as_deref() == Some("long");
+    parse_xml(&xml, |xml| match xml {
+        Xml::Open(b"style", e) if attribute(e, "family").as_deref() == Some("table-cell") => {
+            let data_style = attribute(e, "data-style-name");
+            style = attribute(e, "name").map(|name| (name, OdsCellStyle { data_style, ..OdsCellStyle::default() }));
+        }
+        Xml::Close(b"style") => {
+            if let Some((name, style)) = style.take() {
+                styles.insert(name, style);
+            }
+        }
+        Xml::Open(b"text-properties", e) => {
+            if let Some((_, OdsCellStyle { style, .. })) = &mut style {
+                style.bold = attribute(e, "font-weight").as_deref() == Some("bold");
+                style.italic = attribute(e, "font-style").as_deref() == Some("italic");
+                style.underline = attribute(e, "text-underline-style").is_some_and(|underline| underline != "none");
+                style.fg = attribute(e, "color").and_then(|color| parse_color(&color));
+            }
+        }
+        Xml::Open(b"table-cell-properties", e) => {
+            if let Some((_, OdsCellStyle { style, .. })) = &mut style {
+                style.bg = attribute(e, "background-color").and_then(|color| parse_color(&color));
+            }
+        }
+        Xml::Open(b"paragraph-properties", e) => {
+            if let Some((_, style)) = &mut style {
+                style.align = match attribute(e, "text-align").as_deref() {
+                    Some("start" | "left") => Some(Align::Left),
+                    Some("center") => Some(Align::Center),
+                    Some("end" | "right") => Some(Align::Right),
+                    _ => None,
+                };
+            }
+        }
+        Xml::Open(b"number-style" | b"date-style" | b"time-style", e) => {
+            data_style = attribute(e, "name").map(|name| (name, Some(String::new())));
+        }
+        Xml::Close(element @ (b"number-style" | b"date-style" | b"time-style")) => {
+            if let Some((name, Some(spec))) = data_style.take() {
+                let valid = if element == b"number-style" { spec.matches('%').count() == 1 && NumberSpec::parse(&spec).is_some() } else { is_date_spec(&spec) };
+                if valid {
+                    data_styles.insert(name, spec);
+                }
+            }
+        }
+        Xml::Open(element, e) if data_style.is_some() => {
+            let Some((_, Some(spec))) = &mut data_style else {
+                return;
+            };
+            let part = match element {
+                b"text" => {
+                    literal = true;
+                    return;
+                }
+                // Without decimal places it is shown as in the general format
+                b"number" => attribute(e, "decimal-places").and_then(|places| places.parse::<usize>().ok()).map(|places| {
+                    let grouping = if attribute(e, "grouping").as_deref() == Some("true") { "," } else { "" };
+                    if places == 0 { format!("%{}d", grouping) } else { format!("%{}.{}f", grouping, places) }
+                }),
+                b"year" => Some(if long(e) { "%Y" } else { "%y" }.to_string()),
+                b"month" if attribute(e, "textual").as_deref() == Some("true") => Some(if long(e) { "%B" } else { "%b" }.to_string()),
+                b"month" => Some("%m".to_string()),
+                b"day" => Some("%d".to_string()),
+                b"day-of-week" => Some(if long(e) { "%A" } else { "%a" }.to_string()),
+                b"hours" => Some("%H".to_string()),
+                b"minutes" => Some("%M".to_string()),
+                b"seconds" => Some("%S".to_string()),
+                _ => None,
+            };
+            match part {
+                Some(part) => *spec += &part,
+                None => data_style.as_mut().unwrap().1 = None,
+            }
+        }
+        Xml::Close(b"text") => literal = false,
+        // A % in the text of a number style leaves it without a spec
+        Xml::Text(text) if literal => {
+            if let Some((_, Some(spec))) = &mut data_style {
+                *spec += &text.replace('%', "%%");
+            }
+        }
+        Xml::Open(b"table", e) => {
+            sheet = attribute(e, "name").and_then(|name| sheets.iter().position(|sheet| sheet.name == name));
+            if let Some(i) = sheet {
+                let rows = &sheets[i].rows;
+                used = (rows.len() as u32, rows.iter().map(Vec::len).max().unwrap_or(0) as u32);
+            }
+            row = 0;
+        }
+        Xml::Open(b"table-row", e) => {
+            rows_repeated = repeated(e, "number-rows-repeated");
+            col = 0;
+        }
+        Xml::Close(b"table-row") => row = row.saturating_add(rows_repeated),
+        Xml::Open(b"table-cell" | b"covered-table-cell", e) => {
+            cell = (row, col);
+            let cols_repeated = repeated(e, "number-columns-repeated");
+            let style = attribute(e, "style-name").and_then(|name| styles.get(&name));
+            if let (Some(i), Some(style)) = (sheet, style) {
+                let spec = style.data_style.as_ref().and_then(|name| data_styles.get(name));
+                // Files often repeat a cell to the end of the sheet, only
+                // those with cells are kept then
+                let (rows_end, cols_end) = if rows_repeated == 1 && cols_repeated == 1 {
+                    (row + 1, col + 1)
+                } else {
+                    (row.saturating_add(rows_repeated).min(used.0), col.saturating_add(cols_repeated).min(used.1))
+                };
+                for r in (row..rows_end).filter_map(|r| u16::try_from(r).ok()) {
+                    for c in (col..cols_end).filter_map(|c| u16::try_from(c).ok()) {
+                        if style.style != CellStyle::default() {
+                            sheets[i].styles.insert((r, c), style.style);
+                        }
+                        if let Some(format) = read_format(sheets[i].cell((r, c)), spec.cloned(), style.align) {
+                            sheets[i].formats.insert((r, c), format);
+                        }
+                    }
+                }
+            }
+            col = col.saturating_add(cols_repeated);
+        }
+        Xml::Open(b"annotation", _) => note = Some(Vec::new()),
+        Xml::Open(b"p", _) => {
+            if let Some(note) = &mut note {
+                note.push(String::new());
+                paragraph = true;
+            }
+        }
+        Xml::Close(b"p") => paragraph = false,
+        Xml::Open(element @ (b"s" | b"tab" | b"line-break"), e) => {
+            if let Some(text) = note.as_mut().and_then(|note| note.last_mut()).filter(|_| paragraph) {
+                match element {
+                    b"s" => *text += &" ".repeat(attribute(e, "c").and_then(|c| c.parse().ok()).unwrap_or(1)),
+                    b"tab" => text.push('\t'),
+                    _ => text.push('\n'),
+                }
+            }
+        }
+        Xml::Text(t) if paragraph => {
+            if let Some(text) = note.as_mut().and_then(|note| note.last_mut()) {
+                *text += t;
+            }
+        }
+        Xml::Close(b"annotation") => {
+            let text = note.take().unwrap_or_default().join("\n");
+            let position = (u16::try_from(cell.0), u16::try_from(cell.1));
+            if let (Some(i), (Ok(row), Ok(col))) = (sheet, position) {
+                if !text.is_empty() {
+                    sheets[i].notes.insert((row, col), text);
+                }
+            }
+        }
+        _ => {}
+    })?;
+    Ok(())
+}
+
+fn cell(data: &Data) -> TableCell {
+    match data {
+        Data::Empty => TableCell::Empty,
+        Data::String(s) => TableCell::String(s.clone()),
+        Data::Int(n) => TableCell::from_number(*n as f64),
+        Data::Float(n) => TableCell::from_number(*n),
+        Data::Bool(b) => TableCell::Bool(*b),
+        Data::DateTime(excel) if excel.is_datetime() => {
+            let (year, month, day, hour, min, sec, milli) = excel.to_ymd_hms_milli();
+            // Rounded to seconds, the fraction of a day often isn't exact
+            let date_time = NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+                .and_then(|date| date.and_hms_opt(hour as u32, min as u32, sec as u32))
+                .map(|date_time| (date_time + Duration::milliseconds(milli as i64)).round_subsecs(0));
+            let value = excel.as_f64();
+            match date_time {
+                // Times are stored as a fraction of the first day
+                Some(date_time) if (0.0..1.0).contains(&value) => TableCell::Time(date_time.time()),
+                Some(date_time) if value.fract() == 0.0 => TableCell::Date(date_time.date()),
+                Some(date_time) => TableCell::DateTime(date_time),
+                None => TableCell::from_number(value),
+            }
+        }
+        Data::DateTime(duration) => TableCell::from_number(duration.as_f64()),
+        Data::DateTimeIso(text) => {
+            if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+                TableCell::Date(date)
+            } else if let Ok(date_time) = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f") {
+                TableCell::DateTime(date_time)
+            } else {
+                TableCell::String(text.clone())
+            }
+        }
+        Data::DurationIso(text) => match NaiveTime::parse_from_str(text, "PT%HH%MM%S%.fS") {
+            Ok(time) => TableCell::Time(time),
+            Err(_) => TableCell::String(text.clone()),
+        },
+        Data::Error(e) => TableCell::String(e.to_string()),
+    }
+}
+
+// Writes every sheet, in order, with the formats from :fmt, styles, notes,
+// column widths and hidden columns. Returns the number of formats which have
+// no equivalent in the file, they are left out.
+pub fn write(path: &Path, sheets: &[(&str, &TableContent)]) -> Result<usize> {
+    match kind(path) {
+        Some(Kind::Xlsx) => write_xlsx(path, sheets).map_err(|e| VispError::Command(format!("Cannot write {}: {}", path.display(), e))),
+        Some(Kind::Ods) => write_ods(path, sheets),
+        None => Err(VispError::Command(format!("Not a workbook: {}", path.display()))),
+    }
+}
+
+fn write_xlsx(path: &Path, sheets: &[(&str, &TableContent)]) -> std::result::Result<usize, XlsxError> {
+    let mut workbook = Workbook::new();
+    let mut lost = 0;
+    for (name, content) in sheets {
+        let worksheet = workbook.add_worksheet();
+        worksheet.set_name(*name)?;
+        for ((row, col), cell) in content.iter() {
+            let row = row as u32;
+            match cell {
+                TableCell::Empty => {}
+                TableCell::String(s) => { worksheet.write_string(row, col, s)?; }
+                TableCell::Value(_) | TableCell::Float(_) | TableCell::Currency(_) => { worksheet.write_number(row, col, cell.number().unwrap_or_default())?; }
+                TableCell::Bool(b) => { worksheet.write_boolean(row, col, *b)?; }
+                TableCell::Date(d) => { worksheet.write_datetime(row, col, excel_date(d)?)?; }
+                TableCell::DateTime(d) => {
+                    let value = excel_date(&d.date())?.and_hms(d.hour() as u16, d.minute() as u8, d.second())?;
+                    worksheet.write_datetime(row, col, value)?;
+                }
+                TableCell::Time(t) => { worksheet.write_datetime(row, col, ExcelDateTime::from_hms(t.hour() as u16, t.minute() as u8, t.second())?)?; }
+                TableCell::Formula(f) => {
+                    let result = cell.format_string();
+                    worksheet.write_formula(row, col, rust_xlsxwriter::Formula::new(to_excel_formula(&f.source)).set_result(result))?;
+                }
+            }
+        }
+        // Every cell which has a type with its own number format, a format or
+        // a style, including empty ones
+        let positions: BTreeSet<(u16, u16)> = content.iter()
+            .filter(|(_, cell)| matches!(cell, TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Time(_) | TableCell::Currency(_)))
+            .map(|(position, _)| position)
+            .chain(content.formats.keys().copied())
+            .chain(content.styles.keys().copied())
+            .collect();
+        for (row, col) in positions {
+            let cell = content.get_cell(row, col).unwrap_or(&TableCell::Empty);
+            let format = xlsx_format(cell, content.formats.get(&(row, col)), content.styles.get(&(row, col)), &mut lost);
+            worksheet.set_cell_format(row as u32, col, &format)?;
+        }
+        for (&(row, col), text) in &content.notes {
+            // Without "Author:" in front, which would be read back as part of it
+            worksheet.insert_note(row as u32, col, &Note::new(text).add_author_prefix(false))?;
+        }
+        for (&col, &width) in &content.col_widths {
+            worksheet.set_column_width(col, width)?;
+        }
+        for &col in &content.hidden_cols {
+            worksheet.set_column_hidden(col)?;
+        }
+    }
+    workbook.save(path)?;
+    Ok(lost)
+}
+
+// The number format the cell's type needs, replaced by the one of its :fmt
+// if Excel can show that, and its style
+fn xlsx_format(cell: &TableCell, format: Option<&CellFormat>, style: Option<&CellStyle>, lost: &mut usize) -> Format {
+    let mut number_format = match cell {
+        TableCell::Date(_) => "yyyy-mm-dd".to_string(),
+        TableCell::DateTime(_) => "yyyy-mm-dd hh:mm:ss".to_string(),
+        TableCell::Time(_) => "hh:mm:ss".to_string(),
+        TableCell::Currency(currency) if currency.after => format!("#,##0.00 \"{}\"", currency.symbol),
+        TableCell::Currency(currency) => format!("\"{}\"#,##0.00", currency.symbol),
+        _ => String::new(),
+    };
+    let mut xlsx = Format::new();
+    if let Some(format) = format {
+        if let Some(spec) = format.spec.as_deref().filter(|spec| spec_applies(cell, spec)) {
+            match excel_number_format(spec, is_date(cell)) {
+                Some(spec) => number_format = spec,
+                None => *lost += 1,
+            }
+        }
+        xlsx = match format.align {
+            Some(Align::Left) => xlsx.set_align(FormatAlign::Left),
+            Some(Align::Center) => xlsx.set_align(FormatAlign::Center),
+            Some(Align::Right) => xlsx.set_align(FormatAlign::Right),
+            None => xlsx,
+        };
+    }
+    if !number_format.is_empty() {
+        xlsx = xlsx.set_num_format(number_format);
+    }
+    if let Some(style) = style {
+        if style.bold {
+            xlsx = xlsx.set_bold();
+        }
+        if style.italic {
+            xlsx = xlsx.set_italic();
+        }
+        if style.underline {
+            xlsx = xlsx.set_underline(FormatUnderline::Single);
+        }
+        if let Some(fg) = style.fg {
+            xlsx = xlsx.set_font_color(Color::RGB(fg.rgb()));
+        }
+        if let Some(bg) = style.bg {
+            xlsx = xlsx.set_background_color(Color::RGB(bg.rgb()));
+        }
+    }
+    xlsx
+}
+
+// Whether :fmt shows the cell with `spec`. Empty cells take any spec.
+fn spec_applies(cell: &TableCell, spec: &str) -> bool {
+    match cell {
+        TableCell::Value(_) | TableCell::Float(_) | TableCell::Currency(_) | TableCell::Formula(_) => NumberSpec::parse(spec).is_some(),
+        TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Time(_) => is_date_spec(spec),
+        TableCell::Empty => true,
+        TableCell::String(_) | TableCell::Bool(_) => false,
+    }
+}
+
+fn is_date(cell: &TableCell) -> bool {
+    matches!(cell, TableCell::Date(_) | TableCell::DateTime(_) | TableCell::Time(_))
+}
+
+// A spec of :fmt in Excel's codes, e.g. "$%,.2f" as "$"#,##0.00 or "%d.%m.%Y"
+// as dd"."mm"."yyyy. Specs like %d are read as a date one for dates. None if
+// there is no code for a part of it.
+fn excel_number_format(spec: &str, date: bool) -> Option<String> {
+    let text = |text: &str| if text.is_empty() { Some(String::new()) } else { (!text.contains('"')).then(|| format!("\"{}\"", text)) };
+    if let Some(number) = NumberSpec::parse(spec).filter(|_| !date) {
+        let digits = if number.thousands { "#,##0" } else { "0" };
+        let decimals = if number.decimals > 0 { format!(".{}", "0".repeat(number.decimals)) } else { String::new() };
+        return Some(format!("{}{}{}{}", text(number.prefix)?, digits, decimals, text(number.suffix)?));
+    }
+    let mut excel = String::new();
+    for part in date_parts(spec)? {
+        excel += &match part {
+            DatePart::Text(literal) => text(&literal)?,
+            DatePart::Year { long } => if long { "yyyy" } else { "yy" }.to_string(),
+            DatePart::Month { name: false, .. } => "mm".to_string(),
+            DatePart::Month { long, .. } => if long { "mmmm" } else { "mmm" }.to_string(),
+            DatePart::Day => "dd".to_string(),
+            DatePart::Weekday { long } => if long { "dddd" } else { "ddd" }.to_string(),
+            DatePart::Hour => "hh".to_string(),
+            DatePart::Minute => "mm".to_string(),
+            DatePart::Second => "ss".to_string(),
+        };
+    }
+    Some(excel)
+}
+
+// The spec of :fmt for one of Excel's codes, the other way round from
+// excel_number_format. Of codes with several sections the first one is read,
+// it is the one for positive numbers. None if there is no spec for it, e.g.
+// for percentages.
+fn spec_from_excel(code: &str) -> Option<String> {
+    // Literal text, and the codes for numbers and dates
+    enum Part {
+        Text(String),
+        Number(String),
+        Date(String),
+    }
+    let mut parts = Vec::new();
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        c => text.push(c),
+                    }
+                }
+                parts.push(Part::Text(text));
+            }
+            '\\' => parts.push(Part::Text(chars.next()?.to_string())),
+            // A currency like [$€-407], colors and conditions are left out
+            '[' => {
+                let inside: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                if let Some(currency) = inside.strip_prefix('$') {
+                    parts.push(Part::Text(currency.split('-').next().unwrap_or_default().to_string()));
+                }
+            }
+            // Space as wide as the next character and filling with it
+            '_' | '*' => { chars.next(); }
+            '0' | '#' | '?' | '.' | ',' => {
+                let mut digits = c.to_string();
+                while let Some(c) = chars.next_if(|c| "0#?.,".contains(*c)) {
+                    digits.push(c);
+                }
+                // Alone . and , are text, like in dd.mm.yyyy
+                if digits.contains(['0', '#', '?']) {
+                    parts.push(Part::Number(digits));
+                } else {
+                    parts.push(Part::Text(digits));
+                }
+            }
+            'y' | 'Y' | 'm' | 'M' | 'd' | 'D' | 'h' | 'H' | 's' | 'S' => {
+                let mut letters = c.to_ascii_lowercase().to_string();
+                while let Some(c) = chars.next_if(|next| next.eq_ignore_ascii_case(&c)) {
+                    letters.push(c.to_ascii_lowercase());
+                }
+                parts.push(Part::Date(letters));
+            }
+            c if c.is_alphanumeric() || c == '%' || c == '@' => return None,
+            c => parts.push(Part::Text(c.to_string())),
+        }
+    }
+
+    let numbers = parts.iter().filter(|part| matches!(part, Part::Number(_))).count();
+    let dates = parts.iter().filter(|part| matches!(part, Part::Date(_))).count();
+    let mut spec = String::new();
+    if numbers == 1 && dates == 0 {
+        for part in &parts {
+            match part {
+                Part::Text(text) if text.contains('%') => return None,
+                Part::Text(text) => spec += text,
+                Part::Number(digits) => {
+                    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+                    // A comma at the end divides by 1000
+                    if integer.ends_with(',') || fraction.contains(',') {
+                        return None;
+                    }
+                    spec += if integer.contains(',') { "%," } else { "%" };
+                    spec += &match fraction.len() {
+                        0 => "d".to_string(),
+                        decimals => format!(".{}f", decimals),
+                    };
+                }
+                Part::Date(_) => return None,
+            }
+        }
+        return NumberSpec::parse(&spec).is_some().then_some(spec);
+    }
+    if numbers > 0 || dates == 0 {
+        return None;
+    }
+    let codes: Vec<&str> = parts.iter().filter_map(|part| match part {
+        Part::Date(letters) => Some(letters.as_str()),
+        _ => None,
+    }).collect();
+    let mut date = 0; // Index in codes
+    for part in &parts {
+        match part {
+            Part::Text(text) => spec += &text.replace('%', "%%"),
+            Part::Number(_) => return None,
+            Part::Date(letters) => {
+                // m and mm are minutes after hours or before seconds
+                let minute = (date > 0 && codes[date - 1].starts_with('h')) || codes.get(date + 1).is_some_and(|next| next.starts_with('s'));
+                spec += match (letters.as_bytes()[0], letters.len()) {
+                    (b'y', 1..=2) => "%y",
+                    (b'y', _) => "%Y",
+                    (b'm', 1..=2) if minute => "%M",
+                    (b'm', 1..=2) => "%m",
+                    (b'm', 3) => "%b",
+                    (b'm', 4) => "%B",
+                    (b'd', 1..=2) => "%d",
+                    (b'd', 3) => "%a",
+                    (b'd', _) => "%A",
+                    (b'h', _) => "%H",
+                    (b's', _) => "%S",
+                    _ => return None,
+                };
+                date += 1;
+            }
+        }
+    }
+    is_date_spec(&spec).then_some(spec)
+}
+
+// The parts of a strftime spec which spreadsheets can show too
+enum DatePart {
+    Text(String),
+    Year { long: bool },
+    Month { name: bool, long: bool },
+    Day,
+    Weekday { long: bool },
+    Hour,
+    Minute,
+    Second,
+}
+
+// None if the spec has a part other than those, e.g. %j for the day of the
+// year
+fn date_parts(spec: &str) -> Option<Vec<DatePart>> {
+    if !is_date_spec(spec) {
+        return None;
+    }
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let part = match chars.next()? {
+            '%' => {
+                literal.push('%');
+                continue;
+            }
+            'Y' => DatePart::Year { long: true },
+            'y' => DatePart::Year { long: false },
+            'm' => DatePart::Month { name: false, long: false },
+            'b' => DatePart::Month { name: true, long: false },
+            'B' => DatePart::Month { name: true, long: true },
+            'd' => DatePart::Day,
+            'a' => DatePart::Weekday { long: false },
+            'A' => DatePart::Weekday { long: true },
+            'H' => DatePart::Hour,
+            'M' => DatePart::Minute,
+            'S' => DatePart::Second,
+            _ => return None,
+        };
+        if !literal.is_empty() {
+            parts.push(DatePart::Text(std::mem::take(&mut literal)));
+        }
+        parts.push(part);
+    }
+    if !literal.is_empty() {
+        parts.push(DatePart::Text(literal));
+    }
+    Some(parts)
+}
+
+fn excel_date(date: &NaiveDate) -> std::result::Result<ExcelDateTime, XlsxError> {
+    ExcelDateTime::from_ymd(date.year().clamp(0, u16::MAX as i32) as u16, date.month() as u8, date.day() as u8)
+}
+
+// AVG is AVERAGE in other spreadsheets
+fn to_excel_formula(source: &str) -> String {
+    static AVG: OnceLock<Regex> = OnceLock::new();
+    let avg = AVG.get_or_init(|| Regex::new(r"(?i)\bAVG\s*\(").unwrap());
+    avg.replace_all(source, "AVERAGE(").into_owned()
+}
+
+// An ODS file is a zip archive holding the cells as XML in content.xml
+fn write_ods(path: &Path, sheets: &[(&str, &TableContent)]) -> Result<usize> {
+    let zip_error = |e: zip::result::ZipError| VispError::Command(format!("Cannot write {}: {}", path.display(), e));
+    let mut zip = ZipWriter::new(File::create(path)?);
+    // The mimetype must come first and uncompressed, so the type can be told
+    // from the first bytes
+    zip.start_file("mimetype", SimpleFileOptions::default().compression_method(CompressionMethod::Stored)).map_err(zip_error)?;
+    zip.write_all(b"application/vnd.oasis.opendocument.spreadsheet")?;
+    zip.start_file("META-INF/manifest.xml", SimpleFileOptions::default()).map_err(zip_error)?;
+    zip.write_all(ODS_MANIFEST.as_bytes())?;
+    zip.start_file("content.xml", SimpleFileOptions::default()).map_err(zip_error)?;
+    let (content, lost) = ods_content(sheets);
+    zip.write_all(content.as_bytes())?;
+    zip.finish().map_err(zip_error)?;
+    Ok(lost)
+}
+
+const ODS_MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.2">
+ <manifest:file-entry manifest:full-path="/" manifest:media-type="application/vnd.oasis.opendocument.spreadsheet"/>
+ <manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/>
+</manifest:manifest>
+"#;
+
+// Dates and times need a data style to be shown as such. The styles for
+// formats, styles and column widths follow, see OdsStyles.
+const ODS_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-content xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:fo="urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0" xmlns:style="urn:oasis:names:tc:opendocument:xmlns:style:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0" xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0" xmlns:number="urn:oasis:names:tc:opendocument:xmlns:datastyle:1.0" xmlns:of="urn:oasis:names:tc:opendocument:xmlns:of:1.2" office:version="1.2">
+<office:automatic-styles>
+<number:date-style style:name="N1"><number:year number:style="long"/><number:text>-</number:text><number:month number:style="long"/><number:text>-</number:text><number:day number:style="long"/></number:date-style>
+<number:date-style style:name="N2"><number:year number:style="long"/><number:text>-</number:text><number:month number:style="long"/><number:text>-</number:text><number:day number:style="long"/><number:text> </number:text><number:hours number:style="long"/><number:text>:</number:text><number:minutes number:style="long"/><number:text>:</number:text><number:seconds number:style="long"/></number:date-style>
+<number:time-style style:name="N3"><number:hours number:style="long"/><number:text>:</number:text><number:minutes number:style="long"/><number:text>:</number:text><number:seconds number:style="long"/></number:time-style>
+<style:style style:name="date" style:family="table-cell" style:data-style-name="N1"/>
+<style:style style:name="datetime" style:family="table-cell" style:data-style-name="N2"/>
+<style:style style:name="time" style:family="table-cell" style:data-style-name="N3"/>
+"#;
+
+const ODS_BODY: &str = "</office:automatic-styles>\n<office:body>\n<office:spreadsheet>\n";
+
+// The sheets as content.xml, and how many formats have no data style
+fn ods_content(sheets: &[(&str, &TableContent)]) -> (String, usize) {
+    let mut styles = OdsStyles::default();
+    let mut body = String::new();
+    for (name, content) in sheets {
+        body += &format!("<table:table table:name=\"{}\">\n", escape(name));
+        body += &ods_columns(content, &mut styles);
+        // Cells with a value, a format, a style or a note, row by row
+        let positions: BTreeSet<(u16, u16)> = content.iter().map(|(position, _)| position)
+            .chain(content.formats.keys().copied())
+            .chain(content.styles.keys().copied())
+            .chain(content.notes.keys().copied())
+            .collect();
+        let mut next_row = 0;
+        let mut positions = positions.into_iter().peekable();
+        while let Some(&(row, _)) = positions.peek() {
+            if row > next_row {
+                body += &format!("<table:table-row table:number-rows-repeated=\"{}\"><table:table-cell/></table:table-row>\n", row - next_row);
+            }
+            body += "<table:table-row>";
+            let mut next_col = 0;
+            while let Some((_, col)) = positions.next_if(|&(r, _)| r == row) {
+                if col > next_col {
+                    body += &format!("<table:table-cell table:number-columns-repeated=\"{}\"/>", col - next_col);
+                }
+                let cell = content.get_cell(row, col).unwrap_or(&TableCell::Empty);
+                let style = styles.cell(cell, content.formats.get(&(row, col)), content.styles.get(&(row, col)));
+                body += &ods_cell(cell, style.as_deref(), content.notes.get(&(row, col)).map(String::as_str));
+                next_col = col + 1;
+            }
+            body += "</table:table-row>\n";
+            next_row = row + 1;
+        }
+        if next_row == 0 {
+            body += "<table:table-row><table:table-cell/></table:table-row>\n";
+        }
+        body += "</table:table>\n";
+    }
+    let xml = format!("{}{}{}{}</office:spreadsheet>\n</office:body>\n</office:document-content>\n", ODS_HEADER, styles.xml, ODS_BODY, body);
+    (xml, styles.lost)
+}
+
+// Columns up to the last one which is resized or hidden
+fn ods_columns(content: &TableContent, styles: &mut OdsStyles) -> String {
+    let last = match content.col_widths.keys().chain(&content.hidden_cols).max() {
+        Some(&last) => last,
+        None => return String::new(),
+    };
+    let mut xml = String::new();
+    let mut plain = 0; // Columns with neither before the current one
+    for col in 0..=last {
+        let width = content.col_widths.get(&col);
+        let hidden = content.hidden_cols.contains(&col);
+        if width.is_none() && !hidden {
+            plain += 1;
+            continue;
+        }
+        if plain > 0 {
+            xml += &format!("<table:table-column table:number-columns-repeated=\"{}\"/>", plain);
+            plain = 0;
+        }
+        let style = width.map_or(String::new(), |&width| {
+            let name = styles.add("co", |name| format!(
+                "<style:style style:name=\"{}\" style:family=\"table-column\"><style:table-column-properties style:column-width=\"{:.2}cm\"/></style:style>",
+                name, width as f64 * ODS_CHAR_WIDTH,
+            ));
+            format!(" table:style-name=\"{}\"", name)
+        });
+        let visibility = if hidden { " table:visibility=\"collapse\"" } else { "" };
+        xml += &format!("<table:table-column{}{}/>", style, visibility);
+    }
+    xml + "\n"
+}
+
+// Centimeters per character of a column width, about a digit in the default
+// font
+const ODS_CHAR_WIDTH: f64 = 0.21;
+
+// The automatic styles made while writing the sheets, each one once
+#[derive(Default)]
+struct OdsStyles {
+    xml: String,
+    names: HashMap<String, String>, // Styles written without a name to their names
+    lost: usize, // Formats which have no data style
+}
+
+impl OdsStyles {
+    // The name of the style `element` makes when given its name, which is
+    // only added the first time
+    fn add(&mut self, prefix: &str, element: impl Fn(&str) -> String) -> String {
+        let key = element("");
+        if let Some(name) = self.names.get(&key) {
+            return name.clone();
+        }
+        let name = format!("{}{}", prefix, self.names.len() + 1);
+        self.xml += &element(&name);
+        self.xml += "\n";
+        self.names.insert(key, name.clone());
+        name
+    }
+
+    // The style of a cell with its format and style, None if it needs none
+    fn cell(&mut self, cell: &TableCell, format: Option<&CellFormat>, style: Option<&CellStyle>) -> Option<String> {
+        let default = match cell {
+            TableCell::Date(_) => Some(("date", "N1")),
+            TableCell::DateTime(_) => Some(("datetime", "N2")),
+            TableCell::Time(_) => Some(("time", "N3")),
+            _ => None,
+        };
+        let mut data_style = None;
+        if let Some(spec) = format.and_then(|f| f.spec.as_deref()).filter(|spec| spec_applies(cell, spec)) {
+            data_style = self.data_style(cell, spec);
+            if data_style.is_none() {
+                self.lost += 1;
+            }
+        }
+
+        let mut text = String::new();
+        let mut properties = String::new();
+        if let Some(style) = style {
+            if style.bold {
+                text += " fo:font-weight=\"bold\"";
+            }
+            if style.italic {
+                text += " fo:font-style=\"italic\"";
+            }
+            if style.underline {
+                text += " style:text-underline-style=\"solid\" style:text-underline-width=\"auto\" style:text-underline-color=\"font-color\"";
+            }
+            if let Some(fg) = style.fg {
+                text += &format!(" fo:color=\"#{:06x}\"", fg.rgb());
+            }
+            if let Some(bg) = style.bg {
+                properties += &format!("<style:table-cell-properties fo:background-color=\"#{:06x}\"/>", bg.rgb());
+            }
+        }
+        if let Some(align) = format.and_then(|f| f.align) {
+            let align = match align {
+                Align::Left => "start",
+                Align::Center => "center",
+                Align::Right => "end",
+            };
+            properties += &format!("<style:paragraph-properties fo:text-align=\"{}\"/>", align);
+        }
+        if !text.is_empty() {
+            properties += &format!("<style:text-properties{}/>", text);
+        }
+
+        if data_style.is_none() && properties.is_empty() {
+            return default.map(|(name, _)| name.to_string());
+        }
+        let data_style = data_style.or_else(|| default.map(|(_, data_style)| data_style.to_string()))
+            .map_or(String::new(), |name| format!(" style:data-style-name=\"{}\"", name));
+        Some(self.add("ce", |name| format!(
+            "<style:style style:name=\"{}\" style:family=\"table-cell\"{}>{}</style:style>",
+            name, data_style, properties,
+        )))
+    }
+
+    // A number, date or time style for `spec`, like excel_number_format
+    fn data_style(&mut self, cell: &TableCell, spec: &str) -> Option<String> {
+        let text = |text: &str| if text.is_empty() { String::new() } else { format!("<number:text>{}</number:text>", escape(text)) };
+        if let Some(number) = NumberSpec::parse(spec).filter(|_| !is_date(cell)) {
+            let grouping = if number.thousands { " number:grouping=\"true\"" } else { "" };
+            let inner = format!(
+                "{}<number:number number:decimal-places=\"{2}\" number:min-decimal-places=\"{2}\" number:min-integer-digits=\"1\"{1}/>{3}",
+                text(number.prefix), grouping, number.decimals, text(number.suffix),
+            );
+            return Some(self.add("num", |name| format!("<number:number-style style:name=\"{}\">{}</number:number-style>", name, inner)));
+        }
+        // A time style can't show the date
+        let time = matches!(cell, TableCell::Time(_));
+        let mut inner = String::new();
+        for part in date_parts(spec)? {
+            inner += &match part {
+                DatePart::Text(literal) => text(&literal),
+                DatePart::Hour => "<number:hours number:style=\"long\"/>".to_string(),
+                DatePart::Minute => "<number:minutes number:style=\"long\"/>".to_string(),
+                DatePart::Second => "<number:seconds number:style=\"long\"/>".to_string(),
+                _ if time => return None,
+                DatePart::Year { long } => format!("<number:year{}/>", if long { " number:style=\"long\"" } else { "" }),
+                DatePart::Month { name: false, .. } => "<number:month number:style=\"long\"/>".to_string(),
+                DatePart::Month { long, .. } => format!("<number:month number:textual=\"true\"{}/>", if long { " number:style=\"long\"" } else { "" }),
+                DatePart::Day => "<number:day number:style=\"long\"/>".to_string(),
+                DatePart::Weekday { long } => format!("<number:day-of-week{}/>", if long { " number:style=\"long\"" } else { "" }),
+            };
+        }
+        let element = if time { "number:time-style" } else { "number:date-style" };
+        Some(self.add("num", |name| format!("<{0} style:name=\"{1}\">{2}</{0}>", element, name, inner)))
+    }
+}
+
+fn ods_cell(cell: &TableCell, style: Option<&str>, note: Option<&str>) -> String {
+    let text = escape(&cell.format_string());
+    let mut attributes = match style {
+        Some(style) => format!(" table:style-name=\"{}\"", style),
+        None => String::new(),
+    };
+    attributes += &match cell {
+        TableCell::Empty => String::new(),
+        TableCell::String(_) => " office:value-type=\"string\"".to_string(),
+        TableCell::Value(_) | TableCell::Float(_) => {
+            format!(" office:value-type=\"float\" office:value=\"{}\"", cell.number().unwrap_or_default())
+        }
+        TableCell::Bool(b) => format!(" office:value-type=\"boolean\" office:boolean-value=\"{}\"", b),
+        TableCell::Date(d) => format!(" office:value-type=\"date\" office:date-value=\"{}\"", d.format("%Y-%m-%d")),
+        TableCell::DateTime(d) => format!(" office:value-type=\"date\" office:date-value=\"{}\"", d.format("%Y-%m-%dT%H:%M:%S")),
+        TableCell::Time(t) => format!(" office:value-type=\"time\" office:time-value=\"{}\"", t.format("PT%HH%MM%SS")),
+        TableCell::Currency(currency) => {
+            let code = match currency.symbol {
+                '€' => "EUR",
+                '£' => "GBP",
+                '¥' => "JPY",
+                _ => "USD",
+            };
+            format!(" office:value-type=\"currency\" office:currency=\"{}\" office:value=\"{}\"", code, currency.amount())
+        }
+        TableCell::Formula(f) => {
+            let value = match f.value {
+                Ok(value) => format!("office:value-type=\"float\" office:value=\"{}\"", value),
+                Err(_) => "office:value-type=\"string\"".to_string(),
+            };
+            format!(" table:formula=\"{}\" {}", escape(&to_ods_formula(&f.source)), value)
+        }
+    };
+    let note = note.map_or(String::new(), |note| {
+        let lines: String = note.lines().map(|line| format!("<text:p>{}</text:p>", escape(line))).collect();
+        format!("<office:annotation>{}</office:annotation>", lines)
+    });
+    let text = if *cell == TableCell::Empty { String::new() } else { format!("<text:p>{}</text:p>", text) };
+    if note.is_empty() && text.is_empty() {
+        return format!("<table:table-cell{}/>", attributes);
+    }
+    format!("<table:table-cell{}>{}{}</table:table-cell>", attributes, note, text)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// ODS writes references in brackets with the sheet before a dot, e.g.
+// of:=SUM([.A1:.B2])+[Sheet2.C3], and separates arguments with ;
+fn to_ods_formula(source: &str) -> String {
+    // Compiled once, a sheet can have a formula in every row
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let reference = REFERENCE.get_or_init(|| Regex::new(r"\b(?:([A-Za-z]\w*)!)?([A-Za-z]+\d+)\b(?:\s*:\s*([A-Za-z]+\d+)\b)?").unwrap());
+    let source = to_excel_formula(source).replace(',', ";");
+    let source = reference.replace_all(&source, |c: &Captures| {
+        let sheet = c.get(1).map_or("", |m| m.as_str());
+        match c.get(3) {
+            Some(end) => format!("[{}.{}:.{}]", sheet, &c[2], end.as_str()),
+            None => format!("[{}.{}]", sheet, &c[2]),
+        }
+    });
+    format!("of:={}", source)
+}
+
+fn from_ods_formula(source: &str) -> String {
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let reference = REFERENCE.get_or_init(|| Regex::new(r"\[\$?'?([^.\[\]':]*)'?\.\$?([A-Za-z]+)\$?(\d+)(?::\$?'?[^.\[\]':]*'?\.\$?([A-Za-z]+)\$?(\d+))?\]").unwrap());
+    let source = source.split_once('=').map_or(source, |(_, formula)| formula);
+    let source = reference.replace_all(source, |c: &Captures| {
+        let sheet = if c[1].is_empty() { String::new() } else { format!("{}!", &c[1]) };
+        match (c.get(4), c.get(5)) {
+            (Some(col), Some(row)) => format!("{}{}{}:{}{}", sheet, &c[2], &c[3], col.as_str(), row.as_str()),
+            _ => format!("{}{}{}", sheet, &c[2], &c[3]),
+        }
+    });
+    source.replace(';', ",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::parse_field;
+
+    fn content(rows: &[&[&str]]) -> TableContent {
+        TableContent::from_rows(rows.iter().map(|row| row.iter().map(|&field| parse_field(field.to_string(), None)).collect()).collect())
+    }
+
+    fn sources(rows: &[Vec<TableCell>]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(TableCell::source_string).collect()).collect()
+    }
+
+    // Writes two sheets to a file of that extension and reads them again
+    fn round_trip(extension: &str) {
+        let data: &[&[&str]] = &[
+            &["name", "count", "price", "ok", "day", "at", "time"],
+            &["apple", "3", "1.25", "true", "2024-01-31", "2024-01-31 12:30:00", "08:15:00"],
+            &["pear", "-7", "0.5", "false", "", "", ""],
+            // AVG would come back as AVERAGE
+            &["", "=SUM(B2:B3)", "=AVERAGE(C2:C3)*2", "", "", "", ""],
+        ];
+        let totals: &[&[&str]] = &[&["Total", "=Data!B4"]];
+        let mut styled = content(data);
+        styled.styles.insert((0, 0), CellStyle { bold: true, underline: true, ..CellStyle::default() });
+        styled.styles.insert((1, 2), CellStyle { italic: true, fg: Some(CellColor::Red), bg: Some(CellColor::Rgb(255, 128, 0)), ..CellStyle::default() });
+        styled.notes.insert((2, 0), "Two lines\n& <more>".to_string());
+        let format = |align, spec: &str| CellFormat { spec: Some(spec.to_string()).filter(|spec| !spec.is_empty()), align };
+        styled.formats.insert((0, 0), format(Some(Align::Center), ""));
+        styled.formats.insert((1, 1), format(None, "%d km"));
+        styled.formats.insert((1, 2), format(Some(Align::Right), "%,.2f"));
+        styled.formats.insert((1, 4), format(None, "%d.%m.%Y"));
+        let path = std::env::temp_dir().join(format!("visp-workbook-test-{}.{}", std::process::id(), extension));
+        write(&path, &[("Data", &styled), ("Sum up", &content(totals))]).unwrap();
+        let sheets = read(&path, &Progress::default());
+        std::fs::remove_file(&path).unwrap();
+        let sheets = sheets.unwrap();
+        let names: Vec<&str> = sheets.iter().map(|sheet| sheet.name.as_str()).collect();
+        assert_eq!(names, ["Data", "Sum up"]);
+        let expected = |rows: &[&[&str]]| -> Vec<Vec<String>> {
+            rows.iter().map(|row| {
+                let last = row.iter().rposition(|field| !field.is_empty()).map_or(0, |i| i + 1);
+                row[..last].iter().map(|field| field.to_string()).collect()
+            }).collect()
+        };
+        assert_eq!(sources(&sheets[0].rows), expected(data));
+        assert_eq!(sources(&sheets[1].rows), expected(totals));
+        assert_eq!(sheets[0].styles, styled.styles);
+        assert!(sheets[1].styles.is_empty());
+        assert_eq!(sheets[0].notes, styled.notes);
+        assert_eq!(sheets[0].formats, styled.formats);
+    }
+
+    #[test]
+    fn xlsx_round_trip() {
+        round_trip("xlsx");
+    }
+
+    #[test]
+    fn ods_round_trip() {
+        round_trip("ods");
+    }
+
+    #[test]
+    fn excel_codes() {
+        assert_eq!(spec_from_excel("#,##0.00;[Red]-#,##0.00").as_deref(), Some("%,.2f"));
+        assert_eq!(spec_from_excel("[$€-407]\\ #,##0.00").as_deref(), Some("€ %,.2f"));
+        assert_eq!(spec_from_excel("0\" km\"").as_deref(), Some("%d km"));
+        assert_eq!(spec_from_excel("m/d/yyyy h:mm").as_deref(), Some("%m/%d/%Y %H:%M"));
+        assert_eq!(spec_from_excel("dddd, mmmm d").as_deref(), Some("%A, %B %d"));
+        assert_eq!(spec_from_excel("0.00%"), None);
+        assert_eq!(spec_from_excel("#,##0,"), None);
+        assert_eq!(spec_from_excel("General"), None);
+        assert_eq!(spec_from_excel("h:mm AM/PM"), None);
+    }
+
+    #[test]
+    fn note_author() {
+        let path = std::env::temp_dir().join(format!("visp-workbook-test-{}-author.xlsx", std::process::id()));
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet();
+        worksheet.write_string(0, 0, "a").unwrap();
+        worksheet.insert_note(0, 0, &Note::new("Checked").set_author("Ann")).unwrap();
+        workbook.save(&path).unwrap();
+        let sheets = read(&path, &Progress::default());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sheets.unwrap()[0].notes[&(0, 0)], "Checked");
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(is_workbook(Path::new("a/b.XLSX")));
+        assert!(is_workbook(Path::new("b.ods")));
+        assert!(!is_workbook(Path::new("b.csv")));
+        assert!(write(Path::new("b.csv"), &[]).is_err());
+    }
+}